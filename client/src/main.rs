@@ -1,7 +1,6 @@
 mod scanner;
 
-use serde_json::{json, Map, Value};
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
 use structopt::StructOpt;
 
 #[allow(unused_imports)]
@@ -11,17 +10,28 @@ use scanner::*;
 struct Args {
     #[structopt(short, long)]
     coordinator: SocketAddr,
+
+    /// CA certificate of the servers, connect over TLS
+    #[structopt(long)]
+    ca: Option<PathBuf>,
 }
 
 use lib::{
-    interfaces::{CentCount, OrderReq, OrderType, Quantity, Ticker, UserID},
-    read_writer::ReadWriter,
+    instruments::{Instrument, Instruments},
+    interfaces::{
+        Asset, CancelFilter, Cash, CentCount, ClaimAccount, CoordinatorRequest,
+        CoordinatorResponse, Currency, DepositID, LedgerAccount, LoggedIn, Login, MarginStatus,
+        Memo, NewAccount, NodeRequest, NodeResponse, OrderReq, OrderType, Quantity, StockReq,
+        Ticker, TransferReq, UserID,
+    },
+    session::{CoordinatorSession, NodeSession},
+    statement::{StatementFormat, StatementReq},
+    tls::Tls,
     GResult,
 };
-use tokio::net::TcpStream;
 
 enum ApplicationFlow {
-    LoginToNode(SocketAddr, String),
+    LoginToNode(LoggedIn, UserID),
     Continue,
     Break,
 }
@@ -55,6 +65,9 @@ async fn main() {
     let args = Args::from_args();
 
     let ip_port: SocketAddr = args.coordinator;
+    let tls = args
+        .ca
+        .map(|ca| Tls::client(&ca).expect("Failed to load CA certificate"));
     println!("Contacting coordinator at {ip_port}");
 
     // Launch
@@ -65,27 +78,29 @@ async fn main() {
 
     print_actions();
     loop {
-        match handle_command_logged_out(&mut scanner, &ip_port).await {
+        match handle_command_logged_out(&mut scanner, &ip_port, tls.as_ref()).await {
             ApplicationFlow::Break => break,
             ApplicationFlow::Continue => (),
-            ApplicationFlow::LoginToNode(new_node_socket, account_id) => {
+            ApplicationFlow::LoginToNode(logged_in, user_id) => {
                 // logged in
-                let mut node_rw: ReadWriter = connect_to_server(&new_node_socket).await;
-
-                send_user_id(&mut node_rw, account_id.as_str())
+                println!("Connecting to {}", logged_in.node);
+                let mut session = NodeSession::new(&logged_in, tls.as_ref())
                     .await
-                    .expect("Error sending User ID");
+                    .expect("Failed to log in to node");
 
-                println!("-- Logged in as User {} --", &account_id);
+                println!("-- Logged in as User {user_id} --");
                 print_account_actions();
                 loop {
-                    match handle_command_logged_in(&mut scanner, &mut node_rw).await {
+                    match handle_command_logged_in(&mut scanner, &mut session).await {
                         ApplicationFlow::LoginToNode(_, _) => {
                             // If this happens, something very wrong is going on!
                             eprintln!("Error: Unexpected Login. Already logged in.");
                             break;
                         }
-                        ApplicationFlow::Break => break,
+                        ApplicationFlow::Break => {
+                            session.bye().await.expect("Failed to send goodbye to node");
+                            break;
+                        }
                         ApplicationFlow::Continue => (),
                     }
                 }
@@ -100,9 +115,12 @@ fn print_actions() {
     print!(
         r#"
 Choose an action:
-  c                Create a new account
-  l <account_id>   Login with your Account ID
-  q                Exit the application
+  c <password>                Create a new account
+  l <account_id> <password>  Login with your Account ID
+  a <account_id> <code> <password>
+                             Set the password of an account from before passwords, with
+                             the claim code an admin gave you
+  q                          Exit the application
 
 "#
     );
@@ -117,23 +135,31 @@ Choose an action:
   b <ticker> <price> <quantity>  Submit a buy order
   s <ticker> <price> <quantity>  Submit a sell order
   o                              View your submitted orders
-  a                              View current cash account balance
-  c <amount>                     Set cash account balance
-  p                              View your current stock portfolio
-  i <ticker> <quantity>          IPO: Add new stock to account
+  a                              View current cash account balances and margin
+  d <amount> [currency]          Deposit cash, in cents, credited once an admin confirms it
+  w <amount> [currency]          Withdraw cash, in cents
+  t <account_id> <amount> [cur]  Transfer cash, in cents, to another account
+  t <account_id> <ticker> <qty>  Transfer stock to another account
+  m                              View tradable instruments and currencies
+  l                              View your cash ledger
+  p                              View your stock portfolio, borrowed stock and P&L
+  i <ticker> <quantity>          IPO: Add new stock to account (market operators and admins)
+  x [ticker] [side] [min max]    Cancel your orders, all or those matching, * for any
+  k <on|off>                     Kill switch: cancel every order and stop new ones, or resume
+  e <from> <to> <fmt> [file]     Account statement, json or csv, between two times in seconds
+                                 since the epoch, printed or saved to a file
   q                              Exit the application
 
 "#
     );
 }
 
-async fn connect_to_server(ip_port: &SocketAddr) -> ReadWriter {
+async fn connect_to_coordinator(
+    ip_port: &SocketAddr,
+    tls: Option<&Tls>,
+) -> GResult<CoordinatorSession> {
     println!("Connecting to {ip_port}");
-    ReadWriter::new(
-        TcpStream::connect(ip_port)
-            .await
-            .expect("Failed to connect to server"),
-    )
+    CoordinatorSession::new(*ip_port, tls).await
 }
 
 fn print_remaining_input(scanner: &mut Scanner) {
@@ -144,44 +170,78 @@ fn print_remaining_input(scanner: &mut Scanner) {
     scanner.clear();
 }
 
-async fn handle_command_logged_out(scanner: &mut Scanner, ip_port: &SocketAddr) -> ApplicationFlow {
+async fn handle_command_logged_out(
+    scanner: &mut Scanner,
+    ip_port: &SocketAddr,
+    tls: Option<&Tls>,
+) -> ApplicationFlow {
     match scanner.next::<String>().as_str() {
         "c" => {
             //Create a new account
-            if !scanner.is_empty() {
-                eprintln!("Unexpected input after c: ");
-                print_remaining_input(scanner);
+            if scanner.is_empty() {
+                eprintln!("Invalid input: Expected <password>");
             } else {
-                let res = create_account(ip_port)
-                    .await
-                    .expect("Error creating account");
-                println!("New account created: {}.{}", res.node_id, res.id);
+                let password = scanner.next::<String>();
+                if !scanner.is_empty() {
+                    eprint!("Unexpected input after password: ");
+                    print_remaining_input(scanner);
+                } else {
+                    match create_account(ip_port, tls, password).await {
+                        Ok(res) => println!("New account created: {}.{}", res.node_id, res.id),
+                        Err(e) => eprintln!("Error creating account: {e}"),
+                    }
+                }
             }
         }
         "l" => {
             //Login with your Account ID
             if scanner.is_empty() {
-                eprintln!("Invalid input: Expected <account_id>");
+                eprintln!("Invalid input: Expected <account_id> <password>");
             } else {
                 let entered_account_id = scanner.next::<String>();
 
-                if !scanner.is_empty() {
-                    eprint!("Unexpected input after account_id: ");
-                    print_remaining_input(scanner);
+                if scanner.is_empty() {
+                    eprintln!("Invalid input after account_id: Expected <password>");
                 } else {
-                    match login(ip_port, &entered_account_id).await {
-                        Err(e) => {
-                            scanner.clear();
-                            eprintln!("{}", e);
-                            return ApplicationFlow::Continue;
-                        }
-                        Ok(socket_addr) => {
-                            return ApplicationFlow::LoginToNode(socket_addr, entered_account_id);
+                    let password = scanner.next::<String>();
+                    if !scanner.is_empty() {
+                        eprint!("Unexpected input after password: ");
+                        print_remaining_input(scanner);
+                    } else {
+                        match login(ip_port, tls, &entered_account_id, password).await {
+                            Err(e) => {
+                                scanner.clear();
+                                eprintln!("{}", e);
+                                return ApplicationFlow::Continue;
+                            }
+                            Ok((logged_in, user_id)) => {
+                                return ApplicationFlow::LoginToNode(logged_in, user_id);
+                            }
                         }
                     }
                 }
             }
         }
+        "a" => {
+            // Claim an account from before passwords
+            let mut args = Vec::new();
+            while args.len() < 3 && !scanner.is_empty() {
+                args.push(scanner.next::<String>());
+            }
+            if args.len() < 3 {
+                eprintln!("Invalid input: Expected <account_id> <code> <password>");
+            } else if !scanner.is_empty() {
+                eprint!("Unexpected input after password: ");
+                print_remaining_input(scanner);
+            } else {
+                let password = args.pop().expect("three args");
+                let code = args.pop().expect("three args");
+                match claim_account(ip_port, tls, &args[0], code, password).await {
+                    Ok(()) => println!("Account claimed, log in with your new password."),
+                    Err(e) => eprintln!("Error claiming account: {e}"),
+                }
+            }
+        }
         "q" => {
             // Exit the application
             scanner.clear();
@@ -238,19 +298,146 @@ fn get_tq_input(scanner: &mut Scanner) -> GResult<(Ticker, Quantity)> {
     Ok((ticker, quantity))
 }
 
-fn get_q_input(scanner: &mut Scanner) -> GResult<Quantity> {
+/// `on` or `off`
+fn get_switch_input(scanner: &mut Scanner) -> GResult<bool> {
     if scanner.is_empty() {
-        return Err(Box::from("Invalid input: Expected <quantity>"));
+        return Err(Box::from("Invalid input: Expected <on|off>"));
+    }
+    let engaged = match scanner.next::<String>().as_str() {
+        "on" => true,
+        "off" => false,
+        other => return Err(format!("Invalid input: Expected on or off, got {other}").into()),
+    };
+    if !scanner.is_empty() {
+        print_remaining_input(scanner);
+        return Err(Box::from("Unexpected input after on or off: "));
+    }
+    Ok(engaged)
+}
+
+/// `<from> <to> <json|csv> [file]`
+fn get_statement_input(scanner: &mut Scanner) -> GResult<(StatementReq, Option<PathBuf>)> {
+    if scanner.is_empty() {
+        return Err(Box::from(
+            "Invalid input: Expected <from> <to> <json|csv> [file]",
+        ));
+    }
+    let from = scanner.next::<u64>();
+    if scanner.is_empty() {
+        return Err(Box::from(
+            "Invalid input after from: Expected <to> <json|csv> [file]",
+        ));
+    }
+    let to = scanner.next::<u64>();
+    if scanner.is_empty() {
+        return Err(Box::from(
+            "Invalid input after to: Expected <json|csv> [file]",
+        ));
+    }
+    let format = match scanner.next::<String>().as_str() {
+        "json" => StatementFormat::Json,
+        "csv" => StatementFormat::Csv,
+        other => return Err(format!("Invalid input: Expected json or csv, got {other}").into()),
+    };
+    let file = (!scanner.is_empty()).then(|| scanner.next::<PathBuf>());
+    if !scanner.is_empty() {
+        print_remaining_input(scanner);
+        return Err(Box::from("Unexpected input after file: "));
+    }
+    Ok((StatementReq { from, to, format }, file))
+}
+
+/// `[ticker|*] [buy|sell|*] [min max]`, `*` or nothing for any
+fn get_cancel_input(scanner: &mut Scanner) -> GResult<CancelFilter> {
+    let mut filter = CancelFilter::default();
+    if scanner.is_empty() {
+        return Ok(filter);
+    }
+    filter.ticker = Some(scanner.next::<Ticker>()).filter(|ticker| ticker != "*");
+    if scanner.is_empty() {
+        return Ok(filter);
+    }
+    filter.side = match scanner.next::<String>().as_str() {
+        "buy" => Some(OrderType::Buy),
+        "sell" => Some(OrderType::Sell),
+        "*" => None,
+        other => return Err(format!("Invalid input: Expected buy, sell or *, got {other}").into()),
+    };
+    if scanner.is_empty() {
+        return Ok(filter);
+    }
+    filter.min_price = Some(scanner.next::<CentCount>());
+    if scanner.is_empty() {
+        return Err(Box::from(
+            "Invalid input after min price: Expected <max price>",
+        ));
+    }
+    filter.max_price = Some(scanner.next::<CentCount>());
+    if !scanner.is_empty() {
+        print_remaining_input(scanner);
+        return Err(Box::from("Unexpected input after max price: "));
+    }
+    Ok(filter)
+}
+
+/// `<amount> [currency]`, `None` for the exchange's default currency
+fn get_cash_input(scanner: &mut Scanner) -> GResult<(CentCount, Option<Currency>)> {
+    if scanner.is_empty() {
+        return Err(Box::from("Invalid input: Expected <amount> [currency]"));
+    }
+    let amount = scanner.next::<CentCount>();
+    let currency = (!scanner.is_empty()).then(|| scanner.next::<Currency>());
+    if !scanner.is_empty() {
+        print_remaining_input(scanner);
+        return Err(Box::from("Unexpected input after currency: "));
+    }
+    Ok((amount, currency))
+}
+
+/// What to transfer, cash without a currency is in the exchange's default currency
+enum TransferInput {
+    Cash(CentCount, Option<Currency>),
+    Stock(Ticker, Quantity),
+}
+
+/// `<account_id> <amount> [currency]` for cash or `<account_id> <ticker> <quantity>` for stock
+fn get_transfer_input(scanner: &mut Scanner) -> GResult<(UserID, TransferInput)> {
+    if scanner.is_empty() {
+        return Err(Box::from(
+            "Invalid input: Expected <account_id> <amount> [currency] or <account_id> <ticker> <quantity>",
+        ));
+    }
+    let to =
+        UserID::from_str(&scanner.next::<String>()).map_err(|_| "Invalid format for User ID")?;
+    if scanner.is_empty() {
+        return Err(Box::from(
+            "Invalid input after account_id: Expected <amount> [currency] or <ticker> <quantity>",
+        ));
+    }
+    let amount_or_ticker = scanner.next::<String>();
+    if let Ok(amount) = amount_or_ticker.parse() {
+        let currency = (!scanner.is_empty()).then(|| scanner.next::<Currency>());
+        if !scanner.is_empty() {
+            print_remaining_input(scanner);
+            return Err(Box::from("Unexpected input after currency: "));
+        }
+        return Ok((to, TransferInput::Cash(amount, currency)));
+    }
+    if scanner.is_empty() {
+        return Err(Box::from("Invalid input after ticker: Expected <quantity>"));
     }
     let quantity = scanner.next::<Quantity>();
     if !scanner.is_empty() {
         print_remaining_input(scanner);
         return Err(Box::from("Unexpected input after quantity: "));
     }
-    Ok(quantity)
+    Ok((to, TransferInput::Stock(amount_or_ticker, quantity)))
 }
 
-async fn handle_command_logged_in(scanner: &mut Scanner, rw: &mut ReadWriter) -> ApplicationFlow {
+async fn handle_command_logged_in(
+    scanner: &mut Scanner,
+    session: &mut NodeSession,
+) -> ApplicationFlow {
     match scanner.next::<String>().as_str() {
         "b" => {
             //Submit a buy order
@@ -259,17 +446,9 @@ async fn handle_command_logged_in(scanner: &mut Scanner, rw: &mut ReadWriter) ->
                     eprintln!("{}", e);
                 }
                 Ok((ticker, price, quantity)) => {
-                    match submit_order(rw, OrderType::Buy, ticker, price, quantity).await {
-                        Ok(res) => {
-                            if res == "ok" {
-                                println!("Buy order submitted");
-                            } else {
-                                println!("{res}");
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("{e}");
-                        }
+                    match submit_order(session, OrderType::Buy, ticker, price, quantity).await {
+                        Ok(()) => println!("Buy order submitted"),
+                        Err(e) => eprintln!("{e}"),
                     }
                 }
             }
@@ -281,17 +460,9 @@ async fn handle_command_logged_in(scanner: &mut Scanner, rw: &mut ReadWriter) ->
                     eprintln!("{}", e);
                 }
                 Ok((ticker, price, quantity)) => {
-                    match submit_order(rw, OrderType::Sell, ticker, price, quantity).await {
-                        Ok(res) => {
-                            if res == "ok" {
-                                println!("Sell order submitted");
-                            } else {
-                                println!("{res}");
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("{e}");
-                        }
+                    match submit_order(session, OrderType::Sell, ticker, price, quantity).await {
+                        Ok(()) => println!("Sell order submitted"),
+                        Err(e) => eprintln!("{e}"),
                     }
                 }
             }
@@ -302,7 +473,9 @@ async fn handle_command_logged_in(scanner: &mut Scanner, rw: &mut ReadWriter) ->
                 eprint!("Unexpected input: ");
                 print_remaining_input(scanner);
             }
-            print_orders(rw).await.expect("Error printing orders");
+            if let Err(e) = print_orders(session).await {
+                eprintln!("Error printing orders: {e}");
+            }
         }
         "a" => {
             //See current account details
@@ -310,37 +483,75 @@ async fn handle_command_logged_in(scanner: &mut Scanner, rw: &mut ReadWriter) ->
                 eprint!("Unexpected input: ");
                 print_remaining_input(scanner);
             }
-            print_balance(rw)
-                .await
-                .expect("Error printing account balance");
+            if let Err(e) = print_balance(session).await {
+                eprintln!("Error printing account balance: {e}");
+            }
         }
-        "c" => {
-            //Set cash account balance
-            match get_q_input(scanner) {
+        "d" => {
+            //Deposit cash
+            match get_cash_input(scanner) {
                 Err(e) => {
                     eprintln!("{}", e);
                 }
-                Ok(quantity) => match set_balance(rw, quantity).await {
-                    Ok(res) => {
-                        if res == "ok" {
-                            println!("Account balance updated");
-                        } else {
-                            println!("{res}");
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("{e}");
-                    }
+                Ok((amount, currency)) => match deposit(session, amount, currency).await {
+                    Ok(id) => println!("Deposit {id} is waiting for an admin to confirm it"),
+                    Err(e) => eprintln!("{e}"),
+                },
+            }
+        }
+        "w" => {
+            //Withdraw cash
+            match get_cash_input(scanner) {
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+                Ok((amount, currency)) => match withdraw(session, amount, currency).await {
+                    Ok(()) => println!("Withdrawn"),
+                    Err(e) => eprintln!("{e}"),
+                },
+            }
+        }
+        "t" => {
+            //Transfer cash or stock to another account
+            match get_transfer_input(scanner) {
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+                Ok((to, what)) => match transfer(session, to, what).await {
+                    Ok(()) => println!("Transferred"),
+                    Err(e) => eprintln!("{e}"),
                 },
             }
         }
+        "m" => {
+            //See instruments
+            if !scanner.is_empty() {
+                eprint!("Unexpected input: ");
+                print_remaining_input(scanner);
+            }
+            if let Err(e) = print_instruments(session).await {
+                eprintln!("Error printing instruments: {e}");
+            }
+        }
+        "l" => {
+            //See cash ledger
+            if !scanner.is_empty() {
+                eprint!("Unexpected input: ");
+                print_remaining_input(scanner);
+            }
+            if let Err(e) = print_ledger(session).await {
+                eprintln!("Error printing ledger: {e}");
+            }
+        }
         "p" => {
             //See current stock portfolio
             if !scanner.is_empty() {
                 eprint!("Unexpected input: ");
                 print_remaining_input(scanner);
             }
-            print_portfolio(rw).await.expect("Error printing portfolio");
+            if let Err(e) = print_portfolio(session).await {
+                eprintln!("Error printing portfolio: {e}");
+            }
         }
         "i" => {
             //IPO
@@ -348,26 +559,54 @@ async fn handle_command_logged_in(scanner: &mut Scanner, rw: &mut ReadWriter) ->
                 Err(e) => {
                     eprintln!("{}", e);
                 }
-                Ok((ticker, quantity)) => match ipo(rw, ticker, quantity).await {
-                    Ok(res) => {
-                        if res == "ok" {
-                            println!("IPO submitted");
-                        } else {
-                            println!("{res}");
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("{e}");
+                Ok((ticker, quantity)) => match ipo(session, ticker, quantity).await {
+                    Ok(()) => println!("IPO submitted"),
+                    Err(e) => eprintln!("{e}"),
+                },
+            }
+        }
+        "x" => {
+            //Cancel orders matching a filter
+            match get_cancel_input(scanner) {
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+                Ok(filter) => {
+                    if let Err(e) = cancel_orders(session, filter).await {
+                        eprintln!("Error cancelling orders: {e}");
                     }
+                }
+            }
+        }
+        "k" => {
+            //Kill switch
+            match get_switch_input(scanner) {
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+                Ok(engaged) => match request_ok(session, NodeRequest::KillSwitch(engaged)).await {
+                    Ok(()) if engaged => println!("Kill switch engaged, orders cancelled"),
+                    Ok(()) => println!("Kill switch released"),
+                    Err(e) => eprintln!("{e}"),
                 },
             }
         }
+        "e" => {
+            //Account statement
+            match get_statement_input(scanner) {
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+                Ok((req, file)) => {
+                    if let Err(e) = statement(session, req, file).await {
+                        eprintln!("Error getting statement: {e}");
+                    }
+                }
+            }
+        }
         "q" => {
             // Exit the application
             scanner.clear();
-            rw.write_line(r#""bye""#)
-                .await
-                .expect("Failed to send goodbye to node");
             println!("Shutting down.");
             return ApplicationFlow::Break;
         }
@@ -381,133 +620,392 @@ async fn handle_command_logged_in(scanner: &mut Scanner, rw: &mut ReadWriter) ->
     ApplicationFlow::Continue
 }
 
-async fn create_account(ip_port: &SocketAddr) -> GResult<UserID> {
-    let mut rw: ReadWriter = connect_to_server(ip_port).await;
-    rw.write_line(r#""C account""#).await?;
-    let userid: UserID = serde_json::from_str(&rw.read_line().await?)?;
-    Ok(userid)
+async fn create_account(
+    ip_port: &SocketAddr,
+    tls: Option<&Tls>,
+    password: String,
+) -> GResult<UserID> {
+    let mut session = connect_to_coordinator(ip_port, tls).await?;
+    let res = session
+        .request(CoordinatorRequest::CreateAccount(NewAccount { password }))
+        .await?;
+    session.bye().await?;
+    match res {
+        CoordinatorResponse::Account(user_id) => Ok(user_id),
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
 }
 
-async fn send_user_id(rw: &mut ReadWriter, account_id: &str) -> GResult<()> {
+async fn claim_account(
+    ip_port: &SocketAddr,
+    tls: Option<&Tls>,
+    account_id: &str,
+    code: String,
+    password: String,
+) -> GResult<()> {
     let user_id: UserID = UserID::from_str(account_id).map_err(|_| "Invalid format for User ID")?;
-    let message: String =
-        serde_json::to_string::<UserID>(&user_id).map_err(|_| "Error serialising User ID")?;
-    rw.write_line(&message)
-        .await
-        .map_err(|_| "Error writing User ID to ReadWriter")?;
-    Ok(())
+    let mut session = connect_to_coordinator(ip_port, tls).await?;
+    let res = session
+        .request(CoordinatorRequest::ClaimAccount(ClaimAccount {
+            user_id,
+            code,
+            password,
+        }))
+        .await?;
+    session.bye().await?;
+    match res {
+        CoordinatorResponse::Ok => Ok(()),
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
 }
 
-async fn login(ip_port: &SocketAddr, account_id: &str) -> GResult<SocketAddr> {
-    let mut rw: ReadWriter = connect_to_server(ip_port).await;
-
-    send_user_id(&mut rw, account_id).await?;
+async fn login(
+    ip_port: &SocketAddr,
+    tls: Option<&Tls>,
+    account_id: &str,
+    password: String,
+) -> GResult<(LoggedIn, UserID)> {
+    let user_id: UserID = UserID::from_str(account_id).map_err(|_| "Invalid format for User ID")?;
+    let mut session = connect_to_coordinator(ip_port, tls).await?;
+    let res = session
+        .request(CoordinatorRequest::Login(Login { user_id, password }))
+        .await?;
+    session.bye().await?;
+    match res {
+        CoordinatorResponse::LoggedIn(logged_in) => Ok((logged_in, user_id)),
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
+}
 
-    let node_address = SocketAddr::from_str(&rw.read_line().await?)?;
-    Ok(node_address)
+/// Send a request which is answered with `NodeResponse::Ok`
+async fn request_ok(session: &mut NodeSession, req: NodeRequest) -> GResult<()> {
+    match session.request(req).await? {
+        NodeResponse::Ok => Ok(()),
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
 }
 
 async fn submit_order(
-    rw: &mut ReadWriter,
+    session: &mut NodeSession,
     order_type: OrderType,
     ticker: Ticker,
     price: CentCount,
     quantity: Quantity,
-) -> GResult<String> {
+) -> GResult<()> {
     let order_req: OrderReq = OrderReq {
         order_type,
         ticker,
         price,
         quantity,
     };
-    let msg_json = json!({
-        "type": "C order",
-        "value": order_req
-    });
-    let message = serde_json::to_string(&msg_json).expect("Failed to build trade request");
-
-    rw.write_line(&message).await?;
-
-    let res: String = serde_json::from_str(&rw.read_line().await?)?;
-    Ok(res)
+    request_ok(session, NodeRequest::CreateOrder(order_req)).await
 }
 
-async fn print_balance(rw: &mut ReadWriter) -> GResult<()> {
-    let msg_json = json!({
-        "type": "R balance"
-    });
-    let message = serde_json::to_string(&msg_json).expect("Failed to build balance request");
+async fn read_instruments(session: &mut NodeSession) -> GResult<Instruments> {
+    match session.request(NodeRequest::ReadInstruments).await? {
+        NodeResponse::Instruments(instruments) => Ok(instruments),
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
+}
 
-    rw.write_line(&message).await?;
+/// `amount` of `currency`, or of the exchange's default currency
+async fn cash(
+    session: &mut NodeSession,
+    amount: CentCount,
+    currency: Option<Currency>,
+) -> GResult<Cash> {
+    let currency = match currency {
+        Some(currency) => currency,
+        None => read_instruments(session).await?.default_currency().clone(),
+    };
+    Ok(Cash { currency, amount })
+}
 
-    let res: u64 = serde_json::from_str(&rw.read_line().await?)?;
-    println!("Current account balance: ${}.{}", res / 100, res % 100);
-    Ok(())
+async fn deposit(
+    session: &mut NodeSession,
+    amount: CentCount,
+    currency: Option<Currency>,
+) -> GResult<DepositID> {
+    let cash = cash(session, amount, currency).await?;
+    match session.request(NodeRequest::Deposit(cash)).await? {
+        NodeResponse::Deposit(id) => Ok(id),
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
 }
 
-async fn set_balance(rw: &mut ReadWriter, quantity: u64) -> GResult<String> {
-    let msg_json = json!({
-        "type": "U balance",
-        "value": quantity
-    });
+async fn withdraw(
+    session: &mut NodeSession,
+    amount: CentCount,
+    currency: Option<Currency>,
+) -> GResult<()> {
+    let cash = cash(session, amount, currency).await?;
+    request_ok(session, NodeRequest::Withdraw(cash)).await
+}
 
-    let message = serde_json::to_string(&msg_json).expect("Failed to build balance update request");
+async fn transfer(session: &mut NodeSession, to: UserID, what: TransferInput) -> GResult<()> {
+    let asset = match what {
+        TransferInput::Cash(amount, currency) => {
+            Asset::Cash(cash(session, amount, currency).await?)
+        }
+        TransferInput::Stock(ticker, quantity) => Asset::Stock { ticker, quantity },
+    };
+    request_ok(session, NodeRequest::Transfer(TransferReq { to, asset })).await
+}
 
-    rw.write_line(&message).await?;
+async fn print_balance(session: &mut NodeSession) -> GResult<()> {
+    match session.request(NodeRequest::ReadBalance).await? {
+        NodeResponse::Balance(res) => {
+            println!("Current account balances:");
+            for (currency, balance) in res.iter() {
+                println!(" {}", money(*balance, currency));
+            }
+        }
+        res => return Err(format!("Unexpected response {res:?}").into()),
+    }
+    match session.request(NodeRequest::ReadDeposits).await? {
+        NodeResponse::Deposits(deposits) => {
+            for (id, cash) in deposits {
+                println!(
+                    " deposit {id} of {} waiting for an admin",
+                    money(cash.amount, &cash.currency)
+                );
+            }
+        }
+        res => return Err(format!("Unexpected response {res:?}").into()),
+    }
+    match session.request(NodeRequest::ReadMargin).await? {
+        NodeResponse::Margin(MarginStatus {
+            terms: Some(terms),
+            liquidating,
+            currencies,
+        }) => {
+            let liquidating = if liquidating {
+                ", being liquidated"
+            } else {
+                ""
+            };
+            println!(
+                "Margin account, {}x with {}% maintenance{liquidating}:",
+                terms.leverage, terms.maintenance_pct,
+            );
+            let used = currencies
+                .iter()
+                .filter(|(_, usage)| usage.exposure > 0 || usage.loan > 0);
+            for (currency, usage) in used {
+                println!(
+                    " {currency}: equity {}, positions {}, loan {}, buying power {}",
+                    signed(usage.equity),
+                    decimal(usage.exposure),
+                    decimal(usage.loan),
+                    decimal(usage.buying_power)
+                );
+            }
+            Ok(())
+        }
+        NodeResponse::Margin(_) => Ok(()),
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
+}
 
-    let res: String = serde_json::from_str(&rw.read_line().await?)?;
-    Ok(res)
+async fn print_instruments(session: &mut NodeSession) -> GResult<()> {
+    let instruments = read_instruments(session).await?;
+    println!("Currencies: {}", instruments.currencies.join(", "));
+    println!(
+        "Stock not listed below is priced in {}",
+        instruments.default_currency()
+    );
+    for (ticker, instrument) in instruments.tickers.iter() {
+        match instrument {
+            Instrument::Stock { currency } => println!(" {ticker}: stock priced in {currency}"),
+            Instrument::Fx {
+                currency,
+                base,
+                lot,
+            } => println!(
+                " {ticker}: {} per lot, priced in {currency}",
+                money(*lot, base)
+            ),
+        }
+    }
+    Ok(())
 }
 
-async fn print_portfolio(rw: &mut ReadWriter) -> GResult<()> {
-    let msg_json = json!({
-        "type": "R stock"
-    });
-    let message = serde_json::to_string(&msg_json).expect("Failed to build portfolio request");
+/// Prices are in cents of their instrument's currency
+fn decimal(cents: CentCount) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
 
-    rw.write_line(&message).await?;
+fn signed(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    format!("{sign}{}", decimal(cents.unsigned_abs()))
+}
 
-    let res: Map<String, Value> = serde_json::from_str(&rw.read_line().await?)?;
+fn money(cents: CentCount, currency: &str) -> String {
+    format!("{} {currency}", decimal(cents))
+}
 
-    println!("Current portfolio:");
-    for (k, v) in res.iter() {
-        println!(" {k}: {v}");
+fn ledger_account(account: LedgerAccount) -> String {
+    match account {
+        LedgerAccount::User(user_id) => user_id.to_string(),
+        LedgerAccount::External => "outside".to_owned(),
     }
-
-    Ok(())
 }
 
-async fn print_orders(rw: &mut ReadWriter) -> GResult<()> {
-    let msg_json = json!({
-        "type": "R order"
-    });
-    let message = serde_json::to_string(&msg_json).expect("Failed to build order view request");
-
-    rw.write_line(&message).await?;
-
-    let res: Map<String, Value> = serde_json::from_str(&rw.read_line().await?)?;
+async fn print_ledger(session: &mut NodeSession) -> GResult<()> {
+    match session.request(NodeRequest::ReadLedger).await? {
+        NodeResponse::Ledger(entries) => {
+            println!("Cash ledger:");
+            for entry in entries {
+                let memo = match entry.memo {
+                    Memo::Deposit => "deposit".to_owned(),
+                    Memo::Withdrawal => "withdrawal".to_owned(),
+                    Memo::Trade {
+                        ticker,
+                        quantity,
+                        price,
+                    } => format!("trade {quantity} {ticker} @ {}", decimal(price)),
+                    Memo::Fx {
+                        ticker,
+                        quantity,
+                        price,
+                    } => format!("fx {quantity} {ticker} @ {}", decimal(price)),
+                    Memo::Adjustment { by } => format!("adjustment by {by}"),
+                    Memo::Opening => "opening balance".to_owned(),
+                    Memo::Transfer => "transfer".to_owned(),
+                    Memo::Dividend {
+                        ticker,
+                        quantity,
+                        per_share,
+                    } => format!("dividend for {quantity} {ticker} @ {}", decimal(per_share)),
+                    Memo::Fee {
+                        ticker,
+                        quantity,
+                        price,
+                        ..
+                    } => format!("fee for {quantity} {ticker} @ {}", decimal(price)),
+                    Memo::MarginLoan => "margin loan".to_owned(),
+                    Memo::MarginRepayment => "margin repayment".to_owned(),
+                };
+                println!(
+                    " {}: {} from {} to {}, {memo}",
+                    entry.id,
+                    money(entry.amount, &entry.currency),
+                    ledger_account(entry.from),
+                    ledger_account(entry.to)
+                );
+            }
+            Ok(())
+        }
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
+}
 
-    println!("Pending orders:");
-    for (k, v) in res.iter() {
-        // let buysell: Map<String, Value> = serde_json::from_value(v.clone())?;
-        println!(" {k}: {v}");
+async fn print_portfolio(session: &mut NodeSession) -> GResult<()> {
+    match session.request(NodeRequest::ReadStock).await? {
+        NodeResponse::Stock(res) => {
+            println!("Current portfolio:");
+            for (k, v) in res.iter() {
+                println!(" {k}: {v}");
+            }
+        }
+        res => return Err(format!("Unexpected response {res:?}").into()),
+    }
+    match session.request(NodeRequest::ReadBorrowed).await? {
+        NodeResponse::Borrowed(res) => {
+            for (ticker, borrowed) in res.iter() {
+                println!(
+                    " {ticker}: {} borrowed, {} set aside",
+                    borrowed.quantity,
+                    money(borrowed.collateral.amount, &borrowed.collateral.currency)
+                );
+            }
+        }
+        res => return Err(format!("Unexpected response {res:?}").into()),
     }
+    match session.request(NodeRequest::ReadPositions).await? {
+        NodeResponse::Positions(res) => {
+            println!("Cost basis and P&L:");
+            for (ticker, position) in res.iter() {
+                let unrealised = match (position.unrealised, position.mark) {
+                    (Some(unrealised), Some(mark)) => {
+                        format!("{} at {}", signed(unrealised), decimal(mark))
+                    }
+                    _ => "unknown".to_owned(),
+                };
+                println!(
+                    " {ticker}: {} at {} average, realised {}, unrealised {unrealised}",
+                    position.quantity,
+                    decimal(position.average_cost),
+                    signed(position.realised)
+                );
+            }
+            Ok(())
+        }
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
+}
 
-    Ok(())
+async fn print_orders(session: &mut NodeSession) -> GResult<()> {
+    match session.request(NodeRequest::ReadOrders).await? {
+        NodeResponse::Orders(res) => {
+            println!("Pending orders:");
+            for (ticker, orders) in res.0.iter() {
+                for (side, levels) in [("buy", &orders.buy), ("sell", &orders.sell)] {
+                    for level in levels {
+                        println!(" {ticker}: {side} {} @ {}", level.quantity, level.price);
+                    }
+                }
+            }
+            Ok(())
+        }
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
 }
 
-async fn ipo(rw: &mut ReadWriter, ticker: Ticker, quantity: Quantity) -> GResult<String> {
-    let msg_json = json!({
-        "type": "C stock",
-        "value": {
-            "ticker_id": ticker,
-            "quantity": quantity
+async fn cancel_orders(session: &mut NodeSession, filter: CancelFilter) -> GResult<()> {
+    match session.request(NodeRequest::CancelOrders(filter)).await? {
+        NodeResponse::Cancelled(cancelled) => {
+            println!("Cancelled {} orders:", cancelled.len());
+            for OrderReq {
+                order_type,
+                ticker,
+                price,
+                quantity,
+            } in cancelled
+            {
+                println!(" {ticker}: {order_type:?} {quantity} @ {price}");
+            }
+            Ok(())
         }
-    });
-    let message = serde_json::to_string(&msg_json).expect("Failed to build IPO request");
+        res => Err(format!("Unexpected response {res:?}").into()),
+    }
+}
 
-    rw.write_line(&message).await?;
+/// Printed, or written to `file`
+async fn statement(
+    session: &mut NodeSession,
+    req: StatementReq,
+    file: Option<PathBuf>,
+) -> GResult<()> {
+    let statement = match session.request(NodeRequest::ReadStatement(req)).await? {
+        NodeResponse::Statement(statement) => serde_json::to_string_pretty(&statement)?,
+        NodeResponse::StatementCsv(csv) => csv,
+        res => return Err(format!("Unexpected response {res:?}").into()),
+    };
+    match file {
+        Some(file) => {
+            tokio::fs::write(&file, statement).await?;
+            println!("Statement saved to {}", file.display());
+        }
+        None => println!("{statement}"),
+    }
+    Ok(())
+}
 
-    let res: String = serde_json::from_str(&rw.read_line().await?)?;
-    Ok(res)
+async fn ipo(session: &mut NodeSession, ticker: Ticker, quantity: Quantity) -> GResult<()> {
+    request_ok(
+        session,
+        NodeRequest::CreateStock(StockReq { ticker, quantity }),
+    )
+    .await
 }
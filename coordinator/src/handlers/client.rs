@@ -1,78 +1,647 @@
-use lib::{interfaces::UserID, lock::DeadLockDetect, read_writer::ReadWriter, GResult};
-use std::{str::FromStr, sync::Arc};
-use tokio::sync::oneshot;
+use lib::{
+    auth::{generate_claim_code, hash_password, verify_password},
+    interfaces::{
+        ClaimAccount, CoordinatorHello, CoordinatorRequest, CoordinatorResponse, Distribution,
+        Dividend, DividendID, ErrorCode, ErrorResponse, LoggedIn, Login, NewAccount, NodeID,
+        Payout, Request, Response, Role, ScheduledDividend, SetRole, Split, UserID, Welcome,
+        PROTOCOL_VERSION,
+    },
+    lock::DeadLockDetect,
+    now,
+    read_writer::{is_closed, ReadWriter},
+    GResult,
+};
+use serde_json::Value;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::{sync::oneshot, task::spawn_blocking, time::sleep};
 
-use super::node::Message;
-use crate::State;
+use super::node::{AdminOp, Message};
+use crate::{state::Credential, State};
 
-pub enum FirstLine {
-    CAccount,
-    FindNode(UserID),
-}
+pub struct FirstLine(CoordinatorHello);
 
 impl FromStr for FirstLine {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Ok(user_id) = serde_json::from_str(s) {
-            Ok(FirstLine::FindNode(user_id))
-        } else if s == "\"C account\"" {
-            Ok(FirstLine::CAccount)
-        } else {
-            Err("Did not match first line for client".into())
-        }
+        Ok(FirstLine(serde_json::from_str(s).map_err(|_| {
+            "Did not match first line for client".to_owned()
+        })?))
     }
 }
 
-pub async fn handler(
-    first_line: FirstLine,
-    mut rw: ReadWriter,
-    state: Arc<State>,
-) -> GResult<String> {
+/// Parse a request line, an error response carries the request id if there was one
+fn parse_request(line: &str) -> Result<Request<CoordinatorRequest>, Response<CoordinatorResponse>> {
+    let bad_request = |id, e: serde_json::Error| Response {
+        id,
+        result: Err(ErrorResponse::new(ErrorCode::BadRequest, e.to_string())),
+    };
+    let Request { id, body } =
+        serde_json::from_str::<Request<Value>>(line).map_err(|e| bad_request(None, e))?;
+    let body = serde_json::from_value(body).map_err(|e| bad_request(Some(id), e))?;
+    Ok(Request { id, body })
+}
+
+async fn find_node(user_id: UserID, state: &Arc<State>) -> GResult<CoordinatorResponse> {
+    let node_records = state.node_records.read().dl("cl32").await;
+    let record = node_records
+        .get_records()
+        .get(user_id.node_id)
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No node for {user_id}")))?;
+    println!("Found node {} for account {user_id}.", record.address);
+    Ok(CoordinatorResponse::Node(record.address))
+}
+
+async fn create_account(
+    NewAccount { password }: NewAccount,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    let hash = spawn_blocking(move || hash_password(&password))
+        .await?
+        .map_err(|e| ErrorResponse::new(ErrorCode::BadRequest, e.to_string()))?;
+    let user_id = open_account(hash, Role::Trader, state).await?;
+    Ok(CoordinatorResponse::Account(user_id))
+}
+
+/// Create the admin account given on the command line, unless there already is an admin
+pub async fn create_admin(state: &Arc<State>) -> GResult<()> {
+    let mut admin = state.admin.lock().dl("cl57").await;
+    let Some(hash) = admin.take() else {
+        return Ok(());
+    };
+    if state.credentials.read().dl("cl61").await.has_admin() {
+        println!("Not creating the admin account, there already is an admin.");
+        return Ok(());
+    }
+    if let Err(e) = open_account(hash.clone(), Role::Admin, state).await {
+        // try again once the next node joins
+        *admin = Some(hash);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Open an account on the node with the fewest, with the password `hash`
+async fn open_account(hash: String, role: Role, state: &Arc<State>) -> GResult<UserID> {
     let node_records = state.node_records.read().dl("cl32").await;
     let node_records = node_records.get_records();
-    match first_line {
-        FirstLine::FindNode(user_id) => {
-            let addr = node_records[user_id.node_id].address;
+    let mut account_nums = state.account_nums.write().dl("45").await;
+
+    let mut min_acc = 0;
+    let a_nums = account_nums.get_nums();
+    if a_nums.is_empty() {
+        return Err(ErrorResponse::new(ErrorCode::NotFound, "No node has joined yet").into());
+    }
+    for i in 0..a_nums.len() {
+        if a_nums[i] < a_nums[min_acc] {
+            min_acc = i;
+        }
+    }
+    let min_num = a_nums[min_acc];
+    account_nums.set_num(min_acc, min_num + 1).await;
+
+    let (sender, recver) = oneshot::channel();
+
+    node_records[min_acc]
+        .sender
+        .as_ref()
+        .expect("TODO")
+        .send(Message::CAccount(sender))?;
+
+    let user_id = recver
+        .await
+        .map_err(|e| format!("user_id channel closed: {e}"))?;
+    drop(account_nums);
 
-            rw.write_line(&addr.to_string()).await?;
+    state
+        .credentials
+        .write()
+        .dl("cl80")
+        .await
+        .add(user_id, hash, role)
+        .await?;
 
-            Ok(format!(
-                "Found node {} for account {{ id: {}, node_id {} }}.",
-                addr, user_id.id, user_id.node_id
-            ))
+    println!("Created account {user_id} as {role:?}.");
+    Ok(user_id)
+}
+
+async fn login(
+    Login { user_id, password }: Login,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    let unauthorized = || ErrorResponse::new(ErrorCode::Unauthorized, "Wrong user id or password");
+    let Credential { hash, role } = state
+        .credentials
+        .read()
+        .dl("cl92")
+        .await
+        .get(&user_id)
+        .ok_or_else(unauthorized)?
+        .clone();
+    if !spawn_blocking(move || verify_password(&hash, &password)).await? {
+        return Err(unauthorized().into());
+    }
+
+    let CoordinatorResponse::Node(node) = find_node(user_id, state).await? else {
+        unreachable!("find_node only finds nodes");
+    };
+    println!("Logged in account {user_id}.");
+    Ok(CoordinatorResponse::LoggedIn(LoggedIn {
+        node,
+        token: state.signing_key.issue(user_id, role),
+    }))
+}
+
+/// A wrong user id or code is `unauthorized` like a wrong password
+async fn claim_account(
+    ClaimAccount {
+        user_id,
+        code,
+        password,
+    }: ClaimAccount,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    let unauthorized = || ErrorResponse::new(ErrorCode::Unauthorized, "Wrong user id or code");
+    let claim = state
+        .credentials
+        .read()
+        .dl("cl120")
+        .await
+        .get_claim(&user_id)
+        .ok_or_else(unauthorized)?
+        .clone();
+    let (hash, checked) = spawn_blocking(move || {
+        if !verify_password(&claim, &code) {
+            return Ok(None);
         }
-        FirstLine::CAccount => {
-            let mut account_nums = state.account_nums.write().dl("45").await;
-
-            let mut min_acc = 0;
-            let a_nums = account_nums.get_nums();
-            for i in 0..a_nums.len() {
-                if a_nums[i] < a_nums[min_acc] {
-                    min_acc = i;
-                }
+        hash_password(&password).map(|hash| Some((hash, claim)))
+    })
+    .await?
+    .map_err(|e| ErrorResponse::new(ErrorCode::BadRequest, e.to_string()))?
+    .ok_or_else(unauthorized)?;
+    if !state
+        .credentials
+        .write()
+        .dl("cl137")
+        .await
+        .claim(user_id, &checked, hash)
+        .await?
+    {
+        return Err(unauthorized().into());
+    }
+    println!("Claimed account {user_id}.");
+    Ok(CoordinatorResponse::Ok)
+}
+
+/// For an account some node made, the code is only kept hashed
+async fn issue_claim_code(
+    actor: UserID,
+    user_id: UserID,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    let exists = state
+        .account_nums
+        .read()
+        .dl("cl150")
+        .await
+        .get_nums()
+        .get(user_id.node_id)
+        .is_some_and(|&num| (user_id.id as u64) < num);
+    if !exists {
+        return Err(
+            ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")).into(),
+        );
+    }
+    let code = generate_claim_code();
+    let hash = {
+        let code = code.clone();
+        spawn_blocking(move || hash_password(&code)).await??
+    };
+    state
+        .credentials
+        .write()
+        .dl("cl162")
+        .await
+        .issue_claim(actor, user_id, hash)
+        .await?;
+    println!("{actor} issued a claim code for {user_id}.");
+    Ok(CoordinatorResponse::ClaimCode(code))
+}
+
+/// The account logged in on this session, if it's an admin now rather than when it logged in
+async fn admin(logged_in: Option<UserID>, state: &Arc<State>) -> GResult<UserID> {
+    let user_id =
+        logged_in.ok_or_else(|| ErrorResponse::new(ErrorCode::Unauthorized, "Log in first"))?;
+    match state.credentials.read().dl("cl130").await.get(&user_id) {
+        Some(credential) if credential.role.is_admin() => Ok(user_id),
+        _ => Err(ErrorResponse::new(ErrorCode::Forbidden, "Only admins can do this").into()),
+    }
+}
+
+async fn set_role(
+    actor: UserID,
+    SetRole { user_id, role }: SetRole,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    // the account's node checks the role from now on, tokens issued before still carry the old
+    state
+        .credentials
+        .read()
+        .dl("cl139")
+        .await
+        .check_role(user_id, role)?;
+    let op = AdminOp::SetRole(SetRole { user_id, role });
+    forward(actor, user_id, op, state).await?;
+    state
+        .credentials
+        .write()
+        .dl("cl141")
+        .await
+        .set_role(actor, user_id, role)
+        .await?;
+    println!("{actor} made {user_id} {role:?}.");
+    Ok(CoordinatorResponse::Ok)
+}
+
+async fn handle_admin(
+    logged_in: Option<UserID>,
+    req: CoordinatorRequest,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    let actor = admin(logged_in, state).await?;
+    match req {
+        CoordinatorRequest::SetRole(req) => set_role(actor, req, state).await,
+        CoordinatorRequest::IssueClaimCode(user_id) => {
+            issue_claim_code(actor, user_id, state).await
+        }
+        CoordinatorRequest::MintStock(req) => {
+            forward(actor, req.user_id, AdminOp::MintStock(req), state).await
+        }
+        CoordinatorRequest::AdjustBalance(req) => {
+            forward(actor, req.user_id, AdminOp::AdjustBalance(req), state).await
+        }
+        CoordinatorRequest::ConfirmDeposit(req) => {
+            forward(actor, req.user_id, AdminOp::ConfirmDeposit(req), state).await
+        }
+        CoordinatorRequest::AllowShort(req) => {
+            forward(actor, req.user_id, AdminOp::AllowShort(req), state).await
+        }
+        CoordinatorRequest::SetLendable(req) => {
+            to_node(actor, req.node_id, AdminOp::SetLendable(req), state).await
+        }
+        CoordinatorRequest::SetMargin(req) => {
+            if let Some(terms) = req.terms {
+                terms
+                    .validate()
+                    .map_err(|e| ErrorResponse::new(ErrorCode::BadRequest, e))?;
             }
-            let min_num = a_nums[min_acc];
-            account_nums.set_num(min_acc, min_num + 1).await;
+            forward(actor, req.user_id, AdminOp::SetMargin(req), state).await
+        }
+        CoordinatorRequest::SetRiskLimits(req) => {
+            forward(actor, req.user_id, AdminOp::SetRiskLimits(req), state).await
+        }
+        CoordinatorRequest::KillSwitch(req) => {
+            forward(actor, req.user_id, AdminOp::KillSwitch(req), state).await
+        }
+        CoordinatorRequest::ReadRiskLimits(user_id) => {
+            let limits = ask(actor, user_id, AdminOp::ReadRiskLimits(user_id), state).await?;
+            Ok(CoordinatorResponse::RiskLimits(serde_json::from_value(
+                limits,
+            )?))
+        }
+        CoordinatorRequest::Split(req) => split(actor, req, state).await,
+        CoordinatorRequest::Dividend(req) => dividend(actor, req, state).await,
+        CoordinatorRequest::ReadDividends => Ok(CoordinatorResponse::Dividends(
+            state.dividends.lock().dl("cl316").await.get_all().clone(),
+        )),
+        _ => unreachable!("not an admin request"),
+    }
+}
+
+/// Every node halts the ticker before any of them splits it, so nothing trades at a price from
+/// before the split against one from after, and checks it can before any of them does. Trading
+/// resumes if a node couldn't halt or prepare, as nothing was split. Once one has split it stays
+/// halted if another fails to, so nothing trades at two scales
+async fn split(actor: UserID, split: Split, state: &Arc<State>) -> GResult<CoordinatorResponse> {
+    let bad_request = |e: String| ErrorResponse::new(ErrorCode::BadRequest, e);
+    split.validate().map_err(bad_request)?;
+    if state.instruments.fx(&split.ticker).is_some() {
+        return Err(bad_request(format!("{} is a currency pair", split.ticker)).into());
+    }
+    let ticker = split.ticker.clone();
+    let mut prepared = every_node(actor, AdminOp::Halt(ticker.clone()), state).await;
+    if prepared.is_ok() {
+        prepared = every_node(actor, AdminOp::PrepareSplit(split.clone()), state).await;
+    }
+    if let Err(e) = prepared {
+        every_node(actor, AdminOp::Resume(ticker.clone()), state).await?;
+        return Err(e);
+    }
+    if let Err(e) = every_node(actor, AdminOp::Split(split.clone()), state).await {
+        eprintln!("Split of {ticker} failed part way, trading in it stays halted: {e}");
+        return Err(e);
+    }
+    every_node(actor, AdminOp::Resume(ticker.clone()), state).await?;
+    println!("{actor} split {ticker} {} for {}.", split.to, split.from);
+    Ok(CoordinatorResponse::Ok)
+}
+
+/// Kept by the coordinator before it's paid, so it's paid at the record time whether or not the
+/// admin is still connected. Replies with its id straight away, what's paid is in `ReadDividends`
+async fn dividend(
+    actor: UserID,
+    dividend: Dividend,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    let bad_request = |e: String| ErrorResponse::new(ErrorCode::BadRequest, e);
+    dividend.validate().map_err(bad_request)?;
+    if state.instruments.fx(&dividend.ticker).is_some() {
+        return Err(bad_request(format!("{} is a currency pair", dividend.ticker)).into());
+    }
+    if dividend.record_time < now() {
+        return Err(bad_request("The record time has passed".to_owned()).into());
+    }
+    let id = state
+        .dividends
+        .lock()
+        .dl("cl360")
+        .await
+        .schedule(actor, dividend)
+        .await?;
+    spawn_payment(id, Arc::clone(state));
+    Ok(CoordinatorResponse::Dividend(id))
+}
+
+/// Pay every dividend kept but not yet paid, once a node joins
+pub async fn resume_dividends(state: Arc<State>) {
+    let due = state.dividends.lock().dl("cl375").await.start_due();
+    for id in due {
+        spawn_payment(id, Arc::clone(&state));
+    }
+}
+
+/// Pay dividend `id` in a task of its own, so no session waits for it or stops it by ending
+fn spawn_payment(id: DividendID, state: Arc<State>) {
+    tokio::spawn(async move {
+        if let Err(e) = pay_dividend(id, state).await {
+            eprintln!("Dividend {id} isn't paid yet, it's tried again once a node joins: {e}");
+        }
+    });
+}
+
+/// Waits for the record time, then halts the ticker on every node like a split so no shares are
+/// on their way between nodes while each counts its holders. Each node's part is kept as soon as
+/// it's paid, and nodes that have paid aren't asked again, so a node failing to pay leaves the
+/// dividend to finish once a node joins. The caller has marked it as being paid
+async fn pay_dividend(id: DividendID, state: Arc<State>) -> GResult<()> {
+    let scheduled = state.dividends.lock().dl("cl388").await.get(id).clone();
+    let ScheduledDividend {
+        actor, dividend, ..
+    } = scheduled;
+    sleep(Duration::from_secs(
+        dividend.record_time.saturating_sub(now()),
+    ))
+    .await;
+
+    let ticker = dividend.ticker.clone();
+    let mut result = every_node(actor, AdminOp::Halt(ticker.clone()), &state)
+        .await
+        .map(|_| ());
+    if result.is_ok() {
+        result = pay_every_node(id, actor, &dividend, &state).await;
+    }
+    let resumed = every_node(actor, AdminOp::Resume(ticker.clone()), &state).await;
+    let result = result.and(resumed.map(|_| ()));
 
-            let (sender, recver) = oneshot::channel();
+    let mut dividends = state.dividends.lock().dl("cl403").await;
+    dividends.stop(id, result.is_ok()).await?;
+    let paid = &dividends.get(id).paid;
+    if let Err(e) = result {
+        let paid: Vec<String> = paid
+            .iter()
+            .map(|(node_id, paid)| {
+                format!(
+                    "node {node_id} {} to {} accounts",
+                    paid.amount, paid.accounts
+                )
+            })
+            .collect();
+        eprintln!(
+            "Dividend {id} of {} per {ticker} stopped having paid [{}]: {e}",
+            dividend.per_share,
+            paid.join(", ")
+        );
+        return Err(e);
+    }
+    let total = paid
+        .values()
+        .try_fold(Distribution::default(), |total, &paid| {
+            total.checked_add(paid)
+        })?;
+    println!(
+        "{actor} paid {} per {ticker}, {} to {} accounts.",
+        dividend.per_share, total.amount, total.accounts
+    );
+    Ok(())
+}
 
-            node_records[min_acc]
-                .sender
-                .as_ref()
-                .expect("TODO")
-                .send(Message::CAccount(sender))?;
+/// Have each node that hasn't paid dividend `id` pay it, keeping each part as it's paid
+async fn pay_every_node(
+    id: DividendID,
+    actor: UserID,
+    dividend: &Dividend,
+    state: &Arc<State>,
+) -> GResult<()> {
+    let paid = state
+        .dividends
+        .lock()
+        .dl("cl436")
+        .await
+        .get(id)
+        .paid
+        .clone();
+    let payout = AdminOp::Dividend(Payout {
+        id,
+        dividend: dividend.clone(),
+    });
+    let nodes = state
+        .node_records
+        .read()
+        .dl("cl442")
+        .await
+        .get_records()
+        .len();
+    for node_id in (0..nodes).filter(|node_id| !paid.contains_key(node_id)) {
+        let not_found = || {
+            ErrorResponse::new(
+                ErrorCode::NotFound,
+                format!("Node {node_id} isn't connected"),
+            )
+        };
+        let part = send_admin(actor, node_id, payout.clone(), not_found, state).await?;
+        let part = serde_json::from_value(part)?;
+        state
+            .dividends
+            .lock()
+            .dl("cl449")
+            .await
+            .paid(id, node_id, part)
+            .await?;
+    }
+    Ok(())
+}
 
-            let user_id = recver
+/// Have every node do `op`, one after the other, their replies by node id. Stops at the first
+/// that fails
+async fn every_node(actor: UserID, op: AdminOp, state: &Arc<State>) -> GResult<Vec<Value>> {
+    let senders = {
+        let node_records = state.node_records.read().dl("cl190").await;
+        node_records
+            .get_records()
+            .iter()
+            .enumerate()
+            .map(|(id, record)| {
+                record.sender.clone().ok_or_else(|| {
+                    ErrorResponse::new(ErrorCode::NotFound, format!("Node {id} isn't connected"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let mut replies = Vec::new();
+    for node in senders {
+        let (sender, recver) = oneshot::channel();
+        node.send(Message::Admin(actor, op.clone(), sender))?;
+        replies.push(
+            recver
                 .await
-                .map_err(|e| format!("user_id channel closed: {e}"))?;
+                .map_err(|e| format!("admin reply channel closed: {e}"))??,
+        );
+    }
+    Ok(replies)
+}
 
-            rw.write_line(&serde_json::to_string(&user_id)?).await?;
+/// Have the node holding `user_id` do `op`
+async fn forward(
+    actor: UserID,
+    user_id: UserID,
+    op: AdminOp,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    ask(actor, user_id, op, state).await?;
+    Ok(CoordinatorResponse::Ok)
+}
 
-            Ok(format!(
-                "Created account {{ id: {}, node_id {} }}.",
-                user_id.id, user_id.node_id
-            ))
-        }
+/// Like `forward`, for the node's reply
+async fn ask(actor: UserID, user_id: UserID, op: AdminOp, state: &Arc<State>) -> GResult<Value> {
+    let not_found = || ErrorResponse::new(ErrorCode::NotFound, format!("No node for {user_id}"));
+    send_admin(actor, user_id.node_id, op, not_found, state).await
+}
+
+/// Have node `node_id` do `op`
+async fn to_node(
+    actor: UserID,
+    node_id: NodeID,
+    op: AdminOp,
+    state: &Arc<State>,
+) -> GResult<CoordinatorResponse> {
+    let not_found = || ErrorResponse::new(ErrorCode::NotFound, format!("No node {node_id}"));
+    send_admin(actor, node_id, op, not_found, state).await?;
+    Ok(CoordinatorResponse::Ok)
+}
+
+async fn send_admin(
+    actor: UserID,
+    node_id: NodeID,
+    op: AdminOp,
+    not_found: impl FnOnce() -> ErrorResponse,
+    state: &Arc<State>,
+) -> GResult<Value> {
+    let (sender, recver) = oneshot::channel();
+    {
+        let node_records = state.node_records.read().dl("cl160").await;
+        node_records
+            .get_records()
+            .get(node_id)
+            .and_then(|record| record.sender.as_ref())
+            .ok_or_else(not_found)?
+            .send(Message::Admin(actor, op, sender))?;
+    }
+    Ok(recver
+        .await
+        .map_err(|e| format!("admin reply channel closed: {e}"))??)
+}
+
+pub async fn handler(
+    FirstLine(CoordinatorHello { version }): FirstLine,
+    mut rw: ReadWriter,
+    state: Arc<State>,
+) -> GResult<String> {
+    let welcome = if version == PROTOCOL_VERSION {
+        Ok(Welcome {
+            version: PROTOCOL_VERSION,
+        })
+    } else {
+        Err(ErrorResponse::new(
+            ErrorCode::UnsupportedVersion,
+            format!("Client speaks version {version}, coordinator speaks {PROTOCOL_VERSION}"),
+        ))
+    };
+    rw.write_line(&serde_json::to_string(&welcome)?).await?;
+    if let Err(e) = welcome {
+        return Err(Box::new(e));
+    }
+
+    // account of the last successful login on this session
+    let mut logged_in = None;
+    loop {
+        let line = match rw.read_line().await {
+            Ok(line) => line,
+            Err(e) if is_closed(&*e) => return Ok("Connection with client closed.".to_owned()),
+            Err(e) => return Err(e),
+        };
+        let res = match parse_request(&line) {
+            Ok(Request {
+                body: CoordinatorRequest::Bye,
+                ..
+            }) => return Ok("Connection with client terminated.".to_owned()),
+            Ok(Request { id, body }) => {
+                let result = match body {
+                    CoordinatorRequest::CreateAccount(req) => create_account(req, &state).await,
+                    CoordinatorRequest::Login(req) => {
+                        let user_id = req.user_id;
+                        let result = login(req, &state).await;
+                        if result.is_ok() {
+                            logged_in = Some(user_id);
+                        }
+                        result
+                    }
+                    CoordinatorRequest::ClaimAccount(req) => claim_account(req, &state).await,
+                    CoordinatorRequest::FindNode(user_id) => find_node(user_id, &state).await,
+                    req @ (CoordinatorRequest::SetRole(_)
+                    | CoordinatorRequest::MintStock(_)
+                    | CoordinatorRequest::AdjustBalance(_)
+                    | CoordinatorRequest::ConfirmDeposit(_)
+                    | CoordinatorRequest::AllowShort(_)
+                    | CoordinatorRequest::SetLendable(_)
+                    | CoordinatorRequest::SetMargin(_)
+                    | CoordinatorRequest::SetRiskLimits(_)
+                    | CoordinatorRequest::ReadRiskLimits(_)
+                    | CoordinatorRequest::KillSwitch(_)
+                    | CoordinatorRequest::IssueClaimCode(_)
+                    | CoordinatorRequest::Split(_)
+                    | CoordinatorRequest::Dividend(_)
+                    | CoordinatorRequest::ReadDividends) => {
+                        handle_admin(logged_in, req, &state).await
+                    }
+                    CoordinatorRequest::Bye => unreachable!(),
+                };
+                Response {
+                    id: Some(id),
+                    result: result.map_err(ErrorResponse::from_error),
+                }
+            }
+            Err(res) => res,
+        };
+        rw.write_line(&serde_json::to_string(&res)?).await?;
     }
 }
@@ -1,8 +1,11 @@
-use lib::interfaces::UserID;
+use lib::interfaces::{
+    AccountLimits, Adjustment, DepositDecision, ErrorResponse, KillSwitch, Lendable, MarginAccount,
+    Mint, Payout, SetRole, ShortPermission, Split, Ticker, UserID,
+};
 use lib::lock::DeadLockDetect;
 use lib::{read_writer::ReadWriter, GResult};
-use serde::Deserialize;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::{net::SocketAddr, str::FromStr, sync::Arc};
 use tokio::sync::{mpsc, oneshot::Sender};
 
@@ -32,6 +35,35 @@ struct NodeState {
 pub enum Message {
     Joined(usize, SocketAddr),
     CAccount(Sender<UserID>),
+    /// done by the node on behalf of the admin, null or what the op replies with
+    Admin(UserID, AdminOp, Sender<Result<Value, ErrorResponse>>),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum AdminOp {
+    MintStock(Mint),
+    AdjustBalance(Adjustment),
+    ConfirmDeposit(DepositDecision),
+    AllowShort(ShortPermission),
+    SetLendable(Lendable),
+    SetMargin(MarginAccount),
+    SetRiskLimits(AccountLimits),
+    /// replied to with the account's `RiskLimits`
+    ReadRiskLimits(UserID),
+    KillSwitch(KillSwitch),
+    /// so the account's node checks the role live instead of the one in its session tokens
+    SetRole(SetRole),
+    /// stop trading the ticker and wait for trades under way
+    Halt(Ticker),
+    /// only once every node has halted the ticker, checks the split changing nothing
+    PrepareSplit(Split),
+    /// only once every node has prepared the split
+    Split(Split),
+    Resume(Ticker),
+    /// only once every node has halted the ticker, replied to with a `Distribution`, the one
+    /// already paid if asked again
+    Dividend(Payout),
 }
 
 pub async fn handler(
@@ -39,6 +71,7 @@ pub async fn handler(
     mut rw: ReadWriter,
     state: Arc<State>,
 ) -> GResult<String> {
+    rw.check_peer_certified()?;
     let mut node_records = state.node_records.write().dl("41").await;
     let mut account_nums = state.account_nums.write().dl("42").await;
     let id = first_line
@@ -55,8 +88,12 @@ pub async fn handler(
             .filter(|&(i, _)| i != id)
             .map(|(i, r)| json!({"id": i, "addr": r.address}))
             .collect::<Vec<_>>(),
+        "verifying_key": state.signing_key.verifying_key(),
+        "fees": state.fees,
+        "instruments": state.instruments,
     }))?;
-    // reply with ID and all other servers.
+    // reply with ID, all other servers, the public key to check session tokens with, the fees and
+    // the instrument registry.
     rw.write_line(&rep).await?;
 
     let (sender, mut recver) = mpsc::unbounded_channel();
@@ -99,6 +136,16 @@ pub async fn handler(
     drop(node_records);
     drop(account_nums);
 
+    // the admin given on the command line is opened on the first node to join
+    let admin_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        if let Err(e) = super::client::create_admin(&admin_state).await {
+            eprintln!("Failed to create the admin account: {e}");
+        }
+    });
+    // dividends left unpaid by a restart or a node that was down
+    tokio::spawn(super::client::resume_dividends(Arc::clone(&state)));
+
     loop {
         let msg = recver
             .recv()
@@ -126,6 +173,18 @@ pub async fn handler(
                     .send(serde_json::from_str(&line)?)
                     .map_err(|_| line.clone())?;
             }
+            Message::Admin(actor, op, sender) => {
+                rw.write_line(&serde_json::to_string(&json!({
+                    "type": "admin",
+                    "actor": actor,
+                    "op": op,
+                }))?)
+                .await?;
+
+                let line = rw.read_line().await?;
+                // the admin's session may be gone, nothing to reply to
+                let _ = sender.send(serde_json::from_str(&line)?);
+            }
         }
     }
 }
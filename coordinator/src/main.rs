@@ -1,10 +1,17 @@
 #![allow(clippy::new_without_default)]
 mod handlers;
+mod migrations;
 mod state;
 
 use crate::{handlers::handler, state::State};
-use lib::read_writer::ReadWriter;
-use std::sync::Arc;
+use lib::{
+    auth::hash_password,
+    fees::FeeSchedule,
+    instruments::Instruments,
+    storage::{self, StorageKind},
+    tls::{self, Tls},
+};
+use std::{path::PathBuf, sync::Arc};
 use structopt::StructOpt;
 use tokio::net::TcpListener;
 
@@ -15,28 +22,97 @@ struct Args {
 
     #[structopt(short = "d", long)]
     persistent_dir: String,
+
+    /// file, memory or kv
+    #[structopt(short, long, default_value = "file")]
+    storage: StorageKind,
+
+    /// CA certificate, turns on TLS for every connection
+    #[structopt(long)]
+    ca: Option<PathBuf>,
+
+    /// Certificate signed by the CA, naming the ip clients and nodes connect to
+    #[structopt(long)]
+    cert: Option<PathBuf>,
+
+    /// Private key of the certificate
+    #[structopt(long)]
+    key: Option<PathBuf>,
+
+    /// Fee schedule as json, handed to nodes when they join. No fees without one
+    #[structopt(long)]
+    fees: Option<PathBuf>,
+
+    /// Instrument registry as json, handed to nodes when they join. Everything is in USD
+    /// without one
+    #[structopt(long)]
+    instruments: Option<PathBuf>,
+
+    /// File with the password of an admin account to open once a node has joined, unless there
+    /// already is an admin. Accounts opened by clients are never admins
+    #[structopt(long)]
+    admin: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::from_args();
+    let tls = Tls::from_server_args(
+        args.ca.as_deref(),
+        args.cert.as_deref(),
+        args.key.as_deref(),
+    )
+    .expect("Failed to load TLS certificates")
+    .map(Arc::new);
 
     let ip_port = format!("127.0.0.1:{}", args.port);
     println!("Starting coordinator on {ip_port}");
     let listener: TcpListener = TcpListener::bind(ip_port).await.expect("Failed to bind");
 
-    let global: Arc<State> = Arc::new(State::new_or_restore(args.persistent_dir).await);
+    let storage = storage::open(args.storage, &args.persistent_dir)
+        .await
+        .expect("Failed to open storage");
+    let mut state = State::new_or_restore(storage)
+        .await
+        .expect("Failed to restore state");
+    if let Some(path) = args.fees {
+        let fees: FeeSchedule = serde_json::from_str(
+            &std::fs::read_to_string(path).expect("Failed to read fee schedule"),
+        )
+        .expect("Fee schedule is unreadable");
+        fees.validate().expect("Invalid fee schedule");
+        state.fees = fees;
+    }
+    if let Some(path) = args.instruments {
+        let instruments: Instruments = serde_json::from_str(
+            &std::fs::read_to_string(path).expect("Failed to read instrument registry"),
+        )
+        .expect("Instrument registry is unreadable");
+        instruments.validate().expect("Invalid instrument registry");
+        state.instruments = instruments;
+    }
+    if let Some(path) = args.admin {
+        let password = std::fs::read_to_string(path).expect("Failed to read admin password");
+        let hash = hash_password(password.trim_end()).expect("Invalid admin password");
+        *state.admin.get_mut() = Some(hash);
+    }
+    let global: Arc<State> = Arc::new(state);
 
     loop {
-        let rw = match listener.accept().await {
-            Ok((socket, _)) => ReadWriter::new(socket),
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
             Err(e) => {
                 eprintln!("Error receiving connection from a new connection: {e}");
                 continue;
             }
         };
         let global = Arc::clone(&global);
-        tokio::spawn(async {
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let rw = match tls::accept(socket, tls.as_deref()).await {
+                Ok(rw) => rw,
+                Err(e) => return eprintln!("Error with connection handshake: {e}"),
+            };
             match handler(rw, global).await {
                 Ok(msg) => println!("Connection terminated successfully: {msg}"),
                 Err(e) => eprintln!("Error with connection: {e}"),
@@ -0,0 +1,49 @@
+//! Schema migrations for everything `State` persists, see `lib::storage::load_migrated`.
+//! Add one to the end whenever the persisted shape of the coordinator's state changes.
+
+use lib::{auth::SigningKey, storage::Migration, GResult};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+pub const MIGRATIONS: &[Migration] = &[v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5];
+
+/// Version 0 was written before versioning existed, it has the same shape as version 1.
+fn v0_to_v1(_: &mut HashMap<String, Value>) -> GResult<()> {
+    Ok(())
+}
+
+/// Version 2 adds `session_key` and a `credentials/<user id>` password hash per account.
+/// Accounts made before have no password, they log in once they've set one with a claim code
+/// from an admin, see `Credentials::issue_claim`.
+fn v1_to_v2(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    // a placeholder, version 4 replaces it
+    entries.insert("session_key".to_owned(), Value::Null);
+    Ok(())
+}
+
+/// Version 3 turns each password hash into `{ "hash", "role" }`, every existing account is a
+/// trader. Start the coordinator with `--admin` to open an admin account.
+fn v2_to_v3(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        if key.starts_with("credentials/") {
+            *value = json!({ "hash": value.take(), "role": "trader" });
+        }
+    }
+    Ok(())
+}
+
+/// Version 4 replaces the `session_key` shared with nodes by a `signing_key` only the
+/// coordinator has, tokens signed with the old key are no longer accepted.
+fn v3_to_v4(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    entries.remove("session_key");
+    entries.insert(
+        "signing_key".to_owned(),
+        serde_json::to_value(SigningKey::generate())?,
+    );
+    Ok(())
+}
+
+/// Version 5 keeps each dividend an admin asks for as `dividends/<id>`, there are none before.
+fn v4_to_v5(_: &mut HashMap<String, Value>) -> GResult<()> {
+    Ok(())
+}
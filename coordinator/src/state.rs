@@ -1,12 +1,28 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+};
 
-use serde::{Deserialize, Serialize};
-use tokio::{
-    fs,
-    sync::{mpsc::UnboundedSender, RwLock},
+use lib::{
+    audit::{AuditAction, AuditLog},
+    auth::SigningKey,
+    fees::FeeSchedule,
+    instruments::Instruments,
+    interfaces::{
+        Distribution, Dividend, DividendID, ErrorCode, ErrorResponse, NodeID, Role,
+        ScheduledDividend, UserID,
+    },
+    storage::{load_migrated, Op, Storage},
+    GResult,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc::UnboundedSender, Mutex, RwLock};
 
-use crate::handlers;
+use crate::{handlers, migrations::MIGRATIONS};
 
 #[derive(Serialize, Deserialize)]
 pub struct NodeRecord {
@@ -19,55 +35,102 @@ pub struct NodeRecord {
 pub struct State {
     pub node_records: RwLock<NodeRecords>,
     pub account_nums: RwLock<AccountNums>,
+    pub credentials: RwLock<Credentials>,
+    pub dividends: Mutex<Dividends>,
+    /// signs session tokens, nodes get its verifying key when they join
+    pub signing_key: SigningKey,
+    /// password hash of the admin account to create once a node has joined, from the command line
+    pub admin: Mutex<Option<String>>,
+    /// shared with nodes when they join, not persisted
+    pub fees: FeeSchedule,
+    /// shared with nodes when they join, not persisted
+    pub instruments: Instruments,
 }
 
 impl State {
-    pub async fn new_or_restore(per_dir: String) -> Self {
-        match (
-            NodeRecords::restore(per_dir.clone()).await,
-            AccountNums::restore(per_dir.clone()).await,
+    /// Anything unreadable is an error, never a reason to start over.
+    pub async fn new_or_restore(storage: Arc<dyn Storage>) -> GResult<Self> {
+        let mut entries = load_migrated(&*storage, MIGRATIONS).await?;
+        let credentials = Credentials::restore(&mut entries, Arc::clone(&storage))?;
+        let dividends = Dividends::restore(&mut entries, Arc::clone(&storage))?;
+        let signing_key = entries
+            .remove("signing_key")
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| format!("signing_key is unreadable: {e}"))?;
+        let state = match (
+            NodeRecords::restore(&mut entries, Arc::clone(&storage))?,
+            AccountNums::restore(&mut entries, Arc::clone(&storage))?,
+            signing_key,
         ) {
-            (Some(n), Some(a)) => Self {
+            (Some(n), Some(a), Some(signing_key)) => Self {
                 node_records: RwLock::new(n),
                 account_nums: RwLock::new(a),
+                credentials: RwLock::new(credentials),
+                dividends: Mutex::new(dividends),
+                signing_key,
+                admin: Mutex::new(None),
+                fees: FeeSchedule::default(),
+                instruments: Instruments::default(),
             },
-            _ => Self {
-                node_records: RwLock::new(NodeRecords {
-                    records: Vec::new(),
-                    per_dir: per_dir.clone(),
-                }),
-                account_nums: RwLock::new(AccountNums {
-                    nums: Vec::new(),
-                    per_dir,
-                }),
-            },
+            (None, None, None) => {
+                let signing_key = SigningKey::generate();
+                storage
+                    .commit(vec![Op::put("signing_key", &signing_key)?])
+                    .await?;
+                Self {
+                    node_records: RwLock::new(NodeRecords {
+                        records: Vec::new(),
+                        storage: Arc::clone(&storage),
+                    }),
+                    account_nums: RwLock::new(AccountNums {
+                        nums: Vec::new(),
+                        storage: Arc::clone(&storage),
+                    }),
+                    credentials: RwLock::new(credentials),
+                    dividends: Mutex::new(dividends),
+                    signing_key,
+                    admin: Mutex::new(None),
+                    fees: FeeSchedule::default(),
+                    instruments: Instruments::default(),
+                }
+            }
+            _ => {
+                return Err(
+                    "Only some of node_records, account_nums and signing_key are persisted".into(),
+                )
+            }
+        };
+        if let Some(key) = entries.keys().next() {
+            return Err(format!("Unexpected persisted key {key}").into());
         }
+        Ok(state)
     }
 }
 
 pub struct NodeRecords {
     records: Vec<NodeRecord>,
-    per_dir: String,
+    storage: Arc<dyn Storage>,
 }
 
 impl NodeRecords {
-    async fn restore(per_dir: String) -> Option<Self> {
-        let records: Vec<NodeRecord> = serde_json::from_str(
-            &fs::read_to_string(format!("{per_dir}/node_records"))
-                .await
-                .ok()?,
-        )
-        .ok()?;
-        Some(Self { records, per_dir })
+    fn restore(
+        entries: &mut HashMap<String, Value>,
+        storage: Arc<dyn Storage>,
+    ) -> GResult<Option<Self>> {
+        let Some(value) = entries.remove("node_records") else {
+            return Ok(None);
+        };
+        let records: Vec<NodeRecord> = serde_json::from_value(value)
+            .map_err(|e| format!("node_records is unreadable: {e}"))?;
+        Ok(Some(Self { records, storage }))
     }
 
     async fn update_file(&mut self) {
-        fs::write(
-            format!("{}/node_records", self.per_dir),
-            &serde_json::to_string(&self.records).unwrap(),
-        )
-        .await
-        .expect("can't write to path");
+        self.storage
+            .commit(vec![Op::put("node_records", &self.records).unwrap()])
+            .await
+            .expect("can't write to storage");
     }
 
     pub async fn add_record(&mut self, node: NodeRecord) {
@@ -87,27 +150,27 @@ impl NodeRecords {
 
 pub struct AccountNums {
     nums: Vec<u64>,
-    per_dir: String,
+    storage: Arc<dyn Storage>,
 }
 
 impl AccountNums {
-    async fn restore(per_dir: String) -> Option<Self> {
-        let nums: Vec<u64> = serde_json::from_str(
-            &fs::read_to_string(format!("{per_dir}/account_nums"))
-                .await
-                .ok()?,
-        )
-        .ok()?;
-        Some(Self { nums, per_dir })
+    fn restore(
+        entries: &mut HashMap<String, Value>,
+        storage: Arc<dyn Storage>,
+    ) -> GResult<Option<Self>> {
+        let Some(value) = entries.remove("account_nums") else {
+            return Ok(None);
+        };
+        let nums: Vec<u64> = serde_json::from_value(value)
+            .map_err(|e| format!("account_nums is unreadable: {e}"))?;
+        Ok(Some(Self { nums, storage }))
     }
 
     async fn update_file(&mut self) {
-        fs::write(
-            format!("{}/account_nums", self.per_dir),
-            &serde_json::to_string(&self.nums).unwrap(),
-        )
-        .await
-        .expect("can't write to path");
+        self.storage
+            .commit(vec![Op::put("account_nums", &self.nums).unwrap()])
+            .await
+            .expect("can't write to storage");
     }
 
     pub async fn add_num(&mut self, node: u64) {
@@ -124,3 +187,258 @@ impl AccountNums {
         &self.nums
     }
 }
+
+/// Password hash and role of every account, each stored as `credentials/<user id>`, the hashed
+/// claim code of accounts from before passwords, each stored as `claims/<user id>`, and the
+/// audit trail of role changes and claim codes
+pub struct Credentials {
+    accounts: HashMap<UserID, Credential>,
+    claims: HashMap<UserID, String>,
+    audit: AuditLog,
+    storage: Arc<dyn Storage>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Credential {
+    pub hash: String,
+    pub role: Role,
+}
+
+const CREDENTIALS_PREFIX: &str = "credentials/";
+const CLAIMS_PREFIX: &str = "claims/";
+
+/// Take every `<prefix><id>` entry out of `entries`
+fn take<K: FromStr + Eq + Hash, T: DeserializeOwned>(
+    entries: &mut HashMap<String, Value>,
+    prefix: &str,
+) -> GResult<HashMap<K, T>> {
+    let keys: Vec<String> = entries
+        .keys()
+        .filter(|k| k.starts_with(prefix))
+        .cloned()
+        .collect();
+    let mut taken = HashMap::new();
+    for key in keys {
+        let id: K = key[prefix.len()..]
+            .parse()
+            .map_err(|_| format!("Bad id in {key}"))?;
+        let value = serde_json::from_value(entries.remove(&key).expect("key was listed"))
+            .map_err(|e| format!("{key} is unreadable: {e}"))?;
+        taken.insert(id, value);
+    }
+    Ok(taken)
+}
+
+impl Credentials {
+    fn restore(entries: &mut HashMap<String, Value>, storage: Arc<dyn Storage>) -> GResult<Self> {
+        Ok(Self {
+            accounts: take(entries, CREDENTIALS_PREFIX)?,
+            claims: take(entries, CLAIMS_PREFIX)?,
+            audit: AuditLog::restore(entries)?,
+            storage,
+        })
+    }
+
+    fn op(user_id: UserID, credential: &Credential) -> GResult<Op> {
+        Op::put(format!("{CREDENTIALS_PREFIX}{user_id}"), credential)
+    }
+
+    /// Replace the claim code of `user_id`, an account without a password. `hash` is the code's
+    pub async fn issue_claim(
+        &mut self,
+        actor: UserID,
+        user_id: UserID,
+        hash: String,
+    ) -> GResult<()> {
+        if self.accounts.contains_key(&user_id) {
+            return Err(ErrorResponse::new(
+                ErrorCode::BadRequest,
+                format!("{user_id} already has a password"),
+            )
+            .into());
+        }
+        let record = self
+            .audit
+            .record(actor, AuditAction::IssueClaimCode { user_id })?;
+        self.storage
+            .commit(vec![
+                Op::put(format!("{CLAIMS_PREFIX}{user_id}"), &hash)?,
+                record,
+            ])
+            .await?;
+        self.claims.insert(user_id, hash);
+        Ok(())
+    }
+
+    /// Hash of the code `user_id` can be claimed with, if an admin issued one
+    pub fn get_claim(&self, user_id: &UserID) -> Option<&String> {
+        self.claims.get(user_id)
+    }
+
+    /// Give `user_id` its password as a trader, if its claim code is still `claim`. A code works
+    /// once, false if it was used or replaced since it was checked
+    pub async fn claim(&mut self, user_id: UserID, claim: &str, hash: String) -> GResult<bool> {
+        if self.claims.get(&user_id).map(String::as_str) != Some(claim) {
+            return Ok(false);
+        }
+        let credential = Credential {
+            hash,
+            role: Role::Trader,
+        };
+        self.storage
+            .commit(vec![
+                Op::delete(format!("{CLAIMS_PREFIX}{user_id}")),
+                Self::op(user_id, &credential)?,
+            ])
+            .await?;
+        self.claims.remove(&user_id);
+        self.accounts.insert(user_id, credential);
+        Ok(true)
+    }
+
+    /// Credentials of a new account
+    pub async fn add(&mut self, user_id: UserID, hash: String, role: Role) -> GResult<()> {
+        let credential = Credential { hash, role };
+        self.storage
+            .commit(vec![Self::op(user_id, &credential)?])
+            .await?;
+        self.accounts.insert(user_id, credential);
+        Ok(())
+    }
+
+    pub fn has_admin(&self) -> bool {
+        self.accounts.values().any(|c| c.role.is_admin())
+    }
+
+    /// Whether `user_id` can be given `role`, the credentials it would have then
+    pub fn check_role(&self, user_id: UserID, role: Role) -> GResult<Credential> {
+        let mut credential = self
+            .accounts
+            .get(&user_id)
+            .ok_or_else(|| {
+                ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}"))
+            })?
+            .clone();
+        if credential.role.is_admin()
+            && !role.is_admin()
+            && self.accounts.values().filter(|c| c.role.is_admin()).count() == 1
+        {
+            return Err(
+                ErrorResponse::new(ErrorCode::BadRequest, "Can't demote the last admin").into(),
+            );
+        }
+        credential.role = role;
+        Ok(credential)
+    }
+
+    pub async fn set_role(&mut self, actor: UserID, user_id: UserID, role: Role) -> GResult<()> {
+        let credential = self.check_role(user_id, role)?;
+        let record = self
+            .audit
+            .record(actor, AuditAction::SetRole { user_id, role })?;
+        self.storage
+            .commit(vec![Self::op(user_id, &credential)?, record])
+            .await?;
+        self.accounts.insert(user_id, credential);
+        Ok(())
+    }
+
+    pub fn get(&self, user_id: &UserID) -> Option<&Credential> {
+        self.accounts.get(user_id)
+    }
+}
+
+/// Dividends admins asked for, each stored as `dividends/<id>` with what every node has paid of
+/// it. Kept once paid
+pub struct Dividends {
+    scheduled: BTreeMap<DividendID, ScheduledDividend>,
+    /// being paid by a task, never by two at once
+    paying: HashSet<DividendID>,
+    storage: Arc<dyn Storage>,
+}
+
+const DIVIDENDS_PREFIX: &str = "dividends/";
+
+impl Dividends {
+    fn restore(entries: &mut HashMap<String, Value>, storage: Arc<dyn Storage>) -> GResult<Self> {
+        Ok(Self {
+            scheduled: take(entries, DIVIDENDS_PREFIX)?.into_iter().collect(),
+            paying: HashSet::new(),
+            storage,
+        })
+    }
+
+    async fn update_file(&mut self, id: DividendID) -> GResult<()> {
+        self.storage
+            .commit(vec![Op::put(
+                format!("{DIVIDENDS_PREFIX}{id}"),
+                &self.scheduled[&id],
+            )?])
+            .await
+    }
+
+    /// Keep a new dividend, already marked as being paid by the caller
+    pub async fn schedule(&mut self, actor: UserID, dividend: Dividend) -> GResult<DividendID> {
+        let id = self.scheduled.keys().next_back().map_or(0, |&id| id + 1);
+        self.scheduled.insert(
+            id,
+            ScheduledDividend {
+                actor,
+                dividend,
+                paid: BTreeMap::new(),
+                done: false,
+            },
+        );
+        if let Err(e) = self.update_file(id).await {
+            self.scheduled.remove(&id);
+            return Err(e);
+        }
+        self.paying.insert(id);
+        Ok(id)
+    }
+
+    /// Mark every dividend not paid yet, and not being paid, as being paid by the caller
+    pub fn start_due(&mut self) -> Vec<DividendID> {
+        let due: Vec<DividendID> = self
+            .scheduled
+            .iter()
+            .filter(|(id, scheduled)| !scheduled.done && !self.paying.contains(id))
+            .map(|(&id, _)| id)
+            .collect();
+        self.paying.extend(&due);
+        due
+    }
+
+    pub fn get(&self, id: DividendID) -> &ScheduledDividend {
+        &self.scheduled[&id]
+    }
+
+    pub fn get_all(&self) -> &BTreeMap<DividendID, ScheduledDividend> {
+        &self.scheduled
+    }
+
+    /// Node `node_id`'s part of dividend `id`
+    pub async fn paid(
+        &mut self,
+        id: DividendID,
+        node_id: NodeID,
+        paid: Distribution,
+    ) -> GResult<()> {
+        let scheduled = self.scheduled.get_mut(&id).expect("Invalid dividend id");
+        scheduled.paid.insert(node_id, paid);
+        self.update_file(id).await
+    }
+
+    /// No longer being paid, `done` once every node has
+    pub async fn stop(&mut self, id: DividendID, done: bool) -> GResult<()> {
+        self.paying.remove(&id);
+        if !done {
+            return Ok(());
+        }
+        self.scheduled
+            .get_mut(&id)
+            .expect("Invalid dividend id")
+            .done = true;
+        self.update_file(id).await
+    }
+}
@@ -0,0 +1,155 @@
+//! Audit records, kept the same way by nodes and the coordinator.
+
+use crate::{decimal, money};
+use lib::{
+    audit::{AuditAction, AuditRecord, AUDIT_PREFIX},
+    interfaces::{Distribution, RiskLimits, Split},
+    GResult,
+};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Every record by id, or a problem for each one that can't be read
+pub fn parse(entries: &HashMap<String, Value>) -> (BTreeMap<u64, AuditRecord>, Vec<String>) {
+    let mut records = BTreeMap::new();
+    let mut problems = Vec::new();
+    for (key, value) in entries {
+        let Some(id) = key.strip_prefix(AUDIT_PREFIX) else {
+            continue;
+        };
+        let Ok(id) = id.parse::<u64>() else {
+            problems.push(format!("Unexpected key {key}"));
+            continue;
+        };
+        match serde_json::from_value(value.clone()) {
+            Ok(record) => {
+                records.insert(id, record);
+            }
+            Err(e) => problems.push(format!("Audit record {id} is unreadable: {e}")),
+        }
+    }
+    (records, problems)
+}
+
+fn describe(action: &AuditAction) -> String {
+    match action {
+        AuditAction::SetRole { user_id, role } => format!("made {user_id} {role:?}"),
+        AuditAction::ListStock {
+            user_id,
+            ticker,
+            quantity,
+        } => format!("listed {quantity} {ticker} into {user_id}"),
+        AuditAction::MintStock {
+            user_id,
+            ticker,
+            quantity,
+        } => format!("minted {quantity} {ticker} into {user_id}"),
+        AuditAction::SetBalance {
+            user_id,
+            currency,
+            from,
+            to,
+        } => format!(
+            "set the balance of {user_id} from {} to {}",
+            money(*from, currency),
+            money(*to, currency)
+        ),
+        AuditAction::Split(Split { ticker, from, to }) => {
+            format!("split {ticker}, every {from} shares became {to}")
+        }
+        AuditAction::Dividend {
+            dividend,
+            paid:
+                Distribution {
+                    accounts,
+                    shares,
+                    amount,
+                },
+        } => format!(
+            "paid a dividend of {} per {} held at {}, {} for {shares} shares in {accounts} accounts",
+            decimal(dividend.per_share),
+            dividend.ticker,
+            dividend.record_time,
+            decimal(*amount)
+        ),
+        AuditAction::AllowShort { user_id, allowed } => {
+            let can = if *allowed { "can" } else { "can't" };
+            format!("made it so {user_id} {can} short")
+        }
+        AuditAction::SetLendable { ticker, quantity } => {
+            format!("let the node lend up to {quantity} {ticker}")
+        }
+        AuditAction::SetMargin {
+            user_id,
+            terms: Some(terms),
+        } => format!(
+            "made {user_id} a margin account, {}x with {}% maintenance",
+            terms.leverage, terms.maintenance_pct
+        ),
+        AuditAction::SetMargin {
+            user_id,
+            terms: None,
+        } => format!("made {user_id} a cash account"),
+        AuditAction::KillSwitch { user_id, engaged } => {
+            let did = if *engaged { "engaged" } else { "released" };
+            format!("{did} the kill switch of {user_id}")
+        }
+        AuditAction::SetRiskLimits { user_id, limits } => {
+            format!("limited {user_id} to {}", describe_limits(limits))
+        }
+        AuditAction::IssueClaimCode { user_id } => {
+            format!("issued a code to claim {user_id} with")
+        }
+        AuditAction::ConfirmDeposit {
+            user_id,
+            id,
+            cash,
+            accepted,
+        } => {
+            let did = if *accepted { "credited" } else { "dropped" };
+            format!(
+                "{did} deposit {id} of {user_id}, {}",
+                money(cash.amount, &cash.currency)
+            )
+        }
+    }
+}
+
+/// The limits that are set, or none
+pub fn describe_limits(
+    RiskLimits {
+        max_order_quantity,
+        max_notional,
+        max_open_orders,
+        max_position,
+        max_orders_per_sec,
+    }: &RiskLimits,
+) -> String {
+    let limits: Vec<String> = [
+        max_order_quantity.map(|max| format!("{max} shares an order")),
+        max_notional.map(|max| format!("{} an order", decimal(max))),
+        max_open_orders.map(|max| format!("{max} open orders")),
+        max_position.map(|max| format!("{max} shares of a stock")),
+        max_orders_per_sec.map(|max| format!("{max} orders a second")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if limits.is_empty() {
+        "no limits".to_owned()
+    } else {
+        limits.join(", ")
+    }
+}
+
+pub fn dump(entries: &HashMap<String, Value>) -> GResult<()> {
+    let (records, problems) = parse(entries);
+    if let Some(problem) = problems.first() {
+        return Err(problem.clone().into());
+    }
+    println!("{} audit records", records.len());
+    for (id, AuditRecord { actor, at, action }) in records {
+        println!("  {id} at {at}: {actor} {}", describe(&action));
+    }
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//! Read only copies of what coordinator's `state.rs` persists.
+
+use crate::audit;
+use lib::{
+    audit::AUDIT_PREFIX,
+    auth::SigningKey,
+    interfaces::{Distribution, Dividend, NodeID, Role, UserID},
+    storage::VERSION_KEY,
+    GResult,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+};
+
+/// Number of migrations in coordinator's `migrations::MIGRATIONS`, keep these in sync.
+pub const SCHEMA_VERSION: u64 = 5;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeRecord {
+    address: SocketAddr,
+}
+
+fn parse(entries: &HashMap<String, Value>) -> GResult<(Vec<NodeRecord>, Vec<u64>)> {
+    let records = serde_json::from_value(
+        entries
+            .get("node_records")
+            .cloned()
+            .ok_or("No node_records")?,
+    )
+    .map_err(|e| format!("node_records is unreadable: {e}"))?;
+    let nums = serde_json::from_value(
+        entries
+            .get("account_nums")
+            .cloned()
+            .ok_or("No account_nums")?,
+    )
+    .map_err(|e| format!("account_nums is unreadable: {e}"))?;
+    Ok((records, nums))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Credential {
+    hash: String,
+    role: Role,
+}
+
+const CREDENTIALS_PREFIX: &str = "credentials/";
+const CLAIMS_PREFIX: &str = "claims/";
+
+/// Credentials of every account by id, or a problem for each one that can't be read
+fn parse_credentials(
+    entries: &HashMap<String, Value>,
+) -> (HashMap<UserID, Credential>, Vec<String>) {
+    let mut credentials = HashMap::new();
+    let mut problems = Vec::new();
+    for (key, value) in entries {
+        let Some(user_id) = key.strip_prefix(CREDENTIALS_PREFIX) else {
+            continue;
+        };
+        let Ok(user_id) = user_id.parse::<UserID>() else {
+            problems.push(format!("Unexpected key {key}"));
+            continue;
+        };
+        match serde_json::from_value(value.clone()) {
+            Ok(credential) => {
+                credentials.insert(user_id, credential);
+            }
+            Err(e) => problems.push(format!("Credentials of {user_id} are unreadable: {e}")),
+        }
+    }
+    (credentials, problems)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduledDividend {
+    actor: UserID,
+    dividend: Dividend,
+    paid: BTreeMap<NodeID, Distribution>,
+    done: bool,
+}
+
+const DIVIDENDS_PREFIX: &str = "dividends/";
+
+/// Dividends by id, or a problem for each one that can't be read
+fn parse_dividends(
+    entries: &HashMap<String, Value>,
+) -> (BTreeMap<u64, ScheduledDividend>, Vec<String>) {
+    let mut dividends = BTreeMap::new();
+    let mut problems = Vec::new();
+    for (key, value) in entries {
+        let Some(id) = key.strip_prefix(DIVIDENDS_PREFIX) else {
+            continue;
+        };
+        let Ok(id) = id.parse::<u64>() else {
+            problems.push(format!("Unexpected key {key}"));
+            continue;
+        };
+        match serde_json::from_value(value.clone()) {
+            Ok(dividend) => {
+                dividends.insert(id, dividend);
+            }
+            Err(e) => problems.push(format!("Dividend {id} is unreadable: {e}")),
+        }
+    }
+    (dividends, problems)
+}
+
+pub fn validate(entries: &HashMap<String, Value>) -> Vec<String> {
+    let (credentials, mut problems) = parse_credentials(entries);
+    let (_, audit_problems) = audit::parse(entries);
+    problems.extend(audit_problems);
+    let (dividends, dividend_problems) = parse_dividends(entries);
+    problems.extend(dividend_problems);
+    for key in entries.keys() {
+        if !matches!(
+            key.as_str(),
+            "node_records" | "account_nums" | "signing_key" | VERSION_KEY
+        ) && !key.starts_with(CREDENTIALS_PREFIX)
+            && !key.starts_with(CLAIMS_PREFIX)
+            && !key.starts_with(AUDIT_PREFIX)
+            && !key.starts_with(DIVIDENDS_PREFIX)
+        {
+            problems.push(format!("Unexpected key {key}"));
+        }
+    }
+    for (key, value) in entries {
+        let Some(user_id) = key.strip_prefix(CLAIMS_PREFIX) else {
+            continue;
+        };
+        let Ok(user_id) = user_id.parse::<UserID>() else {
+            problems.push(format!("Unexpected key {key}"));
+            continue;
+        };
+        if credentials.contains_key(&user_id) {
+            problems.push(format!("{user_id} has a password and a claim code"));
+        }
+        if !value
+            .as_str()
+            .is_some_and(|hash| hash.starts_with("$argon2"))
+        {
+            problems.push(format!("Claim code of {user_id} isn't an argon2 hash"));
+        }
+    }
+    match entries.get("signing_key").cloned() {
+        Some(key) => {
+            if let Err(e) = serde_json::from_value::<SigningKey>(key) {
+                problems.push(format!("signing_key is unreadable: {e}"));
+            }
+        }
+        None => problems.push("No signing_key".to_owned()),
+    }
+    match parse(entries) {
+        Ok((records, nums)) => {
+            for (user_id, credential) in &credentials {
+                if user_id.node_id >= records.len() {
+                    problems.push(format!("Password of {user_id} is for an unknown node"));
+                }
+                if !credential.hash.starts_with("$argon2") {
+                    problems.push(format!("Password of {user_id} isn't an argon2 hash"));
+                }
+            }
+            for (id, scheduled) in &dividends {
+                if scheduled
+                    .paid
+                    .keys()
+                    .any(|&node_id| node_id >= records.len())
+                {
+                    problems.push(format!("Dividend {id} was paid by an unknown node"));
+                }
+                if let Err(e) = scheduled.dividend.validate() {
+                    problems.push(format!("Dividend {id}: {e}"));
+                }
+            }
+            if records.len() != nums.len() {
+                problems.push(format!(
+                    "{} node records but {} account counts",
+                    records.len(),
+                    nums.len()
+                ));
+            }
+        }
+        Err(e) => problems.push(e.to_string()),
+    }
+    problems
+}
+
+pub fn dump(entries: &HashMap<String, Value>) -> GResult<()> {
+    let (records, nums) = parse(entries)?;
+    println!(
+        "Coordinator, schema version {}",
+        entries.get(VERSION_KEY).unwrap_or(&Value::Null)
+    );
+    for (id, record) in records.iter().enumerate() {
+        match nums.get(id) {
+            Some(num) => println!("Node {id} at {}, {num} accounts", record.address),
+            None => println!("Node {id} at {}, unknown accounts", record.address),
+        }
+    }
+    let (credentials, _) = parse_credentials(entries);
+    println!("{} accounts have a password", credentials.len());
+    let claims = entries
+        .keys()
+        .filter(|key| key.starts_with(CLAIMS_PREFIX))
+        .count();
+    println!("{claims} accounts have a claim code");
+    let mut admins: Vec<String> = credentials
+        .iter()
+        .filter(|(_, c)| c.role != Role::Trader)
+        .map(|(user_id, c)| format!("{user_id} {:?}", c.role))
+        .collect();
+    admins.sort();
+    for admin in admins {
+        println!("  {admin}");
+    }
+    let (dividends, _) = parse_dividends(entries);
+    for (id, scheduled) in dividends {
+        let dividend = scheduled.dividend;
+        println!(
+            "Dividend {id} of {} per {} at {} by {}, {}",
+            dividend.per_share,
+            dividend.ticker,
+            dividend.record_time,
+            scheduled.actor,
+            if scheduled.done {
+                "paid"
+            } else {
+                "not paid yet"
+            }
+        );
+        for (node_id, paid) in scheduled.paid {
+            println!(
+                "  node {node_id} paid {} to {} accounts",
+                paid.amount, paid.accounts
+            );
+        }
+    }
+    audit::dump(entries)?;
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Offline tool for the persistent directory of a node or coordinator, which must not be running.
+
+mod audit;
+mod coordinator;
+mod node;
+
+use lib::{
+    interfaces::CentCount,
+    storage::{self, Op, StorageKind, VERSION_KEY},
+    GResult,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+use tokio::fs;
+
+#[derive(StructOpt)]
+enum Args {
+    /// Check everything in a persistent directory is readable and consistent
+    Validate(Dir),
+    /// Print a persistent directory in human readable form
+    Dump(Dir),
+    /// Write a validated copy of a persistent directory into one archive file
+    Backup {
+        #[structopt(flatten)]
+        dir: Dir,
+
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+    /// Restore an archive into an empty persistent directory
+    Restore {
+        #[structopt(flatten)]
+        dir: Dir,
+
+        #[structopt(short, long)]
+        input: PathBuf,
+    },
+}
+
+#[derive(StructOpt)]
+struct Dir {
+    #[structopt(short, long)]
+    persistent_dir: String,
+
+    /// file or kv
+    #[structopt(short, long, default_value = "file")]
+    storage: StorageKind,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Node,
+    Coordinator,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    kind: Kind,
+    entries: BTreeMap<String, Value>,
+}
+
+/// Prices are in cents of their instrument's currency
+fn decimal(cents: CentCount) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+fn money(cents: CentCount, currency: &str) -> String {
+    format!("{} {currency}", decimal(cents))
+}
+
+fn kind_of(entries: &HashMap<String, Value>) -> GResult<Kind> {
+    if entries.contains_key("state") {
+        Ok(Kind::Node)
+    } else if entries.contains_key("node_records") {
+        Ok(Kind::Coordinator)
+    } else {
+        Err("Neither a node nor a coordinator directory".into())
+    }
+}
+
+/// Every problem found, empty if valid
+fn validate(kind: Kind, entries: &HashMap<String, Value>) -> Vec<String> {
+    let (latest, mut problems) = match kind {
+        Kind::Node => (node::SCHEMA_VERSION, node::validate(entries)),
+        Kind::Coordinator => (coordinator::SCHEMA_VERSION, coordinator::validate(entries)),
+    };
+    match entries.get(VERSION_KEY).map(|v| v.as_u64()) {
+        Some(Some(version)) if version == latest => (),
+        Some(Some(version)) => problems.insert(
+            0,
+            format!("Schema version {version}, this tool only understands {latest}"),
+        ),
+        Some(None) => problems.insert(0, "Schema version isn't a number".to_owned()),
+        None => problems.insert(
+            0,
+            "No schema version, start the server once to migrate".to_owned(),
+        ),
+    }
+    problems
+}
+
+fn report(kind: Kind, entries: &HashMap<String, Value>) -> GResult<()> {
+    let problems = validate(kind, entries);
+    if problems.is_empty() {
+        println!("{kind:?} state is valid, {} entries", entries.len());
+        Ok(())
+    } else {
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        Err(format!("{} problems found", problems.len()).into())
+    }
+}
+
+#[tokio::main]
+async fn main() -> GResult<()> {
+    match Args::from_args() {
+        Args::Validate(Dir {
+            persistent_dir,
+            storage,
+        }) => {
+            let entries = storage::read(storage, &persistent_dir).await?;
+            report(kind_of(&entries)?, &entries)
+        }
+        Args::Dump(Dir {
+            persistent_dir,
+            storage,
+        }) => {
+            let entries = storage::read(storage, &persistent_dir).await?;
+            match kind_of(&entries)? {
+                Kind::Node => node::dump(&entries),
+                Kind::Coordinator => coordinator::dump(&entries),
+            }
+        }
+        Args::Backup {
+            dir: Dir {
+                persistent_dir,
+                storage,
+            },
+            output,
+        } => {
+            let entries = storage::read(storage, &persistent_dir).await?;
+            let kind = kind_of(&entries)?;
+            report(kind, &entries)?;
+            let backup = Backup {
+                kind,
+                entries: entries.into_iter().collect(),
+            };
+            // write then rename so a partial archive never looks complete
+            let tmp = output.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_vec_pretty(&backup)?).await?;
+            fs::File::open(&tmp).await?.sync_all().await?;
+            fs::rename(&tmp, &output).await?;
+            println!("Backed up to {}", output.display());
+            Ok(())
+        }
+        Args::Restore {
+            dir: Dir {
+                persistent_dir,
+                storage,
+            },
+            input,
+        } => {
+            let Backup { kind, entries } = serde_json::from_slice(&fs::read(&input).await?)?;
+            let entries: HashMap<String, Value> = entries.into_iter().collect();
+            report(kind, &entries)?;
+
+            fs::create_dir_all(&persistent_dir).await?;
+            let storage = storage::open(storage, &persistent_dir).await?;
+            if !storage.entries().await?.is_empty() {
+                return Err(format!("{persistent_dir} already has state in it").into());
+            }
+            let mut ops = Vec::new();
+            for (key, value) in &entries {
+                ops.push(Op::put(key, value)?);
+            }
+            storage.commit(ops).await?;
+            println!("Restored {kind:?} state into {persistent_dir}");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,639 @@
+//! Read only copies of what node's `state.rs` persists.
+
+use crate::{audit, decimal, money};
+use lib::{
+    audit::AUDIT_PREFIX,
+    interfaces::{
+        Asset, Cash, CentCount, Currency, DepositID, Distribution, DividendID, KilledBy,
+        LedgerAccount, LedgerEntry, MarginTerms, Memo, NodeID, OrderType, Quantity, RiskLimits,
+        Role, StockEntry, StockMemo, Ticker, UserID,
+    },
+    storage::VERSION_KEY,
+    GResult,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Number of migrations in node's `migrations::MIGRATIONS`, keep these in sync.
+pub const SCHEMA_VERSION: u64 = 17;
+
+const LEDGER_PREFIX: &str = "ledger/";
+const STOCK_PREFIX: &str = "stock/";
+
+type TradeID = usize;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StateFile {
+    id: NodeID,
+    next_account_id: usize,
+    next_trade_id: usize,
+    pending_to_user: HashMap<TradeID, usize>,
+    lendable: BTreeMap<Ticker, Quantity>,
+    marks: BTreeMap<Ticker, CentCount>,
+    dividends: BTreeMap<DividendID, Distribution>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Account {
+    id: UserID,
+    portfolio: BTreeMap<Ticker, Quantity>,
+    costs: BTreeMap<Ticker, Basis>,
+    can_short: bool,
+    role: Option<Role>,
+    borrowed: BTreeMap<Ticker, Quantity>,
+    collateral: BTreeMap<Ticker, CentCount>,
+    margin: Option<MarginTerms>,
+    loan: BTreeMap<Currency, CentCount>,
+    liquidating: bool,
+    limits: RiskLimits,
+    killed: Option<KilledBy>,
+    buys: BTreeMap<Ticker, BTreeMap<CentCount, Quantity>>,
+    sells: BTreeMap<Ticker, BTreeMap<CentCount, Quantity>>,
+    pending: BTreeMap<TradeID, Trade>,
+    pending_fees: BTreeMap<TradeID, CentCount>,
+    transfers: BTreeMap<TradeID, Transfer>,
+    deposits: BTreeMap<DepositID, Cash>,
+    next_deposit_id: DepositID,
+    owed_fees: Vec<Transfer>,
+}
+
+/// Average cost of a position, below zero for a short one
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Basis {
+    quantity: i64,
+    cost: CentCount,
+    realised: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Transfer {
+    from: UserID,
+    to: UserID,
+    asset: Asset,
+    memo: Memo,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Trade {
+    quantity: Quantity,
+    price: CentCount,
+    ticker: Ticker,
+    buyer_id: UserID,
+    seller_id: UserID,
+    buy_price: CentCount,
+    sell_price: CentCount,
+    taker: OrderType,
+}
+
+fn parse_state(entries: &HashMap<String, Value>) -> GResult<StateFile> {
+    let state = entries.get("state").cloned().ok_or("No state")?;
+    Ok(serde_json::from_value(state).map_err(|e| format!("State is unreadable: {e}"))?)
+}
+
+/// Every account by id, or a problem for each one that can't be read
+fn parse_accounts(entries: &HashMap<String, Value>) -> (BTreeMap<usize, Account>, Vec<String>) {
+    let mut accounts = BTreeMap::new();
+    let mut problems = Vec::new();
+    for (key, value) in entries {
+        if key == "state"
+            || key == VERSION_KEY
+            || key.starts_with(AUDIT_PREFIX)
+            || key.starts_with(LEDGER_PREFIX)
+            || key.starts_with(STOCK_PREFIX)
+        {
+            continue;
+        }
+        let Ok(id) = key.parse::<usize>() else {
+            problems.push(format!("Unexpected key {key}"));
+            continue;
+        };
+        match serde_json::from_value(value.clone()) {
+            Ok(account) => {
+                accounts.insert(id, account);
+            }
+            Err(e) => problems.push(format!("Account {id} is unreadable: {e}")),
+        }
+    }
+    (accounts, problems)
+}
+
+/// Every account's entries under `prefix` by account id in order of `id`, or a problem for each
+/// entry that can't be read
+fn parse_ledgers<T: DeserializeOwned>(
+    entries: &HashMap<String, Value>,
+    prefix: &str,
+    id: fn(&T) -> u64,
+) -> (BTreeMap<usize, Vec<T>>, Vec<String>) {
+    let mut ledgers: BTreeMap<usize, Vec<T>> = BTreeMap::new();
+    let mut problems = Vec::new();
+    for (key, value) in entries {
+        let Some(rest) = key.strip_prefix(prefix) else {
+            continue;
+        };
+        let Some(Ok(account_id)) = rest.split_once('/').map(|(id, _)| id.parse::<usize>()) else {
+            problems.push(format!("Unexpected key {key}"));
+            continue;
+        };
+        match serde_json::from_value::<T>(value.clone()) {
+            Ok(entry) if format!("{prefix}{account_id}/{}", id(&entry)) == *key => {
+                ledgers.entry(account_id).or_default().push(entry);
+            }
+            Ok(entry) => problems.push(format!("{key} holds ledger entry {}", id(&entry))),
+            Err(e) => problems.push(format!("{key} is unreadable: {e}")),
+        }
+    }
+    for ledger in ledgers.values_mut() {
+        ledger.sort_by_key(id);
+    }
+    (ledgers, problems)
+}
+
+/// Cash of `account` by currency according to its ledger
+fn cash(account: &UserID, ledger: &[LedgerEntry]) -> BTreeMap<Currency, i128> {
+    let mut cash = BTreeMap::new();
+    for entry in ledger {
+        *cash.entry(entry.currency.clone()).or_default() +=
+            entry.change_for(LedgerAccount::User(*account));
+    }
+    cash
+}
+
+/// Positions of `account` by ticker according to its fills and stock ledger, FX pairs hold none
+fn positions(
+    account: &UserID,
+    ledger: &[LedgerEntry],
+    stock_ledger: &[StockEntry],
+) -> BTreeMap<Ticker, i128> {
+    let fx: HashSet<&Ticker> = ledger
+        .iter()
+        .filter_map(|entry| match &entry.memo {
+            Memo::Fx { ticker, .. } => Some(ticker),
+            _ => None,
+        })
+        .collect();
+    let mut positions = BTreeMap::new();
+    for entry in ledger {
+        if let Memo::Trade {
+            ticker, quantity, ..
+        } = &entry.memo
+        {
+            if !fx.contains(ticker) {
+                let bought = entry.from == LedgerAccount::User(*account);
+                *positions.entry(ticker.clone()).or_default() +=
+                    i128::from(*quantity) * if bought { 1 } else { -1 };
+            }
+        }
+    }
+    for entry in stock_ledger {
+        *positions.entry(entry.ticker.clone()).or_default() += i128::from(entry.change);
+    }
+    positions
+}
+
+fn ledger_account(account: &LedgerAccount) -> String {
+    match account {
+        LedgerAccount::User(user_id) => user_id.to_string(),
+        LedgerAccount::External => "outside".to_owned(),
+    }
+}
+
+fn describe(entry: &LedgerEntry) -> String {
+    let memo = match &entry.memo {
+        Memo::Deposit => "deposit".to_owned(),
+        Memo::Withdrawal => "withdrawal".to_owned(),
+        Memo::Trade {
+            ticker,
+            quantity,
+            price,
+        } => format!("trade {quantity} {ticker} @ {}", decimal(*price)),
+        Memo::Fx {
+            ticker,
+            quantity,
+            price,
+        } => format!("fx {quantity} {ticker} @ {}", decimal(*price)),
+        Memo::Adjustment { by } => format!("adjustment by {by}"),
+        Memo::Opening => "opening balance".to_owned(),
+        Memo::Transfer => "transfer".to_owned(),
+        Memo::Dividend {
+            ticker,
+            quantity,
+            per_share,
+        } => format!("dividend for {quantity} {ticker} @ {}", decimal(*per_share)),
+        Memo::Fee {
+            ticker,
+            quantity,
+            price,
+            trade,
+        } => match trade {
+            Some(trade) => format!(
+                "fee for {quantity} {ticker} @ {} of entry {trade}",
+                decimal(*price)
+            ),
+            None => format!("fee for {quantity} {ticker} @ {}", decimal(*price)),
+        },
+        Memo::MarginLoan => "margin loan".to_owned(),
+        Memo::MarginRepayment => "margin repayment".to_owned(),
+    };
+    format!(
+        "{} at {}: {} from {} to {}, {memo}",
+        entry.id,
+        entry.at,
+        money(entry.amount, &entry.currency),
+        ledger_account(&entry.from),
+        ledger_account(&entry.to)
+    )
+}
+
+fn describe_stock(entry: &StockEntry) -> String {
+    let memo = match &entry.memo {
+        StockMemo::Listed => "listed".to_owned(),
+        StockMemo::Transfer { from, to } => format!("transfer from {from} to {to}"),
+        StockMemo::Split { from, to } => format!("split, every {from} shares became {to}"),
+        StockMemo::Opening => "opening position".to_owned(),
+    };
+    format!(
+        "{} at {}: {} {}, {memo}",
+        entry.id, entry.at, entry.change, entry.ticker
+    )
+}
+
+pub fn validate(entries: &HashMap<String, Value>) -> Vec<String> {
+    let (accounts, mut problems) = parse_accounts(entries);
+    let (ledgers, ledger_problems) = parse_ledgers(entries, LEDGER_PREFIX, |e: &LedgerEntry| e.id);
+    problems.extend(ledger_problems);
+    let (stock_ledgers, stock_problems) =
+        parse_ledgers(entries, STOCK_PREFIX, |e: &StockEntry| e.id);
+    problems.extend(stock_problems);
+    let (_, audit_problems) = audit::parse(entries);
+    problems.extend(audit_problems);
+    let state = match parse_state(entries) {
+        Ok(state) => state,
+        Err(e) => {
+            problems.push(e.to_string());
+            return problems;
+        }
+    };
+
+    for (&id, account) in &accounts {
+        if id >= state.next_account_id {
+            problems.push(format!(
+                "Account {id} is not below next_account_id {}",
+                state.next_account_id
+            ));
+        }
+        if account.id.id != id || account.id.node_id != state.id {
+            problems.push(format!("Account {id} is stored with UserID {}", account.id));
+        }
+        for (trade_id, trade) in &account.pending {
+            if state.pending_to_user.get(trade_id) != Some(&id) {
+                problems.push(format!(
+                    "Pending trade {trade_id} of account {id} isn't recorded in the state"
+                ));
+            }
+            if trade.buyer_id != account.id && trade.seller_id != account.id {
+                problems.push(format!(
+                    "Pending trade {trade_id} of account {id} is between {} and {}",
+                    trade.buyer_id, trade.seller_id
+                ));
+            }
+        }
+        for (ticker, &borrowed) in &account.borrowed {
+            if borrowed == 0 {
+                problems.push(format!("Account {id} owes nothing of {ticker}"));
+            }
+        }
+        for ticker in account.collateral.keys() {
+            if !account.borrowed.contains_key(ticker) {
+                problems.push(format!(
+                    "Account {id} holds collateral for {ticker} which it didn't borrow"
+                ));
+            }
+        }
+        if let Some(terms) = &account.margin {
+            if let Err(e) = terms.validate() {
+                problems.push(format!("Account {id} has bad margin terms: {e}"));
+            }
+        } else if account.liquidating {
+            problems.push(format!("Account {id} is liquidating without margin"));
+        }
+        for (currency, &loan) in &account.loan {
+            if loan == 0 {
+                problems.push(format!(
+                    "Account {id} owes a margin loan of nothing {currency}"
+                ));
+            }
+        }
+        for trade_id in account.pending_fees.keys() {
+            if !account
+                .pending
+                .get(trade_id)
+                .is_some_and(|trade| trade.buyer_id == account.id)
+            {
+                problems.push(format!(
+                    "Account {id} holds a fee for {trade_id} which isn't a pending buy"
+                ));
+            }
+        }
+        if let Some(&deposit_id) = account.deposits.keys().next_back() {
+            if deposit_id >= account.next_deposit_id {
+                problems.push(format!(
+                    "Account {id} has deposit {deposit_id} but the next id is {}",
+                    account.next_deposit_id
+                ));
+            }
+        }
+        for owed in &account.owed_fees {
+            if owed.from != account.id
+                || !matches!(owed.memo, Memo::Fee { .. })
+                || !matches!(owed.asset, Asset::Cash(Cash { amount, .. }) if amount > 0)
+            {
+                problems.push(format!(
+                    "Account {id} owes a fee to {} that isn't one",
+                    owed.to
+                ));
+            }
+        }
+        for (transfer_id, transfer) in &account.transfers {
+            if state.pending_to_user.get(transfer_id) != Some(&id) {
+                problems.push(format!(
+                    "Pending transfer {transfer_id} of account {id} isn't recorded in the state"
+                ));
+            }
+            if account.pending.contains_key(transfer_id) {
+                problems.push(format!(
+                    "Pending transfer {transfer_id} of account {id} is also a pending trade"
+                ));
+            }
+            if transfer.from != account.id || transfer.to.node_id == state.id {
+                problems.push(format!(
+                    "Pending transfer {transfer_id} of account {id} is from {} to {}",
+                    transfer.from, transfer.to
+                ));
+            }
+        }
+        let ledger = ledgers.get(&id).map(Vec::as_slice).unwrap_or_default();
+        let stock_ledger = stock_ledgers
+            .get(&id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let mut positions = positions(&account.id, ledger, stock_ledger);
+        for (ticker, basis) in &account.costs {
+            let position = positions.remove(ticker).unwrap_or(0);
+            if position != i128::from(basis.quantity) {
+                problems.push(format!(
+                    "Ledgers of account {id} add up to {position} {ticker}, its position is {}",
+                    basis.quantity
+                ));
+            }
+        }
+        for (ticker, position) in positions.iter().filter(|(_, &p)| p != 0) {
+            problems.push(format!(
+                "Ledgers of account {id} add up to {position} {ticker} without a position"
+            ));
+        }
+    }
+
+    for (&id, stock_ledger) in &stock_ledgers {
+        if id >= state.next_account_id {
+            problems.push(format!(
+                "Stock ledger of account {id} is not below next_account_id {}",
+                state.next_account_id
+            ));
+        }
+        if let Some(i) = (0..stock_ledger.len()).find(|&i| stock_ledger[i].id != i as u64) {
+            problems.push(format!("Stock ledger of account {id} is missing entry {i}"));
+        }
+    }
+
+    for (&id, ledger) in &ledgers {
+        if id >= state.next_account_id {
+            problems.push(format!(
+                "Ledger of account {id} is not below next_account_id {}",
+                state.next_account_id
+            ));
+        }
+        let user_id = UserID {
+            id,
+            node_id: state.id,
+        };
+        let account = LedgerAccount::User(user_id);
+        for (i, entry) in ledger.iter().enumerate() {
+            if entry.id != i as u64 {
+                problems.push(format!("Ledger of account {id} is missing entry {i}"));
+                break;
+            }
+            if entry.from != account && entry.to != account {
+                problems.push(format!(
+                    "Ledger entry {i} of account {id} doesn't involve the account"
+                ));
+            }
+            if let Memo::Fee {
+                trade: Some(trade), ..
+            } = entry.memo
+            {
+                let is_trade = ledger.get(trade as usize).is_some_and(|traded| {
+                    trade < entry.id && matches!(traded.memo, Memo::Trade { .. })
+                });
+                if entry.from == account && !is_trade {
+                    problems.push(format!(
+                        "Fee {i} of account {id} is for entry {trade}, which isn't an earlier trade"
+                    ));
+                }
+            }
+        }
+        for (currency, _) in cash(&user_id, ledger).iter().filter(|(_, &c)| c < 0) {
+            problems.push(format!(
+                "Ledger of account {id} sums to below zero {currency}"
+            ));
+        }
+    }
+
+    for (trade_id, user) in &state.pending_to_user {
+        if *trade_id >= state.next_trade_id {
+            problems.push(format!(
+                "Pending trade {trade_id} is not below next_trade_id {}",
+                state.next_trade_id
+            ));
+        }
+        match accounts.get(user) {
+            Some(account)
+                if account.pending.contains_key(trade_id)
+                    || account.transfers.contains_key(trade_id) => {}
+            Some(_) => problems.push(format!(
+                "Pending trade {trade_id} is missing from account {user}"
+            )),
+            None => problems.push(format!(
+                "Pending trade {trade_id} references unknown account {user}"
+            )),
+        }
+    }
+    problems
+}
+
+pub fn dump(entries: &HashMap<String, Value>) -> GResult<()> {
+    let state = parse_state(entries)?;
+    let (accounts, mut problems) = parse_accounts(entries);
+    let (ledgers, ledger_problems) = parse_ledgers(entries, LEDGER_PREFIX, |e: &LedgerEntry| e.id);
+    problems.extend(ledger_problems);
+    let (stock_ledgers, stock_problems) =
+        parse_ledgers(entries, STOCK_PREFIX, |e: &StockEntry| e.id);
+    problems.extend(stock_problems);
+    if let Some(problem) = problems.first() {
+        return Err(problem.clone().into());
+    }
+
+    println!(
+        "Node {}, schema version {}",
+        state.id,
+        entries.get(VERSION_KEY).unwrap_or(&Value::Null)
+    );
+    println!(
+        "next account id {}, next trade id {}, {} pending trades and transfers",
+        state.next_account_id,
+        state.next_trade_id,
+        state.pending_to_user.len()
+    );
+    for (ticker, quantity) in &state.lendable {
+        println!("lends up to {quantity} {ticker}");
+    }
+    for (ticker, &price) in &state.marks {
+        println!("marks {ticker} @ {}", decimal(price));
+    }
+    for (id, paid) in &state.dividends {
+        println!(
+            "paid dividend {id}, {} to {} accounts",
+            paid.amount, paid.accounts
+        );
+    }
+    for (id, account) in &accounts {
+        let ledger = ledgers.get(id).map(Vec::as_slice).unwrap_or_default();
+        println!();
+        println!("Account {}", account.id);
+        for (currency, cash) in cash(&account.id, ledger) {
+            println!("  cash: {}", money(cash as CentCount, &currency));
+        }
+        for (ticker, quantity) in &account.portfolio {
+            println!("  holds {quantity} {ticker}");
+        }
+        let held = |(_, basis): &(&Ticker, &Basis)| basis.quantity != 0 || basis.realised != 0;
+        for (ticker, basis) in account.costs.iter().filter(held) {
+            let sign = if basis.realised < 0 { "-" } else { "" };
+            println!(
+                "  position of {} {ticker} cost {}, realised {sign}{}",
+                basis.quantity,
+                decimal(basis.cost),
+                decimal(basis.realised.unsigned_abs())
+            );
+        }
+        if account.can_short {
+            println!("  can short");
+        }
+        if let Some(role) = account.role {
+            println!("  made {role:?} by an admin");
+        }
+        for (ticker, quantity) in &account.borrowed {
+            let collateral = account.collateral.get(ticker).copied().unwrap_or_default();
+            println!(
+                "  borrowed {quantity} {ticker}, {} collateral",
+                decimal(collateral)
+            );
+        }
+        if let Some(terms) = &account.margin {
+            println!(
+                "  margin account, {}x with {}% maintenance",
+                terms.leverage, terms.maintenance_pct
+            );
+        }
+        for (currency, &loan) in &account.loan {
+            println!("  owes {} of margin loan", money(loan, currency));
+        }
+        if account.liquidating {
+            println!("  liquidating");
+        }
+        if account.limits != RiskLimits::default() {
+            println!("  limited to {}", audit::describe_limits(&account.limits));
+        }
+        match account.killed {
+            Some(KilledBy::Account) => println!("  kill switch engaged"),
+            Some(KilledBy::Admin) => println!("  kill switch engaged by an admin"),
+            None => {}
+        }
+        for (side, orders) in [("buy", &account.buys), ("sell", &account.sells)] {
+            for (ticker, levels) in orders {
+                for (&price, quantity) in levels.iter().filter(|(_, &q)| q > 0) {
+                    println!("  {side} order {quantity} {ticker} @ {}", decimal(price));
+                }
+            }
+        }
+        for (trade_id, trade) in &account.pending {
+            let (side, other, order_price, order_type) = if trade.buyer_id == account.id {
+                ("buying", trade.seller_id, trade.buy_price, OrderType::Buy)
+            } else {
+                ("selling", trade.buyer_id, trade.sell_price, OrderType::Sell)
+            };
+            let role = if trade.taker == order_type {
+                "taker"
+            } else {
+                "maker"
+            };
+            println!(
+                "  pending trade {trade_id}: {side} {} {} @ {} with {other}, {role}, order @ {}",
+                trade.quantity,
+                trade.ticker,
+                decimal(trade.price),
+                decimal(order_price)
+            );
+            if let Some(&fee) = account.pending_fees.get(trade_id) {
+                println!("    fee of {} held", decimal(fee));
+            }
+        }
+        for (transfer_id, transfer) in &account.transfers {
+            let what = match &transfer.asset {
+                Asset::Cash(cash) => money(cash.amount, &cash.currency),
+                Asset::Stock { ticker, quantity } => format!("{quantity} {ticker}"),
+            };
+            let kind = match transfer.memo {
+                Memo::Fee { .. } => "fee",
+                _ => "transfer",
+            };
+            println!("  pending {kind} {transfer_id}: {what} to {}", transfer.to);
+        }
+        for owed in &account.owed_fees {
+            if let Asset::Cash(cash) = &owed.asset {
+                println!(
+                    "  owed fee: {} to {}",
+                    money(cash.amount, &cash.currency),
+                    owed.to
+                );
+            }
+        }
+        for (deposit_id, cash) in &account.deposits {
+            println!(
+                "  pending deposit {deposit_id}: {}",
+                money(cash.amount, &cash.currency)
+            );
+        }
+        for entry in ledger {
+            println!("  ledger {}", describe(entry));
+        }
+        for entry in stock_ledgers.get(id).into_iter().flatten() {
+            println!("  stock ledger {}", describe_stock(entry));
+        }
+    }
+    for (id, ledger) in ledgers.iter().filter(|(id, _)| !accounts.contains_key(id)) {
+        let stock_entries = stock_ledgers.get(id).map(Vec::len).unwrap_or_default();
+        println!();
+        println!(
+            "Closed account {id}, {} ledger entries, {stock_entries} stock ledger entries",
+            ledger.len()
+        );
+    }
+    println!();
+    audit::dump(entries)
+}
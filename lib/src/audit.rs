@@ -0,0 +1,132 @@
+//! Who did what with admin or market operator rights.
+//! Each record is stored as `audit/<n>` next to the state it changed, committed in the same
+//! batch, and never rewritten.
+
+use crate::{
+    interfaces::{
+        Cash, Currency, DepositID, Distribution, Dividend, MarginTerms, Quantity, RiskLimits, Role,
+        Split, Ticker, UserID,
+    },
+    now,
+    storage::Op,
+    GResult,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub const AUDIT_PREFIX: &str = "audit/";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// account whose session did it
+    pub actor: UserID,
+    /// seconds since the unix epoch
+    pub at: u64,
+    pub action: AuditAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum AuditAction {
+    SetRole {
+        user_id: UserID,
+        role: Role,
+    },
+    ListStock {
+        user_id: UserID,
+        ticker: Ticker,
+        quantity: Quantity,
+    },
+    MintStock {
+        user_id: UserID,
+        ticker: Ticker,
+        quantity: Quantity,
+    },
+    SetBalance {
+        user_id: UserID,
+        currency: Currency,
+        from: u64,
+        to: u64,
+    },
+    /// recorded by every node, for all of its accounts
+    Split(Split),
+    /// recorded by every node with what it paid its accounts
+    Dividend {
+        dividend: Dividend,
+        paid: Distribution,
+    },
+    AllowShort {
+        user_id: UserID,
+        allowed: bool,
+    },
+    /// of the node keeping the record
+    SetLendable {
+        ticker: Ticker,
+        quantity: Quantity,
+    },
+    SetMargin {
+        user_id: UserID,
+        terms: Option<MarginTerms>,
+    },
+    SetRiskLimits {
+        user_id: UserID,
+        limits: RiskLimits,
+    },
+    KillSwitch {
+        user_id: UserID,
+        engaged: bool,
+    },
+    /// the code itself isn't kept
+    IssueClaimCode {
+        user_id: UserID,
+    },
+    /// credited if accepted, dropped otherwise
+    ConfirmDeposit {
+        user_id: UserID,
+        id: DepositID,
+        cash: Cash,
+        accepted: bool,
+    },
+}
+
+/// Hands out the keys of new records
+#[derive(Default)]
+pub struct AuditLog {
+    next_id: u64,
+}
+
+impl AuditLog {
+    /// Takes every record out of `entries`, they're only read back by the inspector
+    pub fn restore(entries: &mut HashMap<String, Value>) -> GResult<Self> {
+        let keys: Vec<String> = entries
+            .keys()
+            .filter(|k| k.starts_with(AUDIT_PREFIX))
+            .cloned()
+            .collect();
+        let mut next_id = 0;
+        for key in keys {
+            let id: u64 = key[AUDIT_PREFIX.len()..]
+                .parse()
+                .map_err(|_| format!("Bad audit record id in {key}"))?;
+            next_id = next_id.max(id + 1);
+            entries.remove(&key);
+        }
+        Ok(Self { next_id })
+    }
+
+    /// The op storing a new record, to commit along with the change it describes.
+    /// A failed commit only leaves a gap in the ids.
+    pub fn record(&mut self, actor: UserID, action: AuditAction) -> GResult<Op> {
+        let id = self.next_id;
+        self.next_id += 1;
+        Op::put(
+            format!("{AUDIT_PREFIX}{id}"),
+            &AuditRecord {
+                actor,
+                at: now(),
+                action,
+            },
+        )
+    }
+}
@@ -0,0 +1,148 @@
+//! Credentials and session tokens.
+//! The coordinator checks passwords and issues tokens signed with its Ed25519 `SigningKey`, which
+//! never leaves it. Nodes get the `VerifyingKey` when they join, so they verify tokens without
+//! asking the coordinator but can't issue any.
+//! format: `<base64 claims json>.<base64 ed25519 signature of the first part>`
+
+use crate::{
+    interfaces::{Role, UserID},
+    now, GResult,
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
+
+pub const SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub user_id: UserID,
+    pub role: Role,
+    /// seconds since the unix epoch
+    pub expires: u64,
+}
+
+/// Private key, persisted by the coordinator as pkcs8
+pub struct SigningKey {
+    pkcs8: Vec<u8>,
+    pair: Ed25519KeyPair,
+}
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .expect("Failed to generate a signing key");
+        Self::from_pkcs8(pkcs8.as_ref().to_vec()).expect("Generated signing key is valid")
+    }
+
+    fn from_pkcs8(pkcs8: Vec<u8>) -> Result<Self, String> {
+        let pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|e| e.to_string())?;
+        Ok(Self { pkcs8, pair })
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.pair.public_key().as_ref().to_vec())
+    }
+
+    pub fn issue(&self, user_id: UserID, role: Role) -> String {
+        let claims = Claims {
+            user_id,
+            role,
+            expires: now() + SESSION_TTL.as_secs(),
+        };
+        let claims =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("Claims are serializable"));
+        let signature = URL_SAFE_NO_PAD.encode(self.pair.sign(claims.as_bytes()));
+        format!("{claims}.{signature}")
+    }
+}
+
+/// Public key tokens are checked against
+#[derive(Clone)]
+pub struct VerifyingKey(Vec<u8>);
+
+impl VerifyingKey {
+    /// Claims of a token signed with the matching `SigningKey` that hasn't expired
+    pub fn verify(&self, token: &str) -> GResult<Claims> {
+        let (claims, signature) = token.split_once('.').ok_or("Malformed token")?;
+        UnparsedPublicKey::new(&ED25519, &self.0)
+            .verify(claims.as_bytes(), &URL_SAFE_NO_PAD.decode(signature)?)
+            .map_err(|_| "Token has a bad signature")?;
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?)?;
+        if claims.expires <= now() {
+            return Err("Token expired".into());
+        }
+        Ok(claims)
+    }
+}
+
+impl Serialize for SigningKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&URL_SAFE_NO_PAD.encode(&self.pkcs8))
+    }
+}
+
+impl<'de> Deserialize<'de> for SigningKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pkcs8 = URL_SAFE_NO_PAD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)?;
+        Self::from_pkcs8(pkcs8).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for VerifyingKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&URL_SAFE_NO_PAD.encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for VerifyingKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = URL_SAFE_NO_PAD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)?;
+        if key.len() != 32 {
+            return Err(serde::de::Error::custom("Verifying key isn't 32 bytes"));
+        }
+        Ok(Self(key))
+    }
+}
+
+/// Random code for claiming an account, stored hashed like a password
+pub fn generate_claim_code() -> String {
+    let mut code = [0; 18];
+    rand::thread_rng().fill_bytes(&mut code);
+    URL_SAFE_NO_PAD.encode(code)
+}
+
+/// Argon2 hash in PHC string format, slow on purpose so keep it off the async threads
+pub fn hash_password(password: &str) -> GResult<String> {
+    if password.len() < MIN_PASSWORD_LEN {
+        return Err(format!("Password shorter than {MIN_PASSWORD_LEN} characters").into());
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?
+        .to_string())
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
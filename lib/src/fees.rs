@@ -0,0 +1,123 @@
+//! What trading costs. The coordinator loads the schedule and hands it to every node when it
+//! joins, each node charges its own accounts on settlement.
+
+use crate::interfaces::{CentCount, Ticker, UserID};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Basis points in a whole
+const BPS: u64 = 10_000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    /// where fees are paid to, nothing is charged without one
+    #[serde(default)]
+    pub account: Option<UserID>,
+    /// for tickers not in `tickers`
+    #[serde(default)]
+    pub default: FeeRates,
+    #[serde(default)]
+    pub tickers: HashMap<Ticker, FeeRates>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeRates {
+    /// in basis points of the traded amount, for the side whose order was resting
+    #[serde(default)]
+    pub maker_bps: u64,
+    /// for the side whose order matched on arrival
+    #[serde(default)]
+    pub taker_bps: u64,
+    /// charged per fill when the rate comes to less
+    #[serde(default)]
+    pub minimum: CentCount,
+    /// replace the rates above once an account has traded enough, by ascending volume
+    #[serde(default)]
+    pub tiers: Vec<Tier>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    /// cash an account has traded, buying and selling, before the tier applies
+    pub volume: CentCount,
+    pub maker_bps: u64,
+    pub taker_bps: u64,
+}
+
+impl FeeSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        for (ticker, rates) in self
+            .tickers
+            .iter()
+            .map(|(t, r)| (t.as_str(), r))
+            .chain([("default", &self.default)])
+        {
+            let tier_bps = rates.tiers.iter().map(|t| (t.maker_bps, t.taker_bps));
+            for (maker, taker) in [(rates.maker_bps, rates.taker_bps)]
+                .into_iter()
+                .chain(tier_bps)
+            {
+                if maker > BPS || taker > BPS {
+                    return Err(format!("Fees for {ticker} are more than 100%"));
+                }
+            }
+            if rates.tiers.windows(2).any(|w| w[0].volume >= w[1].volume) {
+                return Err(format!("Fee tiers for {ticker} aren't by ascending volume"));
+            }
+        }
+        Ok(())
+    }
+
+    fn rates(&self, ticker: &Ticker) -> &FeeRates {
+        self.tickers.get(ticker).unwrap_or(&self.default)
+    }
+
+    /// Maker and taker rate for an account that has traded `volume`
+    fn bps(rates: &FeeRates, volume: CentCount) -> (u64, u64) {
+        rates
+            .tiers
+            .iter()
+            .rev()
+            .find(|tier| tier.volume <= volume)
+            .map_or((rates.maker_bps, rates.taker_bps), |tier| {
+                (tier.maker_bps, tier.taker_bps)
+            })
+    }
+
+    /// Worked out in u128 so any amount can be charged, a rate is at most 100% so it fits back
+    fn charge(rates: &FeeRates, bps: u64, amount: CentCount) -> CentCount {
+        let fee = (u128::from(amount) * u128::from(bps)).div_ceil(u128::from(BPS));
+        CentCount::try_from(fee)
+            .expect("fee is at most the amount")
+            .max(rates.minimum)
+    }
+
+    /// Fee for one side of a fill of `amount` cash, by an account that has traded `volume`
+    pub fn fee(
+        &self,
+        ticker: &Ticker,
+        volume: CentCount,
+        maker: bool,
+        amount: CentCount,
+    ) -> CentCount {
+        if self.account.is_none() || amount == 0 {
+            return 0;
+        }
+        let rates = self.rates(ticker);
+        let (maker_bps, taker_bps) = Self::bps(rates, volume);
+        Self::charge(rates, if maker { maker_bps } else { taker_bps }, amount)
+    }
+
+    /// What a buy order for `amount` cash sets aside for its fee, either side could be charged
+    pub fn reserve(&self, ticker: &Ticker, volume: CentCount, amount: CentCount) -> CentCount {
+        if self.account.is_none() || amount == 0 {
+            return 0;
+        }
+        let rates = self.rates(ticker);
+        let (maker_bps, taker_bps) = Self::bps(rates, volume);
+        Self::charge(rates, maker_bps.max(taker_bps), amount)
+    }
+}
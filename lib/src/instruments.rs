@@ -0,0 +1,103 @@
+//! What can be traded and what it's priced in. The coordinator loads the registry and hands it
+//! to every node when it joins, like the fee schedule.
+
+use crate::interfaces::{CentCount, Currency, Ticker};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Cash from before there were currencies is in this one
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Instruments {
+    /// every currency accounts can hold, tickers that aren't listed are quoted in the first
+    pub currencies: Vec<Currency>,
+    #[serde(default)]
+    pub tickers: HashMap<Ticker, Instrument>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Instrument {
+    /// priced in cents of `currency` per share
+    Stock { currency: Currency },
+    /// buying a lot pays its price in cents of `currency` for `lot` cents of `base`, so it
+    /// converts between the two. Held as cash, never as stock
+    Fx {
+        currency: Currency,
+        base: Currency,
+        lot: CentCount,
+    },
+}
+
+impl Default for Instruments {
+    fn default() -> Self {
+        Self {
+            currencies: vec![DEFAULT_CURRENCY.to_owned()],
+            tickers: HashMap::new(),
+        }
+    }
+}
+
+impl Instruments {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.currencies.is_empty() {
+            return Err("No currencies".to_owned());
+        }
+        for (i, currency) in self.currencies.iter().enumerate() {
+            if self.currencies[..i].contains(currency) {
+                return Err(format!("Currency {currency} is listed twice"));
+            }
+        }
+        for (ticker, instrument) in &self.tickers {
+            let (currency, base) = match instrument {
+                Instrument::Stock { currency } => (currency, None),
+                Instrument::Fx {
+                    currency,
+                    base,
+                    lot,
+                } => {
+                    if base == currency {
+                        return Err(format!("{ticker} converts {base} to itself"));
+                    }
+                    if *lot == 0 {
+                        return Err(format!("{ticker} has an empty lot"));
+                    }
+                    (currency, Some(base))
+                }
+            };
+            for currency in [Some(currency), base].into_iter().flatten() {
+                if !self.is_currency(currency) {
+                    return Err(format!("{ticker} uses unknown currency {currency}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_currency(&self, currency: &Currency) -> bool {
+        self.currencies.contains(currency)
+    }
+
+    /// What tickers that aren't listed are quoted in
+    pub fn default_currency(&self) -> &Currency {
+        &self.currencies[0]
+    }
+
+    /// What `ticker` is priced in, and what its buyers pay in
+    pub fn currency(&self, ticker: &Ticker) -> &Currency {
+        match self.tickers.get(ticker) {
+            Some(Instrument::Stock { currency } | Instrument::Fx { currency, .. }) => currency,
+            None => self.default_currency(),
+        }
+    }
+
+    /// Base currency and lot of `ticker` if it's an FX pair
+    pub fn fx(&self, ticker: &Ticker) -> Option<(&Currency, CentCount)> {
+        match self.tickers.get(ticker) {
+            Some(Instrument::Fx { base, lot, .. }) => Some((base, *lot)),
+            _ => None,
+        }
+    }
+}
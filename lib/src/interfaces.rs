@@ -1,10 +1,111 @@
+use crate::{
+    instruments::Instruments,
+    statement::{Statement, StatementReq},
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, str::FromStr, fmt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+};
 
 pub type NodeID = usize;
 pub type CentCount = u64;
 pub type Ticker = String;
 pub type Quantity = u64;
+/// Code of a currency in `instruments::Instruments`, such as "USD"
+pub type Currency = String;
+
+/// An amount of one currency
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cash {
+    pub currency: Currency,
+    pub amount: CentCount,
+}
+
+/// Cents being worked out. Arithmetic is checked, what would overflow or go below zero is an
+/// `ErrorResponse` to reject the request with instead of a panic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Money(pub CentCount);
+
+/// Shares being worked out, checked like `Money`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Shares(pub Quantity);
+
+fn out_of_range() -> ErrorResponse {
+    ErrorResponse::new(ErrorCode::BadRequest, "Amount out of range")
+}
+
+fn below_zero() -> ErrorResponse {
+    ErrorResponse::new(ErrorCode::NotEnough, "Amount can't be negative")
+}
+
+impl Money {
+    /// More than can ever be free, what a reservation too big to count comes to
+    pub const MAX: Self = Self(CentCount::MAX);
+
+    pub fn checked_add(self, other: Self) -> Result<Self, ErrorResponse> {
+        self.0
+            .checked_add(other.0)
+            .map(Self)
+            .ok_or_else(out_of_range)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, ErrorResponse> {
+        self.0.checked_sub(other.0).map(Self).ok_or_else(below_zero)
+    }
+
+    /// By a ledger entry's `change_for`, or an admin's adjustment
+    pub fn checked_add_signed(self, change: i128) -> Result<Self, ErrorResponse> {
+        let amount = Self(CentCount::try_from(change.unsigned_abs()).map_err(|_| out_of_range())?);
+        if change < 0 {
+            self.checked_sub(amount)
+        } else {
+            self.checked_add(amount)
+        }
+    }
+
+    pub fn saturating_add(self, other: Self) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+
+    /// What's left, nothing if `other` is more
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+
+    /// For reservations, where counting too much only leaves less free
+    pub fn saturating_sum(amounts: impl IntoIterator<Item = Self>) -> Self {
+        amounts.into_iter().fold(Self::default(), |total, amount| {
+            total.saturating_add(amount)
+        })
+    }
+}
+
+impl Shares {
+    pub fn checked_add(self, other: Self) -> Result<Self, ErrorResponse> {
+        self.0
+            .checked_add(other.0)
+            .map(Self)
+            .ok_or_else(out_of_range)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, ErrorResponse> {
+        self.0.checked_sub(other.0).map(Self).ok_or_else(below_zero)
+    }
+
+    /// What's left, nothing if `other` is more
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+
+    /// Cash for this many at `each`, a price or the lot of an FX pair
+    pub fn times(self, each: CentCount) -> Result<Money, ErrorResponse> {
+        self.0.checked_mul(each).map(Money).ok_or_else(out_of_range)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AllOrders(pub HashMap<String, BuySell>);
@@ -35,6 +136,12 @@ impl fmt::Display for UserID {
 #[derive(Debug)]
 pub struct InvalidUserIDError;
 
+impl fmt::Display for InvalidUserIDError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected a user id as <node id>.<id>")
+    }
+}
+
 impl FromStr for UserID {
     type Err = InvalidUserIDError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -56,10 +163,698 @@ pub enum OrderType {
     Sell,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderReq {
     pub order_type: OrderType,
     pub ticker: Ticker,
     pub price: CentCount,
     pub quantity: Quantity,
 }
+
+/// Which of an account's orders to cancel, every one if nothing is set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CancelFilter {
+    pub ticker: Option<Ticker>,
+    pub side: Option<OrderType>,
+    /// inclusive
+    pub min_price: Option<CentCount>,
+    pub max_price: Option<CentCount>,
+}
+
+impl CancelFilter {
+    pub fn matches(&self, order: &OrderReq) -> bool {
+        self.ticker
+            .as_ref()
+            .is_none_or(|ticker| ticker == &order.ticker)
+            && self.side.is_none_or(|side| side == order.order_type)
+            && self.min_price.is_none_or(|min| order.price >= min)
+            && self.max_price.is_none_or(|max| order.price <= max)
+    }
+}
+
+/// Bumped whenever a client request or response changes shape.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+pub type RequestID = u64;
+
+/// First message from a client to the coordinator.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoordinatorHello {
+    pub version: u32,
+}
+
+/// First message from a client to the node holding its account.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeHello {
+    pub version: u32,
+    /// from `CoordinatorResponse::LoggedIn`, names the account
+    pub token: String,
+}
+
+/// Reply to a hello, as `Result<Welcome, ErrorResponse>`.
+/// The server closes the connection after an error.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Welcome {
+    pub version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request<T> {
+    pub id: RequestID,
+    pub body: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response<T> {
+    /// id of the request, None if the request was too malformed to have one
+    pub id: Option<RequestID>,
+    pub result: Result<T, ErrorResponse>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    UnsupportedVersion,
+    NotFound,
+    NotEnough,
+    NotEmpty,
+    Unauthorized,
+    /// the account's role doesn't allow the request
+    Forbidden,
+    Internal,
+    /// an order over one of the account's `RiskLimits`, each has its own code
+    OrderTooLarge,
+    NotionalTooLarge,
+    TooManyOrders,
+    PositionTooLarge,
+    RateLimited,
+}
+
+/// Error reply to a request, the session stays open.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Handlers fail with an `ErrorResponse` to reject a request, anything else is internal.
+    pub fn from_error(e: Box<dyn Error + Send + Sync>) -> Self {
+        match e.downcast::<ErrorResponse>() {
+            Ok(e) => *e,
+            Err(e) => Self::new(ErrorCode::Internal, e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl Error for ErrorResponse {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewAccount {
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Login {
+    pub user_id: UserID,
+    pub password: String,
+}
+
+/// What an account may do, carried in its session token and kept by its node once an admin
+/// changes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// trades with what it has
+    #[default]
+    Trader,
+    /// also lists new stock (IPO) into its own account
+    MarketOperator,
+    /// also sets roles, mints stock and adjusts cash of any account
+    Admin,
+}
+
+impl Role {
+    pub fn can_list_stock(self) -> bool {
+        matches!(self, Role::MarketOperator | Role::Admin)
+    }
+
+    pub fn is_admin(self) -> bool {
+        self == Role::Admin
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoggedIn {
+    /// node holding the account
+    pub node: SocketAddr,
+    /// for `NodeHello`, expires after `lib::auth::SESSION_TTL`
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum CoordinatorRequest {
+    CreateAccount(NewAccount),
+    Login(Login),
+    /// set the password of an account made before there were passwords
+    ClaimAccount(ClaimAccount),
+    FindNode(UserID),
+    /// admin only, like the two below; the account's node checks the new role at once
+    SetRole(SetRole),
+    MintStock(Mint),
+    AdjustBalance(Adjustment),
+    /// of a ticker on every node, trading in it is halted while they do
+    Split(Split),
+    /// paid by every node at the record time, trading in the ticker is halted while they do
+    Dividend(Dividend),
+    /// every dividend scheduled, with what each node has paid of it
+    ReadDividends,
+    AllowShort(ShortPermission),
+    SetLendable(Lendable),
+    /// make an account a margin account, or a cash account again
+    SetMargin(MarginAccount),
+    SetRiskLimits(AccountLimits),
+    ReadRiskLimits(UserID),
+    KillSwitch(KillSwitch),
+    /// for an account without a password, replied to with the one-time code to claim it with
+    IssueClaimCode(UserID),
+    /// credit or drop a deposit once it's known whether the cash arrived
+    ConfirmDeposit(DepositDecision),
+    Bye,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum CoordinatorResponse {
+    Account(UserID),
+    LoggedIn(LoggedIn),
+    Node(SocketAddr),
+    /// id of a dividend kept to be paid at its record time
+    Dividend(DividendID),
+    Dividends(BTreeMap<DividendID, ScheduledDividend>),
+    RiskLimits(RiskLimits),
+    ClaimCode(String),
+    Ok,
+}
+
+/// Decides a deposit an account asked for, by the id it was replied to with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepositDecision {
+    pub user_id: UserID,
+    pub id: DepositID,
+    /// the cash arrived, credit it. Dropped otherwise
+    pub accepted: bool,
+}
+
+pub type DepositID = u64;
+
+/// The code is from `CoordinatorRequest::IssueClaimCode`, handed to the account's owner by the
+/// admin. It works once, the account can then log in with `password` as a trader
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClaimAccount {
+    pub user_id: UserID,
+    pub code: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetRole {
+    pub user_id: UserID,
+    pub role: Role,
+}
+
+/// Create stock out of thin air in an account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mint {
+    pub user_id: UserID,
+    pub ticker: Ticker,
+    pub quantity: Quantity,
+}
+
+/// Every `from` shares of `ticker` become `to`, `from` > `to` is a reverse split. Quantities are
+/// rounded down, so a reverse split drops what's left of a share. Prices of orders are divided
+/// by the ratio, buys rounded down and sells up, so an order never gets a worse price.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Split {
+    pub ticker: Ticker,
+    pub from: Quantity,
+    pub to: Quantity,
+}
+
+impl Split {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.from == 0 || self.to == 0 {
+            return Err(format!("Can't split {} into or from nothing", self.ticker));
+        }
+        if self.from == self.to {
+            return Err(format!(
+                "Splitting {} {} for {} changes nothing",
+                self.ticker, self.to, self.from
+            ));
+        }
+        Ok(())
+    }
+
+    /// `n` times `by` over `over`, rounded up or down
+    fn scale(n: u64, by: u64, over: u64, up: bool) -> Result<u64, ErrorResponse> {
+        let (n, by, over) = (u128::from(n), u128::from(by), u128::from(over));
+        let scaled = if up {
+            (n * by).div_ceil(over)
+        } else {
+            n * by / over
+        };
+        u64::try_from(scaled).map_err(|_| out_of_range())
+    }
+
+    pub fn quantity(&self, quantity: Quantity) -> Result<Quantity, ErrorResponse> {
+        Self::scale(quantity, self.to, self.from, false)
+    }
+
+    /// Of a resting order on the `order_type` side
+    pub fn price(
+        &self,
+        order_type: OrderType,
+        price: CentCount,
+    ) -> Result<CentCount, ErrorResponse> {
+        Self::scale(price, self.from, self.to, order_type == OrderType::Sell)
+    }
+}
+
+/// Pay `per_share` cents, in the currency `ticker` is priced in, for every share held at
+/// `record_time`. Shares of pending sells and outgoing transfers are still the holder's
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dividend {
+    pub ticker: Ticker,
+    pub per_share: CentCount,
+    /// seconds since the unix epoch, not in the past
+    pub record_time: u64,
+}
+
+impl Dividend {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.per_share == 0 {
+            return Err(format!("A dividend of nothing per {}", self.ticker));
+        }
+        Ok(())
+    }
+}
+
+/// What a dividend paid out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Distribution {
+    /// holding any of the stock
+    pub accounts: u64,
+    pub shares: Quantity,
+    pub amount: CentCount,
+}
+
+impl Distribution {
+    pub fn checked_add(self, other: Distribution) -> Result<Distribution, ErrorResponse> {
+        Ok(Distribution {
+            accounts: self.accounts + other.accounts,
+            shares: Shares(self.shares).checked_add(Shares(other.shares))?.0,
+            amount: Money(self.amount).checked_add(Money(other.amount))?.0,
+        })
+    }
+}
+
+pub type DividendID = u64;
+
+/// A dividend as the coordinator keeps it from when it's asked for, so it's paid even if the
+/// coordinator restarts or the admin's session ends before the record time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledDividend {
+    pub actor: UserID,
+    pub dividend: Dividend,
+    /// each node's part by node id, as soon as the node has paid it
+    pub paid: BTreeMap<NodeID, Distribution>,
+    /// every node has paid
+    pub done: bool,
+}
+
+/// A dividend for a node to pay, by the id the coordinator keeps it under so it's paid once
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payout {
+    pub id: DividendID,
+    pub dividend: Dividend,
+}
+
+/// Let an account sell more stock than it has, borrowing the rest from its node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShortPermission {
+    pub user_id: UserID,
+    pub allowed: bool,
+}
+
+/// Cancel every resting order of an account and block new ones, or let it trade again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KillSwitch {
+    pub user_id: UserID,
+    pub engaged: bool,
+}
+
+/// Who engaged an account's kill switch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KilledBy {
+    Account,
+    Admin,
+}
+
+/// How many shares of `ticker` a node may lend its accounts for short sales, in all
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lendable {
+    pub node_id: NodeID,
+    pub ticker: Ticker,
+    pub quantity: Quantity,
+}
+
+/// Stock an account borrowed for short sales and still owes, with the cash set aside for it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Borrowed {
+    pub quantity: Quantity,
+    pub collateral: Cash,
+}
+
+/// A position at its average cost, in the currency its ticker is priced in. Trades count at
+/// their price before fees, shares from an IPO or a transfer cost nothing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    /// below zero for a short position
+    pub quantity: i64,
+    /// paid per share, or got per share sold short
+    pub average_cost: CentCount,
+    /// by the trades that reduced the position
+    pub realised: i64,
+    /// `None` without a mark
+    pub unrealised: Option<i64>,
+    /// last price the node's accounts traded the ticker at
+    pub mark: Option<CentCount>,
+}
+
+/// Buying power of a margin account is `leverage` times its equity, its positions are closed
+/// once equity falls under `maintenance_pct` of what they're worth
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarginTerms {
+    pub leverage: u64,
+    pub maintenance_pct: u64,
+}
+
+impl MarginTerms {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.leverage == 0 {
+            return Err("A leverage of nothing".to_owned());
+        }
+        if self.maintenance_pct == 0 || self.maintenance_pct > 100 {
+            return Err(format!(
+                "A maintenance margin of {}% isn't between 1% and 100%",
+                self.maintenance_pct
+            ));
+        }
+        if self.maintenance_pct.saturating_mul(self.leverage) > 100 {
+            return Err(format!(
+                "A maintenance margin of {}% is more than the {}% bought on at {}x",
+                self.maintenance_pct,
+                100 / self.leverage,
+                self.leverage
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `None` makes it a cash account again, once it owes nothing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginAccount {
+    pub user_id: UserID,
+    pub terms: Option<MarginTerms>,
+}
+
+/// A margin account in one currency, positions are worth their last trade price on its node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarginUsage {
+    /// cash and positions less the loan, below zero if they don't cover it
+    pub equity: i64,
+    /// what the positions are worth, long or short
+    pub exposure: CentCount,
+    pub loan: CentCount,
+    /// what more buy orders may come to
+    pub buying_power: CentCount,
+}
+
+/// Checked before an order is placed, on top of the balance and stock it needs. `None` is no
+/// limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RiskLimits {
+    pub max_order_quantity: Option<Quantity>,
+    /// price times quantity of an order
+    pub max_notional: Option<CentCount>,
+    /// resting orders, one per ticker, side and price
+    pub max_open_orders: Option<u64>,
+    /// shares of a stock held long or short, were every open order of it to fill
+    pub max_position: Option<Quantity>,
+    pub max_orders_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountLimits {
+    pub user_id: UserID,
+    pub limits: RiskLimits,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarginStatus {
+    pub terms: Option<MarginTerms>,
+    /// positions are being closed, no orders can be placed or cancelled until it's over
+    pub liquidating: bool,
+    /// by currency, none for a cash account
+    pub currencies: HashMap<Currency, MarginUsage>,
+}
+
+/// Add to or, if negative, take from an account's cash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adjustment {
+    pub user_id: UserID,
+    pub currency: Currency,
+    pub amount: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StockReq {
+    pub ticker: Ticker,
+    pub quantity: Quantity,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum NodeRequest {
+    ReadBalance,
+    /// cash paid in from outside the exchange, in a currency of the instrument registry.
+    /// Replied to with its id, it's credited once an admin confirms the cash arrived
+    Deposit(Cash),
+    /// deposits not confirmed yet
+    ReadDeposits,
+    /// cash paid out, at most what isn't reserved by orders
+    Withdraw(Cash),
+    ReadLedger,
+    ReadStock,
+    /// stock borrowed for short sales
+    ReadBorrowed,
+    /// cost basis and P&L of each position
+    ReadPositions,
+    /// balances, positions and what changed them over a time range
+    ReadStatement(StatementReq),
+    /// buying power and what's used of it
+    ReadMargin,
+    /// IPO into the session's own account, market operators and admins only
+    CreateStock(StockReq),
+    ReadMarket,
+    /// what can be traded and what it's priced in
+    ReadInstruments,
+    CreateOrder(OrderReq),
+    ReadOrders,
+    DeleteOrder(OrderReq),
+    /// every order of the account matching the filter, each level in one go
+    CancelOrders(CancelFilter),
+    /// engaging it cancels every resting order of the account and blocks new ones until it's
+    /// released. One an admin engaged only an admin can release
+    KillSwitch(bool),
+    /// cancel every resting order of the account once this session ends, however it ends
+    CancelOnDisconnect(bool),
+    /// answered once the destination took it
+    Transfer(TransferReq),
+    DeleteAccount,
+    Bye,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum NodeResponse {
+    Ok,
+    /// by currency, every currency of the registry is there
+    Balance(HashMap<Currency, CentCount>),
+    /// oldest first
+    Ledger(Vec<LedgerEntry>),
+    Stock(HashMap<Ticker, Quantity>),
+    /// by ticker, only what's still owed
+    Borrowed(HashMap<Ticker, Borrowed>),
+    /// by ticker, those still held or that realised something
+    Positions(HashMap<Ticker, Position>),
+    Statement(Box<Statement>),
+    /// a statement asked for as CSV
+    StatementCsv(String),
+    Margin(MarginStatus),
+    Market(AllOrders),
+    Instruments(Instruments),
+    Orders(AllOrders),
+    /// quantity deleted, the rest already traded or didn't exist in the first place
+    Deleted(Quantity),
+    /// each order cancelled with the quantity cancelled
+    Cancelled(Vec<OrderReq>),
+    /// of a deposit waiting for an admin
+    Deposit(DepositID),
+    Deposits(HashMap<DepositID, Cash>),
+}
+
+/// Move cash or stock from the session's account to another account, on any node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferReq {
+    pub to: UserID,
+    pub asset: Asset,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Asset {
+    Cash(Cash),
+    Stock { ticker: Ticker, quantity: Quantity },
+}
+
+/// One side of a ledger entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum LedgerAccount {
+    User(UserID),
+    /// cash outside the exchange, deposits come from it and withdrawals go to it
+    External,
+}
+
+/// Moves `amount` of `currency` from one account to another, so the books always balance.
+/// Each account's ledger holds every entry it's a side of, a trade between two accounts is
+/// in both ledgers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// position in the account's ledger
+    pub id: u64,
+    /// seconds since the unix epoch
+    pub at: u64,
+    pub from: LedgerAccount,
+    pub to: LedgerAccount,
+    pub currency: Currency,
+    pub amount: CentCount,
+    pub memo: Memo,
+}
+
+/// What a ledger entry is for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Memo {
+    Deposit,
+    Withdrawal,
+    /// the quoted currency side of a trade, paid by the buyer
+    Trade {
+        ticker: Ticker,
+        quantity: Quantity,
+        price: CentCount,
+    },
+    /// the base currency side of a trade of an FX pair, paid by the seller
+    Fx {
+        ticker: Ticker,
+        quantity: Quantity,
+        price: CentCount,
+    },
+    /// by an admin
+    Adjustment {
+        by: UserID,
+    },
+    /// balance from before the ledger existed
+    Opening,
+    /// between two accounts, without anything in return
+    Transfer,
+    /// from outside, for `quantity` shares held at the record time
+    Dividend {
+        ticker: Ticker,
+        quantity: Quantity,
+        per_share: CentCount,
+    },
+    /// to the exchange's fee account, for a fill of this account
+    Fee {
+        ticker: Ticker,
+        quantity: Quantity,
+        price: CentCount,
+        /// id of the fill's `trade` entry in the payer's ledger, unknown for a fee account's
+        /// copies of fees charged before they were linked
+        trade: Option<u64>,
+    },
+    /// from outside, lent to a margin account for what it bought
+    MarginLoan,
+    /// paying a margin loan back
+    MarginRepayment,
+}
+
+/// Shares of `ticker` an account gained or lost other than by a fill, fills are in the cash
+/// ledger. Each account's stock ledger holds the entries of its positions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StockEntry {
+    /// position in the account's stock ledger
+    pub id: u64,
+    /// seconds since the unix epoch
+    pub at: u64,
+    pub ticker: Ticker,
+    /// below zero for shares it lost
+    pub change: i64,
+    pub memo: StockMemo,
+}
+
+/// What a stock ledger entry is for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum StockMemo {
+    /// listed or minted into the account
+    Listed,
+    /// between two accounts
+    Transfer { from: UserID, to: UserID },
+    /// every `from` shares became `to`
+    Split { from: Quantity, to: Quantity },
+    /// held before the stock ledger existed, less the fills in the cash ledger by then
+    Opening,
+}
+
+impl LedgerEntry {
+    /// What the entry adds to the balance of `account`, in the entry's currency
+    pub fn change_for(&self, account: LedgerAccount) -> i128 {
+        let mut change = 0;
+        if self.to == account {
+            change += self.amount as i128;
+        }
+        if self.from == account {
+            change -= self.amount as i128;
+        }
+        change
+    }
+}
@@ -1,5 +1,21 @@
+pub mod audit;
+pub mod auth;
+pub mod fees;
+pub mod instruments;
 pub mod interfaces;
 pub mod lock;
 pub mod read_writer;
+pub mod session;
+pub mod statement;
+pub mod storage;
+pub mod tls;
 
 pub type GResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Clock before 1970")
+        .as_secs()
+}
@@ -1,48 +1,253 @@
-use std::net::SocketAddr;
+use std::{error::Error, fmt, net::SocketAddr, str::FromStr};
 
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
     },
+    net::TcpStream,
 };
 
 use crate::GResult;
 
+/// Largest line or frame accepted, anything longer is an error
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// How messages are framed and encoded on a connection.
+/// Every connection starts with `Json` so the two sides can agree on another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// one json message per line
+    Json,
+    /// 4 byte big endian length then a MessagePack message
+    MsgPack,
+}
+
+impl Codec {
+    /// Codecs to offer the other side, `self` preferred and json as a fallback
+    pub fn offer(self) -> Vec<Codec> {
+        if self == Codec::Json {
+            vec![Codec::Json]
+        } else {
+            vec![self, Codec::Json]
+        }
+    }
+
+    /// Pick the other side's most preferred codec
+    pub fn choose(offered: &[Codec]) -> Codec {
+        offered.first().copied().unwrap_or(Codec::Json)
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "msgpack" => Ok(Codec::MsgPack),
+            _ => Err(format!("Unknown codec {s}, expected json or msgpack")),
+        }
+    }
+}
+
+/// The other side closed the connection
+#[derive(Debug)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection closed")
+    }
+}
+
+impl Error for Closed {}
+
+pub fn is_closed(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    e.is::<Closed>()
+}
+
+/// What protects a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Plain,
+    /// only the server has a certificate
+    Tls,
+    /// both sides have a certificate signed by our CA
+    MutualTls,
+}
+
 pub struct ReadWriter {
     peer_addr: Result<SocketAddr, String>,
-    reader: BufReader<OwnedReadHalf>,
-    writer: BufWriter<OwnedWriteHalf>,
+    transport: Transport,
+    reader: Reader,
+    writer: Writer,
+}
+
+/// Read half of a `ReadWriter`
+pub struct Reader {
+    inner: BufReader<Box<dyn AsyncRead + Send + Unpin>>,
+    codec: Codec,
+}
+
+/// Write half of a `ReadWriter`
+pub struct Writer {
+    inner: BufWriter<Box<dyn AsyncWrite + Send + Unpin>>,
+    codec: Codec,
 }
 
 impl ReadWriter {
     pub fn new(socket: TcpStream) -> Self {
         let peer_addr = socket.peer_addr().map_err(|e| e.to_string());
-        let (r, w) = socket.into_split();
+        Self::from_stream(socket, peer_addr, Transport::Plain)
+    }
+
+    /// Wrap any stream, e.g. a TLS one
+    pub fn from_stream(
+        stream: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        peer_addr: Result<SocketAddr, String>,
+        transport: Transport,
+    ) -> Self {
+        let (r, w) = tokio::io::split(stream);
         Self {
             peer_addr,
-            reader: BufReader::new(r),
-            writer: BufWriter::new(w),
+            transport,
+            reader: Reader {
+                inner: BufReader::new(Box::new(r)),
+                codec: Codec::Json,
+            },
+            writer: Writer {
+                inner: BufWriter::new(Box::new(w)),
+                codec: Codec::Json,
+            },
         }
     }
 
+    /// Links between servers must use mutual TLS, unless TLS is off altogether
+    pub fn check_peer_certified(&self) -> GResult<()> {
+        match self.transport {
+            Transport::Tls => {
+                let peer = match &self.peer_addr {
+                    Ok(addr) => addr.to_string(),
+                    Err(e) => e.clone(),
+                };
+                Err(format!("{peer} didn't present a certificate, required between servers").into())
+            }
+            Transport::Plain | Transport::MutualTls => Ok(()),
+        }
+    }
+
+    pub async fn write_line(&mut self, s: &str) -> GResult<()> {
+        self.writer.write_line(s).await
+    }
+
+    pub async fn read_line(&mut self) -> GResult<String> {
+        self.reader.read_line().await
+    }
+
+    pub async fn send<T: Serialize>(&mut self, msg: &T) -> GResult<()> {
+        self.writer.send(msg).await
+    }
+
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> GResult<T> {
+        self.reader.recv().await
+    }
+
+    /// Switch both directions to `codec`, once both sides agreed on it
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.reader.codec = codec;
+        self.writer.codec = codec;
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, String> {
+        self.peer_addr.clone()
+    }
+
+    /// So reading and writing can happen in different tasks
+    pub fn into_split(self) -> (Reader, Writer) {
+        (self.reader, self.writer)
+    }
+}
+
+impl Writer {
+    /// Only for connections using the json codec
     pub async fn write_line(&mut self, s: &str) -> GResult<()> {
+        if self.codec != Codec::Json {
+            return Err(format!("Can't write a line with codec {:?}", self.codec).into());
+        }
         // println!("write_line: {s}");
-        self.writer.write_all(s.as_bytes()).await?;
-        self.writer.write_u8(b'\n').await?;
-        self.writer.flush().await?;
+        self.inner.write_all(s.as_bytes()).await?;
+        self.inner.write_u8(b'\n').await?;
+        self.inner.flush().await?;
         Ok(())
     }
 
+    pub async fn send<T: Serialize>(&mut self, msg: &T) -> GResult<()> {
+        match self.codec {
+            Codec::Json => self.write_line(&serde_json::to_string(msg)?).await,
+            Codec::MsgPack => {
+                let frame = rmp_serde::to_vec_named(msg)?;
+                if frame.len() > MAX_FRAME_LEN {
+                    return Err(format!("Frame of {} bytes is too long", frame.len()).into());
+                }
+                self.inner.write_u32(frame.len() as u32).await?;
+                self.inner.write_all(&frame).await?;
+                self.inner.flush().await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Reader {
+    /// Only for connections using the json codec.
+    /// Not cancel safe, a partly read line is lost if the future is dropped
     pub async fn read_line(&mut self) -> GResult<String> {
+        if self.codec != Codec::Json {
+            return Err(format!("Can't read a line with codec {:?}", self.codec).into());
+        }
         let mut line = Vec::new();
-        self.reader.read_until(b'\n', &mut line).await?;
-        line.pop();
+        (&mut self.inner)
+            .take(MAX_FRAME_LEN as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if line.pop() != Some(b'\n') {
+            // the limit was hit if all MAX_FRAME_LEN + 1 bytes were read
+            return if line.len() >= MAX_FRAME_LEN {
+                Err(format!("Line longer than {MAX_FRAME_LEN} bytes").into())
+            } else {
+                // a partial line is as good as nothing
+                Err(Box::new(Closed))
+            };
+        }
         Ok(String::from_utf8(line)?)
     }
 
-    pub fn peer_addr(&self) -> Result<SocketAddr, String> {
-        self.peer_addr.clone()
+    /// Not cancel safe either
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> GResult<T> {
+        match self.codec {
+            Codec::Json => Ok(serde_json::from_str(&self.read_line().await?)?),
+            Codec::MsgPack => {
+                let len = match self.inner.read_u32().await {
+                    Ok(len) => len as usize,
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        return Err(Box::new(Closed))
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                if len > MAX_FRAME_LEN {
+                    return Err(format!("Frame of {len} bytes is too long").into());
+                }
+                let mut frame = vec![0; len];
+                match self.inner.read_exact(&mut frame).await {
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        Err(Box::new(Closed))
+                    }
+                    Err(e) => Err(e.into()),
+                    Ok(_) => Ok(rmp_serde::from_slice(&frame)?),
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,121 @@
+//! Client side of the client protocol: hello, then requests answered by responses with the same id.
+//! Requests can be pipelined with `send` and `recv`, a node may answer them in any order. Those
+//! that change something are handled in the order they were sent, reads alongside them.
+
+use crate::{
+    interfaces::{
+        CoordinatorHello, CoordinatorRequest, CoordinatorResponse, ErrorResponse, LoggedIn,
+        NodeHello, NodeRequest, NodeResponse, Request, RequestID, Response, Welcome,
+        PROTOCOL_VERSION,
+    },
+    read_writer::ReadWriter,
+    tls::{self, Tls},
+    GResult,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, marker::PhantomData, net::SocketAddr};
+
+pub struct Session<Req, Res> {
+    rw: ReadWriter,
+    next_id: RequestID,
+    /// responses that arrived while waiting for another one
+    received: HashMap<RequestID, Result<Res, ErrorResponse>>,
+    _types: PhantomData<(Req, Res)>,
+}
+
+pub type CoordinatorSession = Session<CoordinatorRequest, CoordinatorResponse>;
+pub type NodeSession = Session<NodeRequest, NodeResponse>;
+
+impl<Req: Serialize, Res: DeserializeOwned> Session<Req, Res> {
+    async fn connect(addr: SocketAddr, tls: Option<&Tls>, hello: &impl Serialize) -> GResult<Self> {
+        let mut rw = tls::connect(addr, tls).await?;
+        rw.send(hello).await?;
+        let welcome: Result<Welcome, ErrorResponse> = rw.recv().await?;
+        welcome?;
+        Ok(Self {
+            rw,
+            next_id: 0,
+            received: HashMap::new(),
+            _types: PhantomData,
+        })
+    }
+
+    /// Send a request and wait for its response, an error response is returned as an `ErrorResponse`
+    pub async fn request(&mut self, body: Req) -> GResult<Res> {
+        let id = self.send(body).await?;
+        self.recv(id).await
+    }
+
+    /// Send a request without waiting for its response, returns its id for `recv`
+    pub async fn send(&mut self, body: Req) -> GResult<RequestID> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.rw.send(&Request { id, body }).await?;
+        Ok(id)
+    }
+
+    /// Wait for the response to request `id`, keeping any other response that arrives first
+    pub async fn recv(&mut self, id: RequestID) -> GResult<Res> {
+        loop {
+            if let Some(result) = self.received.remove(&id) {
+                return Ok(result?);
+            }
+            match self.rw.recv().await? {
+                Response {
+                    id: Some(res_id),
+                    result,
+                } => {
+                    self.received.insert(res_id, result);
+                }
+                // only a malformed request gets a response without an id
+                Response { id: None, result } => {
+                    result?;
+                    return Err("Response without a request id".into());
+                }
+            }
+        }
+    }
+
+    /// Send a request the server doesn't reply to before it ends the session
+    async fn close(mut self, body: Req) -> GResult<()> {
+        let id = self.next_id;
+        self.rw.send(&Request { id, body }).await
+    }
+}
+
+impl CoordinatorSession {
+    /// Over TLS unless `tls` is None
+    pub async fn new(addr: SocketAddr, tls: Option<&Tls>) -> GResult<Self> {
+        Self::connect(
+            addr,
+            tls,
+            &CoordinatorHello {
+                version: PROTOCOL_VERSION,
+            },
+        )
+        .await
+    }
+
+    pub async fn bye(self) -> GResult<()> {
+        self.close(CoordinatorRequest::Bye).await
+    }
+}
+
+impl NodeSession {
+    /// Connect to the node a login named, over TLS unless `tls` is None
+    pub async fn new(LoggedIn { node, token }: &LoggedIn, tls: Option<&Tls>) -> GResult<Self> {
+        Self::connect(
+            *node,
+            tls,
+            &NodeHello {
+                version: PROTOCOL_VERSION,
+                token: token.clone(),
+            },
+        )
+        .await
+    }
+
+    pub async fn bye(self) -> GResult<()> {
+        self.close(NodeRequest::Bye).await
+    }
+}
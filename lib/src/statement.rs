@@ -0,0 +1,464 @@
+//! Account statements over a time range, worked out from an account's cash and stock ledgers so
+//! its books can be reconciled against them.
+
+use crate::interfaces::{
+    CentCount, Currency, ErrorCode, ErrorResponse, LedgerAccount, LedgerEntry, Memo, OrderType,
+    Quantity, StockEntry, StockMemo, Ticker, UserID,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementFormat {
+    Json,
+    Csv,
+}
+
+/// Seconds since the unix epoch, `from` included and `to` not
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatementReq {
+    pub from: u64,
+    pub to: u64,
+    pub format: StatementFormat,
+}
+
+impl StatementReq {
+    pub fn validate(&self) -> Result<(), ErrorResponse> {
+        if self.from > self.to {
+            return Err(ErrorResponse::new(
+                ErrorCode::BadRequest,
+                format!("The range {} to {} is empty", self.from, self.to),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Cash by currency and positions by ticker, below zero for a short one
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holdings {
+    pub cash: BTreeMap<Currency, CentCount>,
+    pub positions: BTreeMap<Ticker, i64>,
+}
+
+/// A fill of one of the account's orders and the fee it paid for it, in what it's priced in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fill {
+    pub at: u64,
+    pub ticker: Ticker,
+    pub side: OrderType,
+    pub quantity: Quantity,
+    pub price: CentCount,
+    pub currency: Currency,
+    pub fee: CentCount,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum CorporateAction {
+    /// every `from` shares became `to`, changing the position by `change`
+    Split {
+        at: u64,
+        ticker: Ticker,
+        from: Quantity,
+        to: Quantity,
+        change: i64,
+    },
+    /// `amount` paid for `quantity` shares held at the record time
+    Dividend {
+        at: u64,
+        ticker: Ticker,
+        quantity: Quantity,
+        per_share: CentCount,
+        currency: Currency,
+        amount: CentCount,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Statement {
+    pub user_id: UserID,
+    pub from: u64,
+    pub to: u64,
+    pub opening: Holdings,
+    pub closing: Holdings,
+    /// of the cash ledger in the range, oldest first, as are the rest
+    pub entries: Vec<LedgerEntry>,
+    pub stock_entries: Vec<StockEntry>,
+    pub fills: Vec<Fill>,
+    pub corporate_actions: Vec<CorporateAction>,
+}
+
+const CSV_HEADER: &str = "section,at,type,ticker,currency,quantity,price,amount,fee,from,to";
+
+/// Quoted if it has to be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn account_name(account: LedgerAccount) -> String {
+    match account {
+        LedgerAccount::User(user_id) => user_id.to_string(),
+        LedgerAccount::External => "external".to_owned(),
+    }
+}
+
+fn holding_rows(section: &str, at: u64, holdings: &Holdings) -> Vec<[String; 11]> {
+    let cash = holdings.cash.iter().map(|(currency, cash)| {
+        [
+            section.to_owned(),
+            at.to_string(),
+            "cash".to_owned(),
+            String::new(),
+            currency.clone(),
+            String::new(),
+            String::new(),
+            cash.to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+        ]
+    });
+    let positions = holdings.positions.iter().map(|(ticker, quantity)| {
+        [
+            section.to_owned(),
+            at.to_string(),
+            "position".to_owned(),
+            ticker.clone(),
+            String::new(),
+            quantity.to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+        ]
+    });
+    cash.chain(positions).collect()
+}
+
+/// Snake case name of what the entry is for, with its ticker, quantity and price if it has them
+fn describe_memo(memo: &Memo) -> (&'static str, Option<(&Ticker, Quantity, CentCount)>) {
+    match memo {
+        Memo::Deposit => ("deposit", None),
+        Memo::Withdrawal => ("withdrawal", None),
+        Memo::Trade {
+            ticker,
+            quantity,
+            price,
+        } => ("trade", Some((ticker, *quantity, *price))),
+        Memo::Fx {
+            ticker,
+            quantity,
+            price,
+        } => ("fx", Some((ticker, *quantity, *price))),
+        Memo::Adjustment { .. } => ("adjustment", None),
+        Memo::Opening => ("opening", None),
+        Memo::Transfer => ("transfer", None),
+        Memo::Dividend {
+            ticker,
+            quantity,
+            per_share,
+        } => ("dividend", Some((ticker, *quantity, *per_share))),
+        Memo::Fee {
+            ticker,
+            quantity,
+            price,
+            ..
+        } => ("fee", Some((ticker, *quantity, *price))),
+        Memo::MarginLoan => ("margin_loan", None),
+        Memo::MarginRepayment => ("margin_repayment", None),
+    }
+}
+
+impl Statement {
+    /// Of `user_id` from its whole cash and stock ledgers. Fills of FX pairs move cash, they're
+    /// in no position. Fails if a holding is out of range, which the ledgers never allow
+    pub fn new(
+        user_id: UserID,
+        ledger: &[LedgerEntry],
+        stock: &[StockEntry],
+        StatementReq { from, to, .. }: &StatementReq,
+    ) -> Result<Self, ErrorResponse> {
+        let (from, to) = (*from, *to);
+        let me = LedgerAccount::User(user_id);
+        let in_range = |at: u64| from <= at && at < to;
+        let fx: HashSet<&Ticker> = ledger
+            .iter()
+            .filter_map(|entry| match &entry.memo {
+                Memo::Fx { ticker, .. } => Some(ticker),
+                _ => None,
+            })
+            .collect();
+
+        let mut fills: Vec<Fill> = Vec::new();
+        // index in `fills` by the id of its trade entry
+        let mut traded: HashMap<u64, usize> = HashMap::new();
+        for entry in ledger {
+            match &entry.memo {
+                Memo::Trade {
+                    ticker,
+                    quantity,
+                    price,
+                } => {
+                    traded.insert(entry.id, fills.len());
+                    fills.push(Fill {
+                        at: entry.at,
+                        ticker: ticker.clone(),
+                        side: if entry.from == me {
+                            OrderType::Buy
+                        } else {
+                            OrderType::Sell
+                        },
+                        quantity: *quantity,
+                        price: *price,
+                        currency: entry.currency.clone(),
+                        fee: 0,
+                    });
+                }
+                // an owed fee is paid in parts
+                Memo::Fee {
+                    trade: Some(trade), ..
+                } if entry.from == me => {
+                    if let Some(&fill) = traded.get(trade) {
+                        fills[fill].fee = fills[fill].fee.saturating_add(entry.amount);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let holdings = |before: u64| {
+            let mut cash: BTreeMap<Currency, i128> = BTreeMap::new();
+            let mut positions: BTreeMap<Ticker, i128> = BTreeMap::new();
+            for entry in ledger.iter().filter(|entry| entry.at < before) {
+                *cash.entry(entry.currency.clone()).or_default() += entry.change_for(me);
+            }
+            for fill in fills
+                .iter()
+                .filter(|fill| fill.at < before && !fx.contains(&fill.ticker))
+            {
+                let quantity = i128::from(fill.quantity);
+                *positions.entry(fill.ticker.clone()).or_default() += match fill.side {
+                    OrderType::Buy => quantity,
+                    OrderType::Sell => -quantity,
+                };
+            }
+            for entry in stock.iter().filter(|entry| entry.at < before) {
+                *positions.entry(entry.ticker.clone()).or_default() += i128::from(entry.change);
+            }
+            let out_of_range = |what: String| {
+                ErrorResponse::new(
+                    ErrorCode::Internal,
+                    format!("{user_id}'s {what} is out of range before {before}"),
+                )
+            };
+            Ok::<_, ErrorResponse>(Holdings {
+                cash: cash
+                    .into_iter()
+                    .map(|(currency, cash)| {
+                        let cash = CentCount::try_from(cash)
+                            .map_err(|_| out_of_range(format!("{currency} cash {cash}")))?;
+                        Ok((currency, cash))
+                    })
+                    .collect::<Result<_, ErrorResponse>>()?,
+                positions: positions
+                    .into_iter()
+                    .filter(|&(_, quantity)| quantity != 0)
+                    .map(|(ticker, quantity)| {
+                        let quantity = i64::try_from(quantity)
+                            .map_err(|_| out_of_range(format!("{ticker} position {quantity}")))?;
+                        Ok((ticker, quantity))
+                    })
+                    .collect::<Result<_, ErrorResponse>>()?,
+            })
+        };
+
+        let splits = stock.iter().filter_map(|entry| match entry.memo {
+            StockMemo::Split { from, to } => Some(CorporateAction::Split {
+                at: entry.at,
+                ticker: entry.ticker.clone(),
+                from,
+                to,
+                change: entry.change,
+            }),
+            _ => None,
+        });
+        let dividends = ledger.iter().filter_map(|entry| match &entry.memo {
+            Memo::Dividend {
+                ticker,
+                quantity,
+                per_share,
+            } if entry.to == me => Some(CorporateAction::Dividend {
+                at: entry.at,
+                ticker: ticker.clone(),
+                quantity: *quantity,
+                per_share: *per_share,
+                currency: entry.currency.clone(),
+                amount: entry.amount,
+            }),
+            _ => None,
+        });
+        let mut corporate_actions: Vec<CorporateAction> = splits
+            .chain(dividends)
+            .filter(|action| in_range(action.at()))
+            .collect();
+        corporate_actions.sort_by_key(CorporateAction::at);
+
+        Ok(Self {
+            user_id,
+            from,
+            to,
+            opening: holdings(from)?,
+            closing: holdings(to)?,
+            entries: ledger
+                .iter()
+                .filter(|entry| in_range(entry.at))
+                .cloned()
+                .collect(),
+            stock_entries: stock
+                .iter()
+                .filter(|entry| in_range(entry.at))
+                .cloned()
+                .collect(),
+            fills: fills.into_iter().filter(|fill| in_range(fill.at)).collect(),
+            corporate_actions,
+        })
+    }
+
+    /// One row for each holding and line item under `CSV_HEADER`, fields a row has no use for
+    /// are empty. Amounts of cash entries are what they added to the balance, below zero for
+    /// what they took
+    pub fn to_csv(&self) -> String {
+        let me = LedgerAccount::User(self.user_id);
+        let mut rows = holding_rows("opening", self.from, &self.opening);
+        for entry in &self.entries {
+            let (kind, traded) = describe_memo(&entry.memo);
+            let (ticker, quantity, price) = match traded {
+                Some((ticker, quantity, price)) => {
+                    (ticker.clone(), quantity.to_string(), price.to_string())
+                }
+                None => Default::default(),
+            };
+            rows.push([
+                "entry".to_owned(),
+                entry.at.to_string(),
+                kind.to_owned(),
+                ticker,
+                entry.currency.clone(),
+                quantity,
+                price,
+                entry.change_for(me).to_string(),
+                String::new(),
+                account_name(entry.from),
+                account_name(entry.to),
+            ]);
+        }
+        for entry in &self.stock_entries {
+            let (kind, from, to) = match &entry.memo {
+                StockMemo::Listed => ("listed", String::new(), String::new()),
+                StockMemo::Transfer { from, to } => ("transfer", from.to_string(), to.to_string()),
+                StockMemo::Split { from, to } => ("split", from.to_string(), to.to_string()),
+                StockMemo::Opening => ("opening", String::new(), String::new()),
+            };
+            rows.push([
+                "stock".to_owned(),
+                entry.at.to_string(),
+                kind.to_owned(),
+                entry.ticker.clone(),
+                String::new(),
+                entry.change.to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                from,
+                to,
+            ]);
+        }
+        for fill in &self.fills {
+            let amount = u128::from(fill.quantity) * u128::from(fill.price);
+            rows.push([
+                "fill".to_owned(),
+                fill.at.to_string(),
+                match fill.side {
+                    OrderType::Buy => "buy",
+                    OrderType::Sell => "sell",
+                }
+                .to_owned(),
+                fill.ticker.clone(),
+                fill.currency.clone(),
+                fill.quantity.to_string(),
+                fill.price.to_string(),
+                amount.to_string(),
+                fill.fee.to_string(),
+                String::new(),
+                String::new(),
+            ]);
+        }
+        for action in &self.corporate_actions {
+            rows.push(match action {
+                CorporateAction::Split {
+                    at,
+                    ticker,
+                    from,
+                    to,
+                    change,
+                } => [
+                    "corporate_action".to_owned(),
+                    at.to_string(),
+                    "split".to_owned(),
+                    ticker.clone(),
+                    String::new(),
+                    change.to_string(),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    from.to_string(),
+                    to.to_string(),
+                ],
+                CorporateAction::Dividend {
+                    at,
+                    ticker,
+                    quantity,
+                    per_share,
+                    currency,
+                    amount,
+                } => [
+                    "corporate_action".to_owned(),
+                    at.to_string(),
+                    "dividend".to_owned(),
+                    ticker.clone(),
+                    currency.clone(),
+                    quantity.to_string(),
+                    per_share.to_string(),
+                    amount.to_string(),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                ],
+            });
+        }
+        rows.extend(holding_rows("closing", self.to, &self.closing));
+
+        let mut csv = format!("{CSV_HEADER}\n");
+        for row in rows {
+            let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+impl CorporateAction {
+    pub fn at(&self) -> u64 {
+        match self {
+            CorporateAction::Split { at, .. } | CorporateAction::Dividend { at, .. } => *at,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Persistence for node and coordinator state.
+//! Everything is stored as a flat map of string keys to json values, and every commit of a batch
+//! of `Op`s is atomic and durable once it returns.
+//! The schema version of the values is stored under `VERSION_KEY`, see `load_migrated`.
+
+mod kv;
+mod memory;
+pub mod wal;
+
+use crate::GResult;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+pub use kv::KvStorage;
+pub use memory::MemoryStorage;
+pub use wal::Wal;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Op {
+    Put { key: String, value: Value },
+    Delete { key: String },
+}
+
+impl Op {
+    pub fn put(key: impl Into<String>, value: &impl Serialize) -> GResult<Self> {
+        Ok(Op::Put {
+            key: key.into(),
+            value: serde_json::to_value(value)?,
+        })
+    }
+
+    pub fn delete(key: impl Into<String>) -> Self {
+        Op::Delete { key: key.into() }
+    }
+
+    fn apply_to(self, data: &mut HashMap<String, Value>) {
+        match self {
+            Op::Put { key, value } => {
+                data.insert(key, value);
+            }
+            Op::Delete { key } => {
+                data.remove(&key);
+            }
+        }
+    }
+}
+
+pub const VERSION_KEY: &str = "version";
+
+/// Rewrite every entry from the schema version before it to the one after
+pub type Migration = fn(&mut HashMap<String, Value>) -> GResult<()>;
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Every key value pair currently stored.
+    async fn entries(&self) -> GResult<HashMap<String, Value>>;
+
+    /// Apply all of `ops` or none of them.
+    async fn commit(&self, ops: Vec<Op>) -> GResult<()>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StorageKind {
+    /// write-ahead log and snapshot files in the persistent directory
+    File,
+    /// nothing survives a restart
+    Memory,
+    /// embedded key value database file in the persistent directory
+    Kv,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(StorageKind::File),
+            "memory" => Ok(StorageKind::Memory),
+            "kv" => Ok(StorageKind::Kv),
+            _ => Err(format!("Unknown storage {s}, expected file, memory or kv")),
+        }
+    }
+}
+
+/// Every entry persisted in `per_dir`, without modifying or migrating anything
+pub async fn read(kind: StorageKind, per_dir: &str) -> GResult<HashMap<String, Value>> {
+    match kind {
+        StorageKind::File => Wal::read(per_dir).await,
+        StorageKind::Memory => Err("Nothing is persisted by memory storage".into()),
+        StorageKind::Kv => KvStorage::read(per_dir).await,
+    }
+}
+
+pub async fn open(kind: StorageKind, per_dir: &str) -> GResult<Arc<dyn Storage>> {
+    Ok(match kind {
+        StorageKind::File => Arc::new(Wal::open(per_dir).await?),
+        StorageKind::Memory => Arc::new(MemoryStorage::new()),
+        StorageKind::Kv => Arc::new(KvStorage::open(per_dir).await?),
+    })
+}
+
+/// Read every entry, migrating them to the latest schema version first.
+/// Version `i` is turned into `i + 1` by `migrations[i]` so the latest is `migrations.len()`,
+/// entries without a version were written before versioning and are version 0.
+/// The migrated entries are committed before returning them, without `VERSION_KEY`.
+pub async fn load_migrated(
+    storage: &dyn Storage,
+    migrations: &[Migration],
+) -> GResult<HashMap<String, Value>> {
+    let mut entries = storage.entries().await?;
+    let latest = migrations.len();
+    let version = match entries.remove(VERSION_KEY) {
+        Some(v) => serde_json::from_value(v).map_err(|e| format!("Bad schema version: {e}"))?,
+        None if entries.is_empty() => latest,
+        None => 0,
+    };
+    if version > latest {
+        return Err(format!(
+            "Persisted state is schema version {version}, newer than {latest} which this build understands"
+        )
+        .into());
+    }
+    if version == latest && !entries.is_empty() {
+        return Ok(entries);
+    }
+
+    let before: Vec<String> = entries.keys().cloned().collect();
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        migration(&mut entries)
+            .map_err(|e| format!("Migration from schema version {i} to {} failed: {e}", i + 1))?;
+    }
+    let mut ops: Vec<Op> = before
+        .into_iter()
+        .filter(|key| !entries.contains_key(key))
+        .map(Op::delete)
+        .collect();
+    for (key, value) in &entries {
+        ops.push(Op::put(key, value)?);
+    }
+    ops.push(Op::put(VERSION_KEY, &latest)?);
+    storage.commit(ops).await?;
+    if version != latest {
+        println!("Migrated persisted state from schema version {version} to {latest}");
+    }
+    Ok(entries)
+}
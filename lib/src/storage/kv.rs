@@ -0,0 +1,84 @@
+//! format:
+//! file name = 'kv.redb'
+//! one table 'state', key = str, value = json bytes
+
+use super::{Op, Storage};
+use crate::GResult;
+use async_trait::async_trait;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde_json::Value;
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tokio::task::spawn_blocking;
+
+const TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("state");
+
+pub struct KvStorage {
+    db: Arc<Database>,
+}
+
+impl KvStorage {
+    pub async fn open(dir: impl AsRef<Path>) -> GResult<Self> {
+        let path = dir.as_ref().join("kv.redb");
+        let db = spawn_blocking(move || -> GResult<Database> {
+            let db = Database::create(path)?;
+            // make sure the table exists so reads don't fail on a new database
+            let txn = db.begin_write()?;
+            txn.open_table(TABLE)?;
+            txn.commit()?;
+            Ok(db)
+        })
+        .await??;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Read every entry in `dir` without creating anything
+    pub async fn read(dir: impl AsRef<Path>) -> GResult<HashMap<String, Value>> {
+        let path = dir.as_ref().join("kv.redb");
+        spawn_blocking(move || read_entries(&Database::open(path)?)).await?
+    }
+}
+
+fn read_entries(db: &Database) -> GResult<HashMap<String, Value>> {
+    let txn = db.begin_read()?;
+    let table = txn.open_table(TABLE)?;
+    let mut entries = HashMap::new();
+    for entry in table.iter()? {
+        let (key, value) = entry?;
+        entries.insert(
+            key.value().to_owned(),
+            serde_json::from_slice(value.value())?,
+        );
+    }
+    Ok(entries)
+}
+
+#[async_trait]
+impl Storage for KvStorage {
+    async fn entries(&self) -> GResult<HashMap<String, Value>> {
+        let db = Arc::clone(&self.db);
+        spawn_blocking(move || read_entries(&db)).await?
+    }
+
+    async fn commit(&self, ops: Vec<Op>) -> GResult<()> {
+        let db = Arc::clone(&self.db);
+        spawn_blocking(move || {
+            let txn = db.begin_write()?;
+            {
+                let mut table = txn.open_table(TABLE)?;
+                for op in ops {
+                    match op {
+                        Op::Put { key, value } => {
+                            table.insert(key.as_str(), serde_json::to_vec(&value)?.as_slice())?;
+                        }
+                        Op::Delete { key } => {
+                            table.remove(key.as_str())?;
+                        }
+                    }
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+}
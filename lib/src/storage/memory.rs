@@ -0,0 +1,32 @@
+use super::{Op, Storage};
+use crate::GResult;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Mutex<HashMap<String, Value>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn entries(&self) -> GResult<HashMap<String, Value>> {
+        Ok(self.data.lock().await.clone())
+    }
+
+    async fn commit(&self, ops: Vec<Op>) -> GResult<()> {
+        let mut data = self.data.lock().await;
+        for op in ops {
+            op.apply_to(&mut data);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Append-only write-ahead log with periodic snapshots.
+//!
+//! format:
+//! file name = 'snapshot'
+//! file content = one record, the whole key-value map
+//! file name = 'wal'
+//! file content = one record per line, each a batch of `Op`s committed since the snapshot
+//!
+//! A record is `<crc32 of json as 8 hex digits> <json>\n`.
+//! Restore loads the snapshot then replays the log on top of it. A torn record at the end of the
+//! log (crash mid-append) is dropped, a bad record anywhere else is an error.
+//!
+//! A directory with neither file but with json files in it is from before the log existed, when
+//! every key was its own file, those files are imported into the first snapshot.
+
+use super::{Op, Storage};
+use crate::GResult;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+/// Number of records appended to the log before it's compacted into a new snapshot.
+const SNAPSHOT_INTERVAL: usize = 1000;
+
+pub struct Wal {
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    log: File,
+    /// length of the log up to the end of its last whole record
+    len: u64,
+    records: usize,
+    data: HashMap<String, Value>,
+}
+
+impl Wal {
+    /// Open the log in `dir`, restoring the snapshot and replaying the log.
+    pub async fn open(dir: impl AsRef<Path>) -> GResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+
+        let log_path = dir.join("wal");
+        let (snapshot, bytes) = match (
+            read_if_exists(&dir.join("snapshot")).await?,
+            read_if_exists(&log_path).await?,
+        ) {
+            (None, None) => (import_legacy(&dir).await?, Vec::new()),
+            (snapshot, bytes) => (snapshot, bytes.unwrap_or_default()),
+        };
+        let (data, records, valid_len) = replay(snapshot.as_deref(), &bytes)?;
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&log_path)
+            .await?;
+        log.set_len(valid_len as u64).await?;
+        log.sync_all().await?;
+        log.seek(SeekFrom::End(0)).await?;
+
+        Ok(Self {
+            dir,
+            inner: Mutex::new(Inner {
+                log,
+                len: valid_len as u64,
+                records,
+                data,
+            }),
+        })
+    }
+
+    /// Read what `open` would restore without modifying anything in `dir`
+    pub async fn read(dir: impl AsRef<Path>) -> GResult<HashMap<String, Value>> {
+        let dir = dir.as_ref();
+        let snapshot = read_if_exists(&dir.join("snapshot")).await?;
+        let bytes = read_if_exists(&dir.join("wal")).await?.unwrap_or_default();
+        Ok(replay(snapshot.as_deref(), &bytes)?.0)
+    }
+
+    /// Write everything into a new snapshot and truncate the log.
+    async fn compact(&self, inner: &mut Inner) -> GResult<()> {
+        write_snapshot(&self.dir, &inner.data).await?;
+
+        // replaying the old log over the new snapshot is harmless if we crash before this
+        inner.log.set_len(0).await?;
+        inner.log.seek(SeekFrom::Start(0)).await?;
+        inner.log.sync_all().await?;
+        inner.len = 0;
+        inner.records = 0;
+        Ok(())
+    }
+
+    /// Append `record` and sync it, cutting the log back to its last whole record if that fails
+    /// so the next append doesn't land after a torn one
+    async fn append(inner: &mut Inner, record: &[u8]) -> GResult<()> {
+        let written = async {
+            inner.log.write_all(record).await?;
+            inner.log.sync_data().await
+        }
+        .await;
+        if let Err(e) = written {
+            inner.log.set_len(inner.len).await?;
+            inner.log.seek(SeekFrom::Start(inner.len)).await?;
+            return Err(e.into());
+        }
+        inner.len += record.len() as u64;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for Wal {
+    async fn entries(&self) -> GResult<HashMap<String, Value>> {
+        Ok(self.inner.lock().await.data.clone())
+    }
+
+    /// Append `ops` as a single record, the record is durable once this returns.
+    /// A failed compaction doesn't fail the commit, it's retried after the next one
+    async fn commit(&self, ops: Vec<Op>) -> GResult<()> {
+        let mut inner = self.inner.lock().await;
+        let record = encode_record(&serde_json::to_vec(&ops)?);
+        Self::append(&mut inner, &record).await?;
+        for op in ops {
+            op.apply_to(&mut inner.data);
+        }
+        inner.records += 1;
+        if inner.records >= SNAPSHOT_INTERVAL {
+            if let Err(e) = self.compact(&mut inner).await {
+                eprintln!("Failed to compact the log, will retry: {e}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load the snapshot and apply the log on top of it,
+/// returns the data, number of records and length of the log without the torn record if any.
+fn replay(
+    snapshot: Option<&[u8]>,
+    bytes: &[u8],
+) -> GResult<(HashMap<String, Value>, usize, usize)> {
+    let mut data: HashMap<String, Value> = match snapshot {
+        Some(bytes) => {
+            let line = bytes.strip_suffix(b"\n").ok_or("Snapshot is truncated")?;
+            serde_json::from_slice(decode_record(line).ok_or("Snapshot is corrupted")?)?
+        }
+        None => HashMap::new(),
+    };
+
+    let mut records = 0;
+    let mut valid_len = 0;
+    let mut lines = bytes.split_inclusive(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let ops = line
+            .strip_suffix(b"\n")
+            .and_then(decode_record)
+            .and_then(|json| serde_json::from_slice::<Vec<Op>>(json).ok());
+        let Some(ops) = ops else {
+            if lines.peek().is_some() {
+                return Err(format!("Log record {records} is corrupted").into());
+            }
+            // torn write from a crash during append, drop it
+            eprintln!("Dropping torn record at the end of the log");
+            break;
+        };
+        for op in ops {
+            op.apply_to(&mut data);
+        }
+        records += 1;
+        valid_len += line.len();
+    }
+    Ok((data, records, valid_len))
+}
+
+async fn read_if_exists(path: &Path) -> GResult<Option<Vec<u8>>> {
+    match fs::read(path).await {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Atomically replace the snapshot in `dir` with `data`
+async fn write_snapshot(dir: &Path, data: &HashMap<String, Value>) -> GResult<Vec<u8>> {
+    let snapshot = encode_record(&serde_json::to_vec(data)?);
+    let tmp_path = dir.join("snapshot.tmp");
+    let mut tmp = File::create(&tmp_path).await?;
+    tmp.write_all(&snapshot).await?;
+    tmp.sync_all().await?;
+    fs::rename(&tmp_path, dir.join("snapshot")).await?;
+    File::open(dir).await?.sync_all().await?;
+    Ok(snapshot)
+}
+
+/// Turn a directory of one json file per key into a snapshot, `None` if there's nothing to import
+async fn import_legacy(dir: &Path) -> GResult<Option<Vec<u8>>> {
+    let mut data = HashMap::new();
+    let mut files = fs::read_dir(dir).await?;
+    while let Some(file) = files.next_entry().await? {
+        if !file.file_type().await?.is_file() {
+            continue;
+        }
+        let key = file
+            .file_name()
+            .into_string()
+            .map_err(|name| format!("Bad file name {name:?}"))?;
+        let value = serde_json::from_slice(&fs::read(file.path()).await?)
+            .map_err(|e| format!("Can't import {key}: {e}"))?;
+        data.insert(key, value);
+    }
+    if data.is_empty() {
+        return Ok(None);
+    }
+    println!(
+        "Imported {} files from before the write-ahead log",
+        data.len()
+    );
+    Ok(Some(write_snapshot(dir, &data).await?))
+}
+
+fn encode_record(json: &[u8]) -> Vec<u8> {
+    let mut record = format!("{:08x} ", crc32fast::hash(json)).into_bytes();
+    record.extend_from_slice(json);
+    record.push(b'\n');
+    record
+}
+
+fn decode_record(line: &[u8]) -> Option<&[u8]> {
+    let (crc, json) = (line.get(..8)?, line.get(9..)?);
+    let crc = u32::from_str_radix(std::str::from_utf8(crc).ok()?, 16).ok()?;
+    (crc32fast::hash(json) == crc).then_some(json)
+}
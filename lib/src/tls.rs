@@ -0,0 +1,130 @@
+//! TLS for every connection, off unless a CA is given.
+//! Every server has a certificate signed by the CA, and presents it when connecting to other
+//! servers too, so links between servers are mutually authenticated. Clients only need the CA.
+
+use crate::{
+    read_writer::{ReadWriter, Transport},
+    GResult,
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use std::{fs::File, io::BufReader, net::SocketAddr, path::Path, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+pub struct Tls {
+    /// None for clients, which can't accept connections
+    acceptor: Option<TlsAcceptor>,
+    connector: TlsConnector,
+}
+
+fn load_certs(path: &Path) -> GResult<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> GResult<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("No private key in {}", path.display()))?)
+}
+
+impl Tls {
+    /// For clients, trusting servers signed by `ca`
+    pub fn client(ca: &Path) -> GResult<Self> {
+        let config = ClientConfig::builder()
+            .with_root_certificates(Self::roots(ca)?)
+            .with_no_client_auth();
+        Ok(Self {
+            acceptor: None,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// For servers, with their certificate `cert` signed by `ca` and its private `key`
+    pub fn server(ca: &Path, cert: &Path, key: &Path) -> GResult<Self> {
+        let roots = Arc::new(Self::roots(ca)?);
+        let certs = load_certs(cert)?;
+        let key = load_key(key)?;
+
+        // clients connect to the same port without certificates, servers are checked later
+        let verifier = WebPkiClientVerifier::builder(Arc::clone(&roots))
+            .allow_unauthenticated()
+            .build()?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)?;
+        Ok(Self {
+            acceptor: Some(TlsAcceptor::from(Arc::new(server))),
+            connector: TlsConnector::from(Arc::new(client)),
+        })
+    }
+
+    /// Load the optional tls arguments of a server, all or none must be given
+    pub fn from_server_args(
+        ca: Option<&Path>,
+        cert: Option<&Path>,
+        key: Option<&Path>,
+    ) -> GResult<Option<Self>> {
+        match (ca, cert, key) {
+            (Some(ca), Some(cert), Some(key)) => Ok(Some(Self::server(ca, cert, key)?)),
+            (None, None, None) => Ok(None),
+            _ => Err("--ca, --cert and --key must be given together".into()),
+        }
+    }
+
+    fn roots(ca: &Path) -> GResult<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert)?;
+        }
+        Ok(roots)
+    }
+}
+
+/// Accept a connection, over TLS unless `tls` is None
+pub async fn accept(socket: TcpStream, tls: Option<&Tls>) -> GResult<ReadWriter> {
+    let Some(tls) = tls else {
+        return Ok(ReadWriter::new(socket));
+    };
+    let acceptor = tls
+        .acceptor
+        .as_ref()
+        .ok_or("Client can't accept connections")?;
+    let peer_addr = socket.peer_addr().map_err(|e| e.to_string());
+    let stream = acceptor.accept(socket).await?;
+    let transport = match stream.get_ref().1.peer_certificates() {
+        Some(_) => Transport::MutualTls,
+        None => Transport::Tls,
+    };
+    Ok(ReadWriter::from_stream(stream, peer_addr, transport))
+}
+
+/// Connect to a server, over TLS unless `tls` is None.
+/// Servers are addressed by ip so their certificates must name it.
+pub async fn connect(addr: SocketAddr, tls: Option<&Tls>) -> GResult<ReadWriter> {
+    let socket = TcpStream::connect(addr).await?;
+    let Some(tls) = tls else {
+        return Ok(ReadWriter::new(socket));
+    };
+    let stream = tls
+        .connector
+        .connect(ServerName::from(addr.ip()), socket)
+        .await?;
+    let transport = if tls.acceptor.is_some() {
+        Transport::MutualTls
+    } else {
+        Transport::Tls
+    };
+    Ok(ReadWriter::from_stream(stream, Ok(addr), transport))
+}
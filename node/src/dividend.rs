@@ -0,0 +1,30 @@
+//! Paying a dividend, between the halt and resume of split.rs so no shares are on their way
+//! between accounts while holdings are counted.
+
+use crate::{split::check_halted, Global};
+use lib::{
+    interfaces::{Distribution, Payout, UserID},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::sync::Arc;
+
+pub async fn pay(actor: UserID, payout: Payout, global: &Arc<Global>) -> GResult<Distribution> {
+    let Payout { id, dividend } = payout;
+    // held so trading can't resume while paying
+    let matcher = global.matcher.read().dl("dv12").await;
+    check_halted(&matcher, &dividend.ticker)?;
+    let paid = global
+        .state
+        .write()
+        .dl("dv16")
+        .await
+        .pay_dividend(actor, id, &dividend)
+        .await?;
+    drop(matcher);
+    println!(
+        "{actor} paid {} per {} to {} accounts, {} in all.",
+        dividend.per_share, dividend.ticker, paid.accounts, paid.amount
+    );
+    Ok(paid)
+}
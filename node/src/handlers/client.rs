@@ -1,16 +1,40 @@
-use super::get_value_type;
 use crate::Global;
-use lib::{interfaces::UserID, lock::DeadLockDetect, read_writer::ReadWriter, GResult};
+use lib::{
+    auth::Claims,
+    interfaces::{
+        ErrorCode, ErrorResponse, NodeHello, NodeRequest, NodeResponse, Request, RequestID,
+        Response, Role, UserID, Welcome, PROTOCOL_VERSION,
+    },
+    lock::DeadLockDetect,
+    read_writer::{is_closed, ReadWriter, Reader},
+    GResult,
+};
 use serde_json::Value;
-use std::{str::FromStr, sync::Arc};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{mpsc, Semaphore};
 
-mod account;
-mod balance;
+pub mod account;
+pub mod balance;
+pub mod kill_switch;
+pub mod limits;
+pub mod margin;
 mod market;
 mod order;
-mod stock;
+pub mod short;
+pub mod stock;
+mod transfer;
 
-pub struct FirstLine(UserID);
+/// Requests of one session that change something waiting to be handled, and reads being
+/// handled, before it stops reading more
+const MAX_QUEUED: usize = 256;
+
+pub struct FirstLine(NodeHello);
 
 impl FromStr for FirstLine {
     type Err = String;
@@ -22,101 +46,229 @@ impl FromStr for FirstLine {
     }
 }
 
-#[derive(Debug)]
-pub struct Req {
-    crud: Crud,
-    target: Target,
-    value: Option<Value>,
+/// Parse a request line, an error response carries the request id if there was one
+fn parse_request(line: &str) -> Result<Request<NodeRequest>, Response<NodeResponse>> {
+    let bad_request = |id, e: serde_json::Error| Response {
+        id,
+        result: Err(ErrorResponse::new(ErrorCode::BadRequest, e.to_string())),
+    };
+    let Request { id, body } =
+        serde_json::from_str::<Request<Value>>(line).map_err(|e| bad_request(None, e))?;
+    let body = serde_json::from_value(body).map_err(|e| bad_request(Some(id), e))?;
+    Ok(Request { id, body })
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Crud {
-    Create,
-    Read,
-    Update,
-    Delete,
+/// Changes nothing, so it can be handled alongside the session's other requests
+fn is_read(req: &NodeRequest) -> bool {
+    matches!(
+        req,
+        NodeRequest::ReadBalance
+            | NodeRequest::ReadDeposits
+            | NodeRequest::ReadLedger
+            | NodeRequest::ReadStatement(_)
+            | NodeRequest::ReadStock
+            | NodeRequest::ReadBorrowed
+            | NodeRequest::ReadPositions
+            | NodeRequest::ReadMargin
+            | NodeRequest::ReadMarket
+            | NodeRequest::ReadInstruments
+            | NodeRequest::ReadOrders
+    )
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Target {
-    Balance,
-    Stock,
-    Market,
-    Order,
-    Account,
+async fn handle_request(
+    user_id: &UserID,
+    role: Role,
+    req: NodeRequest,
+    global: &Arc<Global>,
+) -> GResult<NodeResponse> {
+    match req {
+        NodeRequest::ReadBalance => balance::read(user_id, global).await,
+        NodeRequest::Deposit(amount) => balance::deposit(user_id, amount, global).await,
+        NodeRequest::ReadDeposits => balance::read_deposits(user_id, global).await,
+        NodeRequest::Withdraw(amount) => balance::withdraw(user_id, amount, global).await,
+        NodeRequest::ReadLedger => balance::read_ledger(user_id, global).await,
+        NodeRequest::ReadStatement(req) => balance::read_statement(user_id, req, global).await,
+        NodeRequest::ReadStock => stock::read(user_id, global).await,
+        NodeRequest::ReadBorrowed => short::read(user_id, global).await,
+        NodeRequest::ReadPositions => stock::read_positions(user_id, global).await,
+        NodeRequest::ReadMargin => margin::read(user_id, global).await,
+        NodeRequest::CreateStock(req) => stock::create(user_id, role, req, global).await,
+        NodeRequest::ReadMarket => market::read(global).await,
+        NodeRequest::ReadInstruments => market::instruments(global).await,
+        NodeRequest::CreateOrder(req) => order::create(user_id, req, global).await,
+        NodeRequest::ReadOrders => order::read(user_id, global).await,
+        NodeRequest::DeleteOrder(req) => order::delete(user_id, req, global).await,
+        NodeRequest::CancelOrders(filter) => order::cancel(user_id, filter, global).await,
+        NodeRequest::KillSwitch(engaged) => kill_switch::set(user_id, engaged, global).await,
+        NodeRequest::Transfer(req) => transfer::create(user_id, req, global).await,
+        NodeRequest::DeleteAccount => account::delete(user_id, global).await,
+        NodeRequest::CancelOnDisconnect(_) | NodeRequest::Bye => {
+            unreachable!("handled by the session")
+        }
+    }
 }
 
-impl Req {
-    fn from_str(s: &str) -> GResult<Req> {
-        let (req_type, value) = get_value_type(s)?;
-
-        let err: GResult<Req> = Err(Box::from(format!("Wrong type {}.", req_type)));
-
-        let t = req_type.into_bytes();
-
-        if t.len() < 3 {
-            return err;
+pub async fn handler(
+    FirstLine(NodeHello { version, token }): FirstLine,
+    mut rw: ReadWriter,
+    global: Arc<Global>,
+) -> GResult<String> {
+    let welcome = match global.verifying_key.verify(&token) {
+        _ if version != PROTOCOL_VERSION => Err(ErrorResponse::new(
+            ErrorCode::UnsupportedVersion,
+            format!("Client speaks version {version}, node speaks {PROTOCOL_VERSION}"),
+        )),
+        Err(e) => Err(ErrorResponse::new(ErrorCode::Unauthorized, e.to_string())),
+        Ok(Claims { user_id, role, .. }) => {
+            let state = global.state.read().dl("c95").await;
+            if user_id.node_id != state.get_id() || !state.get_accounts().contains_key(&user_id.id)
+            {
+                Err(ErrorResponse::new(
+                    ErrorCode::NotFound,
+                    format!("No account {user_id} on this node"),
+                ))
+            } else {
+                Ok((
+                    (user_id, role),
+                    Welcome {
+                        version: PROTOCOL_VERSION,
+                    },
+                ))
+            }
         }
+    };
+    rw.write_line(&serde_json::to_string(&welcome.as_ref().map(|(_, w)| w))?)
+        .await?;
+    let ((user_id, role), _) = welcome?;
 
-        let crud = match t[0] {
-            b'C' => Crud::Create,
-            b'R' => Crud::Read,
-            b'U' => Crud::Update,
-            b'D' => Crud::Delete,
-            _ => return err,
-        };
+    let (reader, mut writer) = rw.into_split();
+    let (sender, mut recver) = mpsc::unbounded_channel();
+    let cancel_on_disconnect = Arc::new(AtomicBool::new(false));
+    let reading = tokio::spawn(read_requests(
+        user_id,
+        role,
+        reader,
+        sender,
+        Arc::clone(&cancel_on_disconnect),
+        Arc::clone(&global),
+    ));
 
-        if t[1] != b' ' {
-            return err;
+    // the channel closes once reading stopped and every request it started has been answered
+    let ended = loop {
+        let Some((res, deleted)) = recver.recv().await else {
+            break reading.await?;
+        };
+        if let Err(e) = writer.write_line(&serde_json::to_string(&res)?).await {
+            reading.abort();
+            break Err(e);
         }
+        if deleted {
+            reading.abort();
+            return Ok(format!(
+                "Connection with user {user_id} terminated as account deleted."
+            ));
+        }
+    };
+    if cancel_on_disconnect.load(Ordering::SeqCst) {
+        match kill_switch::cancel_all(&user_id, &global).await {
+            Ok(()) => println!("Cancelled the orders of {user_id} as its session ended."),
+            Err(e) => eprintln!("Failed to cancel the orders of {user_id}: {e}"),
+        }
+    }
+    ended
+}
 
-        let target = match &String::from_utf8(t[2..].to_vec()).unwrap()[..] {
-            "balance" => Target::Balance,
-            "stock" => Target::Stock,
-            "market" => Target::Market,
-            "order" => Target::Order,
-            "account" => Target::Account,
-            _ => return err,
-        };
+/// Responses to send back, and whether the account was deleted by the request
+type ResponseSender = mpsc::UnboundedSender<(Response<NodeResponse>, bool)>;
 
-        Ok(Req {
-            crud,
-            target,
-            value,
-        })
-    }
+/// Handle a request and send its response, false once the session is gone
+async fn answer(
+    user_id: &UserID,
+    role: Role,
+    id: RequestID,
+    body: NodeRequest,
+    sender: &ResponseSender,
+    global: &Arc<Global>,
+) -> bool {
+    let delete_account = matches!(body, NodeRequest::DeleteAccount);
+    let result = handle_request(user_id, role, body, global)
+        .await
+        .map_err(ErrorResponse::from_error);
+    let deleted = delete_account && result.is_ok();
+    let res = Response {
+        id: Some(id),
+        result,
+    };
+    sender.send((res, deleted)).is_ok()
 }
 
-pub async fn handler(
-    FirstLine(user_id): FirstLine,
-    mut rw: ReadWriter,
+/// Reads are handled at once, each in a task of its own, so a slow order doesn't hold up a
+/// balance. Requests that change something are handled one at a time in the order they were
+/// sent by a worker of their own, so a later one never overtakes an earlier one. Reading goes on
+/// while they're handled
+async fn read_requests(
+    user_id: UserID,
+    role: Role,
+    mut reader: Reader,
+    sender: ResponseSender,
+    cancel_on_disconnect: Arc<AtomicBool>,
     global: Arc<Global>,
 ) -> GResult<String> {
-    let state = global.state.read().dl("c95").await;
-    if user_id.id >= state.get_accounts().len() || user_id.node_id != state.get_id() {
-        return Err(Box::from(format!("Bad user_id: {user_id:?}")));
+    // stops reading while the queue is full, ends with the queue once it's been emptied
+    let (queue, mut queued) = mpsc::channel::<(RequestID, NodeRequest)>(MAX_QUEUED);
+    {
+        let sender = sender.clone();
+        let global = Arc::clone(&global);
+        tokio::spawn(async move {
+            while let Some((id, body)) = queued.recv().await {
+                // the session is gone if this fails, nothing to reply to
+                if !answer(&user_id, role, id, body, &sender, &global).await {
+                    return;
+                }
+            }
+        });
     }
-    drop(state);
+    let reading = Arc::new(Semaphore::new(MAX_QUEUED));
     loop {
-        let line = rw.read_line().await?;
-        if line == "\"bye\"" {
-            return Ok(format!("Connection with user {user_id:?} terminated."));
-        }
-        let req = Req::from_str(&line)?;
-        let target = req.target;
-        let crud = req.crud;
-        let res = match req.target {
-            Target::Account => account::handler(&user_id, req, &global).await?,
-            Target::Balance => balance::handler(&user_id, req, &global).await?,
-            Target::Market => market::handler(req, &global).await?,
-            Target::Order => order::handler(&user_id, req, &global).await?,
-            Target::Stock => stock::handler(&user_id, req, &global).await?,
+        let line = match reader.read_line().await {
+            Ok(line) => line,
+            Err(e) if is_closed(&*e) => {
+                return Ok(format!("Connection with user {user_id} closed."))
+            }
+            Err(e) => return Err(e),
         };
-        rw.write_line(&res).await?;
-        if matches!(target, Target::Account) && matches!(crud, Crud::Delete) && res == "\"ok\"" {
-            return Ok(format!(
-                "Connection with user {user_id:?} terminated as account deleted."
-            ));
+        match parse_request(&line) {
+            Ok(Request {
+                body: NodeRequest::Bye,
+                ..
+            }) => return Ok(format!("Connection with user {user_id} terminated.")),
+            Ok(Request {
+                id,
+                body: NodeRequest::CancelOnDisconnect(cancel),
+            }) => {
+                cancel_on_disconnect.store(cancel, Ordering::SeqCst);
+                let res = Response {
+                    id: Some(id),
+                    result: Ok(NodeResponse::Ok),
+                };
+                sender.send((res, false))?;
+            }
+            Ok(Request { id, body }) if is_read(&body) => {
+                // stops reading while too many reads are being handled
+                let permit = Arc::clone(&reading).acquire_owned().await?;
+                let sender = sender.clone();
+                let global = Arc::clone(&global);
+                tokio::spawn(async move {
+                    answer(&user_id, role, id, body, &sender, &global).await;
+                    drop(permit);
+                });
+            }
+            Ok(Request { id, body }) => queue
+                .send((id, body))
+                .await
+                .map_err(|_| "Request worker stopped")?,
+            Err(res) => sender.send((res, false))?,
         }
     }
 }
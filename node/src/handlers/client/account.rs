@@ -1,29 +1,43 @@
-use super::{Crud, Req, UserID};
+use super::UserID;
 use crate::Global;
-use lib::{lock::DeadLockDetect, GResult};
+use lib::{
+    interfaces::{ErrorCode, ErrorResponse, NodeResponse, SetRole},
+    lock::DeadLockDetect,
+    GResult,
+};
 use std::sync::Arc;
 
-pub async fn handler(
-    user_id: &UserID,
-    Req { crud, .. }: Req,
-    global: &Arc<Global>,
-) -> GResult<String> {
-    match crud {
-        Crud::Delete => {
-            let state = global.state.read().dl("13").await;
-            let account = state
-                .get_accounts()
-                .get(&user_id.id)
-                .ok_or("Invalid account")?;
-            let delete_status = account.write().dl("15").await.delete().await;
-            match delete_status {
-                Ok(()) => {
-                    global.state.write().dl("13").await.remove_account(user_id.id);
-                    Ok("\"ok\"".to_string())
-                }
-                Err(_msg) => Ok("\"notEmpty\"".to_string()),
-            }
+pub async fn delete(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("13").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let delete_status = account.write().dl("15").await.delete().await;
+    drop(state);
+    match delete_status {
+        Ok(()) => {
+            global
+                .state
+                .write()
+                .dl("13")
+                .await
+                .remove_account(user_id.id);
+            Ok(NodeResponse::Ok)
         }
-        _ => Err(Box::from(format!("Can not {crud:?} account."))),
+        Err(msg) => Err(ErrorResponse::new(ErrorCode::NotEmpty, msg).into()),
     }
 }
+
+/// Forwarded by the coordinator when an admin changes the role, it's checked here from then on
+/// instead of the one in the account's session tokens
+pub async fn set_role(SetRole { user_id, role }: SetRole, global: &Arc<Global>) -> GResult<()> {
+    let state = global.state.read().dl("ac30").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let mut account = account.write().dl("ac36").await;
+    account.set_role(role).await
+}
@@ -1,30 +1,191 @@
-use super::{Crud, Req, UserID};
-use crate::Global;
-use lib::{lock::DeadLockDetect, GResult};
+use super::UserID;
+use crate::{state::Account, Global};
+use lib::{
+    audit::AuditAction,
+    instruments::Instruments,
+    interfaces::{
+        Adjustment, Cash, Currency, DepositDecision, ErrorCode, ErrorResponse, Money, NodeResponse,
+    },
+    lock::DeadLockDetect,
+    statement::{Statement, StatementFormat, StatementReq},
+    GResult,
+};
 use std::sync::Arc;
 
-pub async fn handler(
+pub async fn read(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("11").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let balances = account.read().dl("17").await.get_balances();
+    Ok(NodeResponse::Balance(balances))
+}
+
+pub async fn read_ledger(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("b22").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let ledger = account.read().dl("b27").await.get_ledger().to_vec();
+    Ok(NodeResponse::Ledger(ledger))
+}
+
+pub async fn read_statement(
     user_id: &UserID,
-    Req { crud, value, .. }: Req,
+    req: StatementReq,
     global: &Arc<Global>,
-) -> GResult<String> {
-    let state = global.state.read().dl("11").await;
+) -> GResult<NodeResponse> {
+    req.validate()?;
+    let state = global.state.read().dl("b36").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let account = account.read().dl("b41").await;
+    let statement = Statement::new(
+        *user_id,
+        account.get_ledger(),
+        account.get_stock_ledger(),
+        &req,
+    )?;
+    Ok(match req.format {
+        StatementFormat::Json => NodeResponse::Statement(Box::new(statement)),
+        StatementFormat::Csv => NodeResponse::StatementCsv(statement.to_csv()),
+    })
+}
+
+/// Only credited once an admin confirms the cash arrived
+pub async fn deposit(user_id: &UserID, cash: Cash, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("b35").await;
+    check_currency(state.get_instruments(), &cash.currency)?;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let id = account
+        .write()
+        .dl("b40")
+        .await
+        .request_deposit(cash)
+        .await?;
+    Ok(NodeResponse::Deposit(id))
+}
+
+pub async fn read_deposits(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("b47").await;
     let account = state
         .get_accounts()
         .get(&user_id.id)
         .ok_or("Invalid account")?;
-    match crud {
-        Crud::Read => Ok(account.read().dl("17").await.get_balance().to_string()),
-        Crud::Update => {
-            let mut account = account.write().dl("19").await;
-            let new_balance = value.as_ref().and_then(|v| v.as_u64()).ok_or("Bad value")?;
-            let empty = account.set_balance(new_balance).await?;
-            if empty {
-                Ok("\"ok\"".to_string())
-            } else {
-                Ok("\"notEmpty\"".to_string())
-            }
-        }
-        _ => Err(Box::from(format!("Can not {crud:?} balance."))),
+    let deposits = account.read().dl("b52").await.get_deposits().clone();
+    Ok(NodeResponse::Deposits(deposits))
+}
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn confirm_deposit(
+    actor: UserID,
+    DepositDecision {
+        user_id,
+        id,
+        accepted,
+    }: DepositDecision,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let state = global.state.read().dl("b64").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let mut account = account.write().dl("b70").await;
+    let cash = account.get_deposits().get(&id).cloned().ok_or_else(|| {
+        ErrorResponse::new(ErrorCode::NotFound, format!("No deposit {id} of {user_id}"))
+    })?;
+    let action = AuditAction::ConfirmDeposit {
+        user_id,
+        id,
+        cash,
+        accepted,
+    };
+    let record = state.get_audit().lock().await.record(actor, action)?;
+    account.confirm_deposit(id, accepted, record).await
+}
+
+pub async fn withdraw(user_id: &UserID, cash: Cash, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("b49").await;
+    check_currency(state.get_instruments(), &cash.currency)?;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let mut account = account.write().dl("b54").await;
+    let currency = cash.currency.clone();
+    if account.withdraw(cash).await? {
+        Ok(NodeResponse::Ok)
+    } else {
+        Err(not_enough(&account, &currency))
     }
 }
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn adjust(
+    actor: UserID,
+    Adjustment {
+        user_id,
+        currency,
+        amount,
+    }: Adjustment,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let state = global.state.read().dl("11").await;
+    check_currency(state.get_instruments(), &currency)?;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let mut account = account.write().dl("19").await;
+    let from = account.get_balance(&currency);
+    let to = Money(from).checked_add_signed(amount.into())?.0;
+    let action = AuditAction::SetBalance {
+        user_id,
+        currency: currency.clone(),
+        from,
+        to,
+    };
+    let record = state.get_audit().lock().await.record(actor, action)?;
+    if account
+        .adjust(currency.clone(), amount, actor, record)
+        .await?
+    {
+        Ok(())
+    } else {
+        Err(not_enough(&account, &currency))
+    }
+}
+
+/// Cash can only be in the currencies of the registry
+pub fn check_currency(instruments: &Instruments, currency: &Currency) -> GResult<()> {
+    if instruments.is_currency(currency) {
+        Ok(())
+    } else {
+        Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            format!("Unknown currency {currency}"),
+        )
+        .into())
+    }
+}
+
+fn not_enough(account: &Account, currency: &Currency) -> Box<dyn std::error::Error + Send + Sync> {
+    ErrorResponse::new(
+        ErrorCode::NotEnough,
+        format!(
+            "Only {} {currency} of the balance isn't reserved by orders or owed as fees",
+            account.get_withdrawable(currency)
+        ),
+    )
+    .into()
+}
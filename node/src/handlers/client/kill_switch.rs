@@ -0,0 +1,82 @@
+use super::{
+    order::{cancel_matching, check_liquidating},
+    UserID,
+};
+use crate::Global;
+use lib::{
+    audit::AuditAction,
+    interfaces::{CancelFilter, ErrorCode, ErrorResponse, KillSwitch, KilledBy, NodeResponse},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::sync::Arc;
+
+/// The session's own account
+pub async fn set(user_id: &UserID, engaged: bool, global: &Arc<Global>) -> GResult<NodeResponse> {
+    switch(*user_id, engaged, None, global).await?;
+    Ok(NodeResponse::Ok)
+}
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn admin(
+    actor: UserID,
+    KillSwitch { user_id, engaged }: KillSwitch,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    switch(user_id, engaged, Some(actor), global).await
+}
+
+/// Engaging blocks new orders before cancelling every resting one. It stays engaged if trading
+/// in one of their tickers is halted, cancelling none
+async fn switch(
+    user_id: UserID,
+    engaged: bool,
+    admin: Option<UserID>,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    {
+        let state = global.state.read().dl("ks43").await;
+        let account = state
+            .get_accounts()
+            .get(&user_id.id)
+            .filter(|_| user_id.node_id == state.get_id())
+            .ok_or_else(|| {
+                ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}"))
+            })?;
+        let mut account = account.write().dl("ks51").await;
+        let killed = match (engaged, admin, account.killed_by()) {
+            (true, Some(_), _) => Some(KilledBy::Admin),
+            (true, None, killed) => killed.or(Some(KilledBy::Account)),
+            (false, None, Some(KilledBy::Admin)) => {
+                return Err(ErrorResponse::new(
+                    ErrorCode::Forbidden,
+                    "Only an admin can release the kill switch an admin engaged",
+                )
+                .into())
+            }
+            (false, _, _) => None,
+        };
+        if engaged {
+            // its orders are forced
+            check_liquidating(&account)?;
+        }
+        let record = match admin {
+            Some(actor) => {
+                let action = AuditAction::KillSwitch { user_id, engaged };
+                Some(state.get_audit().lock().await.record(actor, action)?)
+            }
+            None => None,
+        };
+        account.set_killed(killed, record).await?;
+    }
+    if engaged {
+        cancel_all(&user_id, global).await?;
+    }
+    Ok(())
+}
+
+/// Also when a session asking for it ends, of orders from any session of the account
+pub async fn cancel_all(user_id: &UserID, global: &Arc<Global>) -> GResult<()> {
+    cancel_matching(user_id, &CancelFilter::default(), global).await?;
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use super::UserID;
+use crate::Global;
+use lib::{
+    audit::AuditAction,
+    interfaces::{AccountLimits, ErrorCode, ErrorResponse, RiskLimits},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::sync::Arc;
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn read(user_id: UserID, global: &Arc<Global>) -> GResult<RiskLimits> {
+    let state = global.state.read().dl("lm13").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let limits = account.read().dl("lm19").await.get_limits();
+    Ok(limits)
+}
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn set(
+    actor: UserID,
+    AccountLimits { user_id, limits }: AccountLimits,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let state = global.state.read().dl("lm29").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let mut account = account.write().dl("lm35").await;
+    let action = AuditAction::SetRiskLimits { user_id, limits };
+    let record = state.get_audit().lock().await.record(actor, action)?;
+    account.set_limits(limits, record).await
+}
@@ -0,0 +1,40 @@
+use super::UserID;
+use crate::Global;
+use lib::{
+    audit::AuditAction,
+    interfaces::{ErrorCode, ErrorResponse, MarginAccount, NodeResponse},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::sync::Arc;
+
+pub async fn read(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("mg12").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let margin = account.read().dl("mg17").await.get_margin();
+    Ok(NodeResponse::Margin(margin))
+}
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn set(
+    actor: UserID,
+    MarginAccount { user_id, terms }: MarginAccount,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let mut state = global.state.write().dl("mg27").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let mut account = account.write().dl("mg33").await;
+    let action = AuditAction::SetMargin { user_id, terms };
+    let record = state.get_audit().lock().await.record(actor, action)?;
+    account.set_margin(terms, record).await?;
+    drop(account);
+    state.set_margin_account(user_id.id, terms.is_some());
+    Ok(())
+}
@@ -1,14 +1,13 @@
-use super::{Req, Crud};
 use crate::Global;
-use lib::{GResult, lock::DeadLockDetect};
+use lib::{interfaces::NodeResponse, lock::DeadLockDetect, GResult};
 use std::sync::Arc;
 
-pub async fn handler(Req { crud, .. }: Req, global: &Arc<Global>) -> GResult<String> {
-    match crud {
-        Crud::Read => {
-            let matcher = global.matcher.read().dl("9").await;
-            Ok(serde_json::to_string(&matcher.get_stats())?)
-        }
-        _ => Err(Box::from(format!("Can not {crud:?} market."))),
-    }
+pub async fn read(global: &Arc<Global>) -> GResult<NodeResponse> {
+    let matcher = global.matcher.read().dl("9").await;
+    Ok(NodeResponse::Market(matcher.get_stats()))
+}
+
+pub async fn instruments(global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("m14").await;
+    Ok(NodeResponse::Instruments(state.get_instruments().clone()))
 }
@@ -1,96 +1,194 @@
-use super::{Crud, Req, UserID};
+use super::UserID;
 use crate::{
-    matcher::Order,
-    order::{add_order_to_matcher_and_process, matcher_deduct_order},
+    matcher::{Matcher, Order},
+    order::{add_order_to_matcher_and_process, broadcast_deduct_order, cancel_orders, drain},
+    split::check_trading,
+    state::Account,
     Global,
 };
-use lib::{interfaces::OrderReq, lock::DeadLockDetect, GResult};
+use lib::{
+    interfaces::{CancelFilter, ErrorCode, ErrorResponse, NodeResponse, OrderReq},
+    lock::DeadLockDetect,
+    GResult,
+};
 use std::sync::Arc;
 
-pub async fn handler(
+/// Its orders are forced while it is
+pub fn check_liquidating(account: &Account) -> GResult<()> {
+    if account.is_liquidating() {
+        return Err(
+            ErrorResponse::new(ErrorCode::BadRequest, "The account is being liquidated").into(),
+        );
+    }
+    Ok(())
+}
+
+pub async fn create(
     user_id: &UserID,
-    Req { crud, value, .. }: Req,
+    OrderReq {
+        order_type,
+        ticker,
+        price,
+        quantity,
+    }: OrderReq,
     global: &Arc<Global>,
-) -> GResult<String> {
+) -> GResult<NodeResponse> {
+    let matcher = global.matcher.read().dl("o29").await;
+    check_trading(&matcher, &ticker)?;
+    let state = global.state.read().dl("o30").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+
+    let mut account = account.write().dl("o37").await;
+    check_liquidating(&account)?;
+    if account.killed_by().is_some() {
+        return Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            "The account's kill switch is engaged",
+        )
+        .into());
+    }
+    let req = OrderReq {
+        order_type,
+        ticker: ticker.clone(),
+        quantity,
+        price,
+    };
+    account.check_limits(&req)?;
+    let enough = account.add_order(req).await?;
+    if !enough {
+        let what = if account.get_margin().terms.is_some() {
+            "buying power"
+        } else {
+            "balance"
+        };
+        return Err(ErrorResponse::new(
+            ErrorCode::NotEnough,
+            format!("Not enough {what} or stock to {order_type:?} {quantity} {ticker}"),
+        )
+        .into());
+    }
+    drop(account);
+
+    let global = Arc::clone(global);
+    let user_id = *user_id;
+    add_order_to_matcher_and_process(
+        Order {
+            order_type,
+            ticker,
+            user_id,
+            quantity,
+            price,
+        },
+        &global,
+    );
+    Ok(NodeResponse::Ok)
+}
+
+pub async fn read(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
     let state = global.state.read().dl("o15").await;
     let account = state
         .get_accounts()
         .get(&user_id.id)
         .ok_or("Invalid account")?;
-    match crud {
-        Crud::Create => {
-            let OrderReq {
-                order_type,
-                ticker,
-                price,
-                quantity,
-            } = value
-                .and_then(|v| serde_json::from_value(v).ok())
-                .ok_or("Bad value")?;
-            let state = global.state.read().dl("o30").await;
-            let account = state
-                .get_accounts()
-                .get(&user_id.id)
-                .ok_or("Invalid account")?;
+    let account = account.read().dl("o66").await;
+    Ok(NodeResponse::Orders(account.get_orders()))
+}
 
-            let enough = account
-                .write()
-                .dl("o37")
-                .await
-                .add_order(OrderReq {
-                    order_type,
-                    ticker: ticker.clone(),
-                    quantity,
-                    price,
-                })
-                .await?;
-            if !enough {
-                return Ok(r#""notEnough""#.to_owned());
-            }
+pub async fn delete(
+    user_id: &UserID,
+    order: OrderReq,
+    global: &Arc<Global>,
+) -> GResult<NodeResponse> {
+    let mut matcher = global.matcher.write().dl("o70").await;
+    check_trading(&matcher, &order.ticker)?;
+    let state = global.state.read().dl("o15").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let mut account = account.write().dl("o71").await;
+    check_liquidating(&account)?;
+    let quantity = account.deduct_order(order.clone()).await?;
+    let OrderReq {
+        order_type,
+        ticker,
+        price,
+        ..
+    } = order;
 
-            let global = Arc::clone(global);
-            let user_id = *user_id;
-            add_order_to_matcher_and_process(
-                Order {
-                    order_type,
-                    ticker,
-                    user_id,
-                    quantity,
-                    price,
-                },
-                &global,
-            );
-            Ok(r#""ok""#.to_owned())
-        }
-        Crud::Read => {
-            let account = account.read().dl("o66").await;
-            Ok(serde_json::to_string(&account.get_orders())?)
-        }
-        Crud::Delete => {
-            let order: OrderReq = serde_json::from_value(value.ok_or("Bad value")?)?;
-            let mut account = account.write().dl("o71").await;
-            let quantity = account.deduct_order(order.clone()).await?;
-            let OrderReq {
-                order_type,
-                ticker,
-                price,
-                ..
-            } = order;
+    let mut order = Order {
+        order_type,
+        ticker,
+        user_id: *user_id,
+        price,
+        quantity,
+    };
+    // an order still on its way into the matcher was never sent to the others
+    order.quantity = matcher.deduct_order(order.clone());
+    drop(account);
+    drop(state);
+    drop(matcher);
+    if order.quantity > 0 {
+        broadcast_deduct_order(order, global).await?;
+    }
 
-            matcher_deduct_order(
-                Order {
-                    order_type,
-                    ticker,
-                    user_id: *user_id,
-                    price,
-                    quantity,
-                },
-                global,
-            )
-            .await?;
+    Ok(NodeResponse::Deleted(quantity))
+}
 
-            Ok(quantity.to_string())
+pub async fn cancel(
+    user_id: &UserID,
+    filter: CancelFilter,
+    global: &Arc<Global>,
+) -> GResult<NodeResponse> {
+    if let (Some(min), Some(max)) = (filter.min_price, filter.max_price) {
+        if min > max {
+            return Err(ErrorResponse::new(
+                ErrorCode::BadRequest,
+                format!("The price range {min} to {max} is empty"),
+            )
+            .into());
         }
-        _ => Err(Box::from(format!("Can not {crud:?} order."))),
     }
+    let cancelled = cancel_matching(user_id, &filter, global).await?;
+    Ok(NodeResponse::Cancelled(cancelled))
+}
+
+/// Orders of a halted ticker can't be cancelled until it's resumed
+fn check_halted(orders: &[OrderReq], matcher: &Matcher) -> GResult<()> {
+    match orders.iter().find(|order| matcher.is_halted(&order.ticker)) {
+        Some(order) => Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            format!("Trading in {} is halted", order.ticker),
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Cancels none if trading in one of their tickers is halted. Waits for orders on their way
+/// into the matcher, so every one the account placed is cancelled
+pub async fn cancel_matching(
+    user_id: &UserID,
+    filter: &CancelFilter,
+    global: &Arc<Global>,
+) -> GResult<Vec<OrderReq>> {
+    drain(global).await?;
+    let mut matcher = global.matcher.write().dl("o150").await;
+    let state = global.state.read().dl("o151").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let mut account = account.write().dl("o156").await;
+    check_liquidating(&account)?;
+    let orders: Vec<OrderReq> = account
+        .open_orders(None)
+        .into_iter()
+        .filter(|order| filter.matches(order))
+        .collect();
+    check_halted(&orders, &matcher)?;
+    cancel_orders(*user_id, orders, &mut account, &mut matcher, global).await
 }
@@ -0,0 +1,56 @@
+use super::UserID;
+use crate::Global;
+use lib::{
+    audit::AuditAction,
+    interfaces::{ErrorCode, ErrorResponse, Lendable, NodeResponse, ShortPermission},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::sync::Arc;
+
+pub async fn read(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("sh12").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let borrowed = account.read().dl("sh17").await.get_borrowed();
+    Ok(NodeResponse::Borrowed(borrowed))
+}
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn allow(
+    actor: UserID,
+    ShortPermission { user_id, allowed }: ShortPermission,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let state = global.state.read().dl("sh27").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let mut account = account.write().dl("sh33").await;
+    let action = AuditAction::AllowShort { user_id, allowed };
+    let record = state.get_audit().lock().await.record(actor, action)?;
+    account.set_can_short(allowed, record).await
+}
+
+/// On behalf of an admin, forwarded by the coordinator to the node in `Lendable`
+pub async fn lendable(
+    actor: UserID,
+    Lendable {
+        ticker, quantity, ..
+    }: Lendable,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let mut state = global.state.write().dl("sh46").await;
+    if state.get_instruments().fx(&ticker).is_some() {
+        return Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            format!("{ticker} is a currency pair, it can't be lent"),
+        )
+        .into());
+    }
+    state.set_lendable(actor, ticker, quantity).await
+}
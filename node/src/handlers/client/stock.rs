@@ -1,36 +1,103 @@
-use super::{Crud, Req, UserID};
+use super::UserID;
 use crate::Global;
-use lib::{GResult, lock::DeadLockDetect};
-use serde::{Deserialize, Serialize};
+use lib::{
+    audit::AuditAction,
+    interfaces::{ErrorCode, ErrorResponse, Mint, NodeResponse, Role, StockReq},
+    lock::DeadLockDetect,
+    GResult,
+};
 use std::sync::Arc;
 
-#[derive(Serialize, Deserialize)]
-struct CStockV {
-    ticker_id: String,
-    quantity: u64,
+pub async fn read(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("s18").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let account = account.read().dl("s25").await;
+    Ok(NodeResponse::Stock(account.get_portfolio().clone()))
 }
 
-pub async fn handler(
+pub async fn read_positions(user_id: &UserID, global: &Arc<Global>) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("s30").await;
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .ok_or("Invalid account")?;
+    let positions = account.read().dl("s35").await.get_positions();
+    Ok(NodeResponse::Positions(positions))
+}
+
+/// IPO into the session's own account, `role` is the session token's
+pub async fn create(
     user_id: &UserID,
-    Req { crud, value, .. }: Req,
+    role: Role,
+    StockReq { ticker, quantity }: StockReq,
     global: &Arc<Global>,
-) -> GResult<String> {
-    let state = global.state.read().dl("s18").await;
+) -> GResult<NodeResponse> {
+    let state = global.state.read().dl("s38").await;
     let account = state
         .get_accounts()
         .get(&user_id.id)
         .ok_or("Invalid account")?;
-    match crud {
-        Crud::Read => {
-            let account = account.read().dl("s25").await;
-            Ok(serde_json::to_string(account.get_portfolio())?)
-        }
-        Crud::Create => {
-            let account = &mut account.write().dl("s29").await;
-            let req: CStockV = serde_json::from_value(value.ok_or("Bad value".to_string())?)?;
-            account.add_stock(req.ticker_id, req.quantity).await?;
-            Ok("\"ok\"".to_string())
-        }
-        _ => Err(Box::from(format!("Can not {crud:?} market."))),
+    let role = account.read().dl("s43").await.role(role);
+    drop(state);
+    if !role.can_list_stock() {
+        return Err(ErrorResponse::new(
+            ErrorCode::Forbidden,
+            "Only market operators and admins can list stock",
+        )
+        .into());
     }
+    let action = AuditAction::ListStock {
+        user_id: *user_id,
+        ticker: ticker.clone(),
+        quantity,
+    };
+    add(*user_id, *user_id, ticker, quantity, action, global).await?;
+    Ok(NodeResponse::Ok)
+}
+
+/// On behalf of an admin, forwarded by the coordinator
+pub async fn mint(
+    actor: UserID,
+    Mint {
+        user_id,
+        ticker,
+        quantity,
+    }: Mint,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let action = AuditAction::MintStock {
+        user_id,
+        ticker: ticker.clone(),
+        quantity,
+    };
+    add(actor, user_id, ticker, quantity, action, global).await
+}
+
+async fn add(
+    actor: UserID,
+    user_id: UserID,
+    ticker: String,
+    quantity: u64,
+    action: AuditAction,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let state = global.state.read().dl("s18").await;
+    if state.get_instruments().fx(&ticker).is_some() {
+        return Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            format!("{ticker} is a currency pair, deposit its currencies instead"),
+        )
+        .into());
+    }
+    let account = state
+        .get_accounts()
+        .get(&user_id.id)
+        .filter(|_| user_id.node_id == state.get_id())
+        .ok_or_else(|| ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")))?;
+    let mut account = account.write().dl("s29").await;
+    let record = state.get_audit().lock().await.record(actor, action)?;
+    account.add_stock(ticker, quantity, record).await
 }
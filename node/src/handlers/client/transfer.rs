@@ -0,0 +1,51 @@
+use super::{balance::check_currency, UserID};
+use crate::{
+    split::check_trading,
+    transfer::{no_account, not_enough, transfer_remote, Transfer},
+    Global,
+};
+use lib::{
+    interfaces::{Asset, Cash, ErrorCode, ErrorResponse, Memo, NodeResponse, TransferReq},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::sync::Arc;
+
+pub async fn create(
+    user_id: &UserID,
+    TransferReq { to, asset }: TransferReq,
+    global: &Arc<Global>,
+) -> GResult<NodeResponse> {
+    if to == *user_id {
+        return Err(ErrorResponse::new(ErrorCode::BadRequest, "Can't transfer to yourself").into());
+    }
+    if matches!(
+        asset,
+        Asset::Cash(Cash { amount: 0, .. }) | Asset::Stock { quantity: 0, .. }
+    ) {
+        return Err(ErrorResponse::new(ErrorCode::BadRequest, "Nothing to transfer").into());
+    }
+    let transfer = Transfer {
+        from: *user_id,
+        to,
+        asset,
+        memo: Memo::Transfer,
+    };
+
+    let matcher = global.matcher.read().dl("t25").await;
+    let mut state = global.state.write().dl("t26").await;
+    match &transfer.asset {
+        Asset::Cash(cash) => check_currency(state.get_instruments(), &cash.currency)?,
+        Asset::Stock { ticker, .. } => check_trading(&matcher, ticker)?,
+    }
+    if to.node_id != state.get_id() {
+        drop(state);
+        drop(matcher);
+        transfer_remote(transfer, global).await?;
+    } else if !state.get_accounts().contains_key(&to.id) {
+        return Err(no_account(to));
+    } else if !state.transfer_local(&transfer).await? {
+        return Err(not_enough(&transfer));
+    }
+    Ok(NodeResponse::Ok)
+}
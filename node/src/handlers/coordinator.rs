@@ -1,9 +1,17 @@
-use super::{get_value_type, node};
-use crate::{Global, Node, NodeID};
-use lib::{interfaces::UserID, lock::DeadLockDetect, read_writer::ReadWriter, GResult};
+use super::{client, get_value_type, node};
+use crate::{dividend, split, Global, Node, NodeID};
+use lib::{
+    interfaces::{
+        AccountLimits, Adjustment, DepositDecision, ErrorResponse, KillSwitch, Lendable,
+        MarginAccount, Mint, Payout, SetRole, ShortPermission, Split, Ticker, UserID,
+    },
+    lock::DeadLockDetect,
+    read_writer::ReadWriter,
+    GResult,
+};
 use serde::Deserialize;
+use serde_json::Value;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::net::TcpStream;
 
 #[derive(Deserialize)]
 struct JoinedReq {
@@ -11,6 +19,35 @@ struct JoinedReq {
     addr: SocketAddr,
 }
 
+#[derive(Deserialize)]
+struct AdminReq {
+    actor: UserID,
+    op: AdminOp,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+enum AdminOp {
+    MintStock(Mint),
+    AdjustBalance(Adjustment),
+    ConfirmDeposit(DepositDecision),
+    AllowShort(ShortPermission),
+    SetLendable(Lendable),
+    SetMargin(MarginAccount),
+    SetRiskLimits(AccountLimits),
+    /// replied to with its limits
+    ReadRiskLimits(UserID),
+    KillSwitch(KillSwitch),
+    SetRole(SetRole),
+    /// the steps of a split, see split.rs
+    Halt(Ticker),
+    PrepareSplit(Split),
+    Split(Split),
+    Resume(Ticker),
+    /// replied to with what it paid
+    Dividend(Payout),
+}
+
 pub async fn handler(mut rw: ReadWriter, global: Arc<Global>) -> GResult<String> {
     let this_id = (*global.state.read().dl("co17").await).get_id();
 
@@ -22,23 +59,22 @@ pub async fn handler(mut rw: ReadWriter, global: Arc<Global>) -> GResult<String>
             "joined" => {
                 let JoinedReq { id: other_id, addr } = serde_json::from_str(&req)?;
 
-                // modify others
-                global
-                    .others
-                    .write()
-                    .await
-                    .insert(other_id, Node::DisConnected(addr));
+                // modify others, keeping what's waiting for it
+                let mut others = global.others.write().await;
+                let queued = match others.remove(&other_id) {
+                    Some(Node::DisConnected(_, queued)) => queued,
+                    _ => Vec::new(),
+                };
+                others.insert(other_id, Node::DisConnected(addr, queued));
+                drop(others);
 
-                let mut rw = ReadWriter::new(
-                    TcpStream::connect(addr)
-                        .await
-                        .unwrap_or_else(|_| panic!("Failed to connect to node {}", req)),
-                );
+                let rw = node::connect(other_id, addr, &global)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to connect to node {}: {e}", req));
 
-                rw.write_line(&this_id.to_string()).await?;
                 let global = Arc::clone(&global);
                 tokio::spawn(async move {
-                    match node::handler(node::FirstLine(other_id), rw, global).await {
+                    match node::handler(other_id, rw, global).await {
                         Ok(msg) => println!("Connection terminated with node: {msg}"),
                         Err(e) => eprintln!("Error: {e}"),
                     }
@@ -58,6 +94,49 @@ pub async fn handler(mut rw: ReadWriter, global: Arc<Global>) -> GResult<String>
                 })?)
                 .await?;
             }
+            "admin" => {
+                let AdminReq { actor, op } = serde_json::from_str(&req)?;
+                let done = |result: GResult<()>| result.map(|()| Value::Null);
+                let result = match op {
+                    AdminOp::MintStock(mint) => {
+                        done(client::stock::mint(actor, mint, &global).await)
+                    }
+                    AdminOp::AdjustBalance(adjustment) => {
+                        done(client::balance::adjust(actor, adjustment, &global).await)
+                    }
+                    AdminOp::ConfirmDeposit(req) => {
+                        done(client::balance::confirm_deposit(actor, req, &global).await)
+                    }
+                    AdminOp::AllowShort(req) => {
+                        done(client::short::allow(actor, req, &global).await)
+                    }
+                    AdminOp::SetLendable(req) => {
+                        done(client::short::lendable(actor, req, &global).await)
+                    }
+                    AdminOp::SetMargin(req) => done(client::margin::set(actor, req, &global).await),
+                    AdminOp::SetRiskLimits(req) => {
+                        done(client::limits::set(actor, req, &global).await)
+                    }
+                    AdminOp::KillSwitch(req) => {
+                        done(client::kill_switch::admin(actor, req, &global).await)
+                    }
+                    AdminOp::SetRole(req) => done(client::account::set_role(req, &global).await),
+                    AdminOp::ReadRiskLimits(user_id) => client::limits::read(user_id, &global)
+                        .await
+                        .and_then(|limits| Ok(serde_json::to_value(limits)?)),
+                    AdminOp::Halt(ticker) => done(split::halt(ticker, &global).await),
+                    AdminOp::PrepareSplit(req) => done(split::prepare(req, &global).await),
+                    AdminOp::Split(req) => done(split::split(actor, req, &global).await),
+                    AdminOp::Resume(ticker) => done(split::resume(ticker, &global).await),
+                    AdminOp::Dividend(req) => dividend::pay(actor, req, &global)
+                        .await
+                        .and_then(|paid| Ok(serde_json::to_value(paid)?)),
+                };
+                rw.write_line(&serde_json::to_string(
+                    &result.map_err(ErrorResponse::from_error),
+                )?)
+                .await?;
+            }
             req_type => return Err(Box::from(format!("Wrong type {}.", req_type))),
         }
         println!("Handled request from coordinator: {req}")
@@ -14,7 +14,7 @@ pub async fn handler(mut rw: ReadWriter, global: Arc<Global>) -> GResult<String>
         client::handler(first_line, rw, global).await
     } else if let Ok(first_line) = node::FirstLine::from_str(&first_line) {
         tokio::spawn(async move {
-            match node::handler(first_line, rw, global).await {
+            match node::accept(first_line, rw, global).await {
                 Ok(msg) => println!("Connection terminated with node: {msg}"),
                 Err(e) => eprintln!("Error: {e}"),
             }
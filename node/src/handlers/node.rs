@@ -3,15 +3,30 @@ mod offer_replied;
 mod offer_send;
 mod order_recv;
 mod order_send;
-
-use crate::{handlers::get_value_type, matcher::Trade, Global, Node, NodeID, order::OrderUpdate};
-use lib::{lock::DeadLockDetect, read_writer::ReadWriter, GResult};
+mod transfer_recv;
+mod transfer_replied;
+mod transfer_send;
+
+use crate::{matcher::Trade, order::OrderUpdate, transfer::Transfer, Global, Node, NodeID};
+use lib::{
+    lock::DeadLockDetect,
+    read_writer::{is_closed, Codec, ReadWriter, Reader, Writer},
+    tls, GResult,
+};
 use serde::{Deserialize, Serialize};
-use std::{str::FromStr, sync::Arc};
-use tokio::{select, sync::mpsc};
-
-#[derive(Deserialize)]
-pub struct FirstLine(pub NodeID);
+use std::{net::SocketAddr, str::FromStr, sync::Arc};
+use tokio::{
+    select,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+/// Sent as a json line by the node opening the connection, the other replies with the codec it
+/// chose from `codecs` as a json line and both switch to it.
+#[derive(Serialize, Deserialize)]
+pub struct FirstLine {
+    pub id: NodeID,
+    pub codecs: Vec<Codec>,
+}
 
 impl FromStr for FirstLine {
     type Err = serde_json::Error;
@@ -25,6 +40,22 @@ impl FromStr for FirstLine {
 pub enum Message {
     Offer(Offer),
     Order(OrderUpdate),
+    /// several at once, in order
+    Orders(Vec<OrderUpdate>),
+    Transfer(TransferOffer),
+}
+
+/// What's sent between nodes, in the negotiated codec
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+enum NodeMessage {
+    Order(OrderUpdate),
+    Orders(Vec<OrderUpdate>),
+    Offer(Offer),
+    Reply(OfferReply),
+    Transfer(TransferOffer),
+    #[serde(rename = "transfer_reply")]
+    TransferReply(OfferReply),
 }
 
 pub type TradeID = usize;
@@ -37,24 +68,90 @@ pub struct Offer {
     pub trade: Trade,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Shares ids with `Offer`, the source account holds the transfer until it's replied to
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferOffer {
+    pub id: TradeID,
+
+    #[serde(flatten)]
+    pub transfer: Transfer,
+}
+
+/// Reply to an offer or a transfer
+#[derive(Debug, Serialize, Deserialize)]
 struct OfferReply {
     id: TradeID,
     accepted: bool,
 }
 
-pub async fn handler(
-    FirstLine(id): FirstLine,
+/// Send each message to its node, or keep it until the node connects
+pub async fn send(messages: Vec<(NodeID, Message)>, global: &Arc<Global>) -> GResult<()> {
+    let mut others = global.others.write().dl("n96").await;
+    for (node_id, msg) in messages {
+        match others
+            .get_mut(&node_id)
+            .ok_or_else(|| format!("No node {node_id} to send {msg:?} to"))?
+        {
+            Node::DisConnected(_, queued) => queued.push(msg),
+            Node::Connected { sender } => sender.send(msg)?,
+        }
+    }
+    Ok(())
+}
+
+/// Send `updates` to every other node, in one message, queued for those not connected so their
+/// books catch up once they are
+pub async fn broadcast(updates: Vec<OrderUpdate>, global: &Arc<Global>) -> GResult<()> {
+    let msg = || match updates.as_slice() {
+        [update] => Message::Order(update.clone()),
+        _ => Message::Orders(updates.clone()),
+    };
+    for node in global.others.write().dl("n104").await.values_mut() {
+        match node {
+            Node::DisConnected(_, queued) => queued.push(msg()),
+            Node::Connected { sender } => sender.send(msg())?,
+        }
+    }
+    Ok(())
+}
+
+/// Open a connection to node `id` and agree on a codec with it
+pub async fn connect(id: NodeID, addr: SocketAddr, global: &Arc<Global>) -> GResult<ReadWriter> {
+    let this_id = global.state.read().dl("nc70").await.get_id();
+    let mut rw = tls::connect(addr, global.tls.as_ref()).await?;
+    rw.write_line(&serde_json::to_string(&FirstLine {
+        id: this_id,
+        codecs: global.codec.offer(),
+    })?)
+    .await?;
+    let codec: Codec = serde_json::from_str(&rw.read_line().await?)?;
+    rw.set_codec(codec);
+    println!("Talking to Node {id} in {codec:?}");
+    Ok(rw)
+}
+
+/// Reply to the first line of a connection from node `id`
+pub async fn accept(
+    FirstLine { id, codecs }: FirstLine,
     mut rw: ReadWriter,
     global: Arc<Global>,
 ) -> GResult<String> {
+    rw.check_peer_certified()?;
+    let codec = Codec::choose(&codecs);
+    rw.write_line(&serde_json::to_string(&codec)?).await?;
+    rw.set_codec(codec);
+    println!("Talking to Node {id} in {codec:?}");
+    handler(id, rw, global).await
+}
+
+pub async fn handler(id: NodeID, rw: ReadWriter, global: Arc<Global>) -> GResult<String> {
     let mut others = global.others.write().dl("n51").await;
     let addr = rw.peer_addr()?;
 
     // check if expecting
-    if let Some(Node::DisConnected(expected_addr)) = others.get_mut(&id) {
+    let queued = if let Some(Node::DisConnected(expected_addr, queued)) = others.get_mut(&id) {
         if expected_addr.ip() == addr.ip() {
-            Ok(())
+            Ok(std::mem::take(queued))
         } else {
             Err(format!("Not expecting node {id} from {addr}"))
         }
@@ -62,7 +159,11 @@ pub async fn handler(
         Err(format!("Not expecting node {id}"))
     }?;
 
-    let (sender, mut recver) = mpsc::unbounded_channel();
+    let (sender, recver) = mpsc::unbounded_channel();
+    // what was sent while it wasn't connected goes first
+    for msg in queued {
+        sender.send(msg)?;
+    }
 
     others.insert(id, Node::Connected { sender });
 
@@ -71,24 +172,57 @@ pub async fn handler(
 
     println!("Connected with Node {id} from addr {addr}");
 
+    // reading isn't cancel safe so it can't be in the select, it gets its own task
+    let (reader, writer) = rw.into_split();
+    let (incoming_sender, incoming) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(read_messages(reader, incoming_sender));
+    let res = serve(id, writer, recver, incoming, &global).await;
+    reading.abort();
+    res
+}
+
+/// Forward every message read, stopping after the first error
+async fn read_messages(mut reader: Reader, sender: UnboundedSender<GResult<NodeMessage>>) {
+    loop {
+        let msg = reader.recv().await;
+        let failed = msg.is_err();
+        if sender.send(msg).is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn serve(
+    id: NodeID,
+    mut writer: Writer,
+    mut recver: UnboundedReceiver<Message>,
+    mut incoming: UnboundedReceiver<GResult<NodeMessage>>,
+    global: &Arc<Global>,
+) -> GResult<String> {
     loop {
         select! {
             msg = recver.recv() => {
-                let msg = msg.ok_or(format!("Channel for node {addr} closed!"))?;
+                let msg = msg.ok_or(format!("Channel for node {id} closed!"))?;
                  match msg {
-                    Message::Offer(offer) => offer_send::handler(offer, &mut rw, &global).await?,
-                    Message::Order(order) => order_send::handler(order, &mut rw, &global).await?,
+                    Message::Offer(offer) => offer_send::handler(offer, &mut writer, global).await?,
+                    Message::Order(order) => order_send::handler(order, &mut writer, global).await?,
+                    Message::Orders(orders) => order_send::batch_handler(orders, &mut writer, global).await?,
+                    Message::Transfer(transfer) => transfer_send::handler(transfer, &mut writer, global).await?,
                 };
             },
-            line = rw.read_line() => {
-                let line = line?;
-                let (req_type, value) = get_value_type(&line)?;
-                let value = value.ok_or("No value for request")?;
-                match req_type.as_str() {
-                    "order" => order_recv::handler(value, &mut rw, &global).await?,
-                    "offer" => offer_recv::handler(value, &mut rw, &global).await?,
-                    "reply" => offer_replied::handler(value, &mut rw, &global).await?,
-                    req_type => return Err(Box::from(format!("Wrong type {}.", req_type))),
+            msg = incoming.recv() => {
+                let msg = match msg.ok_or("Reader stopped")? {
+                    Ok(msg) => msg,
+                    Err(e) if is_closed(&*e) => return Ok(format!("Node {id} closed the connection")),
+                    Err(e) => return Err(e),
+                };
+                match msg {
+                    NodeMessage::Order(order) => order_recv::handler(order, global).await?,
+                    NodeMessage::Orders(orders) => order_recv::batch_handler(orders, global).await?,
+                    NodeMessage::Offer(offer) => offer_recv::handler(offer, &mut writer, global).await?,
+                    NodeMessage::Reply(reply) => offer_replied::handler(reply, global).await?,
+                    NodeMessage::Transfer(transfer) => transfer_recv::handler(transfer, &mut writer, global).await?,
+                    NodeMessage::TransferReply(reply) => transfer_replied::handler(reply, global).await?,
                 }
             },
         };
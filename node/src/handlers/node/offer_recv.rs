@@ -1,51 +1,41 @@
-use super::Offer;
-use crate::{handlers::node::OfferReply, order::matcher_deduct_order, Global};
-use lib::{lock::DeadLockDetect, read_writer::ReadWriter, GResult};
-use serde_json::{json, Value};
+use super::{send, NodeMessage, Offer, OfferReply};
+use crate::{order::broadcast_deduct_order, risk, Global};
+use lib::{lock::DeadLockDetect, read_writer::Writer, GResult};
 use std::sync::Arc;
 
 /// recieved a trade offer
-pub async fn handler(req: Value, rw: &mut ReadWriter, global: &Arc<Global>) -> GResult<()> {
-    let Offer { id, trade } = serde_json::from_value(req)?;
-    let state = global.state.read().dl("of9").await;
-    let account = if trade.buyer_id.node_id == state.get_id() {
-        state
-            .get_accounts()
-            .get(&trade.buyer_id.id)
-            .expect("Node recieved invalid UserID")
-    } else {
-        assert_eq!(
-            trade.seller_id.node_id,
-            state.get_id(),
-            "Node recieved offer that it doesn't own"
-        );
-        state
-            .get_accounts()
-            .get(&trade.seller_id.id)
-            .expect("Node recieved invalid UserID")
-    };
-    let order_deducted = account
+pub async fn handler(
+    Offer { id, trade }: Offer,
+    writer: &mut Writer,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    // the matcher is locked first like for a new order, so none matches the account's order
+    // between the account giving it up and the matcher
+    let mut matcher = global.matcher.write().dl("of8").await;
+    let (order_deducted, fee) = global
+        .state
         .write()
-        .dl("of23")
+        .dl("of9")
         .await
-        .process_incoming_offer(trade)
+        .process_incoming_offer(trade.clone())
         .await?;
 
-    drop(state);
-
     let accepted = order_deducted.is_some();
 
     if let Some(order) = order_deducted {
         // update the matcher to remove the order
-        matcher_deduct_order(order, global).await?;
+        matcher.deduct_order(order.clone());
+        broadcast_deduct_order(order, global).await?;
     }
+    drop(matcher);
+    send(fee, global).await?;
 
-    rw.write_line(&serde_json::to_string(&json!({
-        "type": "reply",
-        "value": OfferReply {
-            id,
-            accepted,
-        },
-    }))?)
-    .await
+    writer
+        .send(&NodeMessage::Reply(OfferReply { id, accepted }))
+        .await?;
+    // the trade moved the mark
+    if accepted {
+        risk::check(&[trade], global).await?;
+    }
+    Ok(())
 }
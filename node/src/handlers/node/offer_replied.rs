@@ -1,18 +1,21 @@
-use super::OfferReply;
-use crate::{order::add_order_to_matcher_and_process, Global};
-use lib::{lock::DeadLockDetect, read_writer::ReadWriter, GResult};
-use serde_json::Value;
+use super::{send, OfferReply};
+use crate::{order::add_order_to_matcher_and_process, risk, Global};
+use lib::{lock::DeadLockDetect, GResult};
 use std::sync::Arc;
 
-pub async fn handler(req: Value, _: &mut ReadWriter, global: &Arc<Global>) -> GResult<()> {
-    let OfferReply { id, accepted } = serde_json::from_value(req)?;
+pub async fn handler(OfferReply { id, accepted }: OfferReply, global: &Arc<Global>) -> GResult<()> {
     let mut state = global.state.write().dl("ofrp9").await;
     if accepted {
-        state.commit_pending(id).await?;
+        let (trade, fee) = state.commit_pending(id).await?;
+        drop(state);
+        send(fee, global).await?;
+        // the trade moved the mark
+        risk::check(&[trade], global).await?;
     } else {
         let order = state.abort_pending(id).await?;
-        drop(state);
+        // counted before the trade is gone, so a halt never sees neither
         add_order_to_matcher_and_process(order, global);
+        drop(state);
     }
     Ok(())
 }
@@ -1,12 +1,8 @@
+use super::{NodeMessage, Offer};
 use crate::Global;
-use lib::{read_writer::ReadWriter, GResult};
-use serde_json::json;
+use lib::{read_writer::Writer, GResult};
 use std::sync::Arc;
-use super::Offer;
 
-pub async fn handler(offer: Offer, rw: &mut ReadWriter, _global: &Arc<Global>) -> GResult<()> {
-    rw.write_line(&serde_json::to_string(&json!({
-        "type": "offer",
-        "value": offer,
-    }))?).await
+pub async fn handler(offer: Offer, writer: &mut Writer, _global: &Arc<Global>) -> GResult<()> {
+    writer.send(&NodeMessage::Offer(offer)).await
 }
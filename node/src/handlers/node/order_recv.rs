@@ -2,12 +2,13 @@ use crate::{
     order::{add_order_to_matcher_and_process, OrderUpdate},
     Global,
 };
-use lib::{lock::DeadLockDetect, read_writer::ReadWriter, GResult};
-use serde_json::Value;
+use lib::{lock::DeadLockDetect, GResult};
 use std::sync::Arc;
 
-pub async fn handler(req: Value, _rw: &mut ReadWriter, global: &Arc<Global>) -> GResult<()> {
-    let OrderUpdate { deduct, order } = serde_json::from_value(req)?;
+pub async fn handler(
+    OrderUpdate { deduct, order }: OrderUpdate,
+    global: &Arc<Global>,
+) -> GResult<()> {
     if deduct {
         global.matcher.write().dl("o74").await.deduct_order(order);
     } else {
@@ -15,3 +16,10 @@ pub async fn handler(req: Value, _rw: &mut ReadWriter, global: &Arc<Global>) ->
     }
     Ok(())
 }
+
+pub async fn batch_handler(orders: Vec<OrderUpdate>, global: &Arc<Global>) -> GResult<()> {
+    for order in orders {
+        handler(order, global).await?;
+    }
+    Ok(())
+}
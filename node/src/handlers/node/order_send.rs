@@ -1,12 +1,20 @@
-use crate::{Global, order::OrderUpdate};
-use lib::{read_writer::ReadWriter, GResult};
-use serde_json::json;
+use super::NodeMessage;
+use crate::{order::OrderUpdate, Global};
+use lib::{read_writer::Writer, GResult};
 use std::sync::Arc;
 
-pub async fn handler(order: OrderUpdate, rw: &mut ReadWriter, _global: &Arc<Global>) -> GResult<()> {
-    rw.write_line(&serde_json::to_string(&json!({
-        "type": "order",
-        "value": order,
-    }))?).await
+pub async fn handler(
+    order: OrderUpdate,
+    writer: &mut Writer,
+    _global: &Arc<Global>,
+) -> GResult<()> {
+    writer.send(&NodeMessage::Order(order)).await
 }
 
+pub async fn batch_handler(
+    orders: Vec<OrderUpdate>,
+    writer: &mut Writer,
+    _global: &Arc<Global>,
+) -> GResult<()> {
+    writer.send(&NodeMessage::Orders(orders)).await
+}
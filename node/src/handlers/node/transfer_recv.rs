@@ -0,0 +1,34 @@
+use super::{NodeMessage, OfferReply, TransferOffer};
+use crate::Global;
+use lib::{lock::DeadLockDetect, read_writer::Writer, GResult};
+use std::sync::Arc;
+
+/// recieved a transfer, it's rejected if the destination account doesn't exist or can't hold it
+pub async fn handler(
+    TransferOffer { id, transfer }: TransferOffer,
+    writer: &mut Writer,
+    global: &Arc<Global>,
+) -> GResult<()> {
+    let state = global.state.read().dl("tr9").await;
+    assert_eq!(
+        transfer.to.node_id,
+        state.get_id(),
+        "Node recieved transfer that it doesn't own"
+    );
+    let accepted = match state.get_accounts().get(&transfer.to.id) {
+        Some(account) => {
+            account
+                .write()
+                .dl("tr18")
+                .await
+                .receive_transfer(&transfer)
+                .await?
+        }
+        None => false,
+    };
+    drop(state);
+
+    writer
+        .send(&NodeMessage::TransferReply(OfferReply { id, accepted }))
+        .await
+}
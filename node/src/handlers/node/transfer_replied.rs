@@ -0,0 +1,19 @@
+use super::OfferReply;
+use crate::Global;
+use lib::{lock::DeadLockDetect, GResult};
+use std::sync::Arc;
+
+pub async fn handler(OfferReply { id, accepted }: OfferReply, global: &Arc<Global>) -> GResult<()> {
+    let mut state = global.state.write().dl("trp7").await;
+    if accepted {
+        state.commit_transfer(id).await?;
+    } else {
+        state.abort_transfer(id).await?;
+    }
+    drop(state);
+    // nobody waits for transfers started before a restart, or if the client is gone
+    if let Some(done) = global.transfers.lock().await.remove(&id) {
+        let _ = done.send(accepted);
+    }
+    Ok(())
+}
@@ -0,0 +1,12 @@
+use super::{NodeMessage, TransferOffer};
+use crate::Global;
+use lib::{read_writer::Writer, GResult};
+use std::sync::Arc;
+
+pub async fn handler(
+    transfer: TransferOffer,
+    writer: &mut Writer,
+    _global: &Arc<Global>,
+) -> GResult<()> {
+    writer.send(&NodeMessage::Transfer(transfer)).await
+}
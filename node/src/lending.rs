@@ -0,0 +1,78 @@
+//! Shares a node lends its accounts for short sales. How many of each ticker it may lend is set
+//! by an admin and persisted with the state, what each account borrowed is kept by the account
+//! so what's lent out is worked out again when restored.
+
+use lib::interfaces::{Quantity, Ticker};
+use std::{collections::HashMap, sync::Mutex};
+
+#[derive(Default)]
+pub struct Lending(Mutex<HashMap<Ticker, Inventory>>);
+
+#[derive(Default, Clone, Copy)]
+struct Inventory {
+    limit: Quantity,
+    lent: Quantity,
+}
+
+impl Lending {
+    /// `lent` can be more than a limit, one lowered since is only kept to by new loans
+    pub fn new(
+        limits: HashMap<Ticker, Quantity>,
+        lent: impl IntoIterator<Item = (Ticker, Quantity)>,
+    ) -> Self {
+        let lending = Self::default();
+        for (ticker, limit) in limits {
+            lending.set_limit(ticker, limit);
+        }
+        lending.set_lent(lent);
+        lending
+    }
+
+    pub fn get_limits(&self) -> HashMap<Ticker, Quantity> {
+        let inventories = self.0.lock().expect("lending poisoned");
+        inventories
+            .iter()
+            .filter(|(_, inventory)| inventory.limit > 0)
+            .map(|(ticker, inventory)| (ticker.clone(), inventory.limit))
+            .collect()
+    }
+
+    pub fn set_limit(&self, ticker: Ticker, limit: Quantity) {
+        let mut inventories = self.0.lock().expect("lending poisoned");
+        inventories.entry(ticker).or_default().limit = limit;
+    }
+
+    /// Replace what's lent out, every ticker not in `lent` has none
+    pub fn set_lent(&self, lent: impl IntoIterator<Item = (Ticker, Quantity)>) {
+        let mut inventories = self.0.lock().expect("lending poisoned");
+        for inventory in inventories.values_mut() {
+            inventory.lent = 0;
+        }
+        for (ticker, quantity) in lent {
+            let inventory = inventories.entry(ticker).or_default();
+            inventory.lent = inventory.lent.saturating_add(quantity);
+        }
+    }
+
+    /// Lend `quantity` of `ticker`, false if there isn't that much left to lend
+    pub fn lend(&self, ticker: &Ticker, quantity: Quantity) -> bool {
+        let mut inventories = self.0.lock().expect("lending poisoned");
+        let Some(inventory) = inventories.get_mut(ticker) else {
+            return false;
+        };
+        if inventory.limit.saturating_sub(inventory.lent) < quantity {
+            return false;
+        }
+        inventory.lent += quantity;
+        true
+    }
+
+    pub fn give_back(&self, ticker: &Ticker, quantity: Quantity) {
+        let mut inventories = self.0.lock().expect("lending poisoned");
+        let inventory = inventories.get_mut(ticker).expect("lent before");
+        inventory.lent = inventory
+            .lent
+            .checked_sub(quantity)
+            .expect("given back more than was lent");
+    }
+}
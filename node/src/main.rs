@@ -1,18 +1,41 @@
+mod dividend;
 mod handlers;
+mod lending;
 mod matcher;
+mod migrations;
 mod order;
+mod pnl;
+mod risk;
+mod split;
 mod state;
+mod transfer;
 
-use crate::{handlers::handler, state::State};
-use lib::{interfaces::NodeID, read_writer::ReadWriter};
+use crate::{
+    handlers::{handler, node::TradeID},
+    state::State,
+};
+use lib::{
+    auth::VerifyingKey,
+    fees::FeeSchedule,
+    instruments::Instruments,
+    interfaces::NodeID,
+    read_writer::Codec,
+    storage::{self, StorageKind},
+    tls::{self, Tls},
+};
 use matcher::Matcher;
 use serde::Deserialize;
 use serde_json::json;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{atomic::AtomicUsize, Arc},
+};
 use structopt::StructOpt;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{mpsc::UnboundedSender, RwLock},
+    net::TcpListener,
+    sync::{mpsc::UnboundedSender, oneshot, Mutex, RwLock},
 };
 
 #[derive(StructOpt)]
@@ -25,10 +48,31 @@ struct Args {
 
     #[structopt(short, long)]
     persistent_dir: String,
+
+    /// file, memory or kv
+    #[structopt(short, long, default_value = "file")]
+    storage: StorageKind,
+
+    /// msgpack or json, preferred for links to other nodes
+    #[structopt(long, default_value = "msgpack")]
+    codec: Codec,
+
+    /// CA certificate, turns on TLS for every connection
+    #[structopt(long)]
+    ca: Option<PathBuf>,
+
+    /// Certificate signed by the CA, naming the ip of `addr`
+    #[structopt(long)]
+    cert: Option<PathBuf>,
+
+    /// Private key of the certificate
+    #[structopt(long)]
+    key: Option<PathBuf>,
 }
 
 pub enum Node {
-    DisConnected(SocketAddr),
+    /// with the messages waiting for it to connect
+    DisConnected(SocketAddr, Vec<handlers::node::Message>),
     Connected {
         sender: UnboundedSender<handlers::node::Message>,
     },
@@ -38,19 +82,37 @@ pub struct Global {
     matcher: RwLock<Matcher>,
     state: RwLock<State>,
     others: RwLock<HashMap<NodeID, Node>>,
+    codec: Codec,
+    tls: Option<Tls>,
+    verifying_key: VerifyingKey,
+    /// clients waiting for the destination's reply to their transfer
+    transfers: Mutex<HashMap<TradeID, oneshot::Sender<bool>>>,
+    /// orders on their way into the matcher, a halt waits for them
+    order_tasks: AtomicUsize,
 }
 
 impl Global {
-    pub fn new(state: State, others: Vec<NodeRecord>) -> Self {
+    pub fn new(
+        state: State,
+        others: Vec<NodeRecord>,
+        codec: Codec,
+        tls: Option<Tls>,
+        verifying_key: VerifyingKey,
+    ) -> Self {
         Self {
             matcher: RwLock::new(Matcher::new(state.get_id())),
             state: RwLock::new(state),
             others: RwLock::new(
                 others
                     .iter()
-                    .map(|sr| (sr.id, Node::DisConnected(sr.addr)))
+                    .map(|sr| (sr.id, Node::DisConnected(sr.addr, Vec::new())))
                     .collect(),
             ),
+            codec,
+            tls,
+            verifying_key,
+            transfers: Mutex::new(HashMap::new()),
+            order_tasks: AtomicUsize::new(0),
         }
     }
 }
@@ -59,6 +121,10 @@ impl Global {
 struct InitInfo {
     id: Option<NodeID>,
     others: Vec<NodeRecord>,
+    /// verifies the tokens clients log in with
+    verifying_key: VerifyingKey,
+    fees: FeeSchedule,
+    instruments: Instruments,
 }
 #[derive(Deserialize)]
 pub struct NodeRecord {
@@ -72,20 +138,30 @@ async fn main() {
         addr,
         coordinator,
         persistent_dir,
+        storage,
+        codec,
+        ca,
+        cert,
+        key,
     } = Args::from_args();
+    let tls = Tls::from_server_args(ca.as_deref(), cert.as_deref(), key.as_deref())
+        .expect("Failed to load TLS certificates");
 
-    let state = State::restore(persistent_dir.clone()).await;
+    let storage = storage::open(storage, &persistent_dir)
+        .await
+        .expect("Failed to open storage");
+    let state = State::restore(Arc::clone(&storage))
+        .await
+        .expect("Failed to restore state");
 
     println!("Contacting coordinator on {}", coordinator);
 
     let listener: TcpListener = TcpListener::bind(addr).await.expect("Failed to bind");
 
     // Connect to coordinator
-    let mut coord_rw = ReadWriter::new(
-        TcpStream::connect(coordinator)
-            .await
-            .expect("Failed to connect coordinator"),
-    );
+    let mut coord_rw = tls::connect(coordinator, tls.as_ref())
+        .await
+        .expect("Failed to connect coordinator");
 
     // send coordinator
     let state_to_send = state.as_ref().map(|s| json!({
@@ -107,18 +183,26 @@ async fn main() {
     let init_info: InitInfo =
         serde_json::from_str(&coord_rw.read_line().await.unwrap()).expect("Coordinator Error");
 
-    let state = state.unwrap_or_else(|| {
+    let mut state = state.unwrap_or_else(|| {
         State::new(
             init_info.id.expect("Expected NodeID from coordinator"),
-            persistent_dir.clone(),
+            storage,
         )
     });
+    state.set_fees(init_info.fees);
+    state.set_instruments(init_info.instruments);
 
     println!("Node Id: {}", state.get_id());
 
     coord_rw.write_line("\"ok\"").await.expect("Write failed");
 
-    let global = Arc::new(Global::new(state, init_info.others));
+    let global = Arc::new(Global::new(
+        state,
+        init_info.others,
+        codec,
+        tls,
+        init_info.verifying_key,
+    ));
 
     {
         // spawn task to communicate with coordinator
@@ -132,15 +216,19 @@ async fn main() {
     }
 
     loop {
-        let rw = match listener.accept().await {
-            Ok((socket, _)) => ReadWriter::new(socket),
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
             Err(e) => {
                 eprintln!("Error receiving connection: {e}");
                 continue;
             }
         };
         let global = Arc::clone(&global);
-        tokio::spawn(async {
+        tokio::spawn(async move {
+            let rw = match tls::accept(socket, global.tls.as_ref()).await {
+                Ok(rw) => rw,
+                Err(e) => return eprintln!("Error with connection handshake: {e}"),
+            };
             match handler(rw, global).await {
                 Ok(msg) => println!("Connection handled: {msg}"),
                 Err(e) => eprintln!("Error: {e}"),
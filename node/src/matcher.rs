@@ -3,11 +3,12 @@
 
 use std::{
     cmp::min,
-    collections::{BTreeMap, HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
 };
 
 use lib::interfaces::{
-    AllOrders, BuySell, CentCount, NodeID, OrderType, Quantity, QuantityPrice, Ticker, UserID,
+    AllOrders, BuySell, CentCount, ErrorResponse, NodeID, OrderType, Quantity, QuantityPrice,
+    Shares, Split, Ticker, UserID,
 };
 use serde::{Deserialize, Serialize};
 
@@ -29,6 +30,32 @@ pub struct Trade {
     pub seller_id: UserID,
     pub buy_price: CentCount,
     pub sell_price: CentCount,
+    /// side of the order that matched on arrival, the other was resting
+    pub taker: OrderType,
+}
+
+impl Trade {
+    /// Whether `user_id`'s order was resting, and so pays the maker fee
+    pub fn is_maker(&self, user_id: UserID) -> bool {
+        let taker = match self.taker {
+            OrderType::Buy => self.buyer_id,
+            OrderType::Sell => self.seller_id,
+        };
+        taker != user_id
+    }
+}
+
+/// One side of a ticker's book, orders at each price in the order they came
+type Levels = BTreeMap<CentCount, VecDeque<(UserID, Quantity)>>;
+
+/// What's left to deduct of one side of a ticker's orders at each price, by user
+type Deducts = HashMap<CentCount, HashMap<UserID, Quantity>>;
+
+/// One ticker's orders and what's left to deduct of them, by side
+#[derive(Default)]
+pub struct SplitBook {
+    levels: Vec<(OrderType, Levels)>,
+    to_deduct: Vec<(OrderType, Deducts)>,
 }
 
 pub struct Matcher {
@@ -37,6 +64,8 @@ pub struct Matcher {
     sells: HashMap<Ticker, BTreeMap<CentCount, VecDeque<(UserID, Quantity)>>>,
     #[allow(clippy::type_complexity)]
     to_deduct: HashMap<OrderType, HashMap<Ticker, HashMap<CentCount, HashMap<UserID, Quantity>>>>,
+    /// orders of these rest without matching, while a split is under way
+    halted: HashSet<Ticker>,
 }
 
 impl Matcher {
@@ -46,7 +75,78 @@ impl Matcher {
             buys: HashMap::new(),
             sells: HashMap::new(),
             to_deduct: HashMap::new(),
+            halted: HashSet::new(),
+        }
+    }
+
+    pub fn halt(&mut self, ticker: Ticker) {
+        self.halted.insert(ticker);
+    }
+
+    pub fn resume(&mut self, ticker: &Ticker) {
+        self.halted.remove(ticker);
+    }
+
+    pub fn is_halted(&self, ticker: &Ticker) -> bool {
+        self.halted.contains(ticker)
+    }
+
+    /// Put in the ticker's orders as `rescale` made them
+    pub fn split(&mut self, ticker: &Ticker, SplitBook { levels, to_deduct }: SplitBook) {
+        for (order_type, levels) in levels {
+            let book = match order_type {
+                OrderType::Buy => &mut self.buys,
+                OrderType::Sell => &mut self.sells,
+            };
+            book.insert(ticker.clone(), levels);
+        }
+        for (order_type, levels) in to_deduct {
+            self.to_deduct
+                .entry(order_type)
+                .or_default()
+                .insert(ticker.clone(), levels);
+        }
+    }
+
+    /// Every order of the ticker in the book, local or not, and what's left to deduct, rescaled
+    /// by `split` without changing them. Every node does the same to its copy of an order
+    pub fn rescale(&self, split: &Split) -> Result<SplitBook, ErrorResponse> {
+        let mut rescaled = SplitBook::default();
+        for (order_type, book) in [(OrderType::Buy, &self.buys), (OrderType::Sell, &self.sells)] {
+            let Some(levels) = book.get(&split.ticker) else {
+                continue;
+            };
+            let mut book = Levels::new();
+            for (&price, orders) in levels {
+                let price = split.price(order_type, price)?;
+                for &(user_id, quantity) in orders {
+                    let quantity = split.quantity(quantity)?;
+                    if quantity > 0 {
+                        book.entry(price)
+                            .or_default()
+                            .push_back((user_id, quantity));
+                    }
+                }
+            }
+            rescaled.levels.push((order_type, book));
+        }
+        for (&order_type, tickers) in &self.to_deduct {
+            let Some(levels) = tickers.get(&split.ticker) else {
+                continue;
+            };
+            let mut deduct = Deducts::new();
+            for (&price, users) in levels {
+                let price = split.price(order_type, price)?;
+                for (&user_id, &quantity) in users {
+                    let left = deduct.entry(price).or_default().entry(user_id).or_default();
+                    *left = Shares(*left)
+                        .checked_add(Shares(split.quantity(quantity)?))?
+                        .0;
+                }
+            }
+            rescaled.to_deduct.push((order_type, deduct));
         }
+        Ok(rescaled)
     }
 
     pub fn get_stats(&self) -> AllOrders {
@@ -72,8 +172,11 @@ impl Matcher {
         AllOrders(all_orders)
     }
 
-    pub fn deduct_order(&mut self, order: Order) {
+    /// Returns how much was taken off the book, the rest is deducted from the order once it
+    /// arrives. Only what was taken off is on other nodes' books
+    pub fn deduct_order(&mut self, order: Order) -> Quantity {
         println!("deduct {:?}", order);
+        let quantity = order.quantity;
         if let Err(remaining) = self.try_deduct_order(order.clone()) {
             let Order {
                 order_type,
@@ -92,7 +195,9 @@ impl Matcher {
                 .or_default()
                 .entry(user_id)
                 .or_default() += remaining;
+            return quantity - remaining;
         }
+        quantity
     }
 
     /// try to deduct order, if failed return remaining quantity to be deducted
@@ -157,6 +262,7 @@ impl Matcher {
         *current_to_deduct -= deductable;
 
         let mut local_order_deducted: Vec<Order> = Vec::new();
+        let halted = self.halted.contains(&ticker);
 
         let existing_orders = match order_type {
             OrderType::Buy => &mut self.sells,
@@ -169,6 +275,8 @@ impl Matcher {
 
         // rust types slowing me down again
         let price_range: Box<dyn Iterator<Item = _>> = match order_type {
+            // nothing matches until the split is done
+            _ if halted => Box::from(std::iter::empty()),
             OrderType::Buy => Box::from(existing_orders.range_mut(..=price)),
             OrderType::Sell => Box::from(existing_orders.range_mut(price..).rev()),
         };
@@ -176,7 +284,9 @@ impl Matcher {
         'outer: for (other_price, existing_orders) in price_range {
             for (other_user, other_quantity) in
                 existing_orders.iter_mut().filter(|(other_user, _)| {
-                    user_id.node_id == self.this_id || other_user.node_id == self.this_id
+                    // never with itself, a short seller buying back could meet its own sell
+                    *other_user != user_id
+                        && (user_id.node_id == self.this_id || other_user.node_id == self.this_id)
                 })
             {
                 let (buyer_id, seller_id, buy_price, sell_price) = match order_type {
@@ -191,6 +301,7 @@ impl Matcher {
                     seller_id,
                     buy_price,
                     sell_price,
+                    taker: order_type,
                 };
 
                 to_deduct -= new_trade.quantity;
@@ -235,8 +346,9 @@ impl Matcher {
             quantity: if user_id.node_id == self.this_id {
                 to_deduct
             } else {
-                // NEVER deduct remote order they'll be deducted when offer is accepted
-                original_order.quantity
+                // NEVER deduct remote order they'll be deducted when offer is accepted, only what
+                // was deducted before it arrived
+                original_order.quantity - deductable
             },
             price,
         };
@@ -0,0 +1,518 @@
+//! Schema migrations for everything `State` persists, see `lib::storage::load_migrated`.
+//! Add one to the end whenever the persisted shape of `StateFile` or `Account` changes.
+
+use lib::{
+    audit::AUDIT_PREFIX,
+    instruments::DEFAULT_CURRENCY,
+    interfaces::{CentCount, LedgerAccount, Memo, OrderType, StockMemo, UserID},
+    now,
+    storage::Migration,
+    GResult,
+};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+pub const MIGRATIONS: &[Migration] = &[
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8, v8_to_v9,
+    v9_to_v10, v10_to_v11, v11_to_v12, v12_to_v13, v13_to_v14, v14_to_v15, v15_to_v16, v16_to_v17,
+];
+
+/// Version 0 was written before versioning existed, it has the same shape as version 1.
+fn v0_to_v1(_: &mut HashMap<String, Value>) -> GResult<()> {
+    Ok(())
+}
+
+/// Version 2 adds `audit/<n>` records, there are none before.
+fn v1_to_v2(_: &mut HashMap<String, Value>) -> GResult<()> {
+    Ok(())
+}
+
+/// Version 3 replaces each account's `balance` with a ledger, stored as `ledger/<id>/<n>`.
+/// The balance becomes an opening entry, plus what pending buys held as that used to be taken
+/// out of the balance and is now only held.
+fn v2_to_v3(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    let mut ledgers = Vec::new();
+    for (key, account) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        let account = account
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?;
+        let balance: CentCount = serde_json::from_value(
+            account
+                .remove("balance")
+                .ok_or_else(|| format!("Account {key} has no balance"))?,
+        )?;
+        let user_id: UserID = serde_json::from_value(
+            account
+                .get("id")
+                .cloned()
+                .ok_or_else(|| format!("Account {key} has no id"))?,
+        )?;
+        let mut held = 0;
+        for trade in account
+            .get("pending")
+            .and_then(|p| p.as_object())
+            .into_iter()
+            .flat_map(|p| p.values())
+        {
+            if serde_json::from_value::<UserID>(trade["buyer_id"].clone())? == user_id {
+                let quantity = trade["quantity"].as_u64().ok_or("Bad pending trade")?;
+                let price = trade["price"].as_u64().ok_or("Bad pending trade")?;
+                held += quantity * price;
+            }
+        }
+        if balance + held > 0 {
+            let entry = json!({
+                "id": 0,
+                "at": now(),
+                "from": LedgerAccount::External,
+                "to": LedgerAccount::User(user_id),
+                "amount": balance + held,
+                "memo": Memo::Opening,
+            });
+            ledgers.push((format!("ledger/{key}/0"), entry));
+        }
+    }
+    entries.extend(ledgers);
+    Ok(())
+}
+
+/// Version 4 adds each account's pending outgoing `transfers`, there are none before.
+fn v3_to_v4(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, account) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        account
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?
+            .insert("transfers".to_owned(), Value::Object(Default::default()));
+    }
+    Ok(())
+}
+
+/// Version 5 adds fees: each account's `pending_fees`, none before, the `memo` of each pending
+/// transfer, all plain transfers before, and the `taker` of each pending trade. The trade's price
+/// is the resting order's, so the other side took it.
+fn v4_to_v5(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    let transfer_memo = serde_json::to_value(Memo::Transfer)?;
+    for (key, account) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        let account = account
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?;
+        account.insert("pending_fees".to_owned(), Value::Object(Default::default()));
+        for transfer in account
+            .get_mut("transfers")
+            .and_then(|t| t.as_object_mut())
+            .into_iter()
+            .flat_map(|t| t.values_mut())
+        {
+            transfer
+                .as_object_mut()
+                .ok_or("Bad pending transfer")?
+                .insert("memo".to_owned(), transfer_memo.clone());
+        }
+        for trade in account
+            .get_mut("pending")
+            .and_then(|p| p.as_object_mut())
+            .into_iter()
+            .flat_map(|p| p.values_mut())
+        {
+            let taker = if trade["price"] == trade["sell_price"] {
+                OrderType::Buy
+            } else {
+                OrderType::Sell
+            };
+            trade
+                .as_object_mut()
+                .ok_or("Bad pending trade")?
+                .insert("taker".to_owned(), serde_json::to_value(taker)?);
+        }
+    }
+    Ok(())
+}
+
+/// Version 6 adds currencies, all cash before is in `DEFAULT_CURRENCY`: the `currency` of each
+/// ledger entry and admin balance change, and cash of pending transfers becomes `Cash`.
+fn v5_to_v6(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    let currency = Value::from(DEFAULT_CURRENCY);
+    for (key, value) in entries.iter_mut() {
+        if key.starts_with("ledger/") {
+            value
+                .as_object_mut()
+                .ok_or_else(|| format!("{key} isn't an object"))?
+                .insert("currency".to_owned(), currency.clone());
+        } else if key.starts_with(AUDIT_PREFIX) {
+            let action = &mut value["action"];
+            if action["type"] == "set_balance" {
+                action["value"]
+                    .as_object_mut()
+                    .ok_or_else(|| format!("{key} isn't an object"))?
+                    .insert("currency".to_owned(), currency.clone());
+            }
+        } else if key.parse::<usize>().is_ok() {
+            for transfer in value
+                .get_mut("transfers")
+                .and_then(|t| t.as_object_mut())
+                .into_iter()
+                .flat_map(|t| t.values_mut())
+            {
+                let asset = &mut transfer["asset"];
+                if asset["type"] == "cash" {
+                    let amount = asset["value"].take();
+                    asset["value"] = json!({ "currency": currency, "amount": amount });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Version 7 adds short selling: each account's `can_short`, off before, and the `borrowed`
+/// stock and its `collateral`, none before. The state's `lendable` limits, none before.
+fn v6_to_v7(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        let fields = if key == "state" {
+            vec![("lendable", json!({}))]
+        } else if key.parse::<usize>().is_ok() {
+            vec![
+                ("can_short", json!(false)),
+                ("borrowed", json!({})),
+                ("collateral", json!({})),
+            ]
+        } else {
+            continue;
+        };
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| format!("{key} isn't an object"))?;
+        for (field, empty) in fields {
+            object.insert(field.to_owned(), empty);
+        }
+    }
+    Ok(())
+}
+
+/// Version 8 adds margin accounts: each account's `margin` terms, a cash account before, its
+/// `loan`, none before, and `liquidating`, off before. The state's `marks`, none before.
+fn v7_to_v8(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        let fields = if key == "state" {
+            vec![("marks", json!({}))]
+        } else if key.parse::<usize>().is_ok() {
+            vec![
+                ("margin", Value::Null),
+                ("loan", json!({})),
+                ("liquidating", json!(false)),
+            ]
+        } else {
+            continue;
+        };
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| format!("{key} isn't an object"))?;
+        for (field, empty) in fields {
+            object.insert(field.to_owned(), empty);
+        }
+    }
+    Ok(())
+}
+
+/// Version 9 adds each account's risk `limits`, none before.
+fn v8_to_v9(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        value
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?
+            .insert("limits".to_owned(), json!({}));
+    }
+    Ok(())
+}
+
+/// Version 10 adds each account's kill switch, `killed` by no one before.
+fn v9_to_v10(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        value
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?
+            .insert("killed".to_owned(), Value::Null);
+    }
+    Ok(())
+}
+
+/// Version 11 adds each account's `costs`, seeded with what it held at no cost: the portfolio,
+/// shares of pending sells and outgoing transfers, less what's borrowed.
+fn v10_to_v11(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        let mut held: HashMap<String, i128> = HashMap::new();
+        let quantities = |field: &str| -> Vec<(String, i128)> {
+            value[field]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(ticker, quantity)| Some((ticker.clone(), quantity.as_u64()?.into())))
+                .collect()
+        };
+        for (ticker, quantity) in quantities("portfolio") {
+            *held.entry(ticker).or_default() += quantity;
+        }
+        for (ticker, quantity) in quantities("borrowed") {
+            *held.entry(ticker).or_default() -= quantity;
+        }
+        let selling = value["pending"]
+            .as_object()
+            .into_iter()
+            .flat_map(|pending| pending.values())
+            .filter(|trade| trade["seller_id"] == value["id"])
+            .map(|trade| (&trade["ticker"], &trade["quantity"]));
+        let sending = value["transfers"]
+            .as_object()
+            .into_iter()
+            .flat_map(|transfers| transfers.values())
+            .filter(|transfer| transfer["asset"]["type"] == "stock")
+            .map(|transfer| {
+                (
+                    &transfer["asset"]["value"]["ticker"],
+                    &transfer["asset"]["value"]["quantity"],
+                )
+            });
+        for (ticker, quantity) in selling.chain(sending) {
+            if let (Some(ticker), Some(quantity)) = (ticker.as_str(), quantity.as_u64()) {
+                *held.entry(ticker.to_owned()).or_default() += i128::from(quantity);
+            }
+        }
+        let costs: serde_json::Map<String, Value> = held
+            .into_iter()
+            .filter(|&(_, quantity)| quantity != 0)
+            .map(|(ticker, quantity)| {
+                (
+                    ticker,
+                    json!({ "quantity": quantity, "cost": 0, "realised": 0 }),
+                )
+            })
+            .collect();
+        value
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?
+            .insert("costs".to_owned(), Value::Object(costs));
+    }
+    Ok(())
+}
+
+/// Version 12 adds each account's stock ledger, stored as `stock/<id>/<n>`. It opens with each
+/// position's `costs` quantity less the fills of it in the cash ledger, so the two ledgers add up
+/// to the position. Fills of FX pairs hold no position.
+fn v11_to_v12(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    let mut fx = HashSet::new();
+    let mut filled: HashMap<(String, String), i128> = HashMap::new();
+    for (key, entry) in entries.iter() {
+        let Some((account, _)) = key
+            .strip_prefix("ledger/")
+            .and_then(|rest| rest.split_once('/'))
+        else {
+            continue;
+        };
+        let memo = &entry["memo"];
+        let Some(ticker) = memo["value"]["ticker"].as_str() else {
+            continue;
+        };
+        match memo["type"].as_str() {
+            Some("fx") => {
+                fx.insert(ticker.to_owned());
+            }
+            Some("trade") => {
+                // deleted accounts hold nothing
+                let Some(id) = entries.get(account).map(|account| &account["id"]) else {
+                    continue;
+                };
+                let me = LedgerAccount::User(serde_json::from_value(id.clone())?);
+                let buyer: LedgerAccount = serde_json::from_value(entry["from"].clone())?;
+                let quantity = i128::from(memo["value"]["quantity"].as_u64().ok_or("Bad fill")?);
+                *filled
+                    .entry((account.to_owned(), ticker.to_owned()))
+                    .or_default() += if buyer == me { quantity } else { -quantity };
+            }
+            _ => {}
+        }
+    }
+    let mut ledgers = Vec::new();
+    for (key, account) in entries.iter() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        let costs = account["costs"]
+            .as_object()
+            .ok_or_else(|| format!("Account {key} has no costs"))?;
+        let mut id = 0;
+        for (ticker, basis) in costs {
+            if fx.contains(ticker) {
+                continue;
+            }
+            let quantity = basis["quantity"].as_i64().ok_or("Bad cost basis")?;
+            let filled = filled
+                .get(&(key.clone(), ticker.clone()))
+                .copied()
+                .unwrap_or(0);
+            let change = i128::from(quantity) - filled;
+            if change != 0 {
+                let entry = json!({
+                    "id": id,
+                    "at": now(),
+                    "ticker": ticker,
+                    "change": i64::try_from(change)?,
+                    "memo": StockMemo::Opening,
+                });
+                ledgers.push((format!("stock/{key}/{id}"), entry));
+                id += 1;
+            }
+        }
+    }
+    entries.extend(ledgers);
+    Ok(())
+}
+
+/// Version 13 adds each account's `role`, set by an admin. None before, the session token's role
+/// holds until an admin changes it.
+fn v12_to_v13(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        value
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?
+            .insert("role".to_owned(), Value::Null);
+    }
+    Ok(())
+}
+
+/// Version 14 has deposits wait for an admin: each account's pending `deposits`, none before,
+/// and the `next_deposit_id`.
+fn v13_to_v14(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?;
+        object.insert("deposits".to_owned(), json!({}));
+        object.insert("next_deposit_id".to_owned(), json!(0));
+    }
+    Ok(())
+}
+
+/// Version 15 adds each account's `owed_fees`, fees that were more than it had free. None
+/// before, those were charged only what was free.
+fn v14_to_v15(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    for (key, value) in entries.iter_mut() {
+        if key.parse::<usize>().is_err() {
+            continue;
+        }
+        value
+            .as_object_mut()
+            .ok_or_else(|| format!("Account {key} isn't an object"))?
+            .insert("owed_fees".to_owned(), json!([]));
+    }
+    Ok(())
+}
+
+/// Version 16 adds the state's `dividends`, what each dividend paid by the coordinator's id so
+/// one asked for again isn't paid twice. None before, those were paid once by the admin's
+/// session.
+fn v15_to_v16(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    if let Some(state) = entries.get_mut("state") {
+        state
+            .as_object_mut()
+            .ok_or("state isn't an object")?
+            .insert("dividends".to_owned(), json!({}));
+    }
+    Ok(())
+}
+
+/// Version 17 links each fee to its fill: the id of the `trade` entry in the payer's ledger, the
+/// last one before it of the same ticker, quantity and price. Fees owed or on their way to a
+/// remote fee account are linked the same way.
+fn v16_to_v17(entries: &mut HashMap<String, Value>) -> GResult<()> {
+    let ledger_key = |key: &str| -> Option<(String, u64)> {
+        let (account, id) = key.strip_prefix("ledger/")?.split_once('/')?;
+        Some((account.to_owned(), id.parse().ok()?))
+    };
+    // trade entries of each account by id, with their ticker, quantity and price
+    let mut trades: HashMap<String, Vec<(u64, Value)>> = HashMap::new();
+    for (key, entry) in entries.iter() {
+        if let Some((account, id)) = ledger_key(key) {
+            if entry["memo"]["type"] == "trade" {
+                trades
+                    .entry(account)
+                    .or_default()
+                    .push((id, entry["memo"]["value"].clone()));
+            }
+        }
+    }
+    let link = |account: &str, before: u64, memo: &mut Value| -> GResult<()> {
+        if memo["type"] != "fee" {
+            return Ok(());
+        }
+        let value = &memo["value"];
+        let trade = trades.get(account).and_then(|trades| {
+            trades
+                .iter()
+                .filter(|(id, trade)| {
+                    *id < before
+                        && ["ticker", "quantity", "price"]
+                            .iter()
+                            .all(|field| trade[field] == value[field])
+                })
+                .map(|(id, _)| *id)
+                .max()
+        });
+        memo["value"]
+            .as_object_mut()
+            .ok_or("Bad fee memo")?
+            .insert("trade".to_owned(), json!(trade));
+        Ok(())
+    };
+    let payers: HashMap<String, LedgerAccount> = entries
+        .iter()
+        .filter(|(key, _)| key.parse::<usize>().is_ok())
+        .map(|(key, account)| {
+            let id: UserID = serde_json::from_value(account["id"].clone())?;
+            Ok((key.clone(), LedgerAccount::User(id)))
+        })
+        .collect::<GResult<_>>()?;
+    for (key, value) in entries.iter_mut() {
+        if let Some((account, id)) = ledger_key(key) {
+            let from: LedgerAccount = serde_json::from_value(value["from"].clone())?;
+            // a fee account's copies, and a deleted account's fees, are linked to nothing
+            let paid = payers.get(&account) == Some(&from);
+            link(&account, if paid { id } else { 0 }, &mut value["memo"])?;
+        } else if key.parse::<usize>().is_ok() {
+            for field in ["owed_fees", "transfers"] {
+                let fees: Vec<&mut Value> = match &mut value[field] {
+                    Value::Array(fees) => fees.iter_mut().collect(),
+                    Value::Object(fees) => fees.values_mut().collect(),
+                    _ => return Err(format!("Account {key} has no {field}").into()),
+                };
+                for fee in fees {
+                    link(key, u64::MAX, &mut fee["memo"])?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
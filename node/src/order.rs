@@ -1,7 +1,25 @@
-use crate::{handlers::node::Message, matcher::Order, Global, Node};
-use lib::{lock::DeadLockDetect, GResult};
+use crate::{
+    handlers::node,
+    matcher::{Matcher, Order, Trade},
+    risk,
+    state::Account,
+    Global,
+};
+use lib::{
+    interfaces::{OrderReq, UserID},
+    lock::DeadLockDetect,
+    GResult,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// How long to wait for orders and trades already under way
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DRAIN_POLL: Duration = Duration::from_millis(10);
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct OrderUpdate {
@@ -11,91 +29,131 @@ pub struct OrderUpdate {
     pub order: Order,
 }
 
+/// Counted in `Global::order_tasks` from now until it's matched, so call it before letting go
+/// of the state the order came from. Margin accounts are checked once its trades are settled
 pub fn add_order_to_matcher_and_process(order: Order, global: &Arc<Global>) {
+    global.order_tasks.fetch_add(1, Ordering::SeqCst);
     let global = Arc::clone(global);
     tokio::spawn(async move {
-        _add_order_to_matcher_and_process(order, &global)
-            .await
-            .expect("Process order failed");
+        let result = _add_order_to_matcher_and_process(order, &global).await;
+        global.order_tasks.fetch_sub(1, Ordering::SeqCst);
+        let result = match result {
+            Ok(trades) => risk::check(&trades, &global).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to process order: {e}");
+        }
     });
 }
 
-/// add order to the matcher and process the matches
-async fn _add_order_to_matcher_and_process(order: Order, global: &Arc<Global>) -> GResult<()> {
+/// add order to the matcher and process the matches, returns the trades
+async fn _add_order_to_matcher_and_process(
+    order: Order,
+    global: &Arc<Global>,
+) -> GResult<Vec<Trade>> {
     let mut matcher = global.matcher.write().dl("pr12").await;
     let (remaining_order, matches, local_order_deducted) = matcher.add_order(order);
 
     for order in local_order_deducted {
         // need to broadcast
-        broadcast_deduct_order(
-            order,
-            global.others.read().dl("o21").await.values().collect(),
-        )
-        .await?;
+        broadcast_deduct_order(order, global).await?;
     }
 
     if remaining_order.user_id.node_id == global.state.read().dl("o24").await.get_id()
         && remaining_order.quantity > 0
     {
         // Send the order
-        for (_, node) in global.others.read().dl("pr17").await.iter() {
-            match node {
-                crate::Node::DisConnected(addr) => todo!("{addr}"),
-                crate::Node::Connected { sender } => sender.send(Message::Order(OrderUpdate {
-                    deduct: false,
-                    order: remaining_order.clone(),
-                }))?,
-            }
-        }
+        let update = OrderUpdate {
+            deduct: false,
+            order: remaining_order.clone(),
+        };
+        node::broadcast(vec![update], global).await?;
     }
 
-    // Process matches and register pending offer
-    let offers = global
+    // Process matches and register pending offers and fee transfers
+    let messages = global
         .state
         .write()
         .dl("pr31")
         .await
-        .process_matches(matches)
+        .process_matches(matches.clone())
         .await?;
 
-    // Now send the offers
-    for (node_id, trade) in offers {
-        match global
-            .others
-            .read()
-            .dl("pr41")
-            .await
-            .get(&node_id)
-            .expect("Bad node_id for trade offer")
-        {
-            crate::Node::DisConnected(addr) => todo!("{addr}"),
-            crate::Node::Connected { sender } => sender.send(Message::Offer(trade))?,
+    // Now send them, replies will be handled in handlers/node/offer_replied.rs and
+    // handlers/node/transfer_replied.rs
+    node::send(messages, global).await?;
+    Ok(matches)
+}
+
+/// Wait for orders on their way into the matcher, so they're in it to be cancelled
+pub async fn drain(global: &Arc<Global>) -> GResult<()> {
+    let mut waited = Duration::ZERO;
+    while global.order_tasks.load(Ordering::SeqCst) > 0 {
+        if waited >= DRAIN_TIMEOUT {
+            return Err("Orders are still on their way into the matcher".into());
         }
+        sleep(DRAIN_POLL).await;
+        waited += DRAIN_POLL;
     }
-    // Reply will be handled in handlers/node/offer_reply.rs
     Ok(())
 }
 
-// inform all matcher than order has been removed
-pub async fn matcher_deduct_order(order: Order, global: &Arc<Global>) -> GResult<()> {
-    global
-        .matcher
-        .write()
-        .dl("o74")
-        .await
-        .deduct_order(order.clone());
-    broadcast_deduct_order(order, global.others.read().await.values().collect()).await
-}
-
-pub async fn broadcast_deduct_order(order: Order, target_nodes: Vec<&Node>) -> GResult<()> {
-    for node in target_nodes {
-        match node {
-            crate::Node::DisConnected(addr) => todo!("{addr}"),
-            crate::Node::Connected { sender } => sender.send(Message::Order(OrderUpdate {
+/// Cancel `orders` of `account` in it, the matcher and every other node, in one message to each.
+/// The matcher is locked before the account like for a new order, so nothing matches them in
+/// between. Returns what was cancelled of each
+pub async fn cancel_orders(
+    user_id: UserID,
+    orders: Vec<OrderReq>,
+    account: &mut Account,
+    matcher: &mut Matcher,
+    global: &Arc<Global>,
+) -> GResult<Vec<OrderReq>> {
+    let mut cancelled = Vec::new();
+    let mut deducts = Vec::new();
+    for order in orders {
+        let quantity = account.deduct_order(order.clone()).await?;
+        if quantity == 0 {
+            continue;
+        }
+        let OrderReq {
+            order_type,
+            ticker,
+            price,
+            ..
+        } = order;
+        let mut deduct = Order {
+            order_type,
+            ticker: ticker.clone(),
+            user_id,
+            quantity,
+            price,
+        };
+        // an order still on its way into the matcher was never sent to the others
+        deduct.quantity = matcher.deduct_order(deduct.clone());
+        if deduct.quantity > 0 {
+            deducts.push(OrderUpdate {
                 deduct: true,
-                order: order.clone(),
-            }))?,
+                order: deduct,
+            });
         }
+        cancelled.push(OrderReq {
+            order_type,
+            ticker,
+            price,
+            quantity,
+        });
     }
-    Ok(())
+    if !deducts.is_empty() {
+        node::broadcast(deducts, global).await?;
+    }
+    Ok(cancelled)
+}
+
+pub async fn broadcast_deduct_order(order: Order, global: &Arc<Global>) -> GResult<()> {
+    let update = OrderUpdate {
+        deduct: true,
+        order,
+    };
+    node::broadcast(vec![update], global).await
 }
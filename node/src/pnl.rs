@@ -0,0 +1,94 @@
+//! Average cost of each position an account holds and the P&L it realised, kept by the account
+//! and persisted with it. Trades buy and sell at their price, before fees. Shares that come or
+//! go without a trade, from an IPO or a transfer, cost nothing and realise nothing.
+
+use lib::interfaces::{CentCount, ErrorResponse, Position, Split};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Basis {
+    /// below zero for a short position
+    quantity: i64,
+    /// paid for a long position, or got for a short one
+    cost: CentCount,
+    realised: i64,
+}
+
+fn to_i64(value: i128) -> i64 {
+    i64::try_from(value.clamp(i64::MIN.into(), i64::MAX.into())).expect("clamped")
+}
+
+fn to_u64(value: i128) -> u64 {
+    u64::try_from(value.clamp(0, u64::MAX.into())).expect("clamped")
+}
+
+impl Basis {
+    /// How many of `quantity`, bought or sold if below zero, close the position, and their cost
+    fn closing(&self, quantity: i128) -> (i128, i128) {
+        let held = i128::from(self.quantity);
+        if held == 0 || held.signum() == quantity.signum() {
+            return (0, 0);
+        }
+        let closed = quantity.abs().min(held.abs());
+        (closed, i128::from(self.cost) * closed / held.abs())
+    }
+
+    /// `quantity` bought, or sold if below zero, at `price`. What closes the position realises
+    /// the difference to its share of the cost, the rest opens one the other way
+    pub fn trade(&mut self, quantity: i128, price: CentCount) {
+        let held = i128::from(self.quantity);
+        let price = i128::from(price);
+        let (closed, closed_cost) = self.closing(quantity);
+        // a long position gains what it sells over cost, a short one what it buys back under
+        let realised = (closed * price - closed_cost) * held.signum();
+        let opened = quantity.abs() - closed;
+        self.realised = to_i64(i128::from(self.realised) + realised);
+        self.cost = to_u64(i128::from(self.cost) - closed_cost + opened * price);
+        self.quantity = to_i64(held + quantity);
+    }
+
+    /// `quantity` that came, or went if below zero, without a trade. Those going take their
+    /// share of the cost with them
+    pub fn shift(&mut self, quantity: i128) {
+        let (_, closed_cost) = self.closing(quantity);
+        self.cost = to_u64(i128::from(self.cost) - closed_cost);
+        self.quantity = to_i64(i128::from(self.quantity) + quantity);
+    }
+
+    /// The same cost over the shares the split leaves
+    pub fn split(&self, split: &Split) -> Result<Self, ErrorResponse> {
+        let quantity = i128::from(split.quantity(self.quantity.unsigned_abs())?);
+        Ok(Self {
+            quantity: to_i64(quantity * i128::from(self.quantity.signum())),
+            ..*self
+        })
+    }
+
+    /// Marked to `mark`, unrealised P&L is unknown without one
+    pub fn position(&self, mark: Option<CentCount>) -> Position {
+        let held = i128::from(self.quantity);
+        let cost = i128::from(self.cost);
+        Position {
+            quantity: self.quantity,
+            average_cost: if held == 0 {
+                0
+            } else {
+                to_u64(cost / held.abs())
+            },
+            realised: self.realised,
+            unrealised: mark
+                .map(|mark| to_i64((held.abs() * i128::from(mark) - cost) * held.signum())),
+            mark,
+        }
+    }
+
+    /// Held, below zero for a short position
+    pub fn quantity(&self) -> i64 {
+        self.quantity
+    }
+
+    /// Nothing held and nothing realised
+    pub fn is_empty(&self) -> bool {
+        self.quantity == 0 && self.realised == 0
+    }
+}
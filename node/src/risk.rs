@@ -0,0 +1,159 @@
+//! Risk of margin accounts, each node watching its own. Positions are marked to the last price
+//! the node's accounts traded them at, persisted with the state. An account that falls under its
+//! maintenance margin has its orders of the stock cancelled and orders closing its positions
+//! forced in, and can't place or cancel any until it's back over.
+
+use crate::{
+    matcher::{Order, Trade},
+    order::{add_order_to_matcher_and_process, cancel_orders},
+    Global,
+};
+use lib::{
+    interfaces::{CentCount, OrderReq, Ticker, UserID},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// How far under the mark a liquidation sells, and over it buys back, in percent, so it fills
+const LIQUIDATION_SLIPPAGE_PCT: u64 = 10;
+
+#[derive(Default)]
+pub struct Marks(Mutex<HashMap<Ticker, CentCount>>);
+
+impl Marks {
+    pub fn new(marks: HashMap<Ticker, CentCount>) -> Self {
+        Self(Mutex::new(marks))
+    }
+
+    pub fn get_all(&self) -> HashMap<Ticker, CentCount> {
+        self.0.lock().expect("marks poisoned").clone()
+    }
+
+    pub fn get(&self, ticker: &Ticker) -> Option<CentCount> {
+        self.0.lock().expect("marks poisoned").get(ticker).copied()
+    }
+
+    pub fn set(&self, ticker: &Ticker, price: CentCount) {
+        self.0
+            .lock()
+            .expect("marks poisoned")
+            .insert(ticker.clone(), price);
+    }
+}
+
+/// Liquidate margin accounts the trades touched under their maintenance margin, and let those
+/// back over it trade again. Touched are this node's side of each trade and the accounts with a
+/// position in a ticker whose mark it moved. Takes the locks it needs, so call it holding none
+pub async fn check(trades: &[Trade], global: &Arc<Global>) -> GResult<()> {
+    if trades.is_empty() {
+        return Ok(());
+    }
+    let (breached, recovered) = {
+        let state = global.state.read().dl("rk52").await;
+        let parties: HashSet<usize> = trades
+            .iter()
+            .flat_map(|trade| [trade.buyer_id, trade.seller_id])
+            .filter(|user_id| user_id.node_id == state.get_id())
+            .map(|user_id| user_id.id)
+            .collect();
+        let tickers: HashSet<&Ticker> = trades.iter().map(|trade| &trade.ticker).collect();
+        let mut breached = Vec::new();
+        let mut recovered = Vec::new();
+        for &id in state.get_margin_accounts() {
+            let Some(account) = state.get_accounts().get(&id) else {
+                continue;
+            };
+            let account = account.read().dl("rk56").await;
+            if !parties.contains(&id)
+                && !tickers.iter().any(|ticker| account.has_position_in(ticker))
+            {
+                continue;
+            }
+            match (account.breached().is_empty(), account.is_liquidating()) {
+                (false, false) => breached.push(id),
+                (true, true) => recovered.push(id),
+                _ => {}
+            }
+        }
+        (breached, recovered)
+    };
+    for id in recovered {
+        let state = global.state.read().dl("rk67").await;
+        let Some(account) = state.get_accounts().get(&id) else {
+            continue;
+        };
+        let mut account = account.write().dl("rk71").await;
+        if account.breached().is_empty() && account.is_liquidating() {
+            account.stop_liquidating().await?;
+            let user_id = UserID {
+                id,
+                node_id: state.get_id(),
+            };
+            println!("{user_id} is back over its maintenance margin.");
+        }
+    }
+    for id in breached {
+        liquidate(id, global).await?;
+    }
+    Ok(())
+}
+
+/// The matcher is locked first like for a new order, so the account's orders are cancelled in
+/// it along with the account
+async fn liquidate(id: usize, global: &Arc<Global>) -> GResult<()> {
+    let mut matcher = global.matcher.write().dl("rk86").await;
+    let state = global.state.read().dl("rk87").await;
+    let Some(account) = state.get_accounts().get(&id) else {
+        return Ok(());
+    };
+    let mut account = account.write().dl("rk91").await;
+    if account.is_liquidating() {
+        return Ok(());
+    }
+    let currencies = account.breached();
+    let cancels: Vec<OrderReq> = currencies
+        .iter()
+        .flat_map(|currency| account.open_orders(Some(currency)))
+        .collect();
+    let forced: Vec<OrderReq> = currencies
+        .iter()
+        .flat_map(|currency| account.liquidation(currency, LIQUIDATION_SLIPPAGE_PCT))
+        .collect();
+    // not during a split or dividend, checked again after the next trade
+    if forced.is_empty()
+        || cancels
+            .iter()
+            .chain(&forced)
+            .any(|order| matcher.is_halted(&order.ticker))
+    {
+        return Ok(());
+    }
+    let user_id = UserID {
+        id,
+        node_id: state.get_id(),
+    };
+    let order = |OrderReq {
+                     order_type,
+                     ticker,
+                     price,
+                     quantity,
+                 }| Order {
+        order_type,
+        ticker,
+        user_id,
+        quantity,
+        price,
+    };
+    cancel_orders(user_id, cancels, &mut account, &mut matcher, global).await?;
+    account.liquidate(&forced).await?;
+    drop(matcher);
+    println!("Liquidating {user_id}: {forced:?}");
+    for forced in forced {
+        add_order_to_matcher_and_process(order(forced), global);
+    }
+    Ok(())
+}
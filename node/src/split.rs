@@ -0,0 +1,99 @@
+//! Splitting a ticker, done by every node in four steps the coordinator takes them through:
+//! halt trading in it, check the split once every node has halted, split it once every node
+//! can, and resume. Dividends are paid between the same halt and resume.
+
+use crate::{
+    matcher::Matcher,
+    order::{DRAIN_POLL, DRAIN_TIMEOUT},
+    Global,
+};
+use lib::{
+    interfaces::{ErrorCode, ErrorResponse, Split, Ticker, UserID},
+    lock::DeadLockDetect,
+    GResult,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// Stop matching `ticker`, then wait for orders on their way into the matcher and trades and
+/// transfers waiting for other nodes. Clients can't place or cancel orders of it until resumed
+pub async fn halt(ticker: Ticker, global: &Arc<Global>) -> GResult<()> {
+    global.matcher.write().dl("sp20").await.halt(ticker.clone());
+    let mut waited = Duration::ZERO;
+    loop {
+        // a write lock, so requests that saw the ticker still trading are done with the state
+        let state = global.state.write().dl("sp24").await;
+        if !state.is_trading(&ticker).await && global.order_tasks.load(Ordering::SeqCst) == 0 {
+            return Ok(());
+        }
+        drop(state);
+        if waited >= DRAIN_TIMEOUT {
+            return Err(format!("Trades of {ticker} are still under way").into());
+        }
+        sleep(DRAIN_POLL).await;
+        waited += DRAIN_POLL;
+    }
+}
+
+/// Only once halted, whether the split would leave every account and order in range. The
+/// coordinator has every node check before any of them splits
+pub async fn prepare(split: Split, global: &Arc<Global>) -> GResult<()> {
+    let matcher = global.matcher.read().dl("sp39").await;
+    check_halted(&matcher, &split.ticker)?;
+    matcher.rescale(&split)?;
+    global.state.write().dl("sp42").await.check_split(&split)
+}
+
+/// Only once halted, every account and the matcher's book change together
+pub async fn split(actor: UserID, split: Split, global: &Arc<Global>) -> GResult<()> {
+    let mut matcher = global.matcher.write().dl("sp40").await;
+    check_halted(&matcher, &split.ticker)?;
+    let book = matcher.rescale(&split)?;
+    global
+        .state
+        .write()
+        .dl("sp49")
+        .await
+        .split(actor, &split)
+        .await?;
+    matcher.split(&split.ticker, book);
+    println!(
+        "{actor} split {} {} for {}.",
+        split.ticker, split.to, split.from
+    );
+    Ok(())
+}
+
+/// For the steps that need every node to have halted first
+pub fn check_halted(matcher: &Matcher, ticker: &Ticker) -> GResult<()> {
+    if !matcher.is_halted(ticker) {
+        return Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            format!("Trading in {ticker} isn't halted"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub async fn resume(ticker: Ticker, global: &Arc<Global>) -> GResult<()> {
+    global.matcher.write().dl("sp58").await.resume(&ticker);
+    Ok(())
+}
+
+/// For client requests that would change orders or stock of `ticker` during a split or dividend.
+/// They lock the matcher before the state, like for a new order, and hold it until they're done
+/// with the state, so a halt waits for them
+pub fn check_trading(matcher: &Matcher, ticker: &Ticker) -> GResult<()> {
+    if matcher.is_halted(ticker) {
+        return Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            format!("Trading in {ticker} is halted"),
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -1,32 +1,70 @@
-//! format:
-//! file name = UserID.id
-//! file content = serde_json::to_string(Account)
-//! file name = 'state'
-//! file content = serde_json::to_string(StateFile)
+//! format, stored as keys in `Storage`, versioned by `migrations::MIGRATIONS`:
+//! key = UserID.id
+//! value = Account
+//! key = 'state'
+//! value = StateFile
+//! key = 'audit/<n>'
+//! value = lib::audit::AuditRecord
+//! key = 'ledger/<UserID.id>/<n>'
+//! value = LedgerEntry, kept after the account is deleted
+//! key = 'stock/<UserID.id>/<n>'
+//! value = StockEntry, kept after the account is deleted
 
 use crate::{
-    handlers::node::{Offer, TradeID},
+    handlers::node::{Message, Offer, TradeID, TransferOffer},
+    lending::Lending,
     matcher::{Order, Trade},
+    migrations::MIGRATIONS,
+    pnl::Basis,
+    risk::Marks,
+    transfer::Transfer,
 };
 use lib::{
+    audit::{AuditAction, AuditLog},
+    fees::FeeSchedule,
+    instruments::Instruments,
     interfaces::{
-        AllOrders, BuySell, CentCount, NodeID, OrderReq, OrderType, Quantity, QuantityPrice,
-        Ticker, UserID,
+        AllOrders, Asset, Borrowed, BuySell, Cash, CentCount, Currency, DepositID, Distribution,
+        Dividend, DividendID, ErrorCode, ErrorResponse, KilledBy, LedgerAccount, LedgerEntry,
+        MarginStatus, MarginTerms, MarginUsage, Memo, Money, NodeID, OrderReq, OrderType, Position,
+        Quantity, QuantityPrice, RiskLimits, Role, Shares, Split, StockEntry, StockMemo, Ticker,
+        UserID,
     },
     lock::DeadLockDetect,
+    now,
+    storage::{load_migrated, Op, Storage},
     GResult,
 };
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::read_to_string};
-use tokio::{fs, sync::RwLock};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock};
 
 pub struct State {
     id: NodeID,
     next_account_id: usize,
+    /// shared by pending trades and transfers
     next_trade_id: usize,
     pending_to_user: HashMap<TradeID, usize>,
     accounts: HashMap<usize, RwLock<Account>>,
-    per_dir: String,
+    audit: Mutex<AuditLog>,
+    storage: Arc<dyn Storage>,
+    /// from the coordinator, not persisted
+    fees: Arc<FeeSchedule>,
+    /// from the coordinator, not persisted
+    instruments: Arc<Instruments>,
+    /// its limits are persisted in the state file
+    lending: Arc<Lending>,
+    /// persisted in the state file
+    marks: Arc<Marks>,
+    /// what each dividend paid, by the coordinator's id so one asked for again isn't paid twice
+    dividends: HashMap<DividendID, Distribution>,
+    /// ids of the accounts risk.rs watches, rebuilt on restore
+    margin_accounts: HashSet<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,51 +73,127 @@ struct StateFile {
     next_account_id: usize,
     next_trade_id: usize,
     pending_to_user: HashMap<TradeID, usize>,
+    /// how many of each ticker the node may lend
+    lendable: HashMap<Ticker, Quantity>,
+    /// last price each ticker traded at on this node, what margin accounts' positions are worth
+    marks: HashMap<Ticker, CentCount>,
+    dividends: HashMap<DividendID, Distribution>,
 }
 
 impl State {
-    pub fn new(id: NodeID, per_dir: String) -> Self {
+    pub fn new(id: NodeID, storage: Arc<dyn Storage>) -> Self {
         Self {
             id,
             next_account_id: 0,
             next_trade_id: 0,
             accounts: HashMap::new(),
             pending_to_user: HashMap::new(),
-            per_dir,
+            audit: Mutex::new(AuditLog::default()),
+            storage,
+            fees: Arc::default(),
+            instruments: Arc::default(),
+            lending: Arc::default(),
+            marks: Arc::default(),
+            dividends: HashMap::new(),
+            margin_accounts: HashSet::new(),
         }
     }
 
-    pub async fn restore(per_dir: String) -> Option<Self> {
-        let state_file: StateFile =
-            serde_json::from_str(&read_to_string(format!("{per_dir}/state")).ok()?).ok()?;
+    /// Rebuild the state from storage, `None` if this node has never been started.
+    /// Anything unreadable is an error, never a reason to start over.
+    pub async fn restore(storage: Arc<dyn Storage>) -> GResult<Option<Self>> {
+        let mut entries = load_migrated(&*storage, MIGRATIONS).await?;
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        let state_file: StateFile = serde_json::from_value(
+            entries
+                .remove("state")
+                .ok_or("Persisted accounts without a state")?,
+        )
+        .map_err(|e| format!("State is unreadable: {e}"))?;
+        let mut ledgers = take_ledgers(&mut entries, LEDGER_PREFIX, |e: &LedgerEntry| e.id)?;
+        let mut stock_ledgers = take_ledgers(&mut entries, STOCK_PREFIX, |e: &StockEntry| e.id)?;
+        let lending = Arc::new(Lending::new(state_file.lendable, []));
+        let marks = Arc::new(Marks::new(state_file.marks));
         let mut accounts = HashMap::new();
         for i in 0..state_file.next_account_id {
-            if let Some(account) = Account::restore(format!("{per_dir}/{i}")).await {
+            if let Some(v) = entries.remove(&i.to_string()) {
+                let mut account: Account = serde_json::from_value(v)
+                    .map_err(|e| format!("Account {i} is unreadable: {e}"))?;
+                account.storage = Some(Arc::clone(&storage));
+                account.lending = Arc::clone(&lending);
+                account.marks = Arc::clone(&marks);
+                account.attach_ledger(ledgers.remove(&i).unwrap_or_default())?;
+                account.attach_stock_ledger(stock_ledgers.remove(&i).unwrap_or_default())?;
                 accounts.insert(i, RwLock::new(account));
             }
         }
-        Some(Self {
+        lending.set_lent(lent(&mut accounts));
+        let margin_accounts = accounts
+            .iter_mut()
+            .filter_map(|(&id, account)| account.get_mut().margin.map(|_| id))
+            .collect();
+        // what's left is the history of deleted accounts
+        if let Some(&i) = ledgers
+            .keys()
+            .chain(stock_ledgers.keys())
+            .find(|&&i| i >= state_file.next_account_id)
+        {
+            return Err(format!("Ledger of account {i} which was never created").into());
+        }
+        let audit = AuditLog::restore(&mut entries)?;
+        if let Some(key) = entries.keys().next() {
+            return Err(format!("Unexpected persisted key {key}").into());
+        }
+        Ok(Some(Self {
             id: state_file.id,
             accounts,
             next_account_id: state_file.next_account_id,
             next_trade_id: state_file.next_trade_id,
-            per_dir,
-            pending_to_user: HashMap::new(),
-        })
+            storage,
+            pending_to_user: state_file.pending_to_user,
+            audit: Mutex::new(audit),
+            fees: Arc::default(),
+            instruments: Arc::default(),
+            lending,
+            marks,
+            dividends: state_file.dividends,
+            margin_accounts,
+        }))
     }
 
-    async fn update_file(&mut self) -> GResult<()> {
-        fs::write(
-            format!("{}/state", self.per_dir),
-            &serde_json::to_string(&StateFile {
+    pub fn set_fees(&mut self, fees: FeeSchedule) {
+        self.fees = Arc::new(fees);
+        for account in self.accounts.values_mut() {
+            account.get_mut().fees = Arc::clone(&self.fees);
+        }
+    }
+
+    pub fn set_instruments(&mut self, instruments: Instruments) {
+        self.instruments = Arc::new(instruments);
+        for account in self.accounts.values_mut() {
+            account.get_mut().instruments = Arc::clone(&self.instruments);
+        }
+    }
+
+    pub fn get_instruments(&self) -> &Instruments {
+        &self.instruments
+    }
+
+    fn op(&self) -> GResult<Op> {
+        Op::put(
+            "state",
+            &StateFile {
                 id: self.id,
                 next_account_id: self.next_account_id,
                 next_trade_id: self.next_trade_id,
                 pending_to_user: self.pending_to_user.clone(),
-            })?,
+                lendable: self.lending.get_limits(),
+                marks: self.marks.get_all(),
+                dividends: self.dividends.clone(),
+            },
         )
-        .await?;
-        Ok(())
     }
 
     pub fn get_id(&self) -> NodeID {
@@ -88,21 +202,22 @@ impl State {
 
     pub async fn create_account(&mut self) -> GResult<usize> {
         let id = self.next_account_id;
-        self.accounts.insert(
-            id,
-            RwLock::new(
-                Account::new(
-                    format!("{}/{id}", self.per_dir),
-                    UserID {
-                        id,
-                        node_id: self.id,
-                    },
-                )
-                .await?,
-            ),
+        let mut account = Account::new(
+            Arc::clone(&self.storage),
+            UserID {
+                id,
+                node_id: self.id,
+            },
+            Arc::clone(&self.fees),
+            Arc::clone(&self.instruments),
+            Arc::clone(&self.lending),
+            Arc::clone(&self.marks),
         );
         self.next_account_id += 1;
-        self.update_file().await?;
+        let mut ops = account.ops()?;
+        ops.push(self.op()?);
+        self.storage.commit(ops).await?;
+        self.accounts.insert(id, RwLock::new(account));
         Ok(id)
     }
 
@@ -110,66 +225,73 @@ impl State {
         &self.accounts
     }
 
+    /// Lock after the account being changed
+    pub fn get_audit(&self) -> &Mutex<AuditLog> {
+        &self.audit
+    }
+
     pub fn remove_account(&mut self, id: usize) -> Option<RwLock<Account>> {
+        self.margin_accounts.remove(&id);
         self.accounts.remove(&id)
     }
 
-    pub async fn process_matches(&mut self, matches: Vec<Trade>) -> GResult<Vec<(NodeID, Offer)>> {
-        let mut offers = Vec::new();
+    pub fn get_margin_accounts(&self) -> &HashSet<usize> {
+        &self.margin_accounts
+    }
+
+    /// Keep track of account `id` being a margin account once its terms are set
+    pub fn set_margin_account(&mut self, id: usize, margin: bool) {
+        if margin {
+            self.margin_accounts.insert(id);
+        } else {
+            self.margin_accounts.remove(&id);
+        }
+    }
+
+    /// Every account touched by the matches and the state is committed in a single log record.
+    /// Returns the offers and fee transfers to send
+    pub async fn process_matches(
+        &mut self,
+        matches: Vec<Trade>,
+    ) -> GResult<Vec<(NodeID, Message)>> {
+        let mut messages = Vec::new();
+        let mut credits = Vec::new();
+        let mut ops = Vec::new();
         for trade in matches {
             if trade.buyer_id.node_id == self.id && trade.seller_id.node_id == self.id {
                 // Both local, perform trade NOW
-                let Trade {
-                    quantity,
-                    price,
-                    ticker,
-                    buyer_id,
-                    seller_id,
-                    buy_price,
-                    sell_price,
-                } = trade;
                 let buyer = &mut self
                     .accounts
-                    .get(&buyer_id.id)
+                    .get(&trade.buyer_id.id)
                     .expect("Matcher gave invalid UserID")
                     .write()
                     .dl("st134")
                     .await;
                 let seller = &mut self
                     .accounts
-                    .get(&seller_id.id)
+                    .get(&trade.seller_id.id)
                     .expect("Matcher gave invalid UserID")
                     .write()
                     .dl("st141")
                     .await;
-                let new_buyer_balance = buyer.get_balance() - quantity * price;
-                buyer.set_balance(new_buyer_balance).await?;
-                assert_eq!(
-                    quantity,
-                    seller.deduct_stock(ticker.clone(), quantity).await?
-                );
-                let new_seller_balance = seller.get_balance() + quantity * price;
-                seller.set_balance(new_seller_balance).await?;
-                buyer.add_stock(ticker.clone(), quantity).await?;
-
-                let deducted = seller
-                    .deduct_order(OrderReq {
-                        order_type: OrderType::Sell,
-                        ticker: ticker.clone(),
-                        price: sell_price,
-                        quantity,
-                    })
-                    .await?;
-                assert_eq!(deducted, quantity);
-                let deducted = buyer
-                    .deduct_order(OrderReq {
-                        order_type: OrderType::Buy,
-                        ticker: ticker.clone(),
-                        price: buy_price,
-                        quantity,
-                    })
-                    .await?;
-                assert_eq!(deducted, quantity);
+                buyer.settle(&trade);
+                seller.settle(&trade);
+                for payer in [&mut **buyer, &mut **seller] {
+                    let fee = payer.fee(&trade);
+                    for fee in payer.charge_fee(&trade, fee) {
+                        pay_fee(
+                            payer,
+                            fee,
+                            &self.accounts,
+                            &mut self.next_trade_id,
+                            &mut self.pending_to_user,
+                            &mut credits,
+                            &mut messages,
+                        )?;
+                    }
+                }
+                ops.extend(buyer.ops()?);
+                ops.extend(seller.ops()?);
             } else {
                 // One of them remote
                 let (mut local, remote) = if trade.buyer_id.node_id == self.id {
@@ -189,38 +311,117 @@ impl State {
                 };
                 let trade_id = self.next_trade_id;
                 self.next_trade_id += 1;
-                local.add_pending(trade_id, trade.clone()).await?;
+                local.add_pending(trade_id, trade.clone());
+                ops.extend(local.ops()?);
 
                 // record which TradeID belong to which user
                 self.pending_to_user.insert(trade_id, local.id.id);
 
                 // return the offer to be sent
-                offers.push((
+                messages.push((
                     remote.node_id,
-                    Offer {
+                    Message::Offer(Offer {
                         id: trade_id,
                         trade,
-                    },
+                    }),
                 ))
             }
         }
-        self.update_file().await?;
-        Ok(offers)
+        ops.extend(self.credit_fees(credits).await?);
+        ops.push(self.op()?);
+        self.storage.commit(ops).await?;
+        Ok(messages)
     }
 
-    pub async fn commit_pending(&mut self, trade_id: TradeID) -> GResult<()> {
+    /// Returns the fee transfers to send, if any
+    pub async fn commit_pending(
+        &mut self,
+        trade_id: TradeID,
+    ) -> GResult<(Trade, Vec<(NodeID, Message)>)> {
         let user_id = self
             .pending_to_user
             .remove(&trade_id)
             .expect("Non existent trade_id");
-        let account = &self.accounts[&user_id];
-        account
+        let mut messages = Vec::new();
+        let mut credits = Vec::new();
+        let mut account = self.accounts[&user_id].write().dl("st193").await;
+        let (trade, fees) = account.commit_pending(trade_id);
+        for fee in fees {
+            pay_fee(
+                &mut account,
+                fee,
+                &self.accounts,
+                &mut self.next_trade_id,
+                &mut self.pending_to_user,
+                &mut credits,
+                &mut messages,
+            )?;
+        }
+        let mut ops = account.ops()?;
+        drop(account);
+        ops.extend(self.credit_fees(credits).await?);
+        ops.push(self.op()?);
+        self.storage.commit(ops).await?;
+        Ok((trade, messages))
+    }
+
+    /// Accept or reject a trade offer for one of this node's accounts, everything it changes is
+    /// committed in a single log record. Returns the order deducted if accepted, and the fee
+    /// transfer to send
+    pub async fn process_incoming_offer(
+        &mut self,
+        trade: Trade,
+    ) -> GResult<(Option<Order>, Vec<(NodeID, Message)>)> {
+        let user_id = if trade.buyer_id.node_id == self.id {
+            trade.buyer_id
+        } else {
+            assert_eq!(
+                trade.seller_id.node_id, self.id,
+                "Node recieved offer that it doesn't own"
+            );
+            trade.seller_id
+        };
+        let mut account = self
+            .accounts
+            .get(&user_id.id)
+            .expect("Node recieved invalid UserID")
             .write()
-            .dl("st193")
-            .await
-            .commit_pending(trade_id)
-            .await?;
-        self.update_file().await
+            .dl("st236")
+            .await;
+        let Some(order) = account.accept_offer(&trade) else {
+            return Ok((None, Vec::new()));
+        };
+        let mut messages = Vec::new();
+        let mut credits = Vec::new();
+        let fee = account.fee(&trade);
+        for fee in account.charge_fee(&trade, fee) {
+            pay_fee(
+                &mut account,
+                fee,
+                &self.accounts,
+                &mut self.next_trade_id,
+                &mut self.pending_to_user,
+                &mut credits,
+                &mut messages,
+            )?;
+        }
+        let mut ops = account.ops()?;
+        drop(account);
+        ops.extend(self.credit_fees(credits).await?);
+        ops.push(self.op()?);
+        self.storage.commit(ops).await?;
+        Ok((Some(order), messages))
+    }
+
+    /// Credit fees paid to a fee account on this node, once no account is locked
+    async fn credit_fees(&self, credits: Vec<Transfer>) -> GResult<Vec<Op>> {
+        let mut ops = Vec::new();
+        for credit in credits {
+            let mut account = self.accounts[&credit.to.id].write().dl("st270").await;
+            account.receive(&credit)?;
+            ops.extend(account.ops()?);
+        }
+        Ok(ops)
     }
 
     pub async fn abort_pending(&mut self, trade_id: TradeID) -> GResult<Order> {
@@ -228,65 +429,1016 @@ impl State {
             .pending_to_user
             .remove(&trade_id)
             .expect("Non existent trade_id");
-        let account = &self.accounts[&user_id];
-        let order = account
-            .write()
-            .dl("st200")
-            .await
-            .abort_pending(trade_id)
-            .await?;
-        self.update_file().await?;
+        let mut account = self.accounts[&user_id].write().dl("st200").await;
+        let order = account.abort_pending(trade_id);
+        let mut ops = account.ops()?;
+        ops.push(self.op()?);
+        self.storage.commit(ops).await?;
         Ok(order)
     }
+
+    /// Both accounts are committed in a single log record, like a local trade.
+    /// false if the source can't cover it
+    pub async fn transfer_local(&mut self, transfer: &Transfer) -> GResult<bool> {
+        let mut from = self.accounts[&transfer.from.id].write().dl("st218").await;
+        if !from.can_send(&transfer.asset) {
+            return Ok(false);
+        }
+        let mut to = self.accounts[&transfer.to.id].write().dl("st222").await;
+        // received first, it's what can fail
+        to.receive(transfer)?;
+        from.send(transfer)?;
+        let mut ops = from.ops()?;
+        ops.extend(to.ops()?);
+        self.storage.commit(ops).await?;
+        Ok(true)
+    }
+
+    /// Hold what's sent until the destination node replies, committed with the state like a
+    /// pending trade. None if the source can't cover it
+    pub async fn reserve_transfer(&mut self, transfer: Transfer) -> GResult<Option<TradeID>> {
+        let mut account = self.accounts[&transfer.from.id].write().dl("st237").await;
+        if !account.can_send(&transfer.asset) {
+            return Ok(None);
+        }
+        let transfer_id = self.next_trade_id;
+        account.add_pending_transfer(transfer_id, transfer)?;
+        self.next_trade_id += 1;
+        self.pending_to_user.insert(transfer_id, account.id.id);
+        let mut ops = account.ops()?;
+        ops.push(self.op()?);
+        self.storage.commit(ops).await?;
+        Ok(Some(transfer_id))
+    }
+
+    /// Whether an account still has a trade or stock transfer of `ticker` waiting for another node
+    pub async fn is_trading(&self, ticker: &Ticker) -> bool {
+        for account in self.accounts.values() {
+            if account.read().dl("st410").await.is_trading(ticker) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Every account's stock and orders of the ticker, committed in a single log record with
+    /// the audit record. Nothing changes if any of them would go out of range
+    pub async fn split(&mut self, actor: UserID, split: &Split) -> GResult<()> {
+        let SplitState {
+            rescaled,
+            lendable,
+            mark,
+        } = self.rescale(split)?;
+        let mut ops = Vec::new();
+        for (id, rescaled) in rescaled {
+            let account = self.accounts.get_mut(&id).expect("listed above").get_mut();
+            if account.apply_split(split, rescaled) {
+                ops.extend(account.ops()?);
+            }
+        }
+        if let Some(lendable) = lendable {
+            self.lending.set_limit(split.ticker.clone(), lendable);
+        }
+        self.lending.set_lent(lent(&mut self.accounts));
+        if let Some(mark) = mark {
+            self.marks.set(&split.ticker, mark);
+        }
+        ops.push(self.op()?);
+        let action = AuditAction::Split(split.clone());
+        ops.push(self.audit.lock().await.record(actor, action)?);
+        self.storage.commit(ops).await
+    }
+
+    /// Whether `split` would leave everything in range, changes nothing
+    pub fn check_split(&mut self, split: &Split) -> GResult<()> {
+        self.rescale(split).map(|_| ())
+    }
+
+    fn rescale(&mut self, split: &Split) -> GResult<SplitState> {
+        let mut rescaled = Vec::new();
+        for (&id, account) in self.accounts.iter_mut() {
+            rescaled.push((id, account.get_mut().split(split)?));
+        }
+        let lendable = self
+            .lending
+            .get_limits()
+            .get(&split.ticker)
+            .map(|&limit| split.quantity(limit))
+            .transpose()?;
+        // rounded down like a buy's price
+        let mark = self
+            .marks
+            .get(&split.ticker)
+            .map(|mark| split.price(OrderType::Buy, mark))
+            .transpose()?;
+        Ok(SplitState {
+            rescaled,
+            lendable,
+            mark,
+        })
+    }
+
+    /// Credit every account holding the ticker, committed in a single log record with the audit
+    /// record. Nothing is paid if any balance would go out of range. A dividend already paid
+    /// isn't paid again, its distribution is returned
+    pub async fn pay_dividend(
+        &mut self,
+        actor: UserID,
+        id: DividendID,
+        dividend: &Dividend,
+    ) -> GResult<Distribution> {
+        if let Some(&paid) = self.dividends.get(&id) {
+            return Ok(paid);
+        }
+        let currency = self.instruments.currency(&dividend.ticker).clone();
+        let mut payments = Vec::new();
+        let mut paid = Distribution::default();
+        for (&id, account) in self.accounts.iter_mut() {
+            let account = account.get_mut();
+            let shares = account.shares_of_record(&dividend.ticker)?;
+            if shares == Shares(0) {
+                continue;
+            }
+            let cash = Cash {
+                currency: currency.clone(),
+                amount: shares.times(dividend.per_share)?.0,
+            };
+            let holder = LedgerAccount::User(account.id);
+            account.balance_after(LedgerAccount::External, holder, &cash)?;
+            paid = paid.checked_add(Distribution {
+                accounts: 1,
+                shares: shares.0,
+                amount: cash.amount,
+            })?;
+            payments.push((id, shares, cash));
+        }
+        let mut ops = Vec::new();
+        for (id, shares, cash) in payments {
+            let account = self.accounts.get_mut(&id).expect("listed above").get_mut();
+            let memo = Memo::Dividend {
+                ticker: dividend.ticker.clone(),
+                quantity: shares.0,
+                per_share: dividend.per_share,
+            };
+            let holder = LedgerAccount::User(account.id);
+            account
+                .record(LedgerAccount::External, holder, cash, memo)
+                .expect("checked above");
+            ops.extend(account.ops()?);
+        }
+        let action = AuditAction::Dividend {
+            dividend: dividend.clone(),
+            paid,
+        };
+        ops.push(self.audit.lock().await.record(actor, action)?);
+        self.dividends.insert(id, paid);
+        ops.push(self.op()?);
+        self.storage.commit(ops).await?;
+        Ok(paid)
+    }
+
+    /// How many shares of `ticker` this node may lend, those already lent aren't called back
+    pub async fn set_lendable(
+        &mut self,
+        actor: UserID,
+        ticker: Ticker,
+        quantity: Quantity,
+    ) -> GResult<()> {
+        self.lending.set_limit(ticker.clone(), quantity);
+        let action = AuditAction::SetLendable { ticker, quantity };
+        let ops = vec![self.op()?, self.audit.lock().await.record(actor, action)?];
+        self.storage.commit(ops).await
+    }
+
+    pub async fn commit_transfer(&mut self, transfer_id: TradeID) -> GResult<()> {
+        let user_id = self
+            .pending_to_user
+            .remove(&transfer_id)
+            .expect("Non existent transfer id");
+        let mut account = self.accounts[&user_id].write().dl("st256").await;
+        account.commit_pending_transfer(transfer_id);
+        let mut ops = account.ops()?;
+        ops.push(self.op()?);
+        self.storage.commit(ops).await
+    }
+
+    pub async fn abort_transfer(&mut self, transfer_id: TradeID) -> GResult<()> {
+        let user_id = self
+            .pending_to_user
+            .remove(&transfer_id)
+            .expect("Non existent transfer id");
+        let mut account = self.accounts[&user_id].write().dl("st268").await;
+        account.abort_pending_transfer(transfer_id);
+        let mut ops = account.ops()?;
+        ops.push(self.op()?);
+        self.storage.commit(ops).await
+    }
+}
+
+/// Take `fee` from `payer` in the log record being built. A fee account on this node is credited
+/// from `credits`, one on another node gets a pending transfer, added to `messages` to be sent.
+/// A fee account that doesn't exist isn't paid.
+fn pay_fee(
+    payer: &mut Account,
+    fee: Transfer,
+    accounts: &HashMap<usize, RwLock<Account>>,
+    next_trade_id: &mut usize,
+    pending_to_user: &mut HashMap<TradeID, usize>,
+    credits: &mut Vec<Transfer>,
+    messages: &mut Vec<(NodeID, Message)>,
+) -> Result<(), ErrorResponse> {
+    if fee.to.node_id != payer.id.node_id {
+        let transfer_id = *next_trade_id;
+        *next_trade_id += 1;
+        payer.add_pending_transfer(transfer_id, fee.clone())?;
+        pending_to_user.insert(transfer_id, payer.id.id);
+        messages.push((
+            fee.to.node_id,
+            Message::Transfer(TransferOffer {
+                id: transfer_id,
+                transfer: fee,
+            }),
+        ));
+    } else if accounts.contains_key(&fee.to.id) {
+        payer.send(&fee)?;
+        credits.push(fee);
+    } else {
+        println!("No fee account {}, fee not charged", fee.to);
+    }
+    Ok(())
+}
+
+/// What every account borrowed, by ticker
+fn lent(accounts: &mut HashMap<usize, RwLock<Account>>) -> Vec<(Ticker, Quantity)> {
+    accounts
+        .values_mut()
+        .flat_map(|account| account.get_mut().borrowed.clone())
+        .collect()
+}
+
+/// Cash a short sale of shares worth `value` sets aside: their value and half again
+fn short_collateral(value: Money) -> Result<Money, ErrorResponse> {
+    value.checked_add(Money(value.0.div_ceil(2)))
+}
+
+/// A margin account in one currency, in i128 so nothing overflows
+struct Usage {
+    /// cash and positions at their mark, less the loan and cash on its way out
+    equity: i128,
+    /// positions at their mark, long or short
+    exposure: i128,
+    /// of open buy orders
+    buying: i128,
+}
+
+impl Usage {
+    fn buying_power(&self, terms: MarginTerms) -> i128 {
+        (self.equity * i128::from(terms.leverage) - self.exposure - self.buying).max(0)
+    }
+
+    /// Equity needed for what's held and ordered, rounded up
+    fn required(&self, terms: MarginTerms) -> i128 {
+        let leverage = i128::from(terms.leverage);
+        (self.exposure + self.buying + leverage - 1) / leverage
+    }
+
+    fn breached(&self, terms: MarginTerms) -> bool {
+        self.exposure > 0 && self.equity * 100 < self.exposure * i128::from(terms.maintenance_pct)
+    }
+}
+
+/// Cents of what's worked out in i128, nothing if below zero
+fn cents(amount: i128) -> CentCount {
+    CentCount::try_from(amount.max(0)).unwrap_or(CentCount::MAX)
+}
+
+/// Every account, what the node lends and the mark of a ticker once it's split
+struct SplitState {
+    rescaled: Vec<(usize, Rescaled)>,
+    lendable: Option<Quantity>,
+    mark: Option<CentCount>,
+}
+
+/// An account's stock and orders of a ticker once it's split, `None` for what it has none of
+struct Rescaled {
+    held: Option<Quantity>,
+    borrowed: Option<Quantity>,
+    basis: Option<Basis>,
+    buys: Option<HashMap<CentCount, Quantity>>,
+    sells: Option<HashMap<CentCount, Quantity>>,
+}
+
+const LEDGER_PREFIX: &str = "ledger/";
+const STOCK_PREFIX: &str = "stock/";
+
+/// A ledger entry to record: from, to, the cash moved and what for
+type Leg = (LedgerAccount, LedgerAccount, Cash, Memo);
+
+/// Every account's entries under `prefix` in order of `id`, taken out of `entries`
+fn take_ledgers<T: DeserializeOwned>(
+    entries: &mut HashMap<String, Value>,
+    prefix: &str,
+    id: fn(&T) -> u64,
+) -> GResult<HashMap<usize, Vec<T>>> {
+    let keys: Vec<String> = entries
+        .keys()
+        .filter(|k| k.starts_with(prefix))
+        .cloned()
+        .collect();
+    let mut ledgers: HashMap<usize, Vec<T>> = HashMap::new();
+    for key in keys {
+        let account_id = key[prefix.len()..]
+            .split_once('/')
+            .and_then(|(account_id, _)| account_id.parse().ok())
+            .ok_or_else(|| format!("Bad ledger key {key}"))?;
+        let entry = serde_json::from_value(entries.remove(&key).expect("key was listed"))
+            .map_err(|e| format!("{key} is unreadable: {e}"))?;
+        ledgers.entry(account_id).or_default().push(entry);
+    }
+    for ledger in ledgers.values_mut() {
+        ledger.sort_by_key(id);
+    }
+    Ok(ledgers)
 }
 
 /// need to tell matcher seperately
 #[derive(Serialize, Deserialize)]
 pub struct Account {
     #[serde(skip)]
-    path: String,
+    storage: Option<Arc<dyn Storage>>,
+    /// persisted one key per entry, not with the account
+    #[serde(skip)]
+    ledger: Vec<LedgerEntry>,
+    /// entries from here on are yet to be persisted
+    #[serde(skip)]
+    saved_entries: usize,
+    /// shares gained or lost other than by a fill, persisted like the ledger
+    #[serde(skip)]
+    stock_ledger: Vec<StockEntry>,
+    #[serde(skip)]
+    saved_stock_entries: usize,
+    /// sum of the ledger by currency, including cash held for pending trades and transfers
+    #[serde(skip)]
+    cash: HashMap<Currency, CentCount>,
+    /// cash traded, buying and selling, from the ledger. Picks the fee tier
+    #[serde(skip)]
+    volume: CentCount,
+    #[serde(skip)]
+    fees: Arc<FeeSchedule>,
+    #[serde(skip)]
+    instruments: Arc<Instruments>,
+    #[serde(skip)]
+    lending: Arc<Lending>,
+    #[serde(skip)]
+    marks: Arc<Marks>,
+    /// when orders were placed within the last second, for `RiskLimits::max_orders_per_sec`
+    #[serde(skip)]
+    placed: VecDeque<Instant>,
 
     id: UserID,
-    balance: CentCount,
+    /// including borrowed shares not sold yet
     portfolio: HashMap<Ticker, Quantity>,
+    /// of each position, long or short
+    costs: HashMap<Ticker, Basis>,
+    /// may sell more than it has, borrowing the rest
+    can_short: bool,
+    /// set by the coordinator when an admin changes it, the session token's role is current
+    /// until then
+    role: Option<Role>,
+    /// shares borrowed for short sales and still owed, given back once free in the portfolio
+    borrowed: HashMap<Ticker, Quantity>,
+    /// cash set aside for what's borrowed of each ticker, in the ticker's currency
+    collateral: HashMap<Ticker, CentCount>,
+    /// `None` for a cash account
+    margin: Option<MarginTerms>,
+    /// lent to a margin account by currency, paid back from cash nothing else needs
+    loan: HashMap<Currency, CentCount>,
+    /// being brought back over its maintenance margin by forced orders
+    liquidating: bool,
+    limits: RiskLimits,
+    /// engaged, no new orders until it's released
+    killed: Option<KilledBy>,
     buys: HashMap<Ticker, HashMap<CentCount, Quantity>>,
     sells: HashMap<Ticker, HashMap<CentCount, Quantity>>,
     pending: HashMap<TradeID, Trade>,
+    /// fee of each pending buy, held with its cash until the trade is committed
+    pending_fees: HashMap<TradeID, CentCount>,
+    /// outgoing transfers waiting for the destination node's reply
+    transfers: HashMap<TradeID, Transfer>,
+    /// waiting for an admin to confirm the cash arrived
+    deposits: HashMap<DepositID, Cash>,
+    next_deposit_id: DepositID,
+    /// fees that came to more than was free when charged, paid in order at the next fills.
+    /// They can't be withdrawn meanwhile
+    owed_fees: Vec<Transfer>,
 }
 
 impl Account {
-    async fn new(path: String, id: UserID) -> GResult<Self> {
-        let s = Self {
+    fn new(
+        storage: Arc<dyn Storage>,
+        id: UserID,
+        fees: Arc<FeeSchedule>,
+        instruments: Arc<Instruments>,
+        lending: Arc<Lending>,
+        marks: Arc<Marks>,
+    ) -> Self {
+        Self {
             id,
-            path,
-            balance: 0,
+            storage: Some(storage),
+            ledger: Vec::new(),
+            saved_entries: 0,
+            stock_ledger: Vec::new(),
+            saved_stock_entries: 0,
+            cash: HashMap::new(),
+            volume: 0,
+            fees,
+            instruments,
+            lending,
+            marks,
+            placed: VecDeque::new(),
             portfolio: HashMap::new(),
+            costs: HashMap::new(),
+            can_short: false,
+            role: None,
+            borrowed: HashMap::new(),
+            collateral: HashMap::new(),
+            margin: None,
+            loan: HashMap::new(),
+            liquidating: false,
+            limits: RiskLimits::default(),
+            killed: None,
             buys: HashMap::new(),
             sells: HashMap::new(),
             pending: HashMap::new(),
+            pending_fees: HashMap::new(),
+            transfers: HashMap::new(),
+            deposits: HashMap::new(),
+            next_deposit_id: 0,
+            owed_fees: Vec::new(),
+        }
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        self.storage
+            .as_deref()
+            .expect("Account not attached to storage")
+    }
+
+    /// The account and its new ledger and stock ledger entries, marks the entries as persisted.
+    /// Gives back any borrowed shares and repays what margin loan it can first, so whatever freed
+    /// them is persisted with that
+    fn ops(&mut self) -> GResult<Vec<Op>> {
+        self.give_back();
+        self.repay();
+        let mut ops = vec![Op::put(self.id.id.to_string(), self)?];
+        for entry in &self.ledger[self.saved_entries..] {
+            ops.push(Op::put(
+                format!("{LEDGER_PREFIX}{}/{}", self.id.id, entry.id),
+                entry,
+            )?);
+        }
+        self.saved_entries = self.ledger.len();
+        for entry in &self.stock_ledger[self.saved_stock_entries..] {
+            ops.push(Op::put(
+                format!("{STOCK_PREFIX}{}/{}", self.id.id, entry.id),
+                entry,
+            )?);
+        }
+        self.saved_stock_entries = self.stock_ledger.len();
+        Ok(ops)
+    }
+
+    async fn update_file(&mut self) -> GResult<()> {
+        let ops = self.ops()?;
+        self.storage().commit(ops).await
+    }
+
+    /// Takes the ledger as restored, entries must be in order
+    fn attach_ledger(&mut self, ledger: Vec<LedgerEntry>) -> GResult<()> {
+        let me = LedgerAccount::User(self.id);
+        let mut cash: HashMap<&Currency, i128> = HashMap::new();
+        for (i, entry) in ledger.iter().enumerate() {
+            if entry.id != i as u64 {
+                return Err(format!("Ledger of {} is missing entry {i}", self.id).into());
+            }
+            *cash.entry(&entry.currency).or_default() += entry.change_for(me);
+            if let Memo::Trade { .. } = entry.memo {
+                self.volume = self.volume.saturating_add(entry.amount);
+            }
+        }
+        for (currency, cash) in cash {
+            let cash = CentCount::try_from(cash)
+                .map_err(|_| format!("Ledger of {} adds up to {cash} {currency}", self.id))?;
+            self.cash.insert(currency.clone(), cash);
+        }
+        self.saved_entries = ledger.len();
+        self.ledger = ledger;
+        Ok(())
+    }
+
+    /// Takes the stock ledger as restored, entries must be in order
+    fn attach_stock_ledger(&mut self, stock_ledger: Vec<StockEntry>) -> GResult<()> {
+        if let Some(i) = (0..stock_ledger.len()).find(|&i| stock_ledger[i].id != i as u64) {
+            return Err(format!("Stock ledger of {} is missing entry {i}", self.id).into());
+        }
+        self.saved_stock_entries = stock_ledger.len();
+        self.stock_ledger = stock_ledger;
+        Ok(())
+    }
+
+    /// Add an entry, doesn't persist. Nothing is added if the balance would go out of range
+    fn record(
+        &mut self,
+        from: LedgerAccount,
+        to: LedgerAccount,
+        cash: Cash,
+        memo: Memo,
+    ) -> Result<(), ErrorResponse> {
+        let balance = self.balance_after(from, to, &cash)?;
+        let entry = LedgerEntry {
+            id: self.ledger.len() as u64,
+            at: now(),
+            from,
+            to,
+            currency: cash.currency,
+            amount: cash.amount,
+            memo,
         };
-        s.update_file().await?;
-        Ok(s)
+        self.cash.insert(entry.currency.clone(), balance.0);
+        if let Memo::Trade { .. } = entry.memo {
+            self.volume = self.volume.saturating_add(entry.amount);
+        }
+        self.ledger.push(entry);
+        Ok(())
     }
 
-    async fn update_file(&self) -> GResult<()> {
-        fs::write(&self.path, &serde_json::to_string(self)?)
-            .await
-            .expect(&self.path);
+    /// Cash of its currency once `cash` moves from `from` to `to`
+    fn balance_after(
+        &self,
+        from: LedgerAccount,
+        to: LedgerAccount,
+        cash: &Cash,
+    ) -> Result<Money, ErrorResponse> {
+        let me = LedgerAccount::User(self.id);
+        let balance = Money(*self.cash.get(&cash.currency).unwrap_or(&0));
+        match (from == me, to == me) {
+            (true, false) => balance.checked_sub(Money(cash.amount)),
+            (false, true) => balance.checked_add(Money(cash.amount)),
+            _ => Ok(balance),
+        }
+    }
+
+    /// Entries `record_trade` adds for `trade`, once checked they'd leave every balance in
+    /// range with `lent` more of the quote currency than the account has now
+    fn trade_legs(&self, trade: &Trade, lent: Money) -> Result<Vec<Leg>, ErrorResponse> {
+        let (buyer, seller) = (
+            LedgerAccount::User(trade.buyer_id),
+            LedgerAccount::User(trade.seller_id),
+        );
+        let (ticker, quantity, price) = (trade.ticker.clone(), trade.quantity, trade.price);
+        let quote = self.quote(&ticker);
+        let mut legs = vec![(
+            buyer,
+            seller,
+            Cash {
+                currency: quote.clone(),
+                amount: Shares(quantity).times(price)?.0,
+            },
+            Memo::Trade {
+                ticker: ticker.clone(),
+                quantity,
+                price,
+            },
+        )];
+        if let Some((base, lot)) = self.fx(&ticker) {
+            legs.push((
+                seller,
+                buyer,
+                Cash {
+                    currency: base,
+                    amount: Shares(quantity).times(lot)?.0,
+                },
+                Memo::Fx {
+                    ticker,
+                    quantity,
+                    price,
+                },
+            ));
+        }
+        // the legs are in different currencies, so each can be checked before either is recorded
+        for (from, to, cash, _) in &legs {
+            let mut cash = cash.clone();
+            if cash.currency == quote && *from == LedgerAccount::User(self.id) {
+                cash.amount = Money(cash.amount).saturating_sub(lent).0;
+            }
+            self.balance_after(*from, *to, &cash)?;
+        }
+        Ok(legs)
+    }
+
+    /// The cash sides of a trade, both parties record the same entries: the price paid, and
+    /// the base currency sold for an FX pair. An empty fill moves no cash so isn't recorded.
+    /// Nothing is recorded if an amount or balance would go out of range
+    fn record_trade(&mut self, trade: &Trade) -> Result<(), ErrorResponse> {
+        if trade.quantity == 0 {
+            return Ok(());
+        }
+        for (from, to, cash, memo) in self.trade_legs(trade, Money::default())? {
+            self.record(from, to, cash, memo)?;
+        }
+        if self.fx(&trade.ticker).is_none() {
+            self.marks.set(&trade.ticker, trade.price);
+            let bought = i128::from(trade.quantity);
+            let bought = if trade.buyer_id == self.id {
+                bought
+            } else {
+                -bought
+            };
+            self.costs
+                .entry(trade.ticker.clone())
+                .or_default()
+                .trade(bought, trade.price);
+        }
         Ok(())
     }
 
-    async fn restore(path: String) -> Option<Self> {
-        let mut s: Self = serde_json::from_str(&read_to_string(path.clone()).ok()?).ok()?;
-        s.path = path;
-        Some(s)
+    /// The cash side of a transfer, both parties record the same entry
+    fn record_transfer(&mut self, transfer: &Transfer, cash: &Cash) -> Result<(), ErrorResponse> {
+        self.record(
+            LedgerAccount::User(transfer.from),
+            LedgerAccount::User(transfer.to),
+            cash.clone(),
+            transfer.memo.clone(),
+        )
+    }
+
+    /// Currency `ticker` is priced in
+    fn quote(&self, ticker: &Ticker) -> Currency {
+        self.instruments.currency(ticker).clone()
+    }
+
+    /// Base currency and lot if `ticker` is an FX pair, which is held as cash rather than stock
+    fn fx(&self, ticker: &Ticker) -> Option<(Currency, CentCount)> {
+        self.instruments
+            .fx(ticker)
+            .map(|(base, lot)| (base.clone(), lot))
+    }
+
+    /// This account's fee for its side of a settled fill. The fee account pays none
+    fn fee(&self, trade: &Trade) -> CentCount {
+        if self.fees.account == Some(self.id) {
+            return 0;
+        }
+        self.fees.fee(
+            &trade.ticker,
+            self.volume,
+            trade.is_maker(self.id),
+            Shares(trade.quantity)
+                .times(trade.price)
+                .unwrap_or(Money::MAX)
+                .0,
+        )
+    }
+
+    /// Fees to pay now: what's owed from earlier fills, then `fee` for `trade`, as far as free
+    /// cash covers them so other orders keep what they reserve. The rest stays owed
+    fn charge_fee(&mut self, trade: &Trade, fee: CentCount) -> Vec<Transfer> {
+        let charged = self.fee_transfer(trade, fee);
+        self.owed_fees.extend(charged);
+        let mut free: HashMap<Currency, CentCount> = HashMap::new();
+        let mut due = Vec::new();
+        for mut owed in std::mem::take(&mut self.owed_fees) {
+            let Asset::Cash(Cash { currency, amount }) = &owed.asset else {
+                unreachable!("fees are paid in cash");
+            };
+            let (currency, amount) = (currency.clone(), *amount);
+            let free = free
+                .entry(currency.clone())
+                .or_insert_with(|| self.get_free_cash(&currency));
+            let paid = amount.min(*free);
+            *free -= paid;
+            let cash = |amount| {
+                Asset::Cash(Cash {
+                    currency: currency.clone(),
+                    amount,
+                })
+            };
+            if paid > 0 {
+                due.push(Transfer {
+                    asset: cash(paid),
+                    ..owed.clone()
+                });
+            }
+            if paid < amount {
+                owed.asset = cash(amount - paid);
+                self.owed_fees.push(owed);
+            }
+        }
+        due
+    }
+
+    /// Fees of `currency` charged but not paid yet
+    fn owed(&self, currency: &Currency) -> CentCount {
+        Money::saturating_sum(self.owed_fees.iter().filter_map(|owed| match &owed.asset {
+            Asset::Cash(cash) if &cash.currency == currency => Some(Money(cash.amount)),
+            _ => None,
+        }))
+        .0
+    }
+
+    /// Paying `amount` of fees for `trade` to the fee account. Charged right after the trade is
+    /// recorded, so it's for the last trade entry
+    fn fee_transfer(&self, trade: &Trade, amount: CentCount) -> Option<Transfer> {
+        let to = self.fees.account.filter(|_| amount > 0)?;
+        Some(Transfer {
+            from: self.id,
+            to,
+            asset: Asset::Cash(Cash {
+                currency: self.quote(&trade.ticker),
+                amount,
+            }),
+            memo: Memo::Fee {
+                ticker: trade.ticker.clone(),
+                quantity: trade.quantity,
+                price: trade.price,
+                trade: self
+                    .ledger
+                    .iter()
+                    .rev()
+                    .find(|entry| matches!(entry.memo, Memo::Trade { .. }))
+                    .map(|entry| entry.id),
+            },
+        })
+    }
+
+    /// Whether `asset` is free of orders and pending trades, and cash of a margin account's loan
+    fn can_send(&self, asset: &Asset) -> bool {
+        match asset {
+            Asset::Cash(cash) => cash.amount <= self.get_withdrawable(&cash.currency),
+            Asset::Stock { ticker, quantity } => {
+                Shares(*self.portfolio.get(ticker).unwrap_or(&0))
+                    .saturating_sub(Shares(self.get_sell_order_quantity(ticker)))
+                    .saturating_sub(Shares(*self.borrowed.get(ticker).unwrap_or(&0)))
+                    >= Shares(*quantity)
+            }
+        }
+    }
+
+    /// this side of a transfer where both sides are on this node, doesn't persist
+    fn send(&mut self, transfer: &Transfer) -> Result<(), ErrorResponse> {
+        match &transfer.asset {
+            Asset::Cash(cash) => self.record_transfer(transfer, cash),
+            Asset::Stock { ticker, quantity } => {
+                self.change_stock(ticker, |held| held.checked_sub(Shares(*quantity)))?;
+                self.move_stock(ticker, -i128::from(*quantity), transfer.into());
+                Ok(())
+            }
+        }
+    }
+
+    /// doesn't persist, nothing changes if the account can't hold what's received
+    fn receive(&mut self, transfer: &Transfer) -> Result<(), ErrorResponse> {
+        match &transfer.asset {
+            Asset::Cash(cash) => self.record_transfer(transfer, cash),
+            Asset::Stock { ticker, quantity } => {
+                self.change_stock(ticker, |held| held.checked_add(Shares(*quantity)))?;
+                self.move_stock(ticker, i128::from(*quantity), transfer.into());
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the portfolio's `ticker` to what `change` makes of it, unless that's out of range
+    fn change_stock(
+        &mut self,
+        ticker: &Ticker,
+        change: impl FnOnce(Shares) -> Result<Shares, ErrorResponse>,
+    ) -> Result<(), ErrorResponse> {
+        let held = self.portfolio.entry(ticker.clone()).or_default();
+        *held = change(Shares(*held))?.0;
+        Ok(())
+    }
+
+    /// Shares of `ticker` that came, or went if below zero, without a trade
+    fn move_stock(&mut self, ticker: &Ticker, quantity: i128, memo: StockMemo) {
+        self.costs
+            .entry(ticker.clone())
+            .or_default()
+            .shift(quantity);
+        self.record_stock(ticker, quantity, memo);
+    }
+
+    /// Add a stock ledger entry, doesn't persist
+    fn record_stock(&mut self, ticker: &Ticker, change: i128, memo: StockMemo) {
+        self.stock_ledger.push(StockEntry {
+            id: self.stock_ledger.len() as u64,
+            at: now(),
+            ticker: ticker.clone(),
+            change: i64::try_from(change.clamp(i64::MIN.into(), i64::MAX.into())).expect("clamped"),
+            memo,
+        });
+    }
+
+    /// A transfer from another node, false if the account can't hold it
+    pub async fn receive_transfer(&mut self, transfer: &Transfer) -> GResult<bool> {
+        if let Err(e) = self.receive(transfer) {
+            println!("rejected transfer: {e}");
+            return Ok(false);
+        }
+        self.update_file().await?;
+        Ok(true)
+    }
+
+    /// Stock leaves the portfolio now, cash is held until the transfer is committed or
+    /// aborted. doesn't persist
+    fn add_pending_transfer(
+        &mut self,
+        transfer_id: TradeID,
+        transfer: Transfer,
+    ) -> Result<(), ErrorResponse> {
+        assert!(
+            !self.transfers.contains_key(&transfer_id),
+            "duplicate transfer id??"
+        );
+        if let Asset::Stock { ticker, quantity } = &transfer.asset {
+            self.change_stock(ticker, |held| held.checked_sub(Shares(*quantity)))?;
+        }
+        self.transfers.insert(transfer_id, transfer);
+        Ok(())
+    }
+
+    /// doesn't persist
+    fn commit_pending_transfer(&mut self, transfer_id: TradeID) {
+        let transfer = self
+            .transfers
+            .remove(&transfer_id)
+            .expect("Invalid transfer id");
+        // the held cash is now paid, the stock is gone
+        match &transfer.asset {
+            Asset::Cash(cash) => self
+                .record_transfer(&transfer, cash)
+                .expect("Invalid transfer, not enough held"),
+            Asset::Stock { ticker, quantity } => {
+                self.move_stock(ticker, -i128::from(*quantity), (&transfer).into())
+            }
+        }
+    }
+
+    /// doesn't persist
+    fn abort_pending_transfer(&mut self, transfer_id: TradeID) {
+        let transfer = self
+            .transfers
+            .remove(&transfer_id)
+            .expect("Invalid transfer id");
+        // held cash is released by removing the transfer
+        if let Asset::Stock { ticker, quantity } = &transfer.asset {
+            self.change_stock(ticker, |held| held.checked_add(Shares(*quantity)))
+                .expect("Invalid transfer, can't hold the stock returned");
+        }
+    }
+
+    fn is_trading(&self, ticker: &Ticker) -> bool {
+        self.pending.values().any(|trade| &trade.ticker == ticker)
+            || self.transfers.values().any(|transfer| {
+                matches!(&transfer.asset, Asset::Stock { ticker: sent, .. } if sent == ticker)
+            })
+    }
+
+    /// Stock and orders of `split.ticker` after the split, without changing them yet
+    fn split(&self, split: &Split) -> Result<Rescaled, ErrorResponse> {
+        let orders = |order_type, orders: &HashMap<Ticker, HashMap<CentCount, Quantity>>| {
+            let Some(levels) = orders.get(&split.ticker) else {
+                return Ok(None);
+            };
+            let mut rescaled: HashMap<CentCount, Quantity> = HashMap::new();
+            for (&price, &quantity) in levels {
+                let quantity = split.quantity(quantity)?;
+                if quantity > 0 {
+                    // levels can merge, two prices may round to one
+                    let level = rescaled.entry(split.price(order_type, price)?).or_default();
+                    *level = Shares(*level).checked_add(Shares(quantity))?.0;
+                }
+            }
+            Ok::<_, ErrorResponse>(Some(rescaled))
+        };
+        Ok(Rescaled {
+            held: self
+                .portfolio
+                .get(&split.ticker)
+                .map(|&held| split.quantity(held))
+                .transpose()?,
+            borrowed: self
+                .borrowed
+                .get(&split.ticker)
+                .map(|&borrowed| split.quantity(borrowed))
+                .transpose()?,
+            basis: self
+                .costs
+                .get(&split.ticker)
+                .map(|basis| basis.split(split))
+                .transpose()?,
+            buys: orders(OrderType::Buy, &self.buys)?,
+            sells: orders(OrderType::Sell, &self.sells)?,
+        })
+    }
+
+    /// false if the account has none of the ticker, doesn't persist
+    fn apply_split(&mut self, split: &Split, rescaled: Rescaled) -> bool {
+        let ticker = &split.ticker;
+        let Rescaled {
+            held,
+            borrowed,
+            basis,
+            buys,
+            sells,
+        } = rescaled;
+        let changed = held.is_some() || borrowed.is_some() || buys.is_some() || sells.is_some();
+        if let Some(basis) = basis {
+            let before = self.costs.insert(ticker.clone(), basis).unwrap_or_default();
+            if before.quantity() != 0 {
+                let change = i128::from(basis.quantity()) - i128::from(before.quantity());
+                let memo = StockMemo::Split {
+                    from: split.from,
+                    to: split.to,
+                };
+                self.record_stock(ticker, change, memo);
+            }
+        }
+        if let Some(held) = held {
+            self.portfolio.insert(ticker.clone(), held);
+        }
+        if let Some(borrowed) = borrowed {
+            self.borrowed.insert(ticker.clone(), borrowed);
+        }
+        if let Some(buys) = buys {
+            self.buys.insert(ticker.clone(), buys);
+        }
+        if let Some(sells) = sells {
+            self.sells.insert(ticker.clone(), sells);
+        }
+        changed
+    }
+
+    /// Shares of `ticker` the account is paid a dividend for, including those of pending sells
+    /// and outgoing transfers, which are only gone once committed. A short position is paid
+    /// nothing
+    fn shares_of_record(&self, ticker: &Ticker) -> Result<Shares, ErrorResponse> {
+        let selling = self
+            .pending
+            .values()
+            .filter(|trade| &trade.ticker == ticker && trade.seller_id == self.id)
+            .map(|trade| trade.quantity);
+        let sending = self
+            .transfers
+            .values()
+            .filter_map(|transfer| match &transfer.asset {
+                Asset::Stock {
+                    ticker: sent,
+                    quantity,
+                } if sent == ticker => Some(*quantity),
+                _ => None,
+            });
+        let held = Shares(self.portfolio.get(ticker).copied().unwrap_or(0));
+        let owned = selling
+            .chain(sending)
+            .try_fold(held, |total, quantity| total.checked_add(Shares(quantity)))?;
+        Ok(owned.saturating_sub(Shares(*self.borrowed.get(ticker).unwrap_or(&0))))
+    }
+
+    pub fn get_ledger(&self) -> &[LedgerEntry] {
+        &self.ledger
+    }
+
+    pub fn get_stock_ledger(&self) -> &[StockEntry] {
+        &self.stock_ledger
     }
 
     pub async fn delete(&mut self) -> Result<(), String> {
-        if self.balance != 0 {
+        if let Some((currency, &cash)) = self.cash.iter().find(|(_, &cash)| cash != 0) {
+            return Err(format!(
+                "Can't delete account, balance not zero: {} {currency}",
+                cash as f64 / 100.0
+            ));
+        }
+        if !self.transfers.is_empty() {
+            return Err("Can't delete account, transfers are still pending".to_owned());
+        }
+        if !self.deposits.is_empty() {
+            return Err("Can't delete account, deposits are still pending".to_owned());
+        }
+        if !self.owed_fees.is_empty() {
+            return Err("Can't delete account, fees are still owed".to_owned());
+        }
+        if !self.borrowed.is_empty() {
+            return Err(format!(
+                "Can't delete account, borrowed stock is owed: {:?}",
+                self.borrowed
+            ));
+        }
+        if !self.loan.is_empty() {
             return Err(format!(
-                "Can't delete account, balance not zero: {}",
-                self.balance as f64 / 100.0
+                "Can't delete account, margin loan is owed: {:?}",
+                self.loan
             ));
         }
         if self.portfolio.iter().any(|(_, q)| q != &0) {
@@ -315,23 +1467,170 @@ impl Account {
                 self.sells
             ));
         }
-        fs::remove_file(&self.path)
-            .await
-            .map_err(|e| format!("Internal server error {e}"))
+        self.storage()
+            .commit(vec![Op::delete(self.id.id.to_string())])
+            .await
+            .map_err(|e| format!("Internal server error {e}"))
+    }
+
+    /// Cash of `currency` not held for pending trades, their fees or transfers. Nothing if they
+    /// hold more than the account has
+    pub fn get_balance(&self, currency: &Currency) -> CentCount {
+        let held = Money::saturating_sum(
+            self.pending
+                .iter()
+                .map(|(trade_id, trade)| self.held(*trade_id, trade, currency)),
+        );
+        Money(*self.cash.get(currency).unwrap_or(&0))
+            .saturating_sub(held.saturating_add(self.sending(currency)))
+            .0
+    }
+
+    /// Cash of `currency` held for outgoing transfers
+    fn sending(&self, currency: &Currency) -> Money {
+        Money::saturating_sum(self.transfers.values().filter_map(
+            |transfer| match &transfer.asset {
+                Asset::Cash(cash) if &cash.currency == currency => Some(Money(cash.amount)),
+                _ => None,
+            },
+        ))
+    }
+
+    /// Balance in every currency of the registry, and any other the account still has
+    pub fn get_balances(&self) -> HashMap<Currency, CentCount> {
+        self.instruments
+            .currencies
+            .iter()
+            .chain(self.cash.keys())
+            .map(|currency| (currency.clone(), self.get_balance(currency)))
+            .collect()
+    }
+
+    /// `currency` a pending trade holds until it's committed: the price and fee of a buy, or
+    /// the base currency of a sold FX pair
+    fn held(&self, trade_id: TradeID, trade: &Trade, currency: &Currency) -> Money {
+        let quantity = Shares(trade.quantity);
+        if trade.buyer_id == self.id {
+            if self.instruments.currency(&trade.ticker) != currency {
+                return Money::default();
+            }
+            let fee = Money(*self.pending_fees.get(&trade_id).unwrap_or(&0));
+            quantity
+                .times(trade.price)
+                .unwrap_or(Money::MAX)
+                .saturating_add(fee)
+        } else {
+            match self.instruments.fx(&trade.ticker) {
+                Some((base, lot)) if base == currency => quantity.times(lot).unwrap_or(Money::MAX),
+                _ => Money::default(),
+            }
+        }
+    }
+
+    /// Cash of `currency` that's neither held for pending trades nor reserved by orders
+    pub fn get_free_cash(&self, currency: &Currency) -> CentCount {
+        Money(self.get_balance(currency))
+            .saturating_sub(self.get_order_amount(currency))
+            .0
+    }
+
+    /// Cash of `currency` that can leave the account: what's free less fees owed, and for a
+    /// margin account no more than leaves the equity its positions and buy orders need
+    pub fn get_withdrawable(&self, currency: &Currency) -> CentCount {
+        let free = self
+            .get_free_cash(currency)
+            .saturating_sub(self.owed(currency));
+        let Some(terms) = self.margin else {
+            return free;
+        };
+        let usage = self.usage(currency);
+        free.min(cents(usage.equity - usage.required(terms)))
+    }
+
+    /// Ask for a deposit, it waits for an admin to confirm the cash arrived. One that could never
+    /// be credited is refused now
+    pub async fn request_deposit(&mut self, cash: Cash) -> GResult<DepositID> {
+        self.balance_after(LedgerAccount::External, LedgerAccount::User(self.id), &cash)?;
+        let id = self.next_deposit_id;
+        self.next_deposit_id += 1;
+        self.deposits.insert(id, cash);
+        self.update_file().await?;
+        Ok(id)
+    }
+
+    pub fn get_deposits(&self) -> &HashMap<DepositID, Cash> {
+        &self.deposits
+    }
+
+    /// Credit the deposit if `accepted`, drop it otherwise. `record` is the audit record
+    /// committed with it
+    pub async fn confirm_deposit(
+        &mut self,
+        id: DepositID,
+        accepted: bool,
+        record: Op,
+    ) -> GResult<()> {
+        let cash = self.deposits.get(&id).cloned().ok_or_else(|| {
+            ErrorResponse::new(
+                ErrorCode::NotFound,
+                format!("No deposit {id} of {}", self.id),
+            )
+        })?;
+        if accepted {
+            self.record(
+                LedgerAccount::External,
+                LedgerAccount::User(self.id),
+                cash,
+                Memo::Deposit,
+            )?;
+        }
+        self.deposits.remove(&id);
+        let mut ops = self.ops()?;
+        ops.push(record);
+        self.storage().commit(ops).await
     }
 
-    pub fn get_balance(&self) -> u64 {
-        self.balance
+    /// false if the cash is needed for orders, or a margin account's positions
+    pub async fn withdraw(&mut self, cash: Cash) -> GResult<bool> {
+        if cash.amount > self.get_withdrawable(&cash.currency) {
+            return Ok(false);
+        }
+        self.record(
+            LedgerAccount::User(self.id),
+            LedgerAccount::External,
+            cash,
+            Memo::Withdrawal,
+        )?;
+        self.update_file().await?;
+        Ok(true)
     }
 
-    pub async fn set_balance(&mut self, value: u64) -> GResult<bool> {
-        if value < self.get_buy_order_amount() {
-            Ok(false)
+    /// An admin's deposit or, if negative, withdrawal. `record` is the audit record committed
+    /// with it. false if the cash is needed for orders
+    pub async fn adjust(
+        &mut self,
+        currency: Currency,
+        amount: i64,
+        by: UserID,
+        record: Op,
+    ) -> GResult<bool> {
+        let (from, to) = if amount < 0 {
+            if amount.unsigned_abs() > self.get_withdrawable(&currency) {
+                return Ok(false);
+            }
+            (LedgerAccount::User(self.id), LedgerAccount::External)
         } else {
-            self.balance = value;
-            self.update_file().await?;
-            Ok(true)
-        }
+            (LedgerAccount::External, LedgerAccount::User(self.id))
+        };
+        let cash = Cash {
+            currency,
+            amount: amount.unsigned_abs(),
+        };
+        self.record(from, to, cash, Memo::Adjustment { by })?;
+        let mut ops = self.ops()?;
+        ops.push(record);
+        self.storage().commit(ops).await?;
+        Ok(true)
     }
 
     pub fn get_portfolio(&self) -> &HashMap<Ticker, Quantity> {
@@ -356,9 +1655,13 @@ impl Account {
         AllOrders(all_orders)
     }
 
-    pub async fn add_stock(&mut self, t: Ticker, q: Quantity) -> GResult<()> {
-        *self.portfolio.entry(t).or_default() += q;
-        self.update_file().await
+    /// `record` is the audit record committed with the new stock
+    pub async fn add_stock(&mut self, t: Ticker, q: Quantity, record: Op) -> GResult<()> {
+        self.change_stock(&t, |held| held.checked_add(Shares(q)))?;
+        self.move_stock(&t, q.into(), StockMemo::Listed);
+        let mut ops = self.ops()?;
+        ops.push(record);
+        self.storage().commit(ops).await
     }
 
     pub async fn deduct_stock(&mut self, t: Ticker, q: Quantity) -> GResult<Quantity> {
@@ -369,25 +1672,149 @@ impl Account {
         Ok(deducted)
     }
 
-    pub fn get_buy_order_amount(&self) -> CentCount {
-        self.buys
+    /// Cash of `currency` reserved by orders: buys priced in it with their fees, sells of FX
+    /// pairs with it as the base, and collateral of borrowed stock priced in it
+    fn get_order_amount(&self, currency: &Currency) -> Money {
+        let buys = self
+            .buys
+            .iter()
+            .filter(|(ticker, _)| self.instruments.currency(ticker) == currency)
+            .flat_map(|(ticker, orders)| {
+                orders.iter().map(|(&price, &quantity)| {
+                    Shares(quantity)
+                        .times(price)
+                        .and_then(|amount| self.with_fee_reserve(ticker, amount))
+                        .unwrap_or(Money::MAX)
+                })
+            });
+        let sells =
+            self.sells
+                .iter()
+                .filter_map(|(ticker, orders)| match self.instruments.fx(ticker) {
+                    Some((base, lot)) if base == currency => Some(
+                        Self::total(orders)
+                            .and_then(|quantity| quantity.times(lot))
+                            .unwrap_or(Money::MAX),
+                    ),
+                    _ => None,
+                });
+        let collateral = self
+            .collateral
+            .iter()
+            .filter(|(ticker, _)| self.instruments.currency(ticker) == currency)
+            .map(|(_, &collateral)| Money(collateral));
+        Money::saturating_sum(buys.chain(sells).chain(collateral))
+    }
+
+    /// `amount` and what buying that much of `ticker` reserves for fees
+    fn with_fee_reserve(&self, ticker: &Ticker, amount: Money) -> Result<Money, ErrorResponse> {
+        amount.checked_add(Money(self.fees.reserve(ticker, self.volume, amount.0)))
+    }
+
+    /// Quantity of all the orders at every price
+    fn total(orders: &HashMap<CentCount, Quantity>) -> Result<Shares, ErrorResponse> {
+        orders
             .values()
-            .map(|orders| {
-                orders
-                    .iter()
-                    .map(|(price, quantity)| price * quantity)
-                    .sum::<u64>()
+            .try_fold(Shares::default(), |total, &quantity| {
+                total.checked_add(Shares(quantity))
             })
-            .sum()
     }
 
     pub fn get_sell_order_quantity(&self, ticker: &Ticker) -> Quantity {
-        match self.sells.get(ticker) {
-            Some(s) => s,
-            None => return 0,
+        self.sells
+            .get(ticker)
+            .map_or(Ok(Shares::default()), Self::total)
+            .unwrap_or(Shares(Quantity::MAX))
+            .0
+    }
+
+    /// Reject an order over one of the account's limits, with the limit's code. Counts towards
+    /// the rate once it's under the others
+    pub fn check_limits(
+        &mut self,
+        OrderReq {
+            order_type,
+            ticker,
+            price,
+            quantity,
+        }: &OrderReq,
+    ) -> Result<(), ErrorResponse> {
+        let RiskLimits {
+            max_order_quantity,
+            max_notional,
+            max_open_orders,
+            max_position,
+            max_orders_per_sec,
+        } = self.limits;
+        while self
+            .placed
+            .front()
+            .is_some_and(|at| at.elapsed() >= Duration::from_secs(1))
+        {
+            self.placed.pop_front();
+        }
+        if let Some(max) = max_orders_per_sec.filter(|&max| self.placed.len() as u64 >= max) {
+            return Err(ErrorResponse::new(
+                ErrorCode::RateLimited,
+                format!("At most {max} orders a second"),
+            ));
+        }
+        if let Some(max) = max_order_quantity.filter(|&max| *quantity > max) {
+            return Err(ErrorResponse::new(
+                ErrorCode::OrderTooLarge,
+                format!("At most {max} shares an order"),
+            ));
+        }
+        let notional = u128::from(*quantity) * u128::from(*price);
+        if let Some(max) = max_notional.filter(|&max| notional > u128::from(max)) {
+            return Err(ErrorResponse::new(
+                ErrorCode::NotionalTooLarge,
+                format!("Orders worth at most {max}"),
+            ));
+        }
+        let orders = match order_type {
+            OrderType::Buy => &self.buys,
+            OrderType::Sell => &self.sells,
+        };
+        // orders at a price already resting join that order
+        let resting = orders
+            .get(ticker)
+            .and_then(|levels| levels.get(price))
+            .is_some_and(|&resting| resting > 0);
+        let open = self
+            .buys
+            .values()
+            .chain(self.sells.values())
+            .flat_map(HashMap::values)
+            .filter(|&&resting| resting > 0)
+            .count() as u64;
+        if let Some(max) = max_open_orders.filter(|&max| !resting && open >= max) {
+            return Err(ErrorResponse::new(
+                ErrorCode::TooManyOrders,
+                format!("At most {max} open orders"),
+            ));
+        }
+        if let (Some(max), None) = (max_position, self.fx(ticker)) {
+            let open = |orders: &HashMap<Ticker, HashMap<CentCount, Quantity>>| {
+                orders
+                    .get(ticker)
+                    .map_or(0, |levels| levels.values().map(|&q| i128::from(q)).sum())
+            };
+            let quantity = i128::from(*quantity);
+            let position = self.position(ticker);
+            let over = match order_type {
+                OrderType::Buy => position + open(&self.buys) + quantity > i128::from(max),
+                OrderType::Sell => position - open(&self.sells) - quantity < -i128::from(max),
+            };
+            if over {
+                return Err(ErrorResponse::new(
+                    ErrorCode::PositionTooLarge,
+                    format!("At most {max} {ticker} long or short, counting open orders"),
+                ));
+            }
         }
-        .values()
-        .sum()
+        self.placed.push_back(Instant::now());
+        Ok(())
     }
 
     /// Attempt to add order to the account
@@ -401,19 +1828,50 @@ impl Account {
         }: OrderReq,
     ) -> GResult<bool> {
         // check if order can be added
-        match order_type {
-            OrderType::Buy => {
-                if self.balance - self.get_buy_order_amount() < quantity * price {
+        let held = Shares(*self.portfolio.get(&ticker).unwrap_or(&0));
+        match (order_type, self.fx(&ticker)) {
+            (OrderType::Buy, fx) => {
+                let quote = self.quote(&ticker);
+                let amount = self.with_fee_reserve(&ticker, Shares(quantity).times(price)?)?;
+                let buying = self
+                    .buys
+                    .get(&ticker)
+                    .map_or(Ok(Shares::default()), Self::total)?;
+                // buying back borrowed shares can use their collateral
+                let covering = Shares(*self.borrowed.get(&ticker).unwrap_or(&0))
+                    .saturating_sub(buying)
+                    >= Shares(quantity);
+                let collateral = match self.collateral.get(&ticker) {
+                    Some(&collateral) if covering => Money(collateral),
+                    _ => Money::default(),
+                };
+                let enough = match (self.margin, &fx) {
+                    // stock is bought on credit, lent once it's paid for
+                    (Some(terms), None) => {
+                        self.usage(&quote).buying_power(terms) >= i128::from(amount.0)
+                    }
+                    _ => Money(self.get_free_cash(&quote)).saturating_add(collateral) >= amount,
+                };
+                if !enough {
                     // too many orders, not enough money
                     return Ok(false);
                 }
+                if fx.is_none() {
+                    // the portfolio must hold what every buy could fill
+                    held.checked_add(buying)?.checked_add(Shares(quantity))?;
+                }
+            }
+            (OrderType::Sell, Some((base, lot))) => {
+                if Money(self.get_free_cash(&base)) < Shares(quantity).times(lot)? {
+                    // too many orders, not enough of the currency sold
+                    return Ok(false);
+                }
             }
-            OrderType::Sell => {
-                if *self.portfolio.get(&ticker).unwrap_or(&0)
-                    - self.get_sell_order_quantity(&ticker)
-                    < quantity
-                {
-                    // too many orders, not enough stock
+            (OrderType::Sell, None) => {
+                let free = held.saturating_sub(Shares(self.get_sell_order_quantity(&ticker)));
+                let short = Shares(quantity).saturating_sub(free);
+                if short > Shares::default() && !self.borrow(&ticker, short, price)? {
+                    // too many orders, not enough stock and none borrowed
                     return Ok(false);
                 }
             }
@@ -424,11 +1882,412 @@ impl Account {
             OrderType::Sell => &mut self.sells,
         };
 
-        *orders.entry(ticker).or_default().entry(price).or_default() += quantity;
+        let level = orders.entry(ticker).or_default().entry(price).or_default();
+        *level = Shares(*level).checked_add(Shares(quantity))?.0;
         self.update_file().await?;
         Ok(true)
     }
 
+    /// Borrow `shares` for a short sale at `price`, into the portfolio, and set their collateral
+    /// aside. false if the account can't short, the cash isn't free or the node has too few to
+    /// lend. Doesn't persist
+    fn borrow(
+        &mut self,
+        ticker: &Ticker,
+        shares: Shares,
+        price: CentCount,
+    ) -> Result<bool, ErrorResponse> {
+        if !self.can_short {
+            return Ok(false);
+        }
+        let collateral = short_collateral(shares.times(price)?)?;
+        if Money(self.get_free_cash(&self.quote(ticker))) < collateral {
+            return Ok(false);
+        }
+        let held = Shares(*self.portfolio.get(ticker).unwrap_or(&0)).checked_add(shares)?;
+        let borrowed = Shares(*self.borrowed.get(ticker).unwrap_or(&0)).checked_add(shares)?;
+        let collateral =
+            Money(*self.collateral.get(ticker).unwrap_or(&0)).checked_add(collateral)?;
+        if !self.lending.lend(ticker, shares.0) {
+            return Ok(false);
+        }
+        self.portfolio.insert(ticker.clone(), held.0);
+        self.borrowed.insert(ticker.clone(), borrowed.0);
+        self.collateral.insert(ticker.clone(), collateral.0);
+        Ok(true)
+    }
+
+    /// Give borrowed shares no sell order needs back to the node, with their part of the
+    /// collateral. Doesn't persist
+    fn give_back(&mut self) {
+        let tickers: Vec<Ticker> = self.borrowed.keys().cloned().collect();
+        for ticker in tickers {
+            let free = Shares(*self.portfolio.get(&ticker).unwrap_or(&0))
+                .saturating_sub(Shares(self.get_sell_order_quantity(&ticker)));
+            let borrowed = self.borrowed[&ticker];
+            let returned = free.0.min(borrowed);
+            if returned == 0 {
+                continue;
+            }
+            self.change_stock(&ticker, |held| held.checked_sub(Shares(returned)))
+                .expect("free in the portfolio");
+            self.lending.give_back(&ticker, returned);
+            let owed = borrowed - returned;
+            if owed == 0 {
+                self.borrowed.remove(&ticker);
+                self.collateral.remove(&ticker);
+                continue;
+            }
+            self.borrowed.insert(ticker.clone(), owed);
+            // what's still owed keeps its share, rounded up
+            let collateral = self.collateral.entry(ticker).or_default();
+            let kept = (u128::from(*collateral) * u128::from(owed)).div_ceil(u128::from(borrowed));
+            *collateral = CentCount::try_from(kept).expect("less than before");
+        }
+    }
+
+    /// What a margin account is short of to pay `amount` of `currency`, what `fund` lends it.
+    /// Nothing for other accounts
+    fn loan_for(&self, currency: &Currency, amount: Money) -> Result<Money, ErrorResponse> {
+        if self.margin.is_none() {
+            return Ok(Money::default());
+        }
+        let short = amount.saturating_sub(Money(self.get_balance(currency)));
+        Money(*self.loan.get(currency).unwrap_or(&0)).checked_add(short)?;
+        Ok(short)
+    }
+
+    /// Lend a margin account what it's short of to pay `amount` of `currency`, as a ledger
+    /// entry from outside. Doesn't persist
+    fn fund(&mut self, currency: &Currency, amount: Money) -> Result<(), ErrorResponse> {
+        let short = self.loan_for(currency, amount)?;
+        if short == Money::default() {
+            return Ok(());
+        }
+        let loan = Money(*self.loan.get(currency).unwrap_or(&0)).checked_add(short)?;
+        let cash = Cash {
+            currency: currency.clone(),
+            amount: short.0,
+        };
+        let me = LedgerAccount::User(self.id);
+        self.record(LedgerAccount::External, me, cash, Memo::MarginLoan)?;
+        self.loan.insert(currency.clone(), loan.0);
+        Ok(())
+    }
+
+    /// Pay margin loans back from cash no order or pending trade needs. Doesn't persist
+    fn repay(&mut self) {
+        let currencies: Vec<Currency> = self.loan.keys().cloned().collect();
+        for currency in currencies {
+            let owed = self.loan[&currency];
+            let repaid = owed.min(self.get_free_cash(&currency));
+            if repaid == 0 {
+                continue;
+            }
+            let cash = Cash {
+                currency: currency.clone(),
+                amount: repaid,
+            };
+            let me = LedgerAccount::User(self.id);
+            self.record(me, LedgerAccount::External, cash, Memo::MarginRepayment)
+                .expect("repaid from free cash");
+            if owed == repaid {
+                self.loan.remove(&currency);
+            } else {
+                self.loan.insert(currency, owed - repaid);
+            }
+        }
+    }
+
+    /// Whether `ticker` is stock priced in `currency`, an FX pair is cash
+    fn is_stock_in(&self, ticker: &Ticker, currency: &Currency) -> bool {
+        self.fx(ticker).is_none() && self.instruments.currency(ticker) == currency
+    }
+
+    /// Shares of `ticker` held, those of pending sells included, less what's borrowed. Below
+    /// zero for a short position
+    fn position(&self, ticker: &Ticker) -> i128 {
+        let selling: i128 = self
+            .pending
+            .values()
+            .filter(|trade| &trade.ticker == ticker && trade.seller_id == self.id)
+            .map(|trade| i128::from(trade.quantity))
+            .sum();
+        i128::from(*self.portfolio.get(ticker).unwrap_or(&0)) + selling
+            - i128::from(*self.borrowed.get(ticker).unwrap_or(&0))
+    }
+
+    fn usage(&self, currency: &Currency) -> Usage {
+        let tickers: HashSet<&Ticker> = self
+            .portfolio
+            .keys()
+            .chain(self.borrowed.keys())
+            .chain(self.pending.values().map(|trade| &trade.ticker))
+            .filter(|ticker| self.is_stock_in(ticker, currency))
+            .collect();
+        let mut equity = i128::from(*self.cash.get(currency).unwrap_or(&0))
+            - i128::from(self.sending(currency).0)
+            - i128::from(*self.loan.get(currency).unwrap_or(&0));
+        let mut exposure = 0;
+        for ticker in tickers {
+            let value = self.position(ticker) * i128::from(self.marks.get(ticker).unwrap_or(0));
+            equity += value;
+            exposure += value.abs();
+        }
+        let buying = self
+            .buys
+            .iter()
+            .filter(|(ticker, _)| self.is_stock_in(ticker, currency))
+            .flat_map(|(_, levels)| levels.iter())
+            .map(|(&price, &quantity)| i128::from(price) * i128::from(quantity))
+            .sum();
+        Usage {
+            equity,
+            exposure,
+            buying,
+        }
+    }
+
+    /// Every currency of the registry, and any other a loan is in
+    fn margin_currencies(&self) -> HashSet<Currency> {
+        self.instruments
+            .currencies
+            .iter()
+            .chain(self.loan.keys())
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_limits(&self) -> RiskLimits {
+        self.limits
+    }
+
+    /// `record` is the audit record committed with them
+    pub async fn set_limits(&mut self, limits: RiskLimits, record: Op) -> GResult<()> {
+        self.limits = limits;
+        let mut ops = self.ops()?;
+        ops.push(record);
+        self.storage().commit(ops).await
+    }
+
+    pub fn killed_by(&self) -> Option<KilledBy> {
+        self.killed
+    }
+
+    /// `record` is the audit record committed with it, if an admin set it
+    pub async fn set_killed(
+        &mut self,
+        killed: Option<KilledBy>,
+        record: Option<Op>,
+    ) -> GResult<()> {
+        self.killed = killed;
+        let mut ops = self.ops()?;
+        ops.extend(record);
+        self.storage().commit(ops).await
+    }
+
+    pub fn get_margin(&self) -> MarginStatus {
+        let currencies = match self.margin {
+            Some(terms) => {
+                self.margin_currencies()
+                    .into_iter()
+                    .map(|currency| {
+                        let usage = self.usage(&currency);
+                        let margin =
+                            MarginUsage {
+                                equity: i64::try_from(usage.equity)
+                                    .unwrap_or(if usage.equity < 0 { i64::MIN } else { i64::MAX }),
+                                exposure: cents(usage.exposure),
+                                loan: *self.loan.get(&currency).unwrap_or(&0),
+                                buying_power: cents(usage.buying_power(terms)),
+                            };
+                        (currency, margin)
+                    })
+                    .collect()
+            }
+            None => HashMap::new(),
+        };
+        MarginStatus {
+            terms: self.margin,
+            liquidating: self.liquidating,
+            currencies,
+        }
+    }
+
+    /// `record` is the audit record committed with it. Only once it owes nothing can it be a
+    /// cash account again
+    pub async fn set_margin(&mut self, terms: Option<MarginTerms>, record: Op) -> GResult<()> {
+        if terms.is_none() && !self.loan.is_empty() {
+            return Err(ErrorResponse::new(
+                ErrorCode::NotEmpty,
+                format!("{} still owes a margin loan: {:?}", self.id, self.loan),
+            )
+            .into());
+        }
+        self.margin = terms;
+        self.liquidating &= terms.is_some();
+        let mut ops = self.ops()?;
+        ops.push(record);
+        self.storage().commit(ops).await
+    }
+
+    pub fn is_liquidating(&self) -> bool {
+        self.liquidating
+    }
+
+    /// Holds, owes or is trading any of `ticker`, so its mark moving changes the equity
+    pub fn has_position_in(&self, ticker: &Ticker) -> bool {
+        self.portfolio.contains_key(ticker)
+            || self.borrowed.contains_key(ticker)
+            || self.pending.values().any(|trade| &trade.ticker == ticker)
+    }
+
+    /// Currencies a margin account is under its maintenance margin in
+    pub fn breached(&self) -> Vec<Currency> {
+        let Some(terms) = self.margin else {
+            return Vec::new();
+        };
+        self.margin_currencies()
+            .into_iter()
+            .filter(|currency| self.usage(currency).breached(terms))
+            .collect()
+    }
+
+    /// Every open order, only those of stock priced in `currency` if there's one
+    pub fn open_orders(&self, currency: Option<&Currency>) -> Vec<OrderReq> {
+        [(OrderType::Buy, &self.buys), (OrderType::Sell, &self.sells)]
+            .into_iter()
+            .flat_map(|(order_type, orders)| {
+                orders
+                    .iter()
+                    .filter(move |(ticker, _)| {
+                        currency.is_none_or(|currency| self.is_stock_in(ticker, currency))
+                    })
+                    .flat_map(move |(ticker, levels)| {
+                        levels.iter().filter(|(_, &quantity)| quantity > 0).map(
+                            move |(&price, &quantity)| OrderReq {
+                                order_type,
+                                ticker: ticker.clone(),
+                                price,
+                                quantity,
+                            },
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Orders closing every position in `currency` once its orders are cancelled: sells of
+    /// what's held `slippage_pct` under the mark and buys of what's owed as much over it.
+    /// Positions without a mark are worth nothing and left
+    pub fn liquidation(&self, currency: &Currency, slippage_pct: u64) -> Vec<OrderReq> {
+        let mut orders = Vec::new();
+        for (ticker, &held) in &self.portfolio {
+            let borrowed = *self.borrowed.get(ticker).unwrap_or(&0);
+            if held <= borrowed || !self.is_stock_in(ticker, currency) {
+                continue;
+            }
+            let Some(mark) = self.marks.get(ticker) else {
+                continue;
+            };
+            let price = u128::from(mark) * u128::from(100 - slippage_pct.min(100)) / 100;
+            orders.push(OrderReq {
+                order_type: OrderType::Sell,
+                ticker: ticker.clone(),
+                price: CentCount::try_from(price).unwrap_or(CentCount::MAX).max(1),
+                quantity: held - borrowed,
+            });
+        }
+        for (ticker, &borrowed) in &self.borrowed {
+            let held = *self.portfolio.get(ticker).unwrap_or(&0);
+            if borrowed <= held || !self.is_stock_in(ticker, currency) {
+                continue;
+            }
+            let Some(mark) = self.marks.get(ticker) else {
+                continue;
+            };
+            let price = (u128::from(mark) * u128::from(100 + slippage_pct)).div_ceil(100);
+            orders.push(OrderReq {
+                order_type: OrderType::Buy,
+                ticker: ticker.clone(),
+                price: CentCount::try_from(price).unwrap_or(CentCount::MAX),
+                quantity: borrowed - held,
+            });
+        }
+        orders
+    }
+
+    /// Place forced `orders` without the checks of `add_order`, what's bought is lent, and stop
+    /// the account placing or cancelling its own until it's back over its maintenance margin
+    pub async fn liquidate(&mut self, orders: &[OrderReq]) -> GResult<()> {
+        for order in orders {
+            let orders = match order.order_type {
+                OrderType::Buy => &mut self.buys,
+                OrderType::Sell => &mut self.sells,
+            };
+            let level = orders
+                .entry(order.ticker.clone())
+                .or_default()
+                .entry(order.price)
+                .or_default();
+            *level = Shares(*level).checked_add(Shares(order.quantity))?.0;
+        }
+        self.liquidating = true;
+        self.update_file().await
+    }
+
+    pub async fn stop_liquidating(&mut self) -> GResult<()> {
+        self.liquidating = false;
+        self.update_file().await
+    }
+
+    pub fn get_borrowed(&self) -> HashMap<Ticker, Borrowed> {
+        self.borrowed
+            .iter()
+            .map(|(ticker, &quantity)| {
+                let collateral = Cash {
+                    currency: self.quote(ticker),
+                    amount: *self.collateral.get(ticker).unwrap_or(&0),
+                };
+                (
+                    ticker.clone(),
+                    Borrowed {
+                        quantity,
+                        collateral,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Marked to the last trade price on this node
+    pub fn get_positions(&self) -> HashMap<Ticker, Position> {
+        self.costs
+            .iter()
+            .filter(|(ticker, basis)| !basis.is_empty() && self.fx(ticker).is_none())
+            .map(|(ticker, basis)| (ticker.clone(), basis.position(self.marks.get(ticker))))
+            .collect()
+    }
+
+    /// Role the account has now, `token` is the one its session token carries
+    pub fn role(&self, token: Role) -> Role {
+        self.role.unwrap_or(token)
+    }
+
+    pub async fn set_role(&mut self, role: Role) -> GResult<()> {
+        self.role = Some(role);
+        let ops = self.ops()?;
+        self.storage().commit(ops).await
+    }
+
+    /// `record` is the audit record committed with it
+    pub async fn set_can_short(&mut self, allowed: bool, record: Op) -> GResult<()> {
+        self.can_short = allowed;
+        let mut ops = self.ops()?;
+        ops.push(record);
+        self.storage().commit(ops).await
+    }
+
     /// can come from trade request or cancel order
     pub async fn deduct_order(
         &mut self,
@@ -452,17 +2311,18 @@ impl Account {
     }
 
     /// accept or reject a trade offer, modifying to account in case accepted
-    /// Return order deducted if accepted
-    pub async fn process_incoming_offer(&mut self, trade: Trade) -> GResult<Option<Order>> {
+    /// Return order deducted if accepted, doesn't persist
+    fn accept_offer(&mut self, trade: &Trade) -> Option<Order> {
         let Trade {
             quantity,
             price,
-            ticker,
+            ref ticker,
             buyer_id,
             seller_id,
             buy_price,
             sell_price,
-        } = trade;
+            ..
+        } = *trade;
 
         let order_price = if buyer_id == self.id {
             buy_price
@@ -483,53 +2343,162 @@ impl Account {
             .or_default();
         if *current_order_quantity < quantity {
             println!("rejected order: {quantity} {current_order_quantity}");
-            return Ok(None);
+            return None;
         }
 
-        if buyer_id == self.id {
-            let to_deduct = quantity * price;
-            if self.balance < to_deduct {
-                println!("rejected quantity: {} {to_deduct}", self.balance);
-                return Ok(None);
+        let fx = self.fx(ticker);
+        let held = Shares(*self.portfolio.get(ticker).unwrap_or(&0));
+        let quote = self.quote(ticker);
+        // what a margin account borrows to pay for it, lent once nothing can reject the offer
+        let mut to_fund = None;
+        let mut lent = Money::default();
+        let portfolio = if buyer_id == self.id {
+            let Ok(to_deduct) = Shares(quantity).times(price) else {
+                println!("rejected amount: {quantity} {price}");
+                return None;
+            };
+            lent = match self.loan_for(&quote, to_deduct) {
+                Ok(lent) => lent,
+                Err(e) => {
+                    println!("rejected loan: {e}");
+                    return None;
+                }
+            };
+            to_fund = Some(to_deduct);
+            let balance = self.get_balance(&quote);
+            if Money(balance).saturating_add(lent) < to_deduct {
+                println!("rejected quantity: {balance} {}", to_deduct.0);
+                return None;
             }
-            // commit
-            self.balance -= to_deduct;
-            *self.portfolio.entry(ticker.clone()).or_default() += quantity;
-        } else if seller_id == self.id {
-            let current_quantity = self.portfolio.entry(ticker.clone()).or_default();
-            if *current_quantity < quantity {
-                println!("rejected stock: {quantity} {current_quantity}");
-                return Ok(None);
+            held.checked_add(Shares(quantity))
+        } else if let (true, Some((base, lot))) = (seller_id == self.id, &fx) {
+            // the base currency is paid with the trade, checked when it's recorded
+            let balance = self.get_balance(base);
+            if Shares(quantity)
+                .times(*lot)
+                .map_or(true, |to_sell| Money(balance) < to_sell)
+            {
+                println!("rejected currency: {balance} {quantity}");
+                return None;
             }
-            // commit
-            *current_quantity -= quantity;
-            self.balance += quantity * price;
+            Ok(held)
+        } else if seller_id == self.id {
+            held.checked_sub(Shares(quantity))
         } else {
             panic!("This trade doesn't belong to this user");
+        };
+        let portfolio = match portfolio {
+            Ok(portfolio) => portfolio,
+            Err(e) => {
+                println!("rejected stock: {quantity} {}, {e}", held.0);
+                return None;
+            }
+        };
+        if let Err(e) = self.trade_legs(trade, lent) {
+            println!("rejected trade: {e}");
+            return None;
+        }
+        // commit
+        if let Some(to_deduct) = to_fund {
+            self.fund(&quote, to_deduct)
+                .expect("Invalid trade, loan checked above");
+        }
+        self.record_trade(trade)
+            .expect("Invalid trade, balance checked above");
+        if fx.is_none() {
+            self.portfolio.insert(ticker.clone(), portfolio.0);
         }
 
-        *current_order_quantity -= quantity;
-        self.update_file().await?;
-        Ok(Some(Order {
+        let current_orders = if buyer_id == self.id {
+            &mut self.buys
+        } else {
+            &mut self.sells
+        };
+        *current_orders
+            .get_mut(ticker)
+            .and_then(|orders| orders.get_mut(&order_price))
+            .expect("checked above") -= quantity;
+        Some(Order {
             quantity,
             order_type: if buyer_id == self.id {
                 OrderType::Buy
             } else {
                 OrderType::Sell
             },
-            ticker,
+            ticker: ticker.clone(),
             user_id: self.id,
             price: order_price,
-        }))
+        })
+    }
+
+    /// apply this account's side of a trade where both sides are on this node,
+    /// doesn't persist, the caller commits both accounts together
+    fn settle(&mut self, trade: &Trade) {
+        let Trade {
+            quantity,
+            price,
+            ticker,
+            buyer_id,
+            seller_id,
+            buy_price,
+            sell_price,
+            ..
+        } = trade;
+        let fx = self.fx(ticker);
+        let traded = Shares(*quantity);
+        let (orders, order_price) = if buyer_id == &self.id {
+            let quote = self.quote(ticker);
+            let cost = traded.times(*price).expect("reserved by the order");
+            self.fund(&quote, cost)
+                .expect("Invalid trade, loan out of range");
+            assert!(
+                cost <= Money(self.get_balance(&quote)),
+                "Invalid trade, not enough balance"
+            );
+            self.record_trade(trade)
+                .expect("Invalid trade, balance out of range");
+            if fx.is_none() {
+                self.change_stock(ticker, |held| held.checked_add(traded))
+                    .expect("Invalid trade, stock out of range");
+            }
+            (&mut self.buys, buy_price)
+        } else if seller_id == &self.id {
+            if let Some((base, lot)) = fx {
+                assert!(
+                    traded.times(lot).expect("reserved by the order")
+                        <= Money(self.get_balance(&base)),
+                    "Invalid trade, not enough currency"
+                );
+                self.record_trade(trade)
+                    .expect("Invalid trade, balance out of range");
+            } else {
+                self.record_trade(trade)
+                    .expect("Invalid trade, balance out of range");
+                self.change_stock(ticker, |held| held.checked_sub(traded))
+                    .expect("Invalid trade, not enough stock");
+            }
+            (&mut self.sells, sell_price)
+        } else {
+            panic!("This trade doesn't belong to this user");
+        };
+        let current_order_quantity = orders
+            .entry(ticker.clone())
+            .or_default()
+            .entry(*order_price)
+            .or_default();
+        assert!(
+            quantity <= current_order_quantity,
+            "Invalid trade, not enough order {trade:?}"
+        );
+        *current_order_quantity -= quantity;
     }
 
-    /// this function assume the trade will succeed
-    pub async fn add_pending(&mut self, trade_id: TradeID, trade: Trade) -> GResult<()> {
+    /// this function assume the trade will succeed, doesn't persist
+    fn add_pending(&mut self, trade_id: TradeID, trade: Trade) {
         assert!(
-            self.pending.get(&trade_id).is_none(),
+            !self.pending.contains_key(&trade_id),
             "duplicate trade id??"
         );
-        self.pending.insert(trade_id, trade.clone());
         let Trade {
             quantity,
             price,
@@ -538,24 +2507,34 @@ impl Account {
             seller_id,
             buy_price,
             sell_price,
+            ..
         } = trade.clone();
         if buyer_id == self.id {
-            let to_deduct = quantity * price;
+            // held until the trade is committed or aborted
+            let to_hold = Shares(quantity)
+                .times(price)
+                .expect("reserved by the order");
+            let quote = self.quote(&ticker);
+            self.fund(&quote, to_hold)
+                .expect("Invalid trade, loan out of range");
             assert!(
-                to_deduct <= self.balance,
+                to_hold <= Money(self.get_balance(&quote)),
                 "Invalid trade, not enough balance"
             );
-            self.balance -= to_deduct;
-        } else if seller_id == self.id {
-            let current_quantity = self.portfolio.entry(ticker.clone()).or_default();
+        } else if let (true, Some((base, lot))) = (seller_id == self.id, self.fx(&ticker)) {
+            // held like a buy's cash
             assert!(
-                quantity <= *current_quantity,
-                "Invalid trade, not enough stock"
+                Shares(quantity).times(lot).expect("reserved by the order")
+                    <= Money(self.get_balance(&base)),
+                "Invalid trade, not enough currency"
             );
-            *current_quantity -= quantity;
+        } else if seller_id == self.id {
+            self.change_stock(&ticker, |held| held.checked_sub(Shares(quantity)))
+                .expect("Invalid trade, not enough stock");
         } else {
             panic!("This trade doesn't belong to this user");
         }
+        self.pending.insert(trade_id, trade.clone());
 
         // check if enough orders left
         let current_orders = if buyer_id == self.id {
@@ -578,45 +2557,60 @@ impl Account {
         );
         *current_order_quantity -= quantity;
 
-        self.update_file().await
+        // the buyer's fee is held with the cash, the seller's comes out of what it's paid
+        if buyer_id == self.id {
+            let fee = self.fee(&trade);
+            if fee > 0 {
+                self.pending_fees.insert(trade_id, fee);
+            }
+        }
     }
 
-    pub async fn commit_pending(&mut self, trade_id: TradeID) -> GResult<()> {
-        let Trade {
-            quantity,
-            price,
-            ticker,
-            buyer_id,
-            seller_id,
-            ..
-        } = self.pending.remove(&trade_id).expect("Invalid trade_id");
-        if buyer_id == self.id {
-            let current_quantity = self.portfolio.entry(ticker).or_default();
-            *current_quantity += quantity;
-        } else if seller_id == self.id {
-            self.balance += quantity * price;
-        } else {
+    /// Returns the fees to pay, doesn't persist
+    fn commit_pending(&mut self, trade_id: TradeID) -> (Trade, Vec<Transfer>) {
+        let trade = self.pending.remove(&trade_id).expect("Invalid trade_id");
+        let fx = self.fx(&trade.ticker);
+        if trade.buyer_id == self.id {
+            if fx.is_none() {
+                self.change_stock(&trade.ticker, |held| {
+                    held.checked_add(Shares(trade.quantity))
+                })
+                .expect("Invalid trade, stock out of range");
+            }
+        } else if trade.seller_id != self.id {
             panic!("This trade doesn't belong to this user");
         }
-        self.update_file().await
+        // the held cash, and currency of a sold FX pair, is now paid
+        self.record_trade(&trade)
+            .expect("Invalid trade, balance out of range");
+        let fee = match self.pending_fees.remove(&trade_id) {
+            Some(fee) => fee,
+            None if trade.seller_id == self.id => self.fee(&trade),
+            None => 0,
+        };
+        let fees = self.charge_fee(&trade, fee);
+        (trade, fees)
     }
 
-    pub async fn abort_pending(&mut self, trade_id: TradeID) -> GResult<Order> {
+    /// doesn't persist
+    fn abort_pending(&mut self, trade_id: TradeID) -> Order {
         let Trade {
             quantity,
-            price,
             ticker,
             buyer_id,
             seller_id,
             buy_price,
             sell_price,
+            ..
         } = self.pending.remove(&trade_id).expect("Invalid trade_id");
-        if buyer_id == self.id {
-            self.balance += quantity * price;
-        } else if seller_id == self.id {
-            let current_quantity = self.portfolio.entry(ticker.clone()).or_default();
-            *current_quantity += quantity;
-        } else {
+        // held cash, fee and sold currency are released by removing the trade
+        self.pending_fees.remove(&trade_id);
+        if seller_id == self.id {
+            if self.fx(&ticker).is_none() {
+                self.change_stock(&ticker, |held| held.checked_add(Shares(quantity)))
+                    .expect("Invalid trade, can't hold the stock returned");
+            }
+        } else if buyer_id != self.id {
             panic!("This trade doesn't belong to this user");
         }
 
@@ -640,15 +2634,17 @@ impl Account {
                 sell_price
             })
             .or_default();
-        *current_order_quantity += quantity;
+        *current_order_quantity = Shares(*current_order_quantity)
+            .checked_add(Shares(quantity))
+            .expect("Invalid trade, can't hold the order returned")
+            .0;
 
-        self.update_file().await?;
-        Ok(Order {
+        Order {
             price,
             user_id: self.id,
             order_type,
             quantity,
             ticker,
-        })
+        }
     }
 }
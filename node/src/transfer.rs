@@ -0,0 +1,95 @@
+use crate::{
+    handlers::node::{Message, TransferOffer},
+    split::check_trading,
+    Global, Node,
+};
+use lib::{
+    interfaces::{Asset, ErrorCode, ErrorResponse, Memo, StockMemo, UserID},
+    lock::DeadLockDetect,
+    GResult,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+/// Cash or stock moving from one account to another without anything in return, or a fee
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub from: UserID,
+    pub to: UserID,
+    pub asset: Asset,
+    /// of the ledger entry, for cash
+    pub memo: Memo,
+}
+
+impl From<&Transfer> for StockMemo {
+    fn from(transfer: &Transfer) -> Self {
+        StockMemo::Transfer {
+            from: transfer.from,
+            to: transfer.to,
+        }
+    }
+}
+
+/// Reserve the transfer on the source account, offer it to the destination's node and wait for
+/// the reply, which commits or aborts it in handlers/node/transfer_replied.rs
+pub async fn transfer_remote(transfer: Transfer, global: &Arc<Global>) -> GResult<()> {
+    let sender = match global
+        .others
+        .read()
+        .dl("tf25")
+        .await
+        .get(&transfer.to.node_id)
+    {
+        Some(Node::Connected { sender }) => sender.clone(),
+        Some(Node::DisConnected(addr, _)) => {
+            return Err(format!("Node {} at {addr} isn't connected", transfer.to.node_id).into())
+        }
+        None => return Err(no_account(transfer.to)),
+    };
+
+    let matcher = global.matcher.read().dl("tf36").await;
+    // again, a split may have halted the ticker since the matcher was last held
+    if let Asset::Stock { ticker, .. } = &transfer.asset {
+        check_trading(&matcher, ticker)?;
+    }
+    let mut state = global.state.write().dl("tf37").await;
+    let Some(id) = state.reserve_transfer(transfer.clone()).await? else {
+        return Err(not_enough(&transfer));
+    };
+    // registered before the state is released, the reply needs it to be handled
+    let (done, accepted) = oneshot::channel();
+    global.transfers.lock().await.insert(id, done);
+    drop(state);
+    drop(matcher);
+
+    sender.send(Message::Transfer(TransferOffer {
+        id,
+        transfer: transfer.clone(),
+    }))?;
+    if accepted.await? {
+        Ok(())
+    } else {
+        Err(ErrorResponse::new(
+            ErrorCode::NotFound,
+            format!("No account {} or it can't hold the transfer", transfer.to),
+        )
+        .into())
+    }
+}
+
+pub fn no_account(user_id: UserID) -> Box<dyn std::error::Error + Send + Sync> {
+    ErrorResponse::new(ErrorCode::NotFound, format!("No account {user_id}")).into()
+}
+
+pub fn not_enough(transfer: &Transfer) -> Box<dyn std::error::Error + Send + Sync> {
+    let what = match &transfer.asset {
+        Asset::Cash(cash) => cash.currency.clone(),
+        Asset::Stock { ticker, .. } => ticker.clone(),
+    };
+    ErrorResponse::new(
+        ErrorCode::NotEnough,
+        format!("Not enough {what} free of orders to transfer"),
+    )
+    .into()
+}
@@ -0,0 +1,306 @@
+//! Kills a node in the middle of settling trades between two of its accounts, restarts it and
+//! checks that every trade was either fully persisted or not at all.
+//! Both accounts are on the one node so every trade is settled by `State::process_matches` in a
+//! single commit. Runs the coordinator and node built next to this binary, so
+//! `cargo build --workspace` first.
+
+use lib::{
+    instruments::DEFAULT_CURRENCY,
+    interfaces::{
+        Adjustment, CoordinatorRequest, CoordinatorResponse, LoggedIn, Login, Mint, NewAccount,
+        NodeRequest, NodeResponse, OrderReq, OrderType, UserID,
+    },
+    session::{CoordinatorSession, NodeSession},
+    storage::{StorageKind, Wal},
+    GResult,
+};
+use std::{
+    env::current_exe,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+use structopt::StructOpt;
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, BufReader},
+    process::{Child, Command},
+    time::{sleep, Duration},
+};
+
+const TOTAL: u64 = 1_000_000;
+const PRICE: u64 = 10;
+const TICKER: &str = "CRASH";
+
+#[derive(StructOpt)]
+struct Args {
+    /// Directory for the persistent dirs, anything in it is removed
+    #[structopt(short, long)]
+    dir: PathBuf,
+
+    #[structopt(short, long, default_value = "20")]
+    rounds: u64,
+
+    /// file or kv, for the node
+    #[structopt(short, long, default_value = "file")]
+    storage: StorageKind,
+
+    /// The node listens on the port after it
+    #[structopt(short, long, default_value = "8100")]
+    port: u16,
+}
+
+struct Cluster {
+    coordinator: SocketAddr,
+    node: SocketAddr,
+    node_dir: PathBuf,
+    storage: StorageKind,
+}
+
+fn binary(name: &str) -> GResult<PathBuf> {
+    let exe = current_exe()?;
+    Ok(exe.parent().ok_or("No binary directory")?.join(name))
+}
+
+fn password(i: usize) -> String {
+    format!("password{i}")
+}
+
+impl Cluster {
+    /// Start the coordinator, it opens an admin account with `password(0)` once the node joins
+    async fn start_coordinator(&self, dir: &Path) -> GResult<Child> {
+        let admin_password = dir.join("admin_password");
+        fs::write(&admin_password, password(0)).await?;
+        let child = Command::new(binary("coordinator")?)
+            .arg("-p")
+            .arg(self.coordinator.port().to_string())
+            .arg("-d")
+            .arg(dir)
+            .arg("-s")
+            .arg("memory")
+            .arg("--admin")
+            .arg(admin_password)
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        for _ in 0..100 {
+            if let Ok(coord) = CoordinatorSession::new(self.coordinator, None).await {
+                coord.bye().await?;
+                return Ok(child);
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        Err("Coordinator didn't start".into())
+    }
+
+    /// Start the node and wait for it to join the coordinator
+    async fn start_node(&self) -> GResult<Child> {
+        let mut child = Command::new(binary("node")?)
+            .arg("-c")
+            .arg(self.coordinator.to_string())
+            .arg("-a")
+            .arg(self.node.to_string())
+            .arg("-p")
+            .arg(&self.node_dir)
+            .arg("-s")
+            .arg(format!("{:?}", self.storage).to_lowercase())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut lines = BufReader::new(child.stdout.take().ok_or("No node stdout")?).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.starts_with("Node Id") {
+                // keep reading so the node never blocks on a full pipe
+                tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+                return Ok(child);
+            }
+        }
+        Err("Node exited before joining".into())
+    }
+
+    async fn login(&self, user_id: UserID, i: usize) -> GResult<NodeSession> {
+        let mut coord = CoordinatorSession::new(self.coordinator, None).await?;
+        let req = CoordinatorRequest::Login(Login {
+            user_id,
+            password: password(i),
+        });
+        let logged_in: LoggedIn = match coord.request(req).await? {
+            CoordinatorResponse::LoggedIn(logged_in) => logged_in,
+            res => panic!("{res:?}"),
+        };
+        coord.bye().await?;
+        NodeSession::new(&logged_in, None).await
+    }
+}
+
+/// Cash and stock of the account
+async fn holdings(user: &mut NodeSession) -> GResult<(u64, u64)> {
+    let cash = match user.request(NodeRequest::ReadBalance).await? {
+        NodeResponse::Balance(balances) => balances.get(DEFAULT_CURRENCY).copied().unwrap_or(0),
+        res => panic!("{res:?}"),
+    };
+    let stock = match user.request(NodeRequest::ReadStock).await? {
+        NodeResponse::Stock(stock) => stock.get(TICKER).copied().unwrap_or(0),
+        res => panic!("{res:?}"),
+    };
+    Ok((cash, stock))
+}
+
+fn order(order_type: OrderType) -> NodeRequest {
+    NodeRequest::CreateOrder(OrderReq {
+        order_type,
+        ticker: TICKER.to_owned(),
+        price: PRICE,
+        quantity: 1,
+    })
+}
+
+/// Trade one share at a time until the node goes away
+async fn trade(mut buyer: NodeSession, mut seller: NodeSession) {
+    loop {
+        if buyer.request(order(OrderType::Buy)).await.is_err()
+            || seller.request(order(OrderType::Sell)).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Check nothing was half committed, returns the number of trades
+async fn check(buyer: &mut NodeSession, seller: &mut NodeSession) -> GResult<u64> {
+    let (buyer, seller) = (holdings(buyer).await?, holdings(seller).await?);
+    assert_eq!(buyer.0 + seller.0, TOTAL, "{buyer:?} {seller:?}");
+    assert_eq!(buyer.1 + seller.1, TOTAL, "{buyer:?} {seller:?}");
+    assert_eq!(seller.0, buyer.1 * PRICE, "{buyer:?} {seller:?}");
+    Ok(buyer.1)
+}
+
+#[tokio::main]
+async fn main() -> GResult<()> {
+    let Args {
+        dir,
+        rounds,
+        storage,
+        port,
+    } = Args::from_args();
+
+    if fs::metadata(&dir).await.is_ok() {
+        fs::remove_dir_all(&dir).await?;
+    }
+    let coord_dir = dir.join("coord");
+    let node_dir = dir.join("node");
+    fs::create_dir_all(&coord_dir).await?;
+    fs::create_dir_all(&node_dir).await?;
+
+    let cluster = Cluster {
+        coordinator: SocketAddr::from(([127, 0, 0, 1], port)),
+        node: SocketAddr::from(([127, 0, 0, 1], port + 1)),
+        node_dir,
+        storage,
+    };
+    let _coordinator = cluster.start_coordinator(&coord_dir).await?;
+    let mut node = cluster.start_node().await?;
+
+    // the admin is the node's first account, it funds the buyer and lists the seller's stock
+    let mut coord = CoordinatorSession::new(cluster.coordinator, None).await?;
+    let admin = UserID { node_id: 0, id: 0 };
+    for _ in 0..100 {
+        let login = CoordinatorRequest::Login(Login {
+            user_id: admin,
+            password: password(0),
+        });
+        if coord.request(login).await.is_ok() {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    let mut user_ids = Vec::new();
+    for i in 1..3 {
+        let req = CoordinatorRequest::CreateAccount(NewAccount {
+            password: password(i),
+        });
+        match coord.request(req).await? {
+            CoordinatorResponse::Account(user_id) => user_ids.push(user_id),
+            res => panic!("{res:?}"),
+        }
+    }
+    let (buyer, seller) = (user_ids[0], user_ids[1]);
+    for req in [
+        CoordinatorRequest::AdjustBalance(Adjustment {
+            user_id: buyer,
+            currency: DEFAULT_CURRENCY.to_owned(),
+            amount: TOTAL as i64,
+        }),
+        CoordinatorRequest::MintStock(Mint {
+            user_id: seller,
+            ticker: TICKER.to_owned(),
+            quantity: TOTAL,
+        }),
+    ] {
+        assert!(matches!(coord.request(req).await?, CoordinatorResponse::Ok));
+    }
+    coord.bye().await?;
+
+    // kill the node at different points
+    let mut last_trades = 0;
+    for round in 0..rounds {
+        let trading = tokio::spawn(trade(
+            cluster.login(buyer, 1).await?,
+            cluster.login(seller, 2).await?,
+        ));
+        sleep(Duration::from_millis(20 + round * 37 % 100)).await;
+        node.kill().await?;
+        trading.await?;
+
+        node = cluster.start_node().await?;
+        let trades = check(
+            &mut cluster.login(buyer, 1).await?,
+            &mut cluster.login(seller, 2).await?,
+        )
+        .await?;
+        assert!(trades >= last_trades, "Lost committed trades");
+        println!("round {round}: killed after {trades} trades, consistent");
+        last_trades = trades;
+    }
+    assert!(last_trades > 0, "No trade was ever settled");
+
+    if !matches!(storage, StorageKind::File) {
+        return Ok(());
+    }
+
+    // cut the log in its last few records as if the write was torn there
+    node.kill().await?;
+    let log = fs::read(cluster.node_dir.join("wal")).await?;
+    let ends: Vec<usize> = log
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| b == b'\n')
+        .map(|(i, _)| i + 1)
+        .collect();
+    let cut_from = ends.iter().rev().nth(3).copied().unwrap_or(0);
+    for len in cut_from..=log.len() {
+        fs::write(cluster.node_dir.join("wal"), &log[..len]).await?;
+        Wal::read(&cluster.node_dir).await?;
+    }
+    // and restart the node at each record's end and middle
+    let mut cuts = vec![cut_from];
+    for pair in ends.windows(2).filter(|pair| pair[0] >= cut_from) {
+        cuts.extend([(pair[0] + pair[1]) / 2, pair[1]]);
+    }
+    for &len in &cuts {
+        fs::write(cluster.node_dir.join("wal"), &log[..len]).await?;
+        let mut node = cluster.start_node().await?;
+        check(
+            &mut cluster.login(buyer, 1).await?,
+            &mut cluster.login(seller, 2).await?,
+        )
+        .await?;
+        node.kill().await?;
+    }
+    println!(
+        "log torn at {} different points, {} restarted from, consistent",
+        log.len() + 1 - cut_from,
+        cuts.len()
+    );
+    Ok(())
+}
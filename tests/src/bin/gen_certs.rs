@@ -0,0 +1,57 @@
+//! Generates a self-signed CA and certificates signed by it for local tests, e.g.
+//! `cargo run -p tests --bin gen_certs -- -d certs -n coordinator node1 node2`
+//! writes `ca.pem`, and `<name>.pem` with `<name>.key` for each name.
+
+use lib::GResult;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use std::{fs, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+struct Args {
+    /// Directory to write into, created if missing
+    #[structopt(short, long)]
+    dir: PathBuf,
+
+    /// A certificate is made for each name
+    #[structopt(short, long, required = true)]
+    names: Vec<String>,
+
+    /// Ips and host names the certificates are valid for, servers are reached by ip
+    #[structopt(long, default_value = "127.0.0.1,localhost", use_delimiter = true)]
+    hosts: Vec<String>,
+}
+
+fn main() -> GResult<()> {
+    let Args { dir, names, hosts } = Args::from_args();
+    fs::create_dir_all(&dir)?;
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "distributed-exchange test CA");
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca_key = KeyPair::generate()?;
+    let ca = ca_params.self_signed(&ca_key)?;
+    fs::write(dir.join("ca.pem"), ca.pem())?;
+
+    for name in names {
+        // servers present the same certificate when they accept and when they connect
+        let mut params = CertificateParams::new(hosts.clone())?;
+        params.distinguished_name.push(DnType::CommonName, &name);
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &ca, &ca_key)?;
+        fs::write(dir.join(format!("{name}.pem")), cert.pem())?;
+        fs::write(dir.join(format!("{name}.key")), key.serialize_pem())?;
+    }
+    println!("Certificates written to {}", dir.display());
+    Ok(())
+}
@@ -1,78 +1,1273 @@
-use lib::{interfaces::UserID, read_writer::ReadWriter, GResult};
-use std::{net::SocketAddr, str::FromStr};
+use lib::{
+    instruments::{Instrument, DEFAULT_CURRENCY},
+    interfaces::{
+        AccountLimits, Adjustment, Asset, Borrowed, CancelFilter, Cash, CentCount, ClaimAccount,
+        CoordinatorRequest, CoordinatorResponse, DepositDecision, Distribution, Dividend,
+        DividendID, ErrorCode, ErrorResponse, KillSwitch, Lendable, LoggedIn, Login, MarginAccount,
+        MarginStatus, MarginTerms, MarginUsage, Memo, Mint, NewAccount, NodeRequest, NodeResponse,
+        OrderReq, OrderType, Position, Quantity, RiskLimits, Role, ScheduledDividend, SetRole,
+        ShortPermission, Split, StockEntry, StockMemo, StockReq, Ticker, TransferReq, UserID,
+    },
+    now,
+    session::{CoordinatorSession, NodeSession},
+    statement::{Statement, StatementFormat, StatementReq},
+    tls::Tls,
+    GResult,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+};
 use structopt::StructOpt;
-use tokio::{net::TcpStream, time::{sleep, Duration}};
+use tokio::time::{sleep, Duration};
 
 #[derive(StructOpt)]
 struct Args {
     #[structopt(short, long)]
     coordinator: SocketAddr,
+
+    /// CA certificate of the servers, connect over TLS
+    #[structopt(long)]
+    ca: Option<PathBuf>,
+
+    /// The admin account the coordinator opened with `--admin`
+    #[structopt(short, long)]
+    admin: UserID,
+
+    /// File with the admin's password, as given to the coordinator
+    #[structopt(long)]
+    admin_password: PathBuf,
+}
+
+fn order(order_type: OrderType, ticker: &str, price: u64, quantity: u64) -> NodeRequest {
+    NodeRequest::CreateOrder(OrderReq {
+        order_type,
+        ticker: ticker.to_owned(),
+        price,
+        quantity,
+    })
+}
+
+fn transfer(to: UserID, asset: Asset) -> NodeRequest {
+    NodeRequest::Transfer(TransferReq { to, asset })
+}
+
+fn cash(currency: &str, amount: CentCount) -> Cash {
+    Cash {
+        currency: currency.to_owned(),
+        amount,
+    }
+}
+
+fn usd(amount: CentCount) -> Cash {
+    cash(DEFAULT_CURRENCY, amount)
+}
+
+async fn balance(user: &mut NodeSession, currency: &str) -> GResult<CentCount> {
+    match user.request(NodeRequest::ReadBalance).await? {
+        NodeResponse::Balance(balances) => Ok(balances.get(currency).copied().unwrap_or(0)),
+        res => panic!("{res:?}"),
+    }
+}
+
+async fn stock(user: &mut NodeSession, ticker: &str) -> GResult<Quantity> {
+    match user.request(NodeRequest::ReadStock).await? {
+        NodeResponse::Stock(stock) => Ok(stock.get(ticker).copied().unwrap_or(0)),
+        res => panic!("{res:?}"),
+    }
+}
+
+/// Quantity and price of the `side` orders of `ticker`, from `ReadOrders` or `ReadMarket`, lowest
+/// price first
+async fn levels(
+    user: &mut NodeSession,
+    req: NodeRequest,
+    side: OrderType,
+    ticker: &str,
+) -> GResult<Vec<(Quantity, CentCount)>> {
+    let orders = match user.request(req).await? {
+        NodeResponse::Orders(orders) | NodeResponse::Market(orders) => orders,
+        res => panic!("{res:?}"),
+    };
+    let mut levels: Vec<(Quantity, CentCount)> =
+        orders.0.get(ticker).map_or_else(Vec::new, |orders| {
+            let orders = match side {
+                OrderType::Buy => &orders.buy,
+                OrderType::Sell => &orders.sell,
+            };
+            orders
+                .iter()
+                .filter(|order| order.quantity > 0)
+                .map(|order| (order.quantity, order.price))
+                .collect()
+        });
+    levels.sort_by_key(|&(_, price)| price);
+    Ok(levels)
+}
+
+async fn positions(user: &mut NodeSession) -> GResult<HashMap<Ticker, Position>> {
+    match user.request(NodeRequest::ReadPositions).await? {
+        NodeResponse::Positions(positions) => Ok(positions),
+        res => panic!("{res:?}"),
+    }
+}
+
+async fn dividends(
+    coord: &mut CoordinatorSession,
+) -> GResult<BTreeMap<DividendID, ScheduledDividend>> {
+    match coord.request(CoordinatorRequest::ReadDividends).await? {
+        CoordinatorResponse::Dividends(dividends) => Ok(dividends),
+        res => panic!("{res:?}"),
+    }
+}
+
+async fn statement(user: &mut NodeSession, from: u64, to: u64) -> GResult<Statement> {
+    let req = StatementReq {
+        from,
+        to,
+        format: StatementFormat::Json,
+    };
+    match user.request(NodeRequest::ReadStatement(req)).await? {
+        NodeResponse::Statement(statement) => Ok(*statement),
+        res => panic!("{res:?}"),
+    }
+}
+
+async fn sells(
+    user: &mut NodeSession,
+    req: NodeRequest,
+    ticker: &str,
+) -> GResult<Vec<(Quantity, CentCount)>> {
+    levels(user, req, OrderType::Sell, ticker).await
+}
+
+async fn buys(
+    user: &mut NodeSession,
+    req: NodeRequest,
+    ticker: &str,
+) -> GResult<Vec<(Quantity, CentCount)>> {
+    levels(user, req, OrderType::Buy, ticker).await
+}
+
+fn password(i: usize) -> String {
+    format!("password{i}")
+}
+
+fn login(user_id: UserID, i: usize) -> CoordinatorRequest {
+    CoordinatorRequest::Login(Login {
+        user_id,
+        password: password(i),
+    })
+}
+
+/// Deposit `cash` into `user_id`, and have the admin logged in to `desk` confirm it arrived
+async fn deposit(
+    user: &mut NodeSession,
+    user_id: UserID,
+    cash: Cash,
+    desk: &mut CoordinatorSession,
+) -> GResult<()> {
+    let id = match user.request(NodeRequest::Deposit(cash)).await? {
+        NodeResponse::Deposit(id) => id,
+        res => panic!("{res:?}"),
+    };
+    let req = CoordinatorRequest::ConfirmDeposit(DepositDecision {
+        user_id,
+        id,
+        accepted: true,
+    });
+    assert!(matches!(desk.request(req).await?, CoordinatorResponse::Ok));
+    Ok(())
+}
+
+fn error_code(e: Box<dyn std::error::Error + Send + Sync>) -> GResult<ErrorCode> {
+    Ok(e.downcast::<ErrorResponse>()?.code)
 }
 
 #[tokio::main]
 async fn main() -> GResult<()> {
-    let Args { coordinator } = Args::from_args();
+    let Args {
+        coordinator,
+        ca,
+        admin,
+        admin_password,
+    } = Args::from_args();
+    let tls = ca.map(|ca| Tls::client(&ca)).transpose()?;
+    let admin_password = std::fs::read_to_string(admin_password)?
+        .trim_end()
+        .to_owned();
+    let admin_login = || {
+        CoordinatorRequest::Login(Login {
+            user_id: admin,
+            password: admin_password.clone(),
+        })
+    };
 
-    let mut user_ids = Vec::<UserID>::new();
+    let mut user_ids = vec![admin];
 
-    for i in 0..3 {
-        let mut rw = ReadWriter::new(TcpStream::connect(coordinator).await?);
-        rw.write_line("\"C account\"").await?;
-        user_ids.push(serde_json::from_str(&rw.read_line().await?)?);
+    let mut coord = CoordinatorSession::new(coordinator, tls.as_ref()).await?;
+    for i in 1..3 {
+        let req = CoordinatorRequest::CreateAccount(NewAccount {
+            password: password(i),
+        });
+        match coord.request(req).await? {
+            CoordinatorResponse::Account(user_id) => user_ids.push(user_id),
+            res => panic!("{res:?}"),
+        }
         println!("user{i} created: {}", user_ids[i]);
     }
 
-    let mut users = Vec::<ReadWriter>::new();
-    for user_id in user_ids {
-        let mut rw = ReadWriter::new(TcpStream::connect(coordinator).await?);
-        rw.write_line(&serde_json::to_string(&user_id)?).await?;
-        let addr: SocketAddr = SocketAddr::from_str(&rw.read_line().await?)?;
-        println!("Obtained node addr: {addr} for user: {user_id}");
-        let mut rw = ReadWriter::new(TcpStream::connect(addr).await?);
-        rw.write_line(&serde_json::to_string(&user_id)?).await?;
-        users.push(rw);
+    // passwords are checked, and too short ones refused
+    let req = CoordinatorRequest::CreateAccount(NewAccount {
+        password: "short".to_owned(),
+    });
+    let err = coord.request(req).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    let req = CoordinatorRequest::Login(Login {
+        user_id: user_ids[0],
+        password: password(1),
+    });
+    let err = coord.request(req).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::Unauthorized);
+
+    // accounts created by clients are traders, only the admin manages roles, stock and cash
+    let make_operator = || {
+        CoordinatorRequest::SetRole(SetRole {
+            user_id: user_ids[1],
+            role: Role::MarketOperator,
+        })
+    };
+    let err = coord.request(make_operator()).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::Unauthorized);
+    coord.request(login(user_ids[2], 2)).await?;
+    let err = coord.request(make_operator()).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::Forbidden);
+    coord.request(admin_login()).await?;
+    for req in [
+        make_operator(),
+        CoordinatorRequest::AdjustBalance(Adjustment {
+            user_id: user_ids[2],
+            currency: DEFAULT_CURRENCY.to_owned(),
+            amount: 500,
+        }),
+        CoordinatorRequest::MintStock(Mint {
+            user_id: user_ids[1],
+            ticker: "AMD".to_owned(),
+            quantity: 10,
+        }),
+    ] {
+        assert!(matches!(coord.request(req).await?, CoordinatorResponse::Ok));
+    }
+    let req = CoordinatorRequest::AdjustBalance(Adjustment {
+        user_id: user_ids[2],
+        currency: DEFAULT_CURRENCY.to_owned(),
+        amount: -501,
+    });
+    let err = coord.request(req).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+
+    // claim codes are only for accounts that exist and have no password
+    for (user_id, code) in [
+        (user_ids[1], ErrorCode::BadRequest),
+        (
+            UserID {
+                node_id: 0,
+                id: 1000,
+            },
+            ErrorCode::NotFound,
+        ),
+    ] {
+        let err = coord
+            .request(CoordinatorRequest::IssueClaimCode(user_id))
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(err)?, code);
+    }
+    let req = CoordinatorRequest::ClaimAccount(ClaimAccount {
+        user_id: user_ids[1],
+        code: "not a code".to_owned(),
+        password: password(3),
+    });
+    let err = coord.request(req).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::Unauthorized);
+
+    let mut users = Vec::<NodeSession>::new();
+    let mut logins = Vec::<LoggedIn>::new();
+    for (i, &user_id) in user_ids.iter().enumerate() {
+        let req = if i == 0 {
+            admin_login()
+        } else {
+            login(user_id, i)
+        };
+        let logged_in = match coord.request(req).await? {
+            CoordinatorResponse::LoggedIn(logged_in) => logged_in,
+            res => panic!("{res:?}"),
+        };
+        println!("Logged in to node: {} as user: {user_id}", logged_in.node);
+        users.push(NodeSession::new(&logged_in, tls.as_ref()).await?);
         println!("Connection established");
+        logins.push(logged_in);
     }
+    coord.bye().await?;
+    // confirms deposits
+    let mut desk = CoordinatorSession::new(coordinator, tls.as_ref()).await?;
+    desk.request(admin_login()).await?;
+
+    // nodes only accept tokens the coordinator signed
+    let (claims, signature) = logins[0]
+        .token
+        .split_once('.')
+        .expect("token has two parts");
+    let (other_claims, _) = logins[1]
+        .token
+        .split_once('.')
+        .expect("token has two parts");
+    for token in [
+        "garbage".to_owned(),
+        format!("{other_claims}.{signature}"),
+        format!("{claims}.{}", &signature[1..]),
+    ] {
+        let forged = LoggedIn {
+            node: logins[0].node,
+            token,
+        };
+        let err = NodeSession::new(&forged, tls.as_ref())
+            .await
+            .err()
+            .expect("forged token");
+        assert_eq!(error_code(err)?, ErrorCode::Unauthorized);
+    }
+
+    // traders can't list stock
+    let req = NodeRequest::CreateStock(StockReq {
+        ticker: "Intel".to_owned(),
+        quantity: 1000,
+    });
+    let err = users[2].request(req).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::Forbidden);
+
+    // withdrawals can't overdraw the account, cash is only in the registry's currencies
+    let err = users[2]
+        .request(NodeRequest::Withdraw(usd(501)))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+    assert!(matches!(
+        users[2].request(NodeRequest::Withdraw(usd(100))).await?,
+        NodeResponse::Ok
+    ));
+    assert_eq!(balance(&mut users[2], DEFAULT_CURRENCY).await?, 400);
+    let err = users[2]
+        .request(NodeRequest::Deposit(cash("XYZ", 100)))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    match users[1].request(NodeRequest::ReadStock).await? {
+        NodeResponse::Stock(stock) => assert_eq!(stock.get("AMD"), Some(&10)),
+        res => panic!("{res:?}"),
+    }
+
+    // rejected requests get an error and leave the session usable
+    let err = users[2]
+        .request(order(OrderType::Buy, "Intel", 15, 50))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+
+    // amounts too big to count are rejected instead of overflowing
+    let err = users[2]
+        .request(order(OrderType::Buy, "Intel", CentCount::MAX, 2))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    let err = users[2]
+        .request(NodeRequest::Deposit(usd(CentCount::MAX)))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    assert_eq!(balance(&mut users[2], DEFAULT_CURRENCY).await?, 400);
+
+    // deposits are only credited once an admin confirms the cash arrived
+    let id = match users[2].request(NodeRequest::Deposit(usd(50))).await? {
+        NodeResponse::Deposit(id) => id,
+        res => panic!("{res:?}"),
+    };
+    match users[2].request(NodeRequest::ReadDeposits).await? {
+        NodeResponse::Deposits(deposits) => assert_eq!(deposits.get(&id), Some(&usd(50))),
+        res => panic!("{res:?}"),
+    }
+    assert_eq!(balance(&mut users[2], DEFAULT_CURRENCY).await?, 400);
+    let decide = |accepted| {
+        CoordinatorRequest::ConfirmDeposit(DepositDecision {
+            user_id: user_ids[2],
+            id,
+            accepted,
+        })
+    };
+    assert!(matches!(
+        desk.request(decide(false)).await?,
+        CoordinatorResponse::Ok
+    ));
+    let err = desk.request(decide(true)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotFound);
+    assert_eq!(balance(&mut users[2], DEFAULT_CURRENCY).await?, 400);
 
     // basic two node trade
-    users[0]
-        .write_line(r#"{ "type": "U balance", "value": 10000 }"#)
+    deposit(&mut users[0], user_ids[0], usd(10000), &mut desk).await?;
+    assert!(matches!(
+        users[0]
+            .request(order(OrderType::Buy, "Intel", 15, 50))
+            .await?,
+        NodeResponse::Ok
+    ));
+    assert!(matches!(
+        users[1]
+            .request(NodeRequest::CreateStock(StockReq {
+                ticker: "Intel".to_owned(),
+                quantity: 1000
+            }))
+            .await?,
+        NodeResponse::Ok
+    ));
+    assert!(matches!(
+        users[1]
+            .request(order(OrderType::Sell, "Intel", 12, 100))
+            .await?,
+        NodeResponse::Ok
+    ));
+
+    sleep(Duration::from_millis(1000)).await;
+
+    // tests
+    for (i, user) in users.iter_mut().enumerate().take(2) {
+        println!("User{i}:");
+        for req in [
+            NodeRequest::ReadBalance,
+            NodeRequest::ReadStock,
+            NodeRequest::ReadOrders,
+            NodeRequest::ReadMarket,
+        ] {
+            println!("{:?}", user.request(req).await?);
+        }
+    }
+
+    // every change of cash is in the ledger
+    match users[0].request(NodeRequest::ReadLedger).await? {
+        NodeResponse::Ledger(entries) => {
+            assert!(matches!(entries[0].memo, Memo::Deposit));
+            assert!(entries[1..]
+                .iter()
+                .all(|entry| matches!(entry.memo, Memo::Trade { .. })));
+        }
+        res => panic!("{res:?}"),
+    }
+
+    // transfers on the same node and across nodes
+    for (from, to, asset) in [
+        (2, 0, Asset::Cash(usd(100))),
+        (0, 1, Asset::Cash(usd(250))),
+        (
+            1,
+            2,
+            Asset::Stock {
+                ticker: "AMD".to_owned(),
+                quantity: 4,
+            },
+        ),
+    ] {
+        assert!(matches!(
+            users[from].request(transfer(user_ids[to], asset)).await?,
+            NodeResponse::Ok
+        ));
+    }
+    let nobody = UserID { node_id: 1, id: 99 };
+    for (from, req, code) in [
+        (
+            2,
+            transfer(user_ids[0], Asset::Cash(usd(301))),
+            ErrorCode::NotEnough,
+        ),
+        (
+            0,
+            transfer(nobody, Asset::Cash(usd(100))),
+            ErrorCode::NotFound,
+        ),
+        (
+            0,
+            transfer(user_ids[0], Asset::Cash(usd(100))),
+            ErrorCode::BadRequest,
+        ),
+    ] {
+        let err = users[from].request(req).await.unwrap_err();
+        assert_eq!(error_code(err)?, code);
+    }
+    for (user, expected) in [(0, 9100), (1, 1000), (2, 300)] {
+        let b = balance(&mut users[user], DEFAULT_CURRENCY).await?;
+        assert_eq!(b, expected, "balance of user {user}");
+    }
+    match users[2].request(NodeRequest::ReadStock).await? {
+        NodeResponse::Stock(stock) => assert_eq!(stock.get("AMD"), Some(&4)),
+        res => panic!("{res:?}"),
+    }
+    println!("Transfers settled");
+
+    // converting currencies by trading an FX pair, if the exchange lists one
+    let instruments = match users[0].request(NodeRequest::ReadInstruments).await? {
+        NodeResponse::Instruments(instruments) => instruments,
+        res => panic!("{res:?}"),
+    };
+    let fx = instruments
+        .tickers
+        .iter()
+        .find_map(|(ticker, instrument)| match instrument {
+            Instrument::Fx {
+                currency,
+                base,
+                lot,
+            } => Some((ticker.clone(), currency.clone(), base.clone(), *lot)),
+            Instrument::Stock { .. } => None,
+        });
+    if let Some((ticker, quote, base, lot)) = fx {
+        let req = NodeRequest::CreateStock(StockReq {
+            ticker: ticker.clone(),
+            quantity: 1,
+        });
+        let err = users[1].request(req).await.unwrap_err();
+        assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+
+        deposit(&mut users[1], user_ids[1], cash(&base, 2 * lot), &mut desk).await?;
+        deposit(&mut users[0], user_ids[0], cash(&quote, 200), &mut desk).await?;
+        let mut before = Vec::new();
+        for user in &mut users[..2] {
+            before.push((balance(user, &quote).await?, balance(user, &base).await?));
+        }
+        users[1]
+            .request(order(OrderType::Sell, &ticker, 90, 2))
+            .await?;
+        users[0]
+            .request(order(OrderType::Buy, &ticker, 90, 2))
+            .await?;
+        sleep(Duration::from_millis(1000)).await;
+        let (quote_0, base_0) = before[0];
+        let (quote_1, base_1) = before[1];
+        assert_eq!(balance(&mut users[0], &quote).await?, quote_0 - 180);
+        assert_eq!(balance(&mut users[0], &base).await?, base_0 + 2 * lot);
+        assert_eq!(balance(&mut users[1], &quote).await?, quote_1 + 180);
+        assert_eq!(balance(&mut users[1], &base).await?, base_1 - 2 * lot);
+        match users[0].request(NodeRequest::ReadLedger).await? {
+            NodeResponse::Ledger(entries) => {
+                assert!(entries
+                    .iter()
+                    .any(|entry| matches!(entry.memo, Memo::Fx { .. }) && entry.currency == base))
+            }
+            res => panic!("{res:?}"),
+        }
+        println!("Converted {quote} to {base} with {ticker}");
+    } else {
+        println!("No FX pair listed, not converting currencies");
+    }
+
+    // short sales borrow from the node's lendable stock once allowed, against collateral, and
+    // buying back gives it back
+    let mut coord = CoordinatorSession::new(coordinator, tls.as_ref()).await?;
+    coord.request(admin_login()).await?;
+    let short_sell = |quantity| order(OrderType::Sell, "AMD", 20, quantity);
+    let err = users[0].request(short_sell(5)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+    let req = CoordinatorRequest::AllowShort(ShortPermission {
+        user_id: user_ids[0],
+        allowed: true,
+    });
+    assert!(matches!(coord.request(req).await?, CoordinatorResponse::Ok));
+    let err = users[0].request(short_sell(5)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+    let req = CoordinatorRequest::SetLendable(Lendable {
+        node_id: user_ids[0].node_id,
+        ticker: "AMD".to_owned(),
+        quantity: 5,
+    });
+    assert!(matches!(coord.request(req).await?, CoordinatorResponse::Ok));
+    let err = users[0].request(short_sell(6)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+    let before = balance(&mut users[0], DEFAULT_CURRENCY).await?;
+    users[0].request(short_sell(5)).await?;
+    let borrowed = |quantity, amount| {
+        HashMap::from([(
+            "AMD".to_owned(),
+            Borrowed {
+                quantity,
+                collateral: usd(amount),
+            },
+        )])
+    };
+    match users[0].request(NodeRequest::ReadBorrowed).await? {
+        NodeResponse::Borrowed(owed) => assert_eq!(owed, borrowed(5, 150)),
+        res => panic!("{res:?}"),
+    }
+    let err = users[0]
+        .request(transfer(
+            user_ids[1],
+            Asset::Stock {
+                ticker: "AMD".to_owned(),
+                quantity: 1,
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+    users[1]
+        .request(order(OrderType::Buy, "AMD", 20, 5))
         .await?;
-    assert_eq!(users[0].read_line().await?, r#""ok""#);
+    sleep(Duration::from_millis(1000)).await;
+    assert_eq!(stock(&mut users[0], "AMD").await?, 0);
+    assert_eq!(
+        balance(&mut users[0], DEFAULT_CURRENCY).await?,
+        before + 100
+    );
+    users[1].request(short_sell(5)).await?;
+    for (quantity, owed) in [(2, borrowed(3, 90)), (3, HashMap::new())] {
+        users[0]
+            .request(order(OrderType::Buy, "AMD", 20, quantity))
+            .await?;
+        sleep(Duration::from_millis(1000)).await;
+        match users[0].request(NodeRequest::ReadBorrowed).await? {
+            NodeResponse::Borrowed(borrowed) => assert_eq!(borrowed, owed),
+            res => panic!("{res:?}"),
+        }
+    }
+    assert_eq!(stock(&mut users[0], "AMD").await?, 0);
+    assert_eq!(balance(&mut users[0], DEFAULT_CURRENCY).await?, before);
+    println!("Sold AMD short and bought it back");
+
+    // splits rescale stock and resting orders on every node, a reverse split rounds down
+    let split = |from, to| {
+        CoordinatorRequest::Split(Split {
+            ticker: "Intel".to_owned(),
+            from,
+            to,
+        })
+    };
+    let err = coord.request(split(2, 2)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    // one that would put stock out of range fails on every node before any splits, and resumes
+    let held = stock(&mut users[0], "Intel").await?;
+    let err = coord.request(split(1, Quantity::MAX)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    assert_eq!(stock(&mut users[0], "Intel").await?, held);
+    let bid = OrderReq {
+        order_type: OrderType::Buy,
+        ticker: "Intel".to_owned(),
+        price: 1,
+        quantity: 1,
+    };
     users[0]
-        .write_line(r#"{ "type": "C order", "value": { "order_type": "buy", "ticker": "Intel", "price": 15, "quantity": 50 } }"#)
+        .request(NodeRequest::CreateOrder(bid.clone()))
+        .await?;
+    assert!(matches!(
+        users[0].request(NodeRequest::DeleteOrder(bid)).await?,
+        NodeResponse::Deleted(1)
+    ));
+    for (from, to, held, sold, quantity, price) in
+        [(1, 2, 100, 1900, 100, 6), (3, 1, 33, 633, 33, 18)]
+    {
+        assert!(matches!(
+            coord.request(split(from, to)).await?,
+            CoordinatorResponse::Ok
+        ));
+        assert_eq!(stock(&mut users[0], "Intel").await?, held);
+        assert_eq!(stock(&mut users[1], "Intel").await?, sold);
+        assert_eq!(
+            sells(&mut users[1], NodeRequest::ReadOrders, "Intel").await?,
+            [(quantity, price)]
+        );
+        assert_eq!(
+            sells(&mut users[0], NodeRequest::ReadMarket, "Intel").await?,
+            [(quantity, price)]
+        );
+    }
+
+    // dividends are paid for every share held at the record time, resting sells included
+    let dividend = |record_time| {
+        CoordinatorRequest::Dividend(Dividend {
+            ticker: "Intel".to_owned(),
+            per_share: 5,
+            record_time,
+        })
+    };
+    let err = coord.request(dividend(now() - 10)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    let before = [
+        balance(&mut users[0], DEFAULT_CURRENCY).await?,
+        balance(&mut users[1], DEFAULT_CURRENCY).await?,
+    ];
+    // answered once kept, the session is free while it waits for the record time
+    let id = match coord.request(dividend(now() + 2)).await? {
+        CoordinatorResponse::Dividend(id) => id,
+        res => panic!("{res:?}"),
+    };
+    // kept with each node's part, the failed one was never kept
+    let kept = dividends(&mut coord).await?;
+    assert_eq!(kept.len(), 1);
+    let mut scheduled = kept[&id].clone();
+    assert!(!scheduled.done);
+    for _ in 0..10 {
+        if scheduled.done {
+            break;
+        }
+        sleep(Duration::from_millis(1000)).await;
+        scheduled = dividends(&mut coord).await?[&id].clone();
+    }
+    assert!(scheduled.done);
+    assert_eq!(scheduled.dividend.per_share, 5);
+    let paid = Distribution {
+        accounts: 2,
+        shares: 666,
+        amount: 3330,
+    };
+    assert_eq!(
+        scheduled
+            .paid
+            .values()
+            .copied()
+            .try_fold(Distribution::default(), Distribution::checked_add)?,
+        paid
+    );
+    assert_eq!(
+        balance(&mut users[0], DEFAULT_CURRENCY).await?,
+        before[0] + 165
+    );
+    assert_eq!(
+        balance(&mut users[1], DEFAULT_CURRENCY).await?,
+        before[1] + 3165
+    );
+    coord.bye().await?;
+    println!("Split Intel 2 for 1 and back 1 for 3, paid a dividend on it");
+
+    // margin accounts borrow for buys up to their buying power, and are liquidated once under
+    // their maintenance margin
+    let mut coord = CoordinatorSession::new(coordinator, tls.as_ref()).await?;
+    let trader = match coord
+        .request(CoordinatorRequest::CreateAccount(NewAccount {
+            password: password(3),
+        }))
+        .await?
+    {
+        CoordinatorResponse::Account(user_id) => user_id,
+        res => panic!("{res:?}"),
+    };
+    let mut margin = match coord.request(login(trader, 3)).await? {
+        CoordinatorResponse::LoggedIn(logged_in) => {
+            NodeSession::new(&logged_in, tls.as_ref()).await?
+        }
+        res => panic!("{res:?}"),
+    };
+    coord.request(admin_login()).await?;
+    for req in [
+        CoordinatorRequest::AdjustBalance(Adjustment {
+            user_id: trader,
+            currency: DEFAULT_CURRENCY.to_owned(),
+            amount: 1000,
+        }),
+        CoordinatorRequest::MintStock(Mint {
+            user_id: user_ids[1],
+            ticker: "MRG".to_owned(),
+            quantity: 100,
+        }),
+    ] {
+        assert!(matches!(coord.request(req).await?, CoordinatorResponse::Ok));
+    }
+    users[1]
+        .request(order(OrderType::Sell, "MRG", 100, 20))
         .await?;
-    assert_eq!(users[0].read_line().await?, r#""ok""#);
+    let err = margin
+        .request(order(OrderType::Buy, "MRG", 100, 20))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
+    let set_margin = |leverage, maintenance_pct| {
+        CoordinatorRequest::SetMargin(MarginAccount {
+            user_id: trader,
+            terms: Some(MarginTerms {
+                leverage,
+                maintenance_pct,
+            }),
+        })
+    };
+    let err = coord.request(set_margin(2, 60)).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    assert!(matches!(
+        coord.request(set_margin(2, 25)).await?,
+        CoordinatorResponse::Ok
+    ));
+    coord.bye().await?;
+    margin
+        .request(order(OrderType::Buy, "MRG", 100, 20))
+        .await?;
+    sleep(Duration::from_millis(1000)).await;
+    let usage = |margin: MarginStatus| margin.currencies[DEFAULT_CURRENCY].clone();
+    match margin.request(NodeRequest::ReadMargin).await? {
+        NodeResponse::Margin(status) => assert_eq!(
+            usage(status),
+            MarginUsage {
+                equity: 1000,
+                exposure: 2000,
+                loan: 1000,
+                buying_power: 0,
+            }
+        ),
+        res => panic!("{res:?}"),
+    }
+    let err = margin
+        .request(NodeRequest::Withdraw(usd(1)))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::NotEnough);
     users[1]
-        .write_line(r#"{ "type": "C stock", "value": { "ticker_id": "Intel", "quantity": 1000 } }"#)
+        .request(order(OrderType::Buy, "MRG", 60, 1))
         .await?;
-    assert_eq!(users[1].read_line().await?, r#""ok""#);
+    margin.request(order(OrderType::Sell, "MRG", 60, 1)).await?;
+    sleep(Duration::from_millis(1000)).await;
+    match margin.request(NodeRequest::ReadMargin).await? {
+        NodeResponse::Margin(status) => {
+            assert!(status.liquidating);
+            assert_eq!(usage(status).loan, 940);
+        }
+        res => panic!("{res:?}"),
+    }
+    assert_eq!(
+        sells(&mut margin, NodeRequest::ReadOrders, "MRG").await?,
+        [(19, 54)]
+    );
+    let err = margin
+        .request(order(OrderType::Sell, "MRG", 60, 1))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
     users[1]
-        .write_line(r#"{ "type": "C order", "value": { "order_type": "sell", "ticker": "Intel", "price": 12, "quantity": 100 } }"#)
+        .request(order(OrderType::Buy, "MRG", 54, 19))
+        .await?;
+    sleep(Duration::from_millis(1000)).await;
+    match margin.request(NodeRequest::ReadMargin).await? {
+        NodeResponse::Margin(status) => {
+            assert!(!status.liquidating);
+            assert_eq!(usage(status).loan, 0);
+        }
+        res => panic!("{res:?}"),
+    }
+    assert_eq!(stock(&mut margin, "MRG").await?, 0);
+    assert_eq!(balance(&mut margin, DEFAULT_CURRENCY).await?, 86);
+    println!("Bought MRG on margin and was liquidated");
+
+    // pre-trade risk limits reject orders with a code for each, before balances are checked
+    let mut coord = CoordinatorSession::new(coordinator, tls.as_ref()).await?;
+    coord.request(admin_login()).await?;
+    let read_limits = || CoordinatorRequest::ReadRiskLimits(trader);
+    match coord.request(read_limits()).await? {
+        CoordinatorResponse::RiskLimits(limits) => assert_eq!(limits, RiskLimits::default()),
+        res => panic!("{res:?}"),
+    }
+    let limits = RiskLimits {
+        max_order_quantity: Some(10),
+        max_notional: Some(500),
+        max_open_orders: Some(2),
+        max_position: Some(15),
+        max_orders_per_sec: None,
+    };
+    let set_limits = |limits| {
+        CoordinatorRequest::SetRiskLimits(AccountLimits {
+            user_id: trader,
+            limits,
+        })
+    };
+    assert!(matches!(
+        coord.request(set_limits(limits)).await?,
+        CoordinatorResponse::Ok
+    ));
+    match coord.request(read_limits()).await? {
+        CoordinatorResponse::RiskLimits(read) => assert_eq!(read, limits),
+        res => panic!("{res:?}"),
+    }
+    for (price, quantity, code) in [
+        (1, 11, Some(ErrorCode::OrderTooLarge)),
+        (51, 10, Some(ErrorCode::NotionalTooLarge)),
+        (5, 10, None),
+        (4, 6, Some(ErrorCode::PositionTooLarge)),
+        (4, 5, None),
+        (3, 1, Some(ErrorCode::TooManyOrders)),
+    ] {
+        let result = margin
+            .request(order(OrderType::Buy, "MRG", price, quantity))
+            .await;
+        match code {
+            Some(code) => assert_eq!(error_code(result.unwrap_err())?, code),
+            None => assert!(matches!(result?, NodeResponse::Ok)),
+        }
+    }
+    let limits = RiskLimits {
+        max_orders_per_sec: Some(2),
+        ..RiskLimits::default()
+    };
+    assert!(matches!(
+        coord.request(set_limits(limits)).await?,
+        CoordinatorResponse::Ok
+    ));
+    coord.bye().await?;
+    sleep(Duration::from_millis(1000)).await;
+    for _ in 0..2 {
+        margin.request(order(OrderType::Buy, "MRG", 1, 1)).await?;
+    }
+    let err = margin
+        .request(order(OrderType::Buy, "MRG", 1, 1))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::RateLimited);
+    for (price, quantity) in [(5, 10), (4, 5), (1, 2)] {
+        let req = NodeRequest::DeleteOrder(OrderReq {
+            order_type: OrderType::Buy,
+            ticker: "MRG".to_owned(),
+            price,
+            quantity,
+        });
+        assert!(matches!(
+            margin.request(req).await?,
+            NodeResponse::Deleted(deleted) if deleted == quantity
+        ));
+    }
+    println!("Risk limits checked");
+
+    // the kill switch cancels every resting order on every node and blocks new ones, only an
+    // admin can release it once an admin engaged it
+    for price in [1, 2] {
+        users[2]
+            .request(order(OrderType::Buy, "MRG", price, 1))
+            .await?;
+    }
+    users[2].request(NodeRequest::KillSwitch(true)).await?;
+    sleep(Duration::from_millis(1000)).await;
+    assert_eq!(
+        buys(&mut users[2], NodeRequest::ReadOrders, "MRG").await?,
+        []
+    );
+    assert_eq!(
+        buys(&mut users[1], NodeRequest::ReadMarket, "MRG").await?,
+        []
+    );
+    let err = users[2]
+        .request(order(OrderType::Buy, "MRG", 1, 1))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    let mut coord = CoordinatorSession::new(coordinator, tls.as_ref()).await?;
+    coord.request(admin_login()).await?;
+    let admin_switch = |engaged| {
+        CoordinatorRequest::KillSwitch(KillSwitch {
+            user_id: user_ids[2],
+            engaged,
+        })
+    };
+    assert!(matches!(
+        coord.request(admin_switch(true)).await?,
+        CoordinatorResponse::Ok
+    ));
+    let err = users[2]
+        .request(NodeRequest::KillSwitch(false))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::Forbidden);
+    assert!(matches!(
+        coord.request(admin_switch(false)).await?,
+        CoordinatorResponse::Ok
+    ));
+    coord.bye().await?;
+
+    // a session can have its account's orders cancelled once it ends
+    let mut session = NodeSession::new(&logins[2], tls.as_ref()).await?;
+    session
+        .request(NodeRequest::CancelOnDisconnect(true))
         .await?;
-    assert_eq!(users[1].read_line().await?, r#""ok""#);
+    session.request(order(OrderType::Buy, "MRG", 1, 1)).await?;
+    assert_eq!(
+        buys(&mut users[2], NodeRequest::ReadOrders, "MRG").await?,
+        [(1, 1)]
+    );
+    session.bye().await?;
+    sleep(Duration::from_millis(1000)).await;
+    assert_eq!(
+        buys(&mut users[2], NodeRequest::ReadOrders, "MRG").await?,
+        []
+    );
+    assert_eq!(
+        buys(&mut users[1], NodeRequest::ReadMarket, "MRG").await?,
+        []
+    );
+    println!("Killed and cancelled orders on disconnect");
 
+    // mass cancel by ticker, side and price range, each level in one message to the others
+    for price in 1..=4 {
+        users[2]
+            .request(order(OrderType::Buy, "MRG", price, 1))
+            .await?;
+    }
+    let mut filter = CancelFilter {
+        ticker: Some("MRG".to_owned()),
+        side: Some(OrderType::Buy),
+        min_price: Some(3),
+        max_price: Some(2),
+    };
+    let err = users[2]
+        .request(NodeRequest::CancelOrders(filter.clone()))
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::BadRequest);
+    filter.min_price = Some(2);
+    filter.max_price = Some(3);
+    let cancelled = match users[2].request(NodeRequest::CancelOrders(filter)).await? {
+        NodeResponse::Cancelled(cancelled) => cancelled,
+        res => panic!("{res:?}"),
+    };
+    let mut cancelled: Vec<_> = cancelled
+        .into_iter()
+        .map(|order| (order.ticker, order.price, order.quantity))
+        .collect();
+    cancelled.sort();
+    assert_eq!(
+        cancelled,
+        [("MRG".to_owned(), 2, 1), ("MRG".to_owned(), 3, 1)]
+    );
+    sleep(Duration::from_millis(1000)).await;
+    for (user, req) in [(2, NodeRequest::ReadOrders), (1, NodeRequest::ReadMarket)] {
+        assert_eq!(buys(&mut users[user], req, "MRG").await?, [(1, 1), (1, 4)]);
+    }
+    match users[2]
+        .request(NodeRequest::CancelOrders(CancelFilter::default()))
+        .await?
+    {
+        NodeResponse::Cancelled(cancelled) => assert_eq!(cancelled.len(), 2),
+        res => panic!("{res:?}"),
+    }
     sleep(Duration::from_millis(1000)).await;
+    for (user, req) in [(2, NodeRequest::ReadOrders), (1, NodeRequest::ReadMarket)] {
+        assert_eq!(buys(&mut users[user], req, "MRG").await?, []);
+    }
+    println!("Mass cancelled orders");
 
-    // tests
-    println!("User0:");
-    users[0].write_line(r#"{ "type": "R balance" }"#).await?;
-    println!("{}", users[0].read_line().await?);
-    users[0].write_line(r#"{ "type": "R stock" }"#).await?;
-    println!("{}", users[0].read_line().await?);
-    users[0].write_line(r#"{ "type": "R order" }"#).await?;
-    println!("{}", users[0].read_line().await?);
-    users[0].write_line(r#"{ "type": "R market" }"#).await?;
-    println!("{}", users[0].read_line().await?);
-    println!("User1:");
-    users[1].write_line(r#"{ "type": "R balance" }"#).await?;
-    println!("{}", users[1].read_line().await?);
-    users[1].write_line(r#"{ "type": "R stock" }"#).await?;
-    println!("{}", users[1].read_line().await?);
-    users[1].write_line(r#"{ "type": "R order" }"#).await?;
-    println!("{}", users[1].read_line().await?);
-    users[1].write_line(r#"{ "type": "R market" }"#).await?;
-    println!("{}", users[1].read_line().await?);
+    // positions at their average cost, with P&L realised by trades that reduce them and the
+    // rest marked to the last trade price
+    users[1]
+        .request(NodeRequest::CreateStock(StockReq {
+            ticker: "PNL".to_owned(),
+            quantity: 10,
+        }))
+        .await?;
+    deposit(&mut users[2], user_ids[2], usd(1000), &mut desk).await?;
+    for (seller, buyer, price, quantity) in [(1, 2, 10, 4), (2, 1, 12, 1)] {
+        users[seller]
+            .request(order(OrderType::Sell, "PNL", price, quantity))
+            .await?;
+        users[buyer]
+            .request(order(OrderType::Buy, "PNL", price, quantity))
+            .await?;
+        sleep(Duration::from_millis(1000)).await;
+    }
+    assert_eq!(
+        positions(&mut users[2]).await?.get("PNL"),
+        Some(&Position {
+            quantity: 3,
+            average_cost: 10,
+            realised: 2,
+            unrealised: Some(6),
+            mark: Some(12),
+        })
+    );
+    assert_eq!(
+        positions(&mut users[1]).await?.get("PNL"),
+        Some(&Position {
+            quantity: 7,
+            average_cost: 1,
+            realised: 40,
+            unrealised: Some(72),
+            mark: Some(12),
+        })
+    );
+    // shares transferred take their cost with them, and cost the receiver nothing
+    users[2]
+        .request(transfer(
+            user_ids[0],
+            Asset::Stock {
+                ticker: "PNL".to_owned(),
+                quantity: 1,
+            },
+        ))
+        .await?;
+    let position = positions(&mut users[2]).await?["PNL"].clone();
+    assert_eq!((position.quantity, position.average_cost), (2, 10));
+    let position = positions(&mut users[0]).await?["PNL"].clone();
+    assert_eq!((position.quantity, position.average_cost), (1, 0));
+    println!("Tracked cost basis and P&L");
+
+    let until = now() + 1;
+    let history = statement(&mut users[2], 0, until).await?;
+    assert_eq!(history.opening, Default::default());
+    assert_eq!(
+        history.closing.cash.get(DEFAULT_CURRENCY).copied(),
+        Some(balance(&mut users[2], DEFAULT_CURRENCY).await?)
+    );
+    let held: BTreeMap<Ticker, i64> = positions(&mut users[2])
+        .await?
+        .into_iter()
+        .filter(|(_, position)| position.quantity != 0)
+        .map(|(ticker, position)| (ticker, position.quantity))
+        .collect();
+    assert_eq!(history.closing.positions, held);
+    assert_eq!(history.closing.positions.get("PNL"), Some(&2));
+    let fills: Vec<_> = history
+        .fills
+        .iter()
+        .filter(|fill| fill.ticker == "PNL")
+        .map(|fill| (fill.side, fill.quantity, fill.price))
+        .collect();
+    assert_eq!(fills, [(OrderType::Buy, 4, 10), (OrderType::Sell, 1, 12)]);
+    assert!(history.stock_entries.iter().any(|entry| matches!(
+        entry,
+        StockEntry {
+            ticker,
+            change: -1,
+            memo: StockMemo::Transfer { from, to },
+            ..
+        } if ticker == "PNL" && *from == user_ids[2] && *to == user_ids[0]
+    )));
+    // nothing happens in the future, it opens where the history closes
+    let future = statement(&mut users[2], until + 10, until + 20).await?;
+    assert_eq!(future.opening, history.closing);
+    assert_eq!(future.closing, history.closing);
+    assert!(future.entries.is_empty() && future.fills.is_empty());
+    let backwards = statement(&mut users[2], until, 0).await;
+    assert_eq!(error_code(backwards.unwrap_err())?, ErrorCode::BadRequest);
+    let req = StatementReq {
+        from: 0,
+        to: until,
+        format: StatementFormat::Csv,
+    };
+    let csv = match users[2].request(NodeRequest::ReadStatement(req)).await? {
+        NodeResponse::StatementCsv(csv) => csv,
+        res => panic!("{res:?}"),
+    };
+    let mut rows = csv.lines();
+    assert_eq!(
+        rows.next(),
+        Some("section,at,type,ticker,currency,quantity,price,amount,fee,from,to")
+    );
+    let buy = format!(",buy,PNL,{DEFAULT_CURRENCY},4,10,40,");
+    assert!(rows.any(|row| row.starts_with("fill,") && row.contains(&buy)));
+    println!("Account statements");
+
+    // pipelined requests, collected in reverse order
+    let mut ids = Vec::new();
+    for _ in 0..100 {
+        ids.push(users[2].send(NodeRequest::ReadBalance).await?);
+        ids.push(users[2].send(NodeRequest::ReadMarket).await?);
+    }
+    for id in ids.into_iter().rev() {
+        let res = users[2].recv(id).await?;
+        assert!(
+            matches!(res, NodeResponse::Balance(_) | NodeResponse::Market(_)),
+            "{res:?}"
+        );
+    }
+    println!("200 pipelined requests answered");
+
+    // a pipelined order and its cancellation are handled in the order they were sent
+    let placed = users[2].send(order(OrderType::Buy, "PNL", 1, 1)).await?;
+    let cancelled = users[2]
+        .send(NodeRequest::DeleteOrder(OrderReq {
+            order_type: OrderType::Buy,
+            ticker: "PNL".to_owned(),
+            price: 1,
+            quantity: 1,
+        }))
+        .await?;
+    let res = users[2].recv(cancelled).await?;
+    assert!(matches!(res, NodeResponse::Deleted(1)), "{res:?}");
+    let res = users[2].recv(placed).await?;
+    assert!(matches!(res, NodeResponse::Ok), "{res:?}");
+    println!("Pipelined order placed before it's cancelled");
+
+    // an order can be cancelled before it's in its node's matcher, and the other node handles
+    // a remote order in its own task, so a cancellation right behind it can reach its matcher
+    // first. Neither leaves anything resting on the other node, nor keeps a later order from
+    // resting there
+    let mut ids = Vec::new();
+    for _ in 0..20 {
+        ids.push(users[1].send(order(OrderType::Sell, "PNL", 50, 1)).await?);
+        ids.push(
+            users[1]
+                .send(NodeRequest::DeleteOrder(OrderReq {
+                    order_type: OrderType::Sell,
+                    ticker: "PNL".to_owned(),
+                    price: 50,
+                    quantity: 1,
+                }))
+                .await?,
+        );
+    }
+    for id in ids {
+        users[1].recv(id).await?;
+    }
+    let resting_at_50 = |market: Vec<(Quantity, CentCount)>| -> Quantity {
+        market
+            .into_iter()
+            .filter(|&(_, price)| price == 50)
+            .map(|(quantity, _)| quantity)
+            .sum()
+    };
+    sleep(Duration::from_millis(500)).await;
+    let market = levels(
+        &mut users[2],
+        NodeRequest::ReadMarket,
+        OrderType::Sell,
+        "PNL",
+    )
+    .await?;
+    assert_eq!(resting_at_50(market), 0);
+    users[1]
+        .request(order(OrderType::Sell, "PNL", 50, 1))
+        .await?;
+    sleep(Duration::from_millis(500)).await;
+    let market = levels(
+        &mut users[2],
+        NodeRequest::ReadMarket,
+        OrderType::Sell,
+        "PNL",
+    )
+    .await?;
+    assert_eq!(resting_at_50(market), 1);
+    let req = NodeRequest::DeleteOrder(OrderReq {
+        order_type: OrderType::Sell,
+        ticker: "PNL".to_owned(),
+        price: 50,
+        quantity: 1,
+    });
+    let res = users[1].request(req).await?;
+    assert!(matches!(res, NodeResponse::Deleted(1)), "{res:?}");
+    println!("Orders cancelled on their way into a matcher don't rest");
+
+    // the node checks a new role at once, not at the next login
+    let mut coord = CoordinatorSession::new(coordinator, tls.as_ref()).await?;
+    coord.request(admin_login()).await?;
+    let req = CoordinatorRequest::SetRole(SetRole {
+        user_id: user_ids[1],
+        role: Role::Trader,
+    });
+    assert!(matches!(coord.request(req).await?, CoordinatorResponse::Ok));
+    coord.bye().await?;
+    let req = NodeRequest::CreateStock(StockReq {
+        ticker: "LATE".to_owned(),
+        quantity: 1,
+    });
+    let err = users[1].request(req).await.unwrap_err();
+    assert_eq!(error_code(err)?, ErrorCode::Forbidden);
+    println!("Demoted market operator can't list stock with its old session");
 
     // // basic same node trade
     // users[0]
@@ -148,9 +1343,10 @@ async fn main() -> GResult<()> {
     // println!("{}", users[1].read_line().await?);
 
     // say bye
-    for mut user in users {
-        user.write_line("\"bye\"").await?;
+    for user in users {
+        user.bye().await?;
     }
+    desk.bye().await?;
 
     Ok(())
 }